@@ -37,8 +37,7 @@ pub fn gen_base_aarch64(code: &mut Vec<u8>, i: &Ins) -> Result<(), Error> {
 
     writeln!(out, "{begin}").unwrap();
 
-    loop {
-        let Some(label) = lines.next() else { break };
+    while let Some(label) = lines.next() {
         let label = label.unwrap();
 
         if let (Some(start), Some(end)) = (label.find("<gen_"), label.find(">")) {
@@ -115,8 +114,7 @@ pub fn gen_vector_aarch64(code: &mut Vec<u8>, i: &Ins) -> Result<(), Error> {
 
     writeln!(out, "{begin}").unwrap();
 
-    loop {
-        let Some(line) = lines.next() else { break };
+    while let Some(line) = lines.next() {
         let line = line.unwrap();
 
         if let (Some(start), Some(end)) = (line.find("<gen_"), line.find(">")) {
@@ -166,7 +164,7 @@ pub fn build_x86_base() {
         panic!("{}", std::str::from_utf8(&out.stderr).unwrap());
     }
 
-    let mut lines = out.stdout
+    let lines = out.stdout
         .split(|b| *b == b'\n')
         .map(|line| std::str::from_utf8(line));
     let mut out = File::create("./src/aarch64/base.rs").unwrap();
@@ -189,11 +187,12 @@ pub fn gen_base_x86_64(code: &mut Vec<u8>, i: &Ins) -> Result<(), Error> {
 }").unwrap();"#;
 
     writeln!(out, "{begin}").unwrap();
-    loop {
-        let Some(line) = lines.next() else { break };
+    for line in lines {
         let line = line.unwrap();
 
-        let (Some(start), Some(end)) = (line.find("<gen_"), line.find(">")) else { continue };
+        if !line.contains("<gen_") || !line.contains('>') {
+            continue;
+        }
         println!("{line}");
     }
     writeln!(out, "{end}").unwrap();
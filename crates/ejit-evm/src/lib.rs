@@ -75,7 +75,7 @@ impl Compiler {
                     let Some(&imm) = data.get(pc) else { todo!() };
                     self.vstack.push(VElem::Constant([0, 0, 0, imm as u64]));
                     pc += 1;
-                    self.ins.extend([Movi(T0, 3), Add(GASREG, GASREG, T0)]);
+                    self.ins.extend([Addi(GASREG, GASREG, 3)]);
                 }
                 ADD => {
                     let (a, b) = self.vstack.top2();
@@ -90,7 +90,7 @@ impl Compiler {
                     self.gen_addr(T0, value);
                     self.gen_u64(T1, addr);
                     self.gen_mem_expand(T1);
-                    self.ins.extend([Movi(T0, 3), Add(GASREG, GASREG, T0)]);
+                    self.ins.extend([Addi(GASREG, GASREG, 3)]);
                     self.ins.extend([
                         Add(T1, T1, MEM),
                         Ld(U64, T2, T0, 0),
@@ -143,7 +143,7 @@ impl Compiler {
                 }
             }
             VElem::Bp(depth) => {
-                self.ins.extend([Movi(dest, depth as u64), Add(dest, BP, dest)]);
+                self.ins.extend([Addi(dest, BP, depth as u64)]);
             }
         };
     }
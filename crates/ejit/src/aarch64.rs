@@ -1,4 +1,4 @@
-use crate::{Cond, Error, Executable, Ins, Type, Vsize, R, V};
+use crate::{Cond, Error, Executable, Fixup, Ins, Labels, Type, Vsize, R, V};
 
 mod base;
 mod vector;
@@ -7,17 +7,101 @@ pub mod regs {
     use crate::R;
 
     // See https://github.com/ARM-software/abi-aa/blob/main/aapcs64/aapcs64.rst
+    // R(16) and R(17) are used as scratch registers by long branches and large immediates,
+    // V(31) by Popcnt.
     pub const ARG: [R; 8] = [R(0), R(1), R(2), R(3), R(4), R(5), R(6), R(7)];
     pub const RES: [R; 2] = [R(0), R(1)];
     pub const SP: R = R(31);
 }
 
+/// Portable register assignment, see [`crate::portable`].
+pub(crate) mod portable {
+    pub mod regs {
+        use crate::R;
+
+        pub const ARG: [R; 8] = [R(0), R(1), R(2), R(3), R(4), R(5), R(6), R(13)];
+        pub const RES: [R; 2] = [R(0), R(1)];
+        pub const SP: R = R(31);
+    }
+
+    /// x0-x6 caller-saved, x19-x24 callee-saved, then x7-x15 and x25-x28.
+    /// x16 and x17 are kept for the backend, x18 is the platform register.
+    pub const R_MAP: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 19, 20, 21, 22, 23, 24, 7, 8, 9, 10, 11, 12, 13, 14, 15, 25, 26, 27, 28];
+
+    /// The low halves of v8-v15 are callee-saved. v31 is kept for the backend.
+    pub const V_MAP: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30];
+
+    pub const SP: u8 = 31;
+
+    /// x19-x28.
+    pub const CALLEE_SAVED: &[u8] = &[19, 20, 21, 22, 23, 24, 25, 26, 27, 28];
+
+    /// x30 holds the return address and is overwritten by `Call`.
+    pub const LINK: Option<u8> = Some(30);
+}
+
+/// Optional instructions used when the host has them.
+#[derive(Clone, Copy, Debug)]
+struct Features {
+    dotprod: bool,
+}
+
+impl Features {
+    fn host() -> Self {
+        Self { dotprod: std::arch::is_aarch64_feature_detected!("dotprod") }
+    }
+}
+
 impl Executable {
     pub fn from_ir(ins: &[Ins]) -> Result<Executable, Error> {
+        let (code, labels) = Self::compile(ins)?;
+        Executable::new(&code, labels)
+    }
+
+    /// Generate the machine code and label offsets.
+    pub(crate) fn compile(ins: &[Ins]) -> Result<(Vec<u8>, Vec<(u32, usize)>), Error> {
+        Self::compile_for(ins, Features::host())
+    }
+
+    /// Generate code using only the optional instructions in `features`.
+    ///
+    /// Branches start short. Those which do not reach their labels are made longer
+    /// and the code is generated again until every branch reaches.
+    fn compile_for(ins: &[Ins], features: Features) -> Result<(Vec<u8>, Vec<(u32, usize)>), Error> {
+        let mut reach = vec![Reach::Short; ins.len()];
+        loop {
+            let mut far = Vec::new();
+            let res = Self::compile_with(ins, &reach, &mut far, features)?;
+            if far.is_empty() {
+                return Ok(res);
+            }
+            for index in far {
+                reach[index] = match (&ins[index], reach[index]) {
+                    (Ins::B(..) | Ins::Cbz(..) | Ins::Cbnz(..) | Ins::Tbz(..) | Ins::Tbnz(..) | Ins::Bcmp(..), Reach::Short) => {
+                        Reach::Medium
+                    }
+                    _ => Reach::Long,
+                };
+            }
+        }
+    }
+
+    /// Generate code with the given branch sizes, returning the index of each branch that does not reach.
+    fn compile_with(ins: &[Ins], reach: &[Reach], far: &mut Vec<usize>, features: Features) -> Result<(Vec<u8>, Vec<(u32, usize)>), Error> {
         let mut code = Vec::new();
-        let mut labels: Vec<(u32, usize)> = Vec::new();
-        let mut fixups: Vec<(usize, Fixup)> = Vec::new();
-        for i in ins {
+        let mut labels = Labels::new(ins.len());
+
+        // The location of each fixup and the index of its instruction.
+        let mut sites: Vec<(usize, usize)> = Vec::new();
+        let mut far_locs = Vec::new();
+        let mut patch = |code: &mut [u8], loc: usize, f: Fixup, offset: usize| match patch(code, loc, f, offset) {
+            Err(Error::BranchOutOfRange(_)) => {
+                far_locs.push(loc);
+                Ok(())
+            }
+            res => res,
+        };
+        for (index, i) in ins.iter().enumerate() {
             use Ins::*;
             // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions
             match i {
@@ -25,11 +109,460 @@ impl Executable {
                     base::gen_base_aarch64(&mut code, &i)?;
                 }
                 
-                Label(label) => labels.push((*label, code.len())),
+                Addi(dest, src, imm) => gen_addi(&mut code, dest, src, *imm, false, i)?,
+                Subi(dest, src, imm) => gen_addi(&mut code, dest, src, *imm, true, i)?,
+                Andi(dest, src, imm) => gen_logici(&mut code, 0x92000000, 0x8a000000, dest, src, *imm, i)?,
+                Ori(dest, src, imm) => gen_logici(&mut code, 0xb2000000, 0xaa000000, dest, src, *imm, i)?,
+                Xori(dest, src, imm) => gen_logici(&mut code, 0xd2000000, 0xca000000, dest, src, *imm, i)?,
+                Shli(dest, src, imm) => {
+                    // D37CEC20          lsl x0, x1, #4
+                    let imm = (*imm & 63) as u32;
+                    let opcode = 0xd3400000 | ((64 - imm) & 63) << 16 | (63 - imm) << 10 | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                Shri(dest, src, imm) => {
+                    // D344FC20          lsr x0, x1, #4
+                    let opcode = 0xd340fc00 | ((*imm & 63) as u32) << 16 | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                Sari(dest, src, imm) => {
+                    // 9344FC20          asr x0, x1, #4
+                    let opcode = 0x9340fc00 | ((*imm & 63) as u32) << 16 | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+
+                // AB020020          adds x0, x1, x2
+                // BA020020          adcs x0, x1, x2
+                // EB020020          subs x0, x1, x2
+                // FA020020          sbcs x0, x1, x2
+                Adds(dest, src1, src2) => gen3(&mut code, 0xab000000, dest, src1, src2, i)?,
+                Adc(dest, src1, src2) => gen3(&mut code, 0xba000000, dest, src1, src2, i)?,
+                Subs(dest, src1, src2) => gen3(&mut code, 0xeb000000, dest, src1, src2, i)?,
+                Sbc(dest, src1, src2) => gen3(&mut code, 0xfa000000, dest, src1, src2, i)?,
+
+                // 9BC27C20          umulh x0, x1, x2
+                // 9B427C20          smulh x0, x1, x2
+                UMulH(dest, src1, src2) => gen3(&mut code, 0x9bc07c00, dest, src1, src2, i)?,
+                SMulH(dest, src1, src2) => gen3(&mut code, 0x9b407c00, dest, src1, src2, i)?,
+                UMulL(lo, hi, src1, src2) => gen_mull(&mut code, 0x9bc07c00, lo, hi, src1, src2, i)?,
+                SMulL(lo, hi, src1, src2) => gen_mull(&mut code, 0x9b407c00, lo, hi, src1, src2, i)?,
+                URem(dest, src1, src2) => gen_rem(&mut code, 0x9ac00800, dest, src1, src2, i)?,
+                SRem(dest, src1, src2) => gen_rem(&mut code, 0x9ac00c00, dest, src1, src2, i)?,
+
+                Sext(ty, dest, src) => {
+                    // 93401C20          sxtb x0, w1
+                    // 93403C20          sxth x0, w1
+                    // 93407C20          sxtw x0, w1
+                    let opcode = match ty {
+                        Type::U8 | Type::S8 => 0x93401c00,
+                        Type::U16 | Type::S16 => 0x93403c00,
+                        Type::U32 | Type::S32 => 0x93407c00,
+                        _ => return Err(Error::InvalidType(i.clone())),
+                    };
+                    gen2_rn(&mut code, opcode, dest, src);
+                }
+                Zext(ty, dest, src) => {
+                    // D3401C20          ubfx x0, x1, #0, #8
+                    // D3403C20          ubfx x0, x1, #0, #16
+                    // D3407C20          ubfx x0, x1, #0, #32
+                    let opcode = match ty {
+                        Type::U8 | Type::S8 => 0xd3401c00,
+                        Type::U16 | Type::S16 => 0xd3403c00,
+                        Type::U32 | Type::S32 => 0xd3407c00,
+                        _ => return Err(Error::InvalidType(i.clone())),
+                    };
+                    gen2_rn(&mut code, opcode, dest, src);
+                }
+                // DAC01020          clz x0, x1
+                Clz(dest, src) => gen2_rn(&mut code, 0xdac01000, dest, src),
+                Ctz(dest, src) => {
+                    // DAC00020          rbit x0, x1
+                    // DAC01000          clz x0, x0
+                    gen2_rn(&mut code, 0xdac00000, dest, src);
+                    gen2_rn(&mut code, 0xdac01000, dest, dest);
+                }
+                Popcnt(dest, src) => {
+                    // 9E67003F          fmov d31, x1
+                    // 0E205BFF          cnt v31.8b, v31.8b
+                    // 0E31BBFF          addv b31, v31.8b
+                    // 9E6603E0          fmov x0, d31
+                    code.extend((0x9e67001f | src.to_aarch64() << 5).to_le_bytes());
+                    code.extend(0x0e205bff_u32.to_le_bytes());
+                    code.extend(0x0e31bbff_u32.to_le_bytes());
+                    code.extend((0x9e6603e0 | dest.to_aarch64()).to_le_bytes());
+                }
+                Bswap(ty, dest, src) => {
+                    // DAC00C20          rev x0, x1
+                    // 5AC00820          rev w0, w1
+                    // D370FC00          lsr x0, x0, #48
+                    let (opcode, shift) = match ty {
+                        Type::U64 | Type::S64 => (0xdac00c00, 0),
+                        Type::U32 => (0x5ac00800, 0),
+                        Type::U16 => (0xdac00c00, 0xd340fc00 | 48 << 16),
+                        Type::S32 => (0xdac00c00, 0x9340fc00 | 32 << 16),
+                        Type::S16 => (0xdac00c00, 0x9340fc00 | 48 << 16),
+                        _ => return Err(Error::InvalidType(i.clone())),
+                    };
+                    gen2_rn(&mut code, opcode, dest, src);
+                    if shift != 0 {
+                        gen2_rn(&mut code, shift, dest, dest);
+                    }
+                }
+                Rol(dest, src1, src2) => {
+                    // CB1003F0          neg x16, x2
+                    // 9AD02C20          ror x0, x1, x16
+                    let tmp = scratch(src1, src2);
+                    gen2(&mut code, 0xcb0003e0, &tmp, src2, i)?;
+                    gen3(&mut code, 0x9ac02c00, dest, src1, &tmp, i)?;
+                }
+                // 9AC22C20          ror x0, x1, x2
+                Ror(dest, src1, src2) => gen3(&mut code, 0x9ac02c00, dest, src1, src2, i)?,
+                Ubfx(dest, src, lsb, width) => {
+                    // D3441C20          ubfx x0, x1, #4, #4
+                    check_bitfield(*lsb, *width, i)?;
+                    let (lsb, width) = (*lsb as u32, *width as u32);
+                    gen2_rn(&mut code, 0xd3400000 | lsb << 16 | (lsb + width - 1) << 10, dest, src);
+                }
+                Sbfx(dest, src, lsb, width) => {
+                    // 93441C20          sbfx x0, x1, #4, #4
+                    check_bitfield(*lsb, *width, i)?;
+                    let (lsb, width) = (*lsb as u32, *width as u32);
+                    gen2_rn(&mut code, 0x93400000 | lsb << 16 | (lsb + width - 1) << 10, dest, src);
+                }
+                Bfi(dest, src, lsb, width) => {
+                    // B37C0C20          bfi x0, x1, #4, #4
+                    check_bitfield(*lsb, *width, i)?;
+                    let (lsb, width) = (*lsb as u32, *width as u32);
+                    gen2_rn(&mut code, 0xb3400000 | ((64 - lsb) & 63) << 16 | (width - 1) << 10, dest, src);
+                }
+
+                // 1E222820          fadd s0, s1, s2
+                // 1E623820          fsub d0, d1, d2
+                Fadd(ty, dest, src1, src2) => vgen3(&mut code, 0x1e202800 | ftype(*ty, i)?, dest, src1, src2, i)?,
+                Fsub(ty, dest, src1, src2) => vgen3(&mut code, 0x1e203800 | ftype(*ty, i)?, dest, src1, src2, i)?,
+                Fmul(ty, dest, src1, src2) => vgen3(&mut code, 0x1e200800 | ftype(*ty, i)?, dest, src1, src2, i)?,
+                Fdiv(ty, dest, src1, src2) => vgen3(&mut code, 0x1e201800 | ftype(*ty, i)?, dest, src1, src2, i)?,
+                Fmin(ty, dest, src1, src2) => vgen3(&mut code, 0x1e205800 | ftype(*ty, i)?, dest, src1, src2, i)?,
+                Fmax(ty, dest, src1, src2) => vgen3(&mut code, 0x1e204800 | ftype(*ty, i)?, dest, src1, src2, i)?,
+                // 1E21C020          fsqrt s0, s1
+                // 1E60C020          fabs d0, d1
+                Fsqrt(ty, dest, src) => vgen2(&mut code, 0x1e21c000 | ftype(*ty, i)?, dest, src, i)?,
+                Fabs(ty, dest, src) => vgen2(&mut code, 0x1e20c000 | ftype(*ty, i)?, dest, src, i)?,
+                Fma(ty, dest, src1, src2, src3) => {
+                    // 1F420C20          fmadd s0, s1, s2, s3
+                    let opcode = 0x1f000000 | ftype(*ty, i)? | src3.to_aarch64() << 10;
+                    vgen3(&mut code, opcode, dest, src1, src2, i)?;
+                }
+                Fcmp(ty, src1, src2) => {
+                    // 1E622020          fcmp d1, d2
+                    let opcode = 0x1e202000 | ftype(*ty, i)? | src2.to_aarch64() << 16 | src1.to_aarch64() << 5;
+                    code.extend(opcode.to_le_bytes());
+                }
+                Fcvt(dty, sty, dest, src) => {
+                    // 1E22C020          fcvt d0, s1
+                    // 1EE24020          fcvt s0, h1
+                    // 1E63C020          fcvt h0, d1
+                    let fcvt_type = |ty: &Type| match ty {
+                        Type::F32 => Ok(0),
+                        Type::F64 => Ok(1),
+                        Type::F16 => Ok(3),
+                        _ => Err(Error::VectorTypeNotSupported(i.clone())),
+                    };
+                    let (dty, sty) = (fcvt_type(dty)?, fcvt_type(sty)?);
+                    if dty == sty {
+                        // fmov h needs FEAT_FP16, move the whole of s instead.
+                        vgen2(&mut code, 0x1e204000 | (sty & 1) << 22, dest, src, i)?;
+                    } else {
+                        vgen2(&mut code, 0x1e224000 | sty << 22 | dty << 15, dest, src, i)?;
+                    }
+                }
+                Scvtf(ty, dest, src) => {
+                    // 9E220020          scvtf s0, x1
+                    let opcode = 0x9e220000 | ftype(*ty, i)? | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                Fcvtzs(ty, dest, src) => {
+                    // 9E780020          fcvtzs x0, d1
+                    let opcode = 0x9e380000 | ftype(*ty, i)? | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+
+                VmovFromR(ty, dest, src, lane) => {
+                    // 4E1D1C20          mov v0.b[14], w1
+                    let imm5 = lane_imm5(*ty, *lane, i)?;
+                    let opcode = 0x4e001c00 | imm5 << 16 | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                VmovToR(ty, dest, src, lane) => {
+                    // 0E0A3C20          umov w0, v1.h[2]
+                    // 4E183C20          mov x0, v1.d[1]
+                    // 4E0C2C20          smov x0, v1.s[1]
+                    let imm5 = lane_imm5(*ty, *lane, i)?;
+                    let opcode = match ty {
+                        Type::S8 | Type::S16 | Type::S32 => 0x4e002c00,
+                        Type::U64 | Type::S64 | Type::F64 => 0x4e003c00,
+                        _ => 0x0e003c00,
+                    };
+                    let opcode = opcode | imm5 << 16 | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                Vdup(ty, vsize, dest, src) => {
+                    // 4E080C20          dup v0.2d, x1
+                    // 0E010C20          dup v0.8b, w1
+                    // 9E670020          fmov d0, x1
+                    let imm5 = lane_imm5(*ty, 0, i)?;
+                    let opcode = match (vsize, imm5) {
+                        (Vsize::V64, 8) => 0x9e670000,
+                        (Vsize::V32, 8) => return Err(Error::VectorSizeNotSupported(i.clone())),
+                        (Vsize::V32 | Vsize::V64, _) => 0x0e000c00 | imm5 << 16,
+                        (Vsize::V128, _) => 0x4e000c00 | imm5 << 16,
+                        _ => return Err(Error::VectorSizeNotSupported(i.clone())),
+                    };
+                    let opcode = opcode | src.to_aarch64() << 5 | dest.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+
+                Vcmp(_, Type::F16, ..) | Vmin(Type::F16, ..) | Vmax(Type::F16, ..) | Vabs(Type::F16, ..) => {
+                    return Err(Error::VectorTypeNotSupported(i.clone()));
+                }
+                Vcmp(cond, ty @ (Type::F32 | Type::F64), vsize, dest, src1, src2) => {
+                    // 4E22E420          fcmeq v0.4s, v1.4s, v2.4s
+                    // 6EA2E420          fcmgt v0.4s, v1.4s, v2.4s
+                    // 6E22E420          fcmge v0.4s, v1.4s, v2.4s
+                    // The negations are true if unordered.
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    let (fcmeq, fcmgt, fcmge) = (0x0e20e400, 0x2ea0e400, 0x2e20e400);
+                    let (opcode, src1, src2, negate) = match cond {
+                        Cond::Eq => (fcmeq, src1, src2, false),
+                        Cond::Ne => (fcmeq, src1, src2, true),
+                        Cond::Sgt => (fcmgt, src1, src2, false),
+                        Cond::Sge => (fcmge, src1, src2, false),
+                        Cond::Ult => (fcmgt, src2, src1, false),
+                        Cond::Ule => (fcmge, src2, src1, false),
+                        Cond::Slt => (fcmge, src1, src2, true),
+                        Cond::Sle => (fcmgt, src1, src2, true),
+                        Cond::Ugt => (fcmge, src2, src1, true),
+                        Cond::Uge => (fcmgt, src2, src1, true),
+                        _ => return Err(Error::UnsupportedVectorOperation(i.clone())),
+                    };
+                    vgen3(&mut code, opcode | q | (shift & 1) << 22, dest, src1, src2, i)?;
+                    if negate {
+                        gen_vnot(&mut code, q, dest, i)?;
+                    }
+                }
+                Vcmp(cond, ty, vsize, dest, src1, src2) => {
+                    // 6E228C20          cmeq v0.16b, v1.16b, v2.16b
+                    // 4E623420          cmgt v0.8h, v1.8h, v2.8h
+                    // 6EA23C20          cmhs v0.4s, v1.4s, v2.4s
+                    // 7EE23420          cmhi d0, d1, d2
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    let (cmeq, cmgt, cmge, cmhi, cmhs) = (0x2e208c00, 0x0e203400, 0x0e203c00, 0x2e203400, 0x2e203c00);
+                    let (opcode, src1, src2, negate) = match cond {
+                        Cond::Eq => (cmeq, src1, src2, false),
+                        Cond::Ne => (cmeq, src1, src2, true),
+                        Cond::Sgt => (cmgt, src1, src2, false),
+                        Cond::Sge => (cmge, src1, src2, false),
+                        Cond::Slt => (cmgt, src2, src1, false),
+                        Cond::Sle => (cmge, src2, src1, false),
+                        Cond::Ugt => (cmhi, src1, src2, false),
+                        Cond::Uge => (cmhs, src1, src2, false),
+                        Cond::Ult => (cmhi, src2, src1, false),
+                        Cond::Ule => (cmhs, src2, src1, false),
+                        _ => return Err(Error::UnsupportedVectorOperation(i.clone())),
+                    };
+                    vgen3(&mut code, opcode | q | shift << 22, dest, src1, src2, i)?;
+                    if negate {
+                        gen_vnot(&mut code, q, dest, i)?;
+                    }
+                }
+                Vbsl(ty, vsize, dest, mask, src1, src2) => {
+                    let (q, _) = vshape(*ty, *vsize, i)?;
+                    gen_bsl(&mut code, bitwise_q(q), dest, mask, src1, src2, i)?;
+                }
+                Vblend(ty, vsize, dest, mask, src1, src2) => {
+                    // Spread the top bit of each lane of the mask.
+                    // 4F070420          sshr v0.16b, v1.16b, #7
+                    // 5F410420          sshr d0, d1, #63
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    let bits = if dest != src1 && dest != src2 { dest } else { &V(31) };
+                    vgen2(&mut code, 0x0f000400 | q | ((8 << shift) + 1) << 16, bits, mask, i)?;
+                    gen_bsl(&mut code, bitwise_q(q), dest, bits, src1, src2, i)?;
+                }
+                Vmin(ty @ (Type::F32 | Type::F64), vsize, dest, src1, src2) | Vmax(ty @ (Type::F32 | Type::F64), vsize, dest, src1, src2) => {
+                    // 4EA2F420          fmin v0.4s, v1.4s, v2.4s
+                    // 1E624820          fmax d0, d1, d2
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    let opcode = match (i, q) {
+                        (Vmin(..), SCALAR) => 0x1e605800,
+                        (Vmax(..), SCALAR) => 0x1e604800,
+                        (Vmin(..), _) => 0x0ea0f400 | q | (shift & 1) << 22,
+                        _ => 0x0e20f400 | q | (shift & 1) << 22,
+                    };
+                    vgen3(&mut code, opcode, dest, src1, src2, i)?;
+                }
+                Vmin(ty, vsize, dest, src1, src2) | Vmax(ty, vsize, dest, src1, src2) => {
+                    // 4E226C20          smin v0.16b, v1.16b, v2.16b
+                    // 6EA26420          umax v0.4s, v1.4s, v2.4s
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    let opcode = match (i, ty) {
+                        (_, Type::S64 | Type::U64) => return Err(Error::VectorTypeNotSupported(i.clone())),
+                        (Vmin(..), Type::S8 | Type::S16 | Type::S32) => 0x0e206c00,
+                        (Vmin(..), _) => 0x2e206c00,
+                        (_, Type::S8 | Type::S16 | Type::S32) => 0x0e206400,
+                        _ => 0x2e206400,
+                    };
+                    vgen3(&mut code, opcode | q | shift << 22, dest, src1, src2, i)?;
+                }
+                Vabs(ty @ (Type::F32 | Type::F64), vsize, dest, src) => {
+                    // 4EE0F820          fabs v0.2d, v1.2d
+                    // 1E60C020          fabs d0, d1
+                    let opcode = match vshape(*ty, *vsize, i)? {
+                        (SCALAR, _) => 0x1e60c000,
+                        (q, shift) => 0x0ea0f800 | q | (shift & 1) << 22,
+                    };
+                    vgen2(&mut code, opcode, dest, src, i)?;
+                }
+                Vabs(ty, vsize, dest, src) => {
+                    // 4E20B820          abs v0.16b, v1.16b
+                    // 5EE0B820          abs d0, d1
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    vgen2(&mut code, 0x0e20b800 | q | shift << 22, dest, src, i)?;
+                }
+
+                Vtbl(vsize, dest, table, index) => {
+                    // 4E020020          tbl v0.16b, { v1.16b }, v2.16b
+                    let (q, _) = permute_shape(Type::U8, *vsize, 0, i)?;
+                    vgen3(&mut code, 0x0e000000 | q, dest, table, index, i)?;
+                }
+                Vzip(ty, vsize, dest, src1, src2, part) | Vuzp(ty, vsize, dest, src1, src2, part) | Vtrn(ty, vsize, dest, src1, src2, part) => {
+                    // 4E823820          zip1 v0.4s, v1.4s, v2.4s
+                    // 4E425820          uzp2 v0.8h, v1.8h, v2.8h
+                    // 0E022820          trn1 v0.8b, v1.8b, v2.8b
+                    let (q, shift) = permute_shape(*ty, *vsize, *part, i)?;
+                    let opcode = match i {
+                        Vzip(..) => 0x0e003800,
+                        Vuzp(..) => 0x0e001800,
+                        _ => 0x0e002800,
+                    };
+                    vgen3(&mut code, opcode | q | shift << 22 | (*part as u32) << 14, dest, src1, src2, i)?;
+                }
+                Vext(ty, vsize, dest, src1, src2, n) => {
+                    // 6E022020          ext v0.16b, v1.16b, v2.16b, #4
+                    let (q, shift) = permute_shape(*ty, *vsize, 0, i)?;
+                    let bytes = (*n as u32) << shift;
+                    if bytes >= if q == 0 { 8 } else { 16 } {
+                        return Err(Error::InvalidImmediate(i.clone()));
+                    }
+                    vgen3(&mut code, 0x2e000000 | q | bytes << 11, dest, src1, src2, i)?;
+                }
+                Vrev(ty, vsize, dest, src) => {
+                    // Reverse within 64 bits, then swap the halves.
+                    // 4E200820          rev64 v0.16b, v1.16b
+                    // 6E004000          ext v0.16b, v0.16b, v0.16b, #8
+                    let (q, shift) = permute_shape(*ty, *vsize, 0, i)?;
+                    let mut src = src;
+                    if shift < 3 {
+                        vgen2(&mut code, 0x0e200800 | q | shift << 22, dest, src, i)?;
+                        src = dest;
+                    }
+                    if q != 0 {
+                        vgen3(&mut code, 0x6e004000, dest, src, src, i)?;
+                    }
+                }
+
+                Vqadd(ty, vsize, dest, src1, src2) | Vqsub(ty, vsize, dest, src1, src2) => {
+                    // 4E220C20          sqadd v0.16b, v1.16b, v2.16b
+                    // 2E622C20          uqsub v0.4h, v1.4h, v2.4h
+                    let (q, shift) = vshape(*ty, *vsize, i)?;
+                    let opcode = match (i, ty) {
+                        (Vqadd(..), Type::S8 | Type::S16) => 0x0e200c00,
+                        (Vqadd(..), Type::U8 | Type::U16) => 0x2e200c00,
+                        (Vqsub(..), Type::S8 | Type::S16) => 0x0e202c00,
+                        (Vqsub(..), Type::U8 | Type::U16) => 0x2e202c00,
+                        _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+                    };
+                    vgen3(&mut code, opcode | q | shift << 22, dest, src1, src2, i)?;
+                }
+                Vaddl(ty, vsize, dest, src1, src2) | Vmull(ty, vsize, dest, src1, src2) => {
+                    // 0E220020          saddl v0.8h, v1.8b, v2.8b
+                    // 2EA2C020          umull v0.2d, v1.2s, v2.2s
+                    let (shift, signed) = wide_shape(*ty, *vsize, i)?;
+                    let opcode = match (i, signed) {
+                        (Vaddl(..), true) => 0x0e200000,
+                        (Vaddl(..), false) => 0x2e200000,
+                        (_, true) => 0x0e20c000,
+                        (_, false) => 0x2e20c000,
+                    };
+                    vgen3(&mut code, opcode | (shift - 1) << 22, dest, src1, src2, i)?;
+                }
+                Vxtn(ty, vsize, dest, src) | Vqmovn(ty, vsize, dest, src) => {
+                    // 0E212820          xtn v0.8b, v1.8h
+                    // 0E614820          sqxtn v0.4h, v1.4s
+                    // 2EA14820          uqxtn v0.2s, v1.2d
+                    let (shift, signed) = wide_shape(*ty, *vsize, i)?;
+                    let opcode = match (i, signed) {
+                        (Vxtn(..), _) => 0x0e212800,
+                        // For the same results as x86, which has no 64 bit min or max.
+                        _ if shift == 3 => return Err(Error::VectorTypeNotSupported(i.clone())),
+                        (_, true) => 0x0e214800,
+                        (_, false) => 0x2e214800,
+                    };
+                    vgen2(&mut code, opcode | (shift - 1) << 22, dest, src, i)?;
+                }
+                Vaddv(ty, vsize, dest, src) => {
+                    // 4E31B820          addv b0, v1.16b
+                    // 0EA2BC20          addp v0.2s, v1.2s, v1.2s
+                    // 5EF1B820          addp d0, v1.2d
+                    if let Type::F16 | Type::F32 | Type::F64 = ty {
+                        return Err(Error::VectorTypeNotSupported(i.clone()));
+                    }
+                    match permute_shape(*ty, *vsize, 0, i)? {
+                        (0, 2) => vgen3(&mut code, 0x0ea0bc00, dest, src, src, i)?,
+                        (_, 3) => vgen2(&mut code, 0x5ef1b800, dest, src, i)?,
+                        (q, shift) => vgen2(&mut code, 0x0e31b800 | q | shift << 22, dest, src, i)?,
+                    }
+                }
+                Vaddp(ty, vsize, dest, src1, src2) => {
+                    // 4E22BC20          addp v0.16b, v1.16b, v2.16b
+                    // 6E62D420          faddp v0.2d, v1.2d, v2.2d
+                    let (q, shift) = permute_shape(*ty, *vsize, 0, i)?;
+                    let opcode = match ty {
+                        Type::F16 => return Err(Error::VectorTypeNotSupported(i.clone())),
+                        Type::F32 | Type::F64 => 0x2e20d400 | q | (shift & 1) << 22,
+                        _ => 0x0e20bc00 | q | shift << 22,
+                    };
+                    vgen3(&mut code, opcode, dest, src1, src2, i)?;
+                }
+                Vdot(ty, vsize, dest, src1, src2) => {
+                    // 4E829420          sdot v0.4s, v1.16b, v2.16b
+                    // 2E829420          udot v0.2s, v1.8b, v2.8b
+                    let opcode = match ty {
+                        Type::S8 => 0x0e809400,
+                        Type::U8 => 0x2e809400,
+                        _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+                    };
+                    let q = match vsize {
+                        Vsize::V64 => 0,
+                        Vsize::V128 => 1 << 30,
+                        _ => return Err(Error::VectorSizeNotSupported(i.clone())),
+                    };
+                    if !features.dotprod {
+                        return Err(Error::UnsupportedOperation(i.clone()));
+                    }
+                    vgen3(&mut code, opcode | q, dest, src1, src2, i)?;
+                }
+
+                Label(label) => labels.define(*label, &mut code, &mut patch)?,
 
                 Addr(dest, label) => {
-                    fixups.push((code.len(), Fixup::Adr(*dest, *label)));
-                    code.extend(0x10000000_u32.to_le_bytes());
+                    if reach[index] == Reach::Short {
+                        code.extend(0x10000000_u32.to_le_bytes());
+                        sites.push((code.len() - 4, index));
+                        labels.fixup(code.len() - 4, Fixup::Adr(*dest, *label), &mut code, &mut patch)?;
+                    } else {
+                        gen_veneer(&mut code, dest.to_aarch64(), false);
+                        labels.fixup(code.len() - 8, Fixup::Rel64(*label), &mut code, &mut patch)?;
+                    }
                 }
                 Call(target) => {
                     let opcode = 0xd63f0000_u32 | target.to_aarch64() << 5;
@@ -39,30 +572,97 @@ impl Executable {
                     let opcode = 0xd61f0000_u32 | target.to_aarch64() << 5;
                     code.extend(opcode.to_le_bytes());
                 }
-                B(cond, label) => {
-                    fixups.push((code.len(), Fixup::B(*cond, *label)));
-                    code.extend(0x10000000_u32.to_le_bytes());
+                B(..) | Cbz(..) | Cbnz(..) | Tbz(..) | Tbnz(..) | Bcmp(..) => {
+                    // The branch, with a zero offset, and its inverse.
+                    let (opcode, inverse, fixup) = match i {
+                        B(cond, label) => {
+                            let cc = cond.to_aarch64();
+                            (0x54000000 | cc, 0x54000000 | cc ^ 1, Fixup::B(*cond, *label))
+                        }
+                        Bcmp(cond, src1, src2, label) => {
+                            // EB02003F          cmp x1, x2
+                            gen_cmp(&mut code, 0xeb00001f, src1, src2, i)?;
+                            let cc = cond.to_aarch64();
+                            (0x54000000 | cc, 0x54000000 | cc ^ 1, Fixup::B(*cond, *label))
+                        }
+                        // B4000000          cbz x0, label
+                        // B5000000          cbnz x0, label
+                        Cbz(src, label) => (0xb4000000 | src.to_aarch64(), 0xb5000000 | src.to_aarch64(), Fixup::Imm19(*label)),
+                        Cbnz(src, label) => (0xb5000000 | src.to_aarch64(), 0xb4000000 | src.to_aarch64(), Fixup::Imm19(*label)),
+                        // B6F80000          tbz x0, #63, label
+                        // 37000000          tbnz w0, #0, label
+                        Tbz(src, bit, label) | Tbnz(src, bit, label) => {
+                            if *bit >= 64 {
+                                return Err(Error::InvalidImmediate(i.clone()));
+                            }
+                            let bit = *bit as u32;
+                            let opcode = (bit >> 5) << 31 | (bit & 31) << 19 | src.to_aarch64();
+                            let (tbz, tbnz) = (0x36000000 | opcode, 0x37000000 | opcode);
+                            if matches!(i, Tbz(..)) {
+                                (tbz, tbnz, Fixup::Imm14(*label))
+                            } else {
+                                (tbnz, tbz, Fixup::Imm14(*label))
+                            }
+                        }
+                        _ => unreachable!(),
+                    };
+                    match reach[index] {
+                        Reach::Short => {
+                            code.extend(opcode.to_le_bytes());
+                            sites.push((code.len() - 4, index));
+                            labels.fixup(code.len() - 4, fixup, &mut code, &mut patch)?;
+                        }
+                        Reach::Medium => {
+                            // 54000041    b.ne #8
+                            // 14000000    b label
+                            code.extend((inverse | 2 << 5).to_le_bytes());
+                            code.extend(0x14000000_u32.to_le_bytes());
+                            sites.push((code.len() - 4, index));
+                            labels.fixup(code.len() - 4, Fixup::J(fixup.label()), &mut code, &mut patch)?;
+                        }
+                        Reach::Long => {
+                            // 540000e1    b.ne #28
+                            code.extend((inverse | 7 << 5).to_le_bytes());
+                            gen_veneer(&mut code, 16, true);
+                            labels.fixup(code.len() - 8, Fixup::Rel64(fixup.label()), &mut code, &mut patch)?;
+                        }
+                    }
                 }
                 J(label) => {
-                    fixups.push((code.len(), Fixup::J(*label)));
-                    code.extend(0x14000000_u32.to_le_bytes());
+                    if reach[index] == Reach::Short {
+                        code.extend(0x14000000_u32.to_le_bytes());
+                        sites.push((code.len() - 4, index));
+                        labels.fixup(code.len() - 4, Fixup::J(*label), &mut code, &mut patch)?;
+                    } else {
+                        gen_veneer(&mut code, 16, true);
+                        labels.fixup(code.len() - 8, Fixup::Rel64(*label), &mut code, &mut patch)?;
+                    }
                 }
                 Ret => {
                     code.extend(0xd65f03c0_u32.to_le_bytes());
                 }
                 Sel(cond, d, t, f) => {
-                    let opcode: u32 = match cond {
-                        Cond::Eq => 0x9a800000,
-                        Cond::Ne => 0x9a801000,
-                        Cond::Sgt => 0x9a80C000,
-                        Cond::Sge => 0x9a80A000,
-                        Cond::Slt => 0x9a80B000,
-                        Cond::Sle => 0x9a80D000,
-                        Cond::Ugt => 0x9a808000,
-                        Cond::Uge => 0x9a802000,
-                        Cond::Ult => 0x9a803000,
-                        Cond::Ule => 0x9a809000,
+                    // 9A800000          csel x0, x0, x0, eq
+                    let opcode = 0x9a800000 | cond.to_aarch64() << 12;
+                    let opcode =
+                        opcode | f.to_aarch64() << 16 | t.to_aarch64() << 5 | d.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                Cset(cond, d) => {
+                    // 9A9F17E0          cset x0, eq
+                    let opcode = 0x9a9f07e0 | (cond.to_aarch64() ^ 1) << 12 | d.to_aarch64();
+                    code.extend(opcode.to_le_bytes());
+                }
+                Csinc(cond, d, t, f) | Csneg(cond, d, t, f) | Csinv(cond, d, t, f) => {
+                    // 9A800400          csinc x0, x0, x0, eq
+                    // DA800400          csneg x0, x0, x0, eq
+                    // DA800000          csinv x0, x0, x0, eq
+                    let opcode = match i {
+                        Csinc(..) => 0x9a800400,
+                        Csneg(..) => 0xda800400,
+                        _ => 0xda800000,
                     };
+                    let opcode = opcode | cond.to_aarch64() << 12;
                     let opcode =
                         opcode | f.to_aarch64() << 16 | t.to_aarch64() << 5 | d.to_aarch64();
                     code.extend(opcode.to_le_bytes());
@@ -90,56 +690,20 @@ impl Executable {
                     code.extend(opcode.to_le_bytes());
                 }
                 Ld(ty, r, ra, imm) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/LDR--register---Load-register--register--?lang=en
-                    use Type::*;
-                    let (shift, opcode) = match ty {
-                        U8 => (0, 0x39400000),  // 00004039 	    ldrb w0, [x0, #0]
-                        U16 => (1, 0x79400000), // 00004079 	    ldrh w0, [x0, #0]
-                        U32 => (2, 0xb9400000), // 000040B9 	    ldr w0, [x0, #0]
-                        U64 => (3, 0xf9400000), // 000040F9 	    ldr x0, [x0, #0]
-                        S8 => (0, 0x39c00000),  // 0000C039 	    ldrsb w0, [x0, #0]
-                        S16 => (1, 0x79c00000), // 0000C079 	    ldrsh w0, [x0, #0]
-                        S32 => (2, 0xb9800000), // 000080B9 	    ldrsw x0, [x0, #0]
-                        S64 => (3, 0xf9400000), // 000040F9 	    ldr x0, [x0, #0]
-                        _ => return Err(Error::InvalidType(i.clone())),
-                    };
-                    if *imm >> shift << shift != *imm {
-                        return Err(Error::InvalidImmediate(i.clone()));
-                    }
-                    if *imm >> shift >= 0x1000 {
-                        return Err(Error::InvalidImmediate(i.clone()));
-                    }
-
-                    let opcode = opcode
-                        | ((*imm >> shift) as u32) << 10
-                        | ra.to_aarch64() << 5
-                        | r.to_aarch64();
-                    code.extend(opcode.to_le_bytes());
+                    let (shift, opcode) = ld_opcode(*ty, i)?;
+                    gen_ldst_imm(&mut code, opcode, shift, r, ra, *imm, i)?;
                 }
                 St(ty, r, ra, imm) => {
-                    use Type::*;
-                    let (shift, opcode) = match ty {
-                        U8 => (0, 0x39000000),  // 00000039 	    strb w0, [x0, #0]
-                        U16 => (1, 0x79000000), // 00000079 	    strh w0, [x0, #0]
-                        U32 => (2, 0xB9000000), // 000000B9 	    str w0, [x0, #0]
-                        U64 => (3, 0xF9000000), // 000000F9 	    str x0, [x0, #0]
-                        S8 => (0, 0x39000000),  // 00000039 	    strb w0, [x0, #0]
-                        S16 => (1, 0x79000000), // 00000079 	    strh w0, [x0, #0]
-                        S32 => (2, 0xB9000000), // 000000B9 	    str w0, [x0, #0]
-                        S64 => (3, 0xF9000000), // 000000F9 	    str x0, [x0, #0]
-                        _ => return Err(Error::InvalidType(i.clone())),
-                    };
-                    if *imm >> shift << shift != *imm {
-                        return Err(Error::InvalidImmediate(i.clone()));
-                    }
-                    if *imm >> shift >= 0x1000 {
-                        return Err(Error::InvalidImmediate(i.clone()));
-                    }
-                    let opcode = opcode
-                        | ((*imm >> shift) as u32) << 10
-                        | ra.to_aarch64() << 5
-                        | r.to_aarch64();
-                    code.extend(opcode.to_le_bytes());
+                    let (shift, opcode) = st_opcode(*ty, i)?;
+                    gen_ldst_imm(&mut code, opcode, shift, r, ra, *imm, i)?;
+                }
+                Ldx(ty, r, ra, rb, scale) => {
+                    let (shift, opcode) = ld_opcode(*ty, i)?;
+                    gen_ldst_index(&mut code, opcode, shift, r, ra, rb, *scale, i)?;
+                }
+                Stx(ty, r, ra, rb, scale) => {
+                    let (shift, opcode) = st_opcode(*ty, i)?;
+                    gen_ldst_index(&mut code, opcode, shift, r, ra, rb, *scale, i)?;
                 }
 
                 Vmov(..) | Vnot(..) | Vneg(..) | Vadd(..) | Vsub(..) | Vdiv(..)
@@ -160,80 +724,129 @@ impl Executable {
 
             }
         }
-        for (loc, f) in fixups {
-            match f {
-                Fixup::Adr(dest, label) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/ADR--Form-PC-relative-address-?lang=en
-                    if let Some((_, offset)) = labels.iter().find(|(n, _)| *n == label) {
-                        let delta = *offset as isize - loc as isize;
-                        let opcode = 0x10000000_u32
-                            | ((delta & 3) as u32) << 29
-                            | ((delta >> 2 & 0x1fffff) as u32) * 32
-                            | dest.to_aarch64();
-                        code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
-                    } else {
-                        return Err(Error::MissingLabel(label));
-                    }
-                }
-                Fixup::B(cond, label) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/B-cond--Branch-conditionally-?lang=en
-                    if let Some((_, offset)) = labels.iter().find(|(n, _)| *n == label) {
-                        let delta = *offset as isize - loc as isize;
-                        if (delta & 3) != 0 {
-                            return Err(Error::BranchNotMod4(label));
-                        }
-                        if delta < -(1 << 19 + 2 - 1) || delta >= (1 << 19 + 2 - 1) {
-                            return Err(Error::BranchOutOfRange(label));
-                        }
-                        let opcode = match cond {
-                            // Cond::Always => 0x5400000e,
-                            Cond::Eq => 0x54000000,
-                            Cond::Ne => 0x54000001,
-                            Cond::Sgt => 0x5400000c,
-                            Cond::Sge => 0x5400000a,
-                            Cond::Slt => 0x5400000b,
-                            Cond::Sle => 0x5400000d,
-                            Cond::Ugt => 0x54000008,
-                            Cond::Uge => 0x54000002,
-                            Cond::Ult => 0x54000003,
-                            Cond::Ule => 0x54000009,
-                        } | ((delta >> 1) & 0xfffff) as u32 * 16;
-                        code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
-                    } else {
-                        return Err(Error::MissingLabel(label));
-                    }
-                }
-                Fixup::J(label) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/B-cond--Branch-conditionally-?lang=en
-                    if let Some((_, offset)) = labels.iter().find(|(n, _)| *n == label) {
-                        let delta = *offset as isize - loc as isize;
-                        if (delta & 3) != 0 {
-                            return Err(Error::BranchNotMod4(label));
-                        }
-                        if delta < -(1 << 26 + 2 - 1) || delta >= (1 << 26 + 2 - 1) {
-                            return Err(Error::BranchOutOfRange(label));
-                        }
-                        let opcode = 0x14000000_u32 | ((delta >> 1) & 0x3ffffff) as u32;
-                        code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
-                    } else {
-                        return Err(Error::MissingLabel(label));
-                    }
-                }
+        let labels = labels.finish()?;
+        far.extend(far_locs.iter().map(|loc| sites[sites.partition_point(|(l, _)| l < loc)].1));
+        Ok((code, labels))
+    }
+}
+
+/// Size of the code generated for a branch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reach {
+    /// `b.cond`, `cbz` and `adr` reach 1MB, `tbz` 32KB and `b` 128MB.
+    Short,
+    /// An inverted `b.cond` over a `b`.
+    Medium,
+    /// A veneer which adds a 64 bit offset to the pc.
+    Long,
+}
+
+/// Load a 64 bit offset from the end of the sequence and add it to its start,
+/// then branch to the result or skip the offset.
+fn gen_veneer(code: &mut Vec<u8>, dest: u32, jump: bool) {
+    let tmp = if dest == 17 { 16 } else { 17 };
+    let start = code.len();
+    // 58000091    ldr x17, #16
+    // 10ffffe0    adr x16, #-4
+    // 8b110210    add x16, x16, x17
+    // d61f0200    br x16 or 14000003 b #12
+    code.extend((0x58000080_u32 | tmp).to_le_bytes());
+    code.extend(adr_opcode(start + 4, R(dest as u16), start).to_le_bytes());
+    code.extend((0x8b000000_u32 | tmp << 16 | dest << 5 | dest).to_le_bytes());
+    let next = if jump { 0xd61f0000_u32 | dest << 5 } else { 0x14000003 };
+    code.extend(next.to_le_bytes());
+    code.extend(0_u64.to_le_bytes());
+}
+
+/// Patch the instruction at `loc` to refer to `offset`.
+fn patch(code: &mut [u8], loc: usize, f: Fixup, offset: usize) -> Result<(), Error> {
+    let delta = offset as isize - loc as isize;
+    let opcode = match f {
+        Fixup::Adr(dest, label) => {
+            // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/ADR--Form-PC-relative-address-?lang=en
+            if delta < -(1 << 20) || delta >= (1 << 20) {
+                return Err(Error::BranchOutOfRange(label));
             }
+            adr_opcode(loc, dest, offset)
         }
-        Ok(Executable::new(&code, labels))
-    }
+        Fixup::B(cond, label) => {
+            // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/B-cond--Branch-conditionally-?lang=en
+            if (delta & 3) != 0 {
+                return Err(Error::BranchNotMod4(label));
+            }
+            if delta < -(1 << 19 + 2 - 1) || delta >= (1 << 19 + 2 - 1) {
+                return Err(Error::BranchOutOfRange(label));
+            }
+            0x54000000 | cond.to_aarch64() | ((delta >> 2) & 0x7ffff) as u32 * 32
+        }
+        Fixup::Imm19(label) | Fixup::Imm14(label) => {
+            // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/CBZ--Compare-and-branch-on-zero-?lang=en
+            // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/TBZ--Test-bit-and-branch-if-zero-?lang=en
+            let bits = if matches!(f, Fixup::Imm19(_)) { 19 } else { 14 };
+            if (delta & 3) != 0 {
+                return Err(Error::BranchNotMod4(label));
+            }
+            if delta < -(1 << bits + 2 - 1) || delta >= (1 << bits + 2 - 1) {
+                return Err(Error::BranchOutOfRange(label));
+            }
+            let mask = ((1 << bits) - 1) << 5;
+            let opcode = u32::from_le_bytes(code[loc..loc + 4].try_into().unwrap());
+            opcode & !mask | ((delta >> 2) as u32) << 5 & mask
+        }
+        Fixup::J(label) => {
+            // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/B--Branch-?lang=en
+            if (delta & 3) != 0 {
+                return Err(Error::BranchNotMod4(label));
+            }
+            if delta < -(1 << 26 + 2 - 1) || delta >= (1 << 26 + 2 - 1) {
+                return Err(Error::BranchOutOfRange(label));
+            }
+            0x14000000_u32 | ((delta >> 2) & 0x3ffffff) as u32
+        }
+        Fixup::Rel64(_) => {
+            let delta = offset as i64 - (loc - 16) as i64;
+            code[loc..loc + 8].copy_from_slice(&delta.to_le_bytes());
+            return Ok(());
+        }
+    };
+    code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
+    Ok(())
 }
 
 fn adr_opcode(loc: usize, dest: R, offset: usize) -> u32 {
     let delta = offset as isize - loc as isize;
     let opcode = 0x10000000_u32
         | ((delta & 3) as u32) << 29
-        | ((delta >> 2 & 0x1fffff) as u32) * 32
+        | ((delta >> 2 & 0x7ffff) as u32) * 32
         | dest.to_aarch64();
     opcode
 }
 
+impl Cond {
+    /// The condition code used by `b.cond`.
+    pub fn to_aarch64(&self) -> u32 {
+        match self {
+            // Cond::Always => 0xe,
+            Cond::Eq => 0x0,
+            Cond::Ne => 0x1,
+            Cond::Sgt => 0xc,
+            Cond::Sge => 0xa,
+            Cond::Slt => 0xb,
+            Cond::Sle => 0xd,
+            Cond::Ugt => 0x8,
+            Cond::Uge => 0x2,
+            Cond::Ult => 0x3,
+            Cond::Ule => 0x9,
+            Cond::Cs => 0x2,
+            Cond::Cc => 0x3,
+            Cond::Vs => 0x6,
+            Cond::Vc => 0x7,
+            Cond::Mi => 0x4,
+            Cond::Pl => 0x5,
+        }
+    }
+}
+
 impl R {
     // Return the REX bit and the MODRM bits.
     pub fn to_aarch64(&self) -> u32 {
@@ -261,6 +874,7 @@ fn movzkn(code: &mut Vec<u8>, opcode: u32, dest: &R, imm: u32, shift: u32) {
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use super::{Features, Reach};
 
     #[test]
     fn basic() {
@@ -289,7 +903,7 @@ mod tests {
             println!("{}", prog.fmt_32());
             assert_eq!(
                 prog.fmt_32(),
-                "60000010 40000010 20000010 00000010 e0ffff13 c0ffff13 c0035fd6"
+                "60000010 40000010 20000010 00000010 e0ffff10 c0ffff10 c0035fd6"
             );
         }
         {
@@ -375,6 +989,499 @@ mod tests {
         assert_eq!(prog.fmt_32(), "41f06af9 61f06af9 81f06af9 41f06a39 41f06a79 41f06ab9 41f06af9 41f0ea39 41f0ea79 41f0aab9 41f06af9 41f02af9 61f02af9 81f02af9 41f02a39 41f02a79 41f02ab9 41f02af9 41f02a39 41f02a79 41f02ab9 41f02af9 c0035fd6");
     }
 
+    #[test]
+    fn arith_imm() {
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Addi(R(0), R(1), 0x12),
+            Addi(R(31), R(31), 0x123000),
+            Addi(R(0), R(1), !0),
+            Subi(R(0), R(1), 0x12),
+            Subi(R(0), R(16), 0x1001),
+            Andi(R(0), R(1), 0xf),
+            Ori(R(0), R(1), 0x5555_5555_5555_5555),
+            Xori(R(0), R(1), 0x1234),
+            Shli(R(0), R(1), 4),
+            Shri(R(0), R(1), 4),
+            Sari(R(0), R(1), 68),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // add x0, x1, #18; add sp, sp, #0x123, lsl #12; sub x0, x1, #1; sub x0, x1, #18; mov x17, #4097; sub x0, x16, x17, uxtx
+        // and x0, x1, #0xf; orr x0, x1, #0x5555555555555555; mov x16, #4660; eor x0, x1, x16; lsl x0, x1, #4; lsr x0, x1, #4; asr x0, x1, #4
+        assert_eq!(
+            prog.fmt_32(),
+            "20480091 ff8f4491 200400d1 204800d1 310082d2 006231cb 200c4092 20f000b2 904682d2 200010ca 20ec7cd3 20fc44d3 20fc4493 c0035fd6"
+        );
+    }
+
+    #[test]
+    fn carry() {
+        use Ins::*;
+        use Cond::*;
+        let prog = Executable::from_ir(&[
+            Label(0),
+            Adds(R(0), R(1), R(2)),
+            Adc(R(0), R(1), R(2)),
+            Subs(R(0), R(1), R(2)),
+            Sbc(R(0), R(1), R(2)),
+            B(Cs, 0),
+            B(Vc, 0),
+            B(Mi, 0),
+            Sel(Pl, R(0), R(1), R(2)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // adds x0, x1, x2; adcs x0, x1, x2; subs x0, x1, x2; sbcs x0, x1, x2
+        // b.hs #-16; b.vc #-20; b.mi #-24; csel x0, x1, x2, pl
+        assert_eq!(prog.fmt_32(), "200002ab 200002ba 200002eb 200002fa 82ffff54 67ffff54 44ffff54 2050829a c0035fd6");
+    }
+
+    #[test]
+    fn mul_rem() {
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Mov(R(0), R(2)),
+            Not(R(0), R(2)),
+            Neg(R(0), R(2)),
+            UMulH(R(0), R(1), R(2)),
+            SMulH(R(0), R(1), R(2)),
+            UMulL(R(0), R(3), R(1), R(2)),
+            SMulL(R(1), R(0), R(1), R(2)),
+            UMulL(R(1), R(2), R(1), R(2)),
+            URem(R(0), R(1), R(2)),
+            SRem(R(0), R(16), R(2)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // mov x0, x2; mvn x0, x2; neg x0, x2; umulh x0, x1, x2; smulh x0, x1, x2
+        // mul x0, x1, x2; umulh x3, x1, x2; smulh x0, x1, x2; mul x1, x1, x2; umulh x16, x1, x2; mul x1, x1, x2; mov x2, x16
+        // udiv x16, x1, x2; msub x0, x16, x2, x1; sdiv x17, x16, x2; msub x0, x17, x2, x16
+        assert_eq!(
+            prog.fmt_32(),
+            "e00302aa e00322aa e00302cb 207cc29b 207c429b 207c029b 237cc29b 207c429b 217c029b 307cc29b 217c029b e20310aa \
+             3008c29a 0086029b 110ec29a 20c2029b c0035fd6"
+        );
+    }
+
+    #[test]
+    fn bits() {
+        use Ins::*;
+        use Type::*;
+        let prog = Executable::from_ir(&[
+            Clz(R(0), R(1)),
+            Ctz(R(0), R(1)),
+            Popcnt(R(0), R(1)),
+            Bswap(U64, R(0), R(1)),
+            Bswap(U32, R(0), R(1)),
+            Bswap(S16, R(0), R(1)),
+            Rol(R(0), R(1), R(2)),
+            Ror(R(0), R(1), R(2)),
+            Ubfx(R(0), R(1), 4, 4),
+            Sbfx(R(0), R(1), 0, 64),
+            Bfi(R(0), R(1), 4, 4),
+            Bfi(R(0), R(1), 0, 8),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // clz x0, x1; rbit x0, x1; clz x0, x0; fmov d31, x1; cnt v31.8b, v31.8b; addv b31, v31.8b; fmov x0, d31
+        // rev x0, x1; rev w0, w1; rev x0, x1; asr x0, x0, #48; neg x16, x2; ror x0, x1, x16; ror x0, x1, x2
+        // ubfx x0, x1, #4, #4; sbfx x0, x1, #0, #64; bfi x0, x1, #4, #4; bfi x0, x1, #0, #8
+        assert_eq!(
+            prog.fmt_32(),
+            "2010c0da 2000c0da 0010c0da 3f00679e ff5b200e ffbb310e e003669e 200cc0da 2008c05a 200cc0da 00fc7093 \
+             f00302cb 202cd09a 202cc29a 201c44d3 20fc4093 200c7cb3 201c40b3 c0035fd6"
+        );
+    }
+
+    #[test]
+    fn cond_select() {
+        use Cond::*;
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Cset(Eq, R(0)),
+            Cset(Ult, R(3)),
+            Csinc(Ne, R(0), R(1), R(2)),
+            Csneg(Sgt, R(0), R(1), R(2)),
+            Csinv(Vs, R(0), R(1), R(2)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // cset x0, eq; cset x3, lo; csinc x0, x1, x2, ne; csneg x0, x1, x2, gt; csinv x0, x1, x2, vs
+        assert_eq!(prog.fmt_32(), "e0179f9a e3279f9a 2014829a 20c482da 206082da c0035fd6");
+    }
+
+    #[test]
+    fn extend() {
+        use Ins::*;
+        use Type::*;
+        let prog = Executable::from_ir(&[
+            Sext(S8, R(0), R(1)),
+            Sext(U16, R(0), R(1)),
+            Sext(S32, R(0), R(1)),
+            Zext(U8, R(0), R(1)),
+            Zext(S16, R(0), R(1)),
+            Zext(U32, R(0), R(1)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // sxtb x0, w1; sxth x0, w1; sxtw x0, w1; ubfx x0, x1, #0, #8; ubfx x0, x1, #0, #16; ubfx x0, x1, #0, #32
+        assert_eq!(prog.fmt_32(), "201c4093 203c4093 207c4093 201c40d3 203c40d3 207c40d3 c0035fd6");
+    }
+
+    #[test]
+    fn offsets() {
+        use Ins::*;
+        use Type::*;
+        let prog = Executable::from_ir(&[
+            Ld(U64, R(0), R(6), -32),
+            Ld(U64, R(0), R(6), 4),
+            Ld(S16, R(0), R(31), 255),
+            St(U32, R(1), R(2), -256),
+            Ld(U64, R(0), R(2), 0x8000),
+            St(U8, R(16), R(2), -0x1001),
+            Ldx(U64, R(0), R(1), R(2), 3),
+            Ldx(U8, R(0), R(1), R(2), 0),
+            Ldx(S32, R(0), R(31), R(2), 4),
+            Stx(U16, R(0), R(1), R(2), 1),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // ldur x0, [x6, #-32]; ldur x0, [x6, #4]; ldursh w0, [sp, #255]; stur w1, [x2, #-256]
+        // mov x16, #0x8000; ldr x0, [x2, x16]; mov x17, #-4097; strb w16, [x2, x17]
+        // ldr x0, [x1, x2, lsl #3]; ldrb w0, [x1, x2]; lsl x16, x2, #4; ldrsw x0, [sp, x16]; strh w0, [x1, x2, lsl #1]
+        assert_eq!(
+            prog.fmt_32(),
+            "c0005ef8 c04040f8 e0f3cf78 410010b8 100090d2 406870f8 11008292 50683138 \
+             207862f8 20686238 50ec7cd3 e06bb0b8 20782278 c0035fd6"
+        );
+        assert!(Executable::from_ir(&[Ldx(U64, R(0), R(1), R(31), 0)]).is_err());
+    }
+
+    #[test]
+    fn branches() {
+        use Ins::*;
+        // 14000000 	b	#0
+        // 14000002 	b	#8
+        // 54ffffc0 	b.eq	#-8
+        let prog = Executable::from_ir(&[
+            Label(0),
+            J(0),
+            J(1),
+            B(Cond::Eq, 0),
+            Label(1),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(prog.fmt_32(), "00000014 02000014 c0ffff54 c0035fd6");
+    }
+
+    #[test]
+    fn long_branches() {
+        use Ins::*;
+        let ins = [
+            Label(0),
+            B(Cond::Eq, 0),
+            B(Cond::Eq, 1),
+            J(1),
+            Addr(R(17), 1),
+            Label(1),
+            Ret,
+        ];
+        let reach = [Reach::Short, Reach::Medium, Reach::Long, Reach::Long, Reach::Long, Reach::Short, Reach::Short];
+        let mut far = Vec::new();
+        let (code, _) = Executable::compile_with(&ins, &reach, &mut far, Features { dotprod: true }).unwrap();
+        assert!(far.is_empty());
+        let words = code.chunks_exact(4).map(|c| format!("{:08x}", u32::from_le_bytes(c.try_into().unwrap()))).collect::<Vec<_>>();
+        assert_eq!(
+            words.join(" "),
+            [
+                "54000041 17ffffff", // b.ne #8; b #-4
+                "540000e1 58000091 10fffff0 8b110210 d61f0200 00000048 00000000", // b.ne #28; ldr x17, #16; adr x16, #-4; add x16, x16, x17; br x16
+                "58000091 10fffff0 8b110210 d61f0200 00000030 00000000",
+                "58000090 10fffff1 8b100231 14000003 00000018 00000000", // ldr x16, #16; adr x17, #-4; add x17, x17, x16; b #12
+                "d65f03c0",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn relaxation() {
+        use Ins::*;
+        // Out of range of b.cond but not of b.
+        let mut ins = vec![B(Cond::Eq, 1)];
+        ins.extend(std::iter::repeat_n(Ret, 300000));
+        ins.extend([Label(1), Ret]);
+        let (code, labels) = Executable::compile(&ins).unwrap();
+        assert_eq!(labels, [(1, 8 + 300000 * 4)]);
+        // 54000041 b.ne #8
+        // 140493e1 b #1200004
+        assert_eq!(&code[0..8], &[0x41, 0, 0, 0x54, 0xe1, 0x93, 0x04, 0x14]);
+    }
+
+    #[test]
+    fn float() {
+        use Ins::*;
+        use Type::*;
+        let prog = Executable::from_ir(&[
+            Fadd(F32, V(0), V(1), V(2)),
+            Fsub(F64, V(0), V(1), V(2)),
+            Fmul(F32, V(0), V(1), V(2)),
+            Fdiv(F64, V(0), V(1), V(2)),
+            Fmin(F32, V(0), V(1), V(2)),
+            Fmax(F64, V(0), V(1), V(2)),
+            Fsqrt(F32, V(0), V(1)),
+            Fabs(F64, V(0), V(1)),
+            Fma(F32, V(0), V(1), V(2), V(3)),
+            Fcmp(F64, V(1), V(2)),
+            Fcvt(F64, F32, V(0), V(1)),
+            Fcvt(F32, F16, V(0), V(1)),
+            Fcvt(F16, F64, V(0), V(1)),
+            Fcvt(F64, F64, V(0), V(1)),
+            Scvtf(F32, V(0), R(1)),
+            Fcvtzs(F64, R(0), V(1)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // fadd s0, s1, s2; fsub d0, d1, d2; fmul s0, s1, s2; fdiv d0, d1, d2; fmin s0, s1, s2; fmax d0, d1, d2
+        // fsqrt s0, s1; fabs d0, d1; fmadd s0, s1, s2, s3; fcmp d1, d2
+        // fcvt d0, s1; fcvt s0, h1; fcvt h0, d1; fmov d0, d1; scvtf s0, x1; fcvtzs x0, d1
+        assert_eq!(prog.fmt_32(), "2028221e 2038621e 2008221e 2018621e 2058221e 2048621e 20c0211e 20c0601e 200c021f 2020621e 20c0221e 2040e21e 20c0631e 2040601e 2000229e 2000789e c0035fd6");
+        assert!(Executable::from_ir(&[Fadd(F16, V(0), V(1), V(2))]).is_err());
+        assert!(Executable::from_ir(&[Fcvt(U32, F32, V(0), V(1))]).is_err());
+    }
+
+    #[test]
+    fn lanes() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = Executable::from_ir(&[
+            VmovFromR(U8, V(0), R(1), 14),
+            VmovFromR(F64, V(0), R(1), 1),
+            VmovToR(U16, R(0), V(1), 2),
+            VmovToR(S32, R(0), V(1), 1),
+            VmovToR(U64, R(0), V(1), 1),
+            Vdup(U8, V64, V(0), R(1)),
+            Vdup(S64, V128, V(0), R(1)),
+            Vdup(U64, V64, V(0), R(1)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // mov v0.b[14], w1; mov v0.d[1], x1; umov w0, v1.h[2]; smov x0, v1.s[1]; mov x0, v1.d[1]
+        // dup v0.8b, w1; dup v0.2d, x1; fmov d0, x1
+        assert_eq!(prog.fmt_32(), "201c1d4e 201c184e 203c0a0e 202c0c4e 203c184e 200c010e 200c084e 2000679e c0035fd6");
+        assert!(Executable::from_ir(&[VmovFromR(U16, V(0), R(1), 8)]).is_err());
+        assert!(Executable::from_ir(&[Vdup(U64, V32, V(0), R(1))]).is_err());
+    }
+
+    #[test]
+    fn vector_select() {
+        use Cond::*;
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = Executable::from_ir(&[
+            Vcmp(Eq, U8, V128, V(0), V(1), V(2)),
+            Vcmp(Ne, S16, V64, V(0), V(1), V(2)),
+            Vcmp(Slt, S32, V128, V(0), V(1), V(2)),
+            Vcmp(Ugt, U64, V64, V(0), V(1), V(2)),
+            Vcmp(Sge, F32, V128, V(0), V(1), V(2)),
+            Vcmp(Ugt, F64, V64, V(0), V(1), V(2)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // cmeq v0.16b, v1.16b, v2.16b; cmeq v0.4h, v1.4h, v2.4h; mvn v0.8b, v0.8b; cmgt v0.4s, v2.4s, v1.4s
+        // cmhi d0, d1, d2; fcmge v0.4s, v1.4s, v2.4s; fcmge d0, d2, d1; mvn v0.8b, v0.8b
+        assert_eq!(prog.fmt_32(), "208c226e 208c622e 0058202e 4034a14e 2034e27e 20e4226e 40e4617e 0058202e c0035fd6");
+        let prog = Executable::from_ir(&[
+            Vbsl(U8, V128, V(0), V(1), V(2), V(3)),
+            Vbsl(U8, V128, V(0), V(0), V(2), V(3)),
+            Vbsl(U8, V64, V(0), V(1), V(0), V(3)),
+            Vbsl(U8, V64, V(0), V(1), V(2), V(0)),
+            Vblend(S16, V128, V(0), V(1), V(2), V(3)),
+            Vblend(F64, V64, V(2), V(1), V(2), V(3)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // mov v0.16b, v1.16b; bsl v0.16b, v2.16b, v3.16b; bsl v0.16b, v2.16b, v3.16b; bif v0.8b, v3.8b, v1.8b
+        // bit v0.8b, v2.8b, v1.8b; sshr v0.8h, v1.8h, #15; bsl v0.16b, v2.16b, v3.16b; sshr d31, d1, #63
+        // bif v2.8b, v3.8b, v31.8b
+        assert_eq!(prog.fmt_32(), "201ca14e 401c636e 401c636e 601ce12e 401ca12e 2004114f 401c636e 3f04415f 621cff2e c0035fd6");
+        let prog = Executable::from_ir(&[
+            Vmin(S8, V128, V(0), V(1), V(2)),
+            Vmax(U32, V64, V(0), V(1), V(2)),
+            Vmin(F32, V128, V(0), V(1), V(2)),
+            Vmax(F64, V64, V(0), V(1), V(2)),
+            Vabs(S16, V128, V(0), V(1)),
+            Vabs(S64, V64, V(0), V(1)),
+            Vabs(F64, V128, V(0), V(1)),
+            Vabs(F64, V64, V(0), V(1)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // smin v0.16b, v1.16b, v2.16b; umax v0.2s, v1.2s, v2.2s; fmin v0.4s, v1.4s, v2.4s; fmax d0, d1, d2
+        // abs v0.8h, v1.8h; abs d0, d1; fabs v0.2d, v1.2d; fabs d0, d1
+        assert_eq!(prog.fmt_32(), "206c224e 2064a22e 20f4a24e 2048621e 20b8604e 20b8e05e 20f8e04e 20c0601e c0035fd6");
+        assert!(Executable::from_ir(&[Vmin(S64, V128, V(0), V(1), V(2))]).is_err());
+        assert!(Executable::from_ir(&[Vcmp(Eq, F16, V128, V(0), V(1), V(2))]).is_err());
+        assert!(Executable::from_ir(&[Vabs(U8, V256, V(0), V(1))]).is_err());
+    }
+
+    #[test]
+    fn permute() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = Executable::from_ir(&[
+            Vtbl(V128, V(0), V(1), V(2)),
+            Vtbl(V64, V(0), V(1), V(2)),
+            Vzip(U32, V128, V(0), V(1), V(2), 0),
+            Vzip(U8, V64, V(0), V(1), V(2), 1),
+            Vuzp(S16, V128, V(0), V(1), V(2), 1),
+            Vtrn(U64, V128, V(0), V(1), V(2), 0),
+            Vext(U32, V128, V(0), V(1), V(2), 1),
+            Vext(U8, V64, V(0), V(1), V(2), 7),
+            Vrev(U8, V128, V(0), V(1)),
+            Vrev(U16, V64, V(0), V(1)),
+            Vrev(F64, V128, V(0), V(1)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // tbl v0.16b, { v1.16b }, v2.16b; tbl v0.8b, { v1.16b }, v2.8b; zip1 v0.4s, v1.4s, v2.4s; zip2 v0.8b, v1.8b, v2.8b
+        // uzp2 v0.8h, v1.8h, v2.8h; trn1 v0.2d, v1.2d, v2.2d; ext v0.16b, v1.16b, v2.16b, #4; ext v0.8b, v1.8b, v2.8b, #7
+        // rev64 v0.16b, v1.16b; ext v0.16b, v0.16b, v0.16b, #8; rev64 v0.4h, v1.4h; ext v0.16b, v1.16b, v1.16b, #8
+        assert_eq!(
+            prog.fmt_32(),
+            "2000024e 2000020e 2038824e 2078020e 2058424e 2028c24e 2020026e 2038022e 2008204e 0040006e 2008600e 2040016e c0035fd6"
+        );
+        assert!(Executable::from_ir(&[Vtbl(V256, V(0), V(1), V(2))]).is_err());
+        assert!(Executable::from_ir(&[Vzip(U64, V64, V(0), V(1), V(2), 0)]).is_err());
+        assert!(Executable::from_ir(&[Vext(U16, V64, V(0), V(1), V(2), 4)]).is_err());
+    }
+
+    #[test]
+    fn widen_narrow() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = Executable::from_ir(&[
+            Vqadd(S8, V128, V(0), V(1), V(2)),
+            Vqsub(U16, V64, V(0), V(1), V(2)),
+            Vaddl(S16, V128, V(0), V(1), V(2)),
+            Vmull(U64, V128, V(0), V(1), V(2)),
+            Vxtn(U16, V128, V(0), V(1)),
+            Vqmovn(S32, V128, V(0), V(1)),
+            Vqmovn(U16, V128, V(0), V(1)),
+            Vaddv(U8, V128, V(0), V(1)),
+            Vaddv(S32, V64, V(0), V(1)),
+            Vaddv(U64, V128, V(0), V(1)),
+            Vaddp(U16, V64, V(0), V(1), V(2)),
+            Vaddp(F64, V128, V(0), V(1), V(2)),
+            Vdot(S8, V128, V(0), V(1), V(2)),
+            Vdot(U8, V64, V(0), V(1), V(2)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // sqadd v0.16b, v1.16b, v2.16b; uqsub v0.4h, v1.4h, v2.4h; saddl v0.8h, v1.8b, v2.8b; umull v0.2d, v1.2s, v2.2s
+        // xtn v0.8b, v1.8h; sqxtn v0.4h, v1.4s; uqxtn v0.8b, v1.8h; addv b0, v1.16b; addp v0.2s, v1.2s, v1.2s; addp d0, v1.2d
+        // addp v0.4h, v1.4h, v2.4h; faddp v0.2d, v1.2d, v2.2d; sdot v0.4s, v1.16b, v2.16b; udot v0.2s, v1.8b, v2.8b
+        assert_eq!(
+            prog.fmt_32(),
+            "200c224e 202c622e 2000220e 20c0a22e 2028210e 2048610e 2048212e 20b8314e 20bca10e 20b8f15e 20bc620e 20d4626e 2094824e 2094822e c0035fd6"
+        );
+        assert!(Executable::from_ir(&[Vqadd(S32, V128, V(0), V(1), V(2))]).is_err());
+        assert!(Executable::from_ir(&[Vaddl(U8, V128, V(0), V(1), V(2))]).is_err());
+        assert!(Executable::from_ir(&[Vxtn(U32, V64, V(0), V(1))]).is_err());
+        assert!(Executable::from_ir(&[Vqmovn(S64, V128, V(0), V(1))]).is_err());
+        assert!(Executable::from_ir(&[Vaddv(F32, V128, V(0), V(1))]).is_err());
+        let ins = Vdot(U8, V128, V(0), V(1), V(2));
+        let res = Executable::compile_for(&[ins.clone(), Ret], Features { dotprod: false });
+        assert_eq!(res.unwrap_err(), Error::UnsupportedOperation(ins));
+    }
+
+    #[test]
+    fn fused_branches() {
+        use Cond::*;
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Label(0),
+            Cbz(R(0), 0),
+            Cbnz(R(1), 0),
+            Tbz(R(2), 3, 0),
+            Tbnz(R(3), 63, 1),
+            Bcmp(Slt, R(1), R(2), 1),
+            Label(1),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // cbz x0, #0; cbnz x1, #-4; tbz w2, #3, #-8; tbnz x3, #63, #12; cmp x1, x2; b.lt #4
+        assert_eq!(prog.fmt_32(), "000000b4 e1ffffb5 c2ff1f36 6300f8b7 3f0002eb 2b000054 c0035fd6");
+    }
+
+    #[test]
+    fn tbz_relaxation() {
+        use Ins::*;
+        // Out of range of tbz but not of b.
+        let mut ins = vec![Tbz(R(0), 1, 1), Cbz(R(0), 1)];
+        ins.extend(std::iter::repeat_n(Ret, 10000));
+        ins.extend([Label(1), Ret]);
+        let (code, labels) = Executable::compile(&ins).unwrap();
+        assert_eq!(labels, [(1, 12 + 10000 * 4)]);
+        let words = code[0..12].chunks_exact(4).map(|c| format!("{:08x}", u32::from_le_bytes(c.try_into().unwrap()))).collect::<Vec<_>>();
+        // tbnz w0, #1, #8; b #40008; cbz x0, #40004
+        assert_eq!(words.join(" "), "37080040 14002712 b404e220");
+    }
+
+    #[test]
+    fn immediates() {
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Movi(R(0), 0),
+            Movi(R(0), 0x1234_0000),
+            Movi(R(0), !0),
+            Movi(R(0), !0x1234),
+            Movi(R(0), 0x5555_5555_5555_5555),
+            Movi(R(0), 0x00ff_0000_0000_0000),
+            Movi(R(0), 0x0000_1234_0000_5678),
+            Movi(R(0), 0xffff_1234_ffff_5678),
+            Movi(R(0), 0x1234_5678_9abc_def0),
+            Cmpi(R(1), 0xfff),
+            Cmpi(R(1), 0x123000),
+            Cmpi(R(1), !0),
+            Cmpi(R(1), 0x1001),
+            Cmpi(R(16), 0x1001),
+            Cmp(R(1), R(2)),
+            Ret,
+        ])
+        .unwrap();
+        println!("{}", prog.fmt_url());
+        // mov x0, #0x1234_0000; mov x0, #-1; mov x0, #-4661; orr x0, xzr, #0x5555555555555555
+        // mov x0, #0x5678; movk x0, #0x1234, lsl #32; mov x0, #-43400; movk x0, #0x1234, lsl #32
+        // cmp x1, #0x123, lsl #12; cmn x1, #1; mov x16, #4097; cmp x1, x16; mov x17, #4097; cmp x16, x17
+        assert_eq!(
+            prog.fmt_32(),
+            "000080d2 8046a2d2 00008092 80468292 e0f300b2 e01fe0d2 00cf8ad2 8046c2f2 e0309592 8046c2f2 \
+             00de9bd2 8057b3f2 00cfcaf2 8046e2f2 3ffc3ff1 3f8c44f1 3f0400b1 300082d2 3f0010eb 310082d2 1f0211eb 3f0002eb c0035fd6"
+        );
+    }
+
     #[test]
     fn enter_leave() {
         use Ins::*;
@@ -434,10 +1541,11 @@ mod tests {
 
 }
 
+/// Two operand aliases of three operand instructions with xzr as the first source, such as `mov x0, x1`.
 fn gen2(code: &mut Vec<u8>, opcode: u32, dest: &R, src: &R, i: &Ins) -> Result<(), Error> {
-    let opcode = opcode & !(0x1f<<5 | 0x1f);
+    let opcode = opcode & !(0x1f<<16 | 0x1f);
     let opcode = opcode
-        | src.to_aarch64() << 5
+        | src.to_aarch64() << 16
         | dest.to_aarch64();
     code.extend(opcode.to_le_bytes());
     Ok(())
@@ -465,36 +1573,291 @@ fn gen_mov(code: &mut Vec<u8>, opcode: u32, dest: &R, src: &R, i: &Ins) -> Resul
 
 fn gen_movi(code: &mut Vec<u8>, opcode: u32, dest: &R, imm: &u64, i: &Ins) -> Result<(), Error> {
     // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/MOVZ--Move-wide-with-zero-?lang=en
-    if *imm >= 0x10000 {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/MOVN--Move-wide-with-NOT-?lang=en
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/MOVK--Move-wide-with-keep-?lang=en
+    let dest = dest.to_aarch64();
+    let halves = [0, 1, 2, 3].map(|hw| (*imm >> (hw * 16)) as u32 & 0xffff);
+    let zeros = halves.iter().filter(|h| **h == 0).count();
+    let ones = halves.iter().filter(|h| **h == 0xffff).count();
+    if zeros < 3 && ones < 3 {
+        if let Some(bitmask) = encode_bitmask(*imm) {
+            // B200F3E0          mov x0, #0x5555555555555555 (orr x0, xzr, #0x5555555555555555)
+            code.extend((0xb20003e0 | bitmask << 10 | dest).to_le_bytes());
+            return Ok(());
+        }
+    }
+    // Start with movz or movn, whichever leaves fewer halves to movk.
+    let (first, skip) = if ones > zeros { (0x92800000_u32, 0xffff) } else { (0xd2800000_u32, 0) };
+    let mut rest = (0..4).filter(|&hw| halves[hw] != skip);
+    let hw = rest.next().unwrap_or(0);
+    let h = if skip == 0 { halves[hw] } else { !halves[hw] & 0xffff };
+    // D2824680          mov x0, #0x1234
+    // 92800000          mov x0, #-1
+    code.extend((first | (hw as u32) << 21 | h << 5 | dest).to_le_bytes());
+    for hw in rest {
+        // F2A24680          movk x0, #0x1234, lsl #16
+        code.extend((0xf2800000_u32 | (hw as u32) << 21 | halves[hw] << 5 | dest).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Encode a 64 bit logical immediate as N:immr:imms, if it is a repeated rotated run of ones.
+fn encode_bitmask(imm: u64) -> Option<u32> {
+    if imm == 0 || imm == !0 {
+        return None;
+    }
+    // Find the smallest repeating element.
+    let mut size = 64;
+    while size > 2 {
+        let half = size / 2;
+        let mask = (1_u64 << half) - 1;
+        if imm & mask != (imm >> half) & mask {
+            break;
+        }
+        size = half;
+    }
+    let mask = if size == 64 { !0 } else { (1_u64 << size) - 1 };
+    let elem = imm & mask;
+    let ones = elem.count_ones();
+    let run = (1_u64 << ones) - 1;
+    let rotr = |r: u32| if r == 0 { elem } else { (elem >> r | elem << (size - r)) & mask };
+    let rot = (0..size).find(|&r| rotr(r) == run)?;
+    let immr = (size - rot) % size;
+    let imms = ((size as i32 * -2) as u32 | (ones - 1)) & 0x3f;
+    Some(((size == 64) as u32) << 12 | immr << 6 | imms)
+}
+
+fn gen_cmp(code: &mut Vec<u8>, opcode: u32, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/CMP--shifted-register---Compare--shifted-register---an-alias-of-SUBS--shifted-register--?lang=en
+    let opcode = opcode & !(0x1f << 16 | 0x1f << 5);
+    let opcode = opcode
+        | src2.to_aarch64() << 16
+        | src1.to_aarch64() << 5;
+    code.extend(opcode.to_le_bytes());
+    Ok(())
+}
+
+fn gen_cmpi(code: &mut Vec<u8>, opcode: u32, src: &R, imm: &u64, i: &Ins) -> Result<(), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/CMP--immediate---Compare--immediate---an-alias-of-SUBS--immediate--?lang=en
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/CMN--immediate---Compare-negative--immediate---an-alias-of-ADDS--immediate--?lang=en
+    let opcode = opcode & !(0x1 << 22 | 0xfff << 10 | 0x1f << 5);
+    let neg = imm.wrapping_neg();
+    let (opcode, imm) = if *imm < 0x1000 || *imm < 0x1000000 && *imm & 0xfff == 0 {
+        // F100481F          cmp x0, #0x12
+        (opcode, *imm)
+    } else if neg < 0x1000 || neg < 0x1000000 && neg & 0xfff == 0 {
+        // B100481F          cmn x0, #0x12
+        (opcode & !0x40000000, neg)
+    } else {
+        // D2824690          mov x16, #0x1234
+        // EB10001F          cmp x0, x16
+        let tmp = if src.0 == 16 { R(17) } else { R(16) };
+        gen_movi(code, 0, &tmp, imm, i)?;
+        return gen_cmp(code, 0xeb00001f, src, &tmp, i);
+    };
+    // F140041F          cmp x0, #0x1, lsl #12
+    let (sh, imm) = if imm < 0x1000 { (0, imm) } else { (1, imm >> 12) };
+    let opcode = opcode
+        | sh << 22
+        | (imm as u32) << 10
+        | src.to_aarch64() << 5;
+    code.extend(opcode.to_le_bytes());
+    Ok(())
+}
+
+/// One source register in the Rn field.
+fn gen2_rn(code: &mut Vec<u8>, opcode: u32, dest: &R, src: &R) {
+    let opcode = opcode
+        | src.to_aarch64() << 5
+        | dest.to_aarch64();
+    code.extend(opcode.to_le_bytes());
+}
+
+/// A bitfield must have at least one bit and fit in 64 bits.
+fn check_bitfield(lsb: u8, width: u8, i: &Ins) -> Result<(), Error> {
+    if width == 0 || lsb as u32 + width as u32 > 64 {
         return Err(Error::InvalidImmediate(i.clone()));
     }
-    let opcode = opcode & !(0xffff << 5 | 0x1f);
+    Ok(())
+}
+
+/// Both halves of a product, `opcode` is umulh or smulh.
+fn gen_mull(code: &mut Vec<u8>, opcode: u32, lo: &R, hi: &R, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    // 9B027C20          mul x0, x1, x2
+    if lo == hi {
+        return Err(Error::InvalidRegisterNumber(i.clone()));
+    }
+    if lo != src1 && lo != src2 {
+        gen3(code, 0x9b007c00, lo, src1, src2, i)?;
+        gen3(code, opcode, hi, src1, src2, i)
+    } else if hi != src1 && hi != src2 {
+        gen3(code, opcode, hi, src1, src2, i)?;
+        gen3(code, 0x9b007c00, lo, src1, src2, i)
+    } else {
+        let tmp = scratch(src1, src2);
+        gen3(code, opcode, &tmp, src1, src2, i)?;
+        gen3(code, 0x9b007c00, lo, src1, src2, i)?;
+        gen2(code, 0xaa0003e0, hi, &tmp, i)
+    }
+}
+
+/// Remainder, `opcode` is udiv or sdiv.
+/// Division by zero gives zero so the remainder is the dividend.
+fn gen_rem(code: &mut Vec<u8>, opcode: u32, dest: &R, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    // 9AC20830          udiv x16, x1, x2
+    // 9B028600          msub x0, x16, x2, x1
+    let tmp = scratch(src1, src2);
+    gen3(code, opcode, &tmp, src1, src2, i)?;
+    let opcode = 0x9b008000
+        | src2.to_aarch64() << 16
+        | src1.to_aarch64() << 10
+        | tmp.to_aarch64() << 5
+        | dest.to_aarch64();
+    code.extend(opcode.to_le_bytes());
+    Ok(())
+}
+
+/// An unsigned 12 bit immediate, optionally shifted left by 12.
+fn arith_imm(imm: u64) -> Option<u32> {
+    if imm < 0x1000 {
+        Some((imm as u32) << 10)
+    } else if imm < 0x1000000 && imm & 0xfff == 0 {
+        Some(1 << 22 | ((imm >> 12) as u32) << 10)
+    } else {
+        None
+    }
+}
+
+fn gen_addi(code: &mut Vec<u8>, dest: &R, src: &R, imm: u64, sub: bool, i: &Ins) -> Result<(), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/ADD--immediate---Add--immediate--?lang=en
+    let opcode = if let Some(imm) = arith_imm(imm) {
+        // 91004820          add x0, x1, #0x12
+        // D1004820          sub x0, x1, #0x12
+        (if sub { 0xd1000000 } else { 0x91000000 }) | imm
+    } else if let Some(imm) = arith_imm(imm.wrapping_neg()) {
+        (if sub { 0x91000000 } else { 0xd1000000 }) | imm
+    } else {
+        // D2824690          mov x16, #0x1234
+        // 8B306020          add x0, x1, x16
+        let tmp = if src.0 == 16 { R(17) } else { R(16) };
+        gen_movi(code, 0, &tmp, &imm, i)?;
+        (if sub { 0xcb206000 } else { 0x8b206000 }) | tmp.to_aarch64() << 16
+    };
     let opcode = opcode
-        | (*imm << 5) as u32
+        | src.to_aarch64() << 5
         | dest.to_aarch64();
     code.extend(opcode.to_le_bytes());
     Ok(())
 }
 
-fn gen_cmp(code: &mut Vec<u8>, opcode: u32, dest: &R, src: &R, i: &Ins) -> Result<(), Error> {
-    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/CMP--extended-register---Compare--extended-register---an-alias-of-SUBS--extended-register--?lang=en
-    let opcode = opcode & !(0x1f << 5 | 0x1f);
+/// Logical operation with a bitmask immediate or, failing that, a scratch register.
+fn gen_logici(code: &mut Vec<u8>, opcode: u32, reg_opcode: u32, dest: &R, src: &R, imm: u64, i: &Ins) -> Result<(), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/AND--immediate---Bitwise-AND--immediate--?lang=en
+    let opcode = if let Some(bitmask) = encode_bitmask(imm) {
+        // 92400C20          and x0, x1, #0xf
+        opcode | bitmask << 10
+    } else {
+        // D2824690          mov x16, #0x1234
+        // 8A100020          and x0, x1, x16
+        let tmp = if src.0 == 16 { R(17) } else { R(16) };
+        gen_movi(code, 0, &tmp, &imm, i)?;
+        reg_opcode | tmp.to_aarch64() << 16
+    };
     let opcode = opcode
-        | dest.to_aarch64() << 5
+        | src.to_aarch64() << 5
         | dest.to_aarch64();
     code.extend(opcode.to_le_bytes());
     Ok(())
 }
 
-fn gen_cmpi(code: &mut Vec<u8>, opcode: u32, dest: &R, imm: &u64, i: &Ins) -> Result<(), Error> {
-    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/CMP--immediate---Compare--immediate---an-alias-of-SUBS--immediate--?lang=en
-    if *imm >= 0x1000 {
+/// The log2 size and unsigned offset form of a load of `ty`.
+fn ld_opcode(ty: Type, i: &Ins) -> Result<(u32, u32), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/LDR--immediate---Load-register--immediate--?lang=en
+    use Type::*;
+    Ok(match ty {
+        U8 => (0, 0x39400000),  // 00004039 	    ldrb w0, [x0, #0]
+        U16 => (1, 0x79400000), // 00004079 	    ldrh w0, [x0, #0]
+        U32 => (2, 0xb9400000), // 000040B9 	    ldr w0, [x0, #0]
+        U64 => (3, 0xf9400000), // 000040F9 	    ldr x0, [x0, #0]
+        S8 => (0, 0x39c00000),  // 0000C039 	    ldrsb w0, [x0, #0]
+        S16 => (1, 0x79c00000), // 0000C079 	    ldrsh w0, [x0, #0]
+        S32 => (2, 0xb9800000), // 000080B9 	    ldrsw x0, [x0, #0]
+        S64 => (3, 0xf9400000), // 000040F9 	    ldr x0, [x0, #0]
+        _ => return Err(Error::InvalidType(i.clone())),
+    })
+}
+
+/// The log2 size and unsigned offset form of a store of `ty`.
+fn st_opcode(ty: Type, i: &Ins) -> Result<(u32, u32), Error> {
+    use Type::*;
+    Ok(match ty {
+        U8 | S8 => (0, 0x39000000),   // 00000039 	    strb w0, [x0, #0]
+        U16 | S16 => (1, 0x79000000), // 00000079 	    strh w0, [x0, #0]
+        U32 | S32 => (2, 0xb9000000), // 000000B9 	    str w0, [x0, #0]
+        U64 | S64 => (3, 0xf9000000), // 000000F9 	    str x0, [x0, #0]
+        _ => return Err(Error::InvalidType(i.clone())),
+    })
+}
+
+/// x16 unless it is an operand, x16 and x17 may not both be operands.
+fn scratch(r: &R, ra: &R) -> R {
+    if r.0 == 16 || ra.0 == 16 { R(17) } else { R(16) }
+}
+
+/// Load or store at `[ra + imm]`.
+fn gen_ldst_imm(code: &mut Vec<u8>, opcode: u32, shift: u32, r: &R, ra: &R, imm: i32, i: &Ins) -> Result<(), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/LDUR--Load-register--unscaled--?lang=en
+    let opcode = if imm >= 0 && imm >> shift << shift == imm && imm >> shift < 0x1000 {
+        // F9455C41          ldr x1, [x2, #0xab8]
+        opcode | ((imm >> shift) as u32) << 10
+    } else if (-0x100..0x100).contains(&imm) {
+        // F85E00C0          ldur x0, [x6, #-0x20]
+        opcode & !(1 << 24) | ((imm as u32) & 0x1ff) << 12
+    } else {
+        // D2824690          mov x16, #0x1234
+        // F8706840          ldr x0, [x2, x16]
+        let tmp = scratch(r, ra);
+        gen_movi(code, 0, &tmp, &(imm as i64 as u64), i)?;
+        return gen_ldst_reg(code, opcode, r, ra, &tmp, false);
+    };
+    let opcode = opcode
+        | ra.to_aarch64() << 5
+        | r.to_aarch64();
+    code.extend(opcode.to_le_bytes());
+    Ok(())
+}
+
+/// Load or store at `[ra + (rb << scale)]`.
+fn gen_ldst_index(code: &mut Vec<u8>, opcode: u32, shift: u32, r: &R, ra: &R, rb: &R, scale: u8, i: &Ins) -> Result<(), Error> {
+    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/LDR--register---Load-register--register--?lang=en
+    if rb.to_aarch64() == 31 {
+        return Err(Error::InvalidRegisterNumber(i.clone()));
+    }
+    let scale = scale as u32;
+    if scale == 0 || scale == shift {
+        return gen_ldst_reg(code, opcode, r, ra, rb, scale != 0);
+    }
+    if scale >= 64 {
         return Err(Error::InvalidImmediate(i.clone()));
     }
-    let opcode = opcode & !(0xfff << 5 | 0x1f);
-    let opcode = opcode
-        | (*imm << 5) as u32
-        | dest.to_aarch64();
+    // D37DF050          lsl x16, x2, #3
+    let tmp = scratch(r, ra);
+    let lsl = 0xd3400000 | ((64 - scale) & 0x3f) << 16 | (63 - scale) << 10 | rb.to_aarch64() << 5 | tmp.to_aarch64();
+    code.extend(lsl.to_le_bytes());
+    gen_ldst_reg(code, opcode, r, ra, &tmp, false)
+}
+
+/// Register offset form of a load or store, `[ra, rb]` or `[ra, rb, lsl #size]`.
+fn gen_ldst_reg(code: &mut Vec<u8>, opcode: u32, r: &R, ra: &R, rb: &R, scaled: bool) -> Result<(), Error> {
+    // F8627820          ldr x0, [x1, x2, lsl #3]
+    let opcode = opcode & !(1 << 24)
+        | 1 << 21
+        | rb.to_aarch64() << 16
+        | 0b011 << 13
+        | (scaled as u32) << 12
+        | 0b10 << 10
+        | ra.to_aarch64() << 5
+        | r.to_aarch64();
     code.extend(opcode.to_le_bytes());
     Ok(())
 }
@@ -508,6 +1871,100 @@ fn gen_ldst(code: &mut Vec<u8>, opcode: u32, ty: Type, dest: &R, ra: &R, imm: &i
     Ok(())
 }
 
+/// The imm5 field of ins, umov, smov and dup which encodes the lane size and index.
+fn lane_imm5(ty: Type, lane: u8, i: &Ins) -> Result<u32, Error> {
+    use Type::*;
+    let shift = match ty {
+        U8 | S8 => 0,
+        U16 | S16 | F16 => 1,
+        U32 | S32 | F32 => 2,
+        U64 | S64 | F64 => 3,
+        _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+    };
+    if lane as u32 >= 16 >> shift {
+        return Err(Error::InvalidImmediate(i.clone()));
+    }
+    Ok(((lane as u32) << 1 | 1) << shift)
+}
+
+/// Bits 28 and 30, which turn a vector encoding into its scalar form.
+const SCALAR: u32 = 0x50000000;
+
+/// The Q bit and log2 lane size of a vector operation,
+/// a single 64 bit lane uses the scalar form.
+fn vshape(ty: Type, vsize: Vsize, i: &Ins) -> Result<(u32, u32), Error> {
+    let shift = lane_imm5(ty, 0, i)?.trailing_zeros();
+    match (vsize, shift) {
+        (Vsize::V32, 3) => Err(Error::VectorSizeNotSupported(i.clone())),
+        (Vsize::V64, 3) => Ok((SCALAR, 3)),
+        (Vsize::V32 | Vsize::V64, _) => Ok((0, shift)),
+        (Vsize::V128, _) => Ok((1 << 30, shift)),
+        _ => Err(Error::VectorSizeNotSupported(i.clone())),
+    }
+}
+
+/// Permutes need at least two lanes and a part of 0 or 1, returns the Q bit and lane shift.
+fn permute_shape(ty: Type, vsize: Vsize, part: u8, i: &Ins) -> Result<(u32, u32), Error> {
+    if part > 1 {
+        return Err(Error::InvalidImmediate(i.clone()));
+    }
+    match vshape(ty, vsize, i)? {
+        (SCALAR, _) => Err(Error::VectorSizeNotSupported(i.clone())),
+        _ if vsize == Vsize::V32 => Err(Error::VectorSizeNotSupported(i.clone())),
+        shape => Ok(shape),
+    }
+}
+
+/// Widening and narrowing use a V128 wide vector of 16, 32 or 64 bit integers,
+/// returns the lane shift of the wide type and whether it is signed.
+fn wide_shape(ty: Type, vsize: Vsize, i: &Ins) -> Result<(u32, bool), Error> {
+    let signed = match ty {
+        Type::S16 | Type::S32 | Type::S64 => true,
+        Type::U16 | Type::U32 | Type::U64 => false,
+        _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+    };
+    match vsize {
+        Vsize::V128 => Ok((lane_imm5(ty, 0, i)?.trailing_zeros(), signed)),
+        _ => Err(Error::VectorSizeNotSupported(i.clone())),
+    }
+}
+
+/// 6E205800          mvn v0.16b, v0.16b
+fn gen_vnot(code: &mut Vec<u8>, q: u32, v: &V, i: &Ins) -> Result<(), Error> {
+    vgen2(code, 0x2e205800 | bitwise_q(q), v, v, i)
+}
+
+/// The Q bit for a bitwise operation, which has no scalar form.
+fn bitwise_q(q: u32) -> u32 {
+    if q == SCALAR { 0 } else { q }
+}
+
+/// dest = mask & src1 | !mask & src2 using whichever of bsl, bit and bif keeps the other sources.
+/// 6E611C40          bsl v0.16b, v2.16b, v1.16b
+/// 6EE11C40          bif v0.16b, v2.16b, v1.16b
+/// 6EA11C40          bit v0.16b, v2.16b, v1.16b
+fn gen_bsl(code: &mut Vec<u8>, q: u32, dest: &V, mask: &V, src1: &V, src2: &V, i: &Ins) -> Result<(), Error> {
+    if dest == src1 {
+        vgen3(code, 0x2ee01c00 | q, dest, src2, mask, i)
+    } else if dest == src2 {
+        vgen3(code, 0x2ea01c00 | q, dest, src1, mask, i)
+    } else {
+        if dest != mask {
+            vgen3(code, 0x0ea01c00 | q, dest, mask, mask, i)?;
+        }
+        vgen3(code, 0x2e601c00 | q, dest, src1, src2, i)
+    }
+}
+
+/// The ftype field of a scalar floating point instruction.
+fn ftype(ty: Type, i: &Ins) -> Result<u32, Error> {
+    match ty {
+        Type::F32 => Ok(0),
+        Type::F64 => Ok(1 << 22),
+        _ => Err(Error::VectorTypeNotSupported(i.clone())),
+    }
+}
+
 fn vgen2(code: &mut Vec<u8>, opcode: u32, dest: &V, src: &V, i: &Ins) -> Result<(), Error> {
     let opcode = opcode & !(0x1f<<5 | 0x1f);
     let opcode = opcode
@@ -1,6 +1,8 @@
 // #![feature(stdarch_aarch64_feature_detection)]
+#[cfg(target_arch = "aarch64")]
 use std::arch::is_aarch64_feature_detected;
 
+#[cfg(target_arch = "aarch64")]
 fn main() {
     if is_aarch64_feature_detected!("neon") { println!("neon") }
     if is_aarch64_feature_detected!("pmull") { println!("pmull") }
@@ -94,3 +96,22 @@ fn main() {
     // if is_aarch64_feature_detected!("v9a") { println!("v9a") }
 }
 
+#[cfg(target_arch = "x86_64")]
+fn main() {
+    if is_x86_feature_detected!("sse2") { println!("sse2") }
+    if is_x86_feature_detected!("sse3") { println!("sse3") }
+    if is_x86_feature_detected!("ssse3") { println!("ssse3") }
+    if is_x86_feature_detected!("sse4.1") { println!("sse4.1") }
+    if is_x86_feature_detected!("sse4.2") { println!("sse4.2") }
+    if is_x86_feature_detected!("popcnt") { println!("popcnt") }
+    if is_x86_feature_detected!("lzcnt") { println!("lzcnt") }
+    if is_x86_feature_detected!("bmi1") { println!("bmi1") }
+    if is_x86_feature_detected!("bmi2") { println!("bmi2") }
+    if is_x86_feature_detected!("avx") { println!("avx") }
+    if is_x86_feature_detected!("avx2") { println!("avx2") }
+    if is_x86_feature_detected!("fma") { println!("fma") }
+    if is_x86_feature_detected!("f16c") { println!("f16c") }
+    if is_x86_feature_detected!("avx512f") { println!("avx512f") }
+    if is_x86_feature_detected!("avx512bw") { println!("avx512bw") }
+    if is_x86_feature_detected!("avxvnni") { println!("avxvnni") }
+}
@@ -0,0 +1,267 @@
+//! Shared executable memory.
+//!
+//! Every `Executable` made by [`Executable::from_ir`] maps pages of its own.
+//! A [`CodeHeap`] maps large arenas instead and places many executables in each,
+//! reusing their space when they are dropped.
+//!
+//! On Linux each arena is a memfd mapped twice, once writable and once executable,
+//! so no page is ever both and code may be added while other code in the arena runs.
+//! On macOS the arenas are `MAP_JIT` and writes are enabled for the current thread only.
+use std::{cell::RefCell, ops::Range, rc::Rc};
+
+use crate::{errno, page_size, Error, Executable, Ins, Memory};
+
+/// Alignment of each executable in an arena.
+const ALIGN: usize = 64;
+
+/// Executable memory shared by many executables.
+///
+/// ```
+/// # use ejit::*;
+/// use Ins::*;
+/// use regs::*;
+/// let heap = CodeHeap::new();
+/// let progs = (0..100).map(|n| heap.compile(&[Movi(RES[0], n), Ret]).unwrap()).collect::<Vec<_>>();
+/// let (res, _) = unsafe { progs[42].call(0, &[]).unwrap() };
+/// assert_eq!(res, 42);
+/// ```
+pub struct CodeHeap {
+    arenas: Rc<RefCell<Arenas>>,
+}
+
+struct Arenas {
+    /// Size of new arenas, a whole number of pages.
+    size: usize,
+    arenas: Vec<Arena>,
+}
+
+struct Arena {
+    /// Executable view.
+    rx: *const u8,
+
+    /// Writable view, the same as `rx` on macOS.
+    rw: *mut u8,
+
+    size: usize,
+
+    /// Unused parts, sorted and never adjacent.
+    free: Vec<Range<usize>>,
+}
+
+/// The part of an arena used by one `Executable`.
+pub(crate) struct Block {
+    arenas: Rc<RefCell<Arenas>>,
+    arena: usize,
+    range: Range<usize>,
+}
+
+impl CodeHeap {
+    /// A heap with 1MB arenas.
+    pub fn new() -> Self {
+        Self::with_arena_size(1 << 20)
+    }
+
+    /// A heap with arenas of at least `size` bytes.
+    /// Executables larger than this get an arena of their own.
+    pub fn with_arena_size(size: usize) -> Self {
+        let size = size.max(1).next_multiple_of(page_size());
+        Self { arenas: Rc::new(RefCell::new(Arenas { size, arenas: Vec::new() })) }
+    }
+
+    /// Compile IR into this heap.
+    pub fn compile(&self, ins: &[Ins]) -> Result<Executable, Error> {
+        let (code, labels) = Executable::compile(ins)?;
+        self.alloc(&code, labels)
+    }
+
+    fn alloc(&self, code: &[u8], labels: Vec<(u32, usize)>) -> Result<Executable, Error> {
+        if code.is_empty() {
+            return Err(Error::EmptyProgram);
+        }
+        let len = code.len().next_multiple_of(ALIGN);
+        let mut arenas = self.arenas.borrow_mut();
+
+        // First fit, adding an arena if none has room.
+        let found = arenas
+            .arenas
+            .iter()
+            .enumerate()
+            .find_map(|(a, arena)| Some((a, arena.free.iter().position(|f| f.len() >= len)?)));
+        let (a, k) = match found {
+            Some(found) => found,
+            None => {
+                let size = arenas.size.max(len.next_multiple_of(page_size()));
+                arenas.arenas.push(Arena::new(size)?);
+                (arenas.arenas.len() - 1, 0)
+            }
+        };
+        let arena = &mut arenas.arenas[a];
+        let start = arena.free[k].start;
+        arena.free[k].start += len;
+        if arena.free[k].is_empty() {
+            arena.free.remove(k);
+        }
+
+        let bytes = unsafe { arena.write(start, code) };
+        let block = Block { arenas: self.arenas.clone(), arena: a, range: start..start + len };
+        Ok(Executable { bytes, len: code.len(), memory: Memory::Heap(block), labels })
+    }
+}
+
+impl Default for CodeHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Arena {
+    #[cfg(target_os="linux")]
+    fn new(size: usize) -> Result<Self, Error> {
+        unsafe {
+            let fd = libc::memfd_create(c"ejit".as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(Error::MmapFailed(errno()));
+            }
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                let err = errno();
+                libc::close(fd);
+                return Err(Error::MmapFailed(err));
+            }
+            let addr = std::ptr::null_mut();
+            let rw = libc::mmap(addr, size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0);
+            if rw == libc::MAP_FAILED {
+                let err = errno();
+                libc::close(fd);
+                return Err(Error::MmapFailed(err));
+            }
+            let rx = libc::mmap(addr, size, libc::PROT_READ | libc::PROT_EXEC, libc::MAP_SHARED, fd, 0);
+            if rx == libc::MAP_FAILED {
+                let err = errno();
+                libc::munmap(rw, size);
+                libc::close(fd);
+                return Err(Error::MmapFailed(err));
+            }
+            // The mappings keep the memory alive.
+            libc::close(fd);
+            Ok(Self { rx: rx as *const u8, rw: rw as *mut u8, size, free: vec![0..size] })
+        }
+    }
+
+    #[cfg(target_os="macos")]
+    fn new(size: usize) -> Result<Self, Error> {
+        unsafe {
+            let prot = libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_JIT;
+            let mem = libc::mmap(std::ptr::null_mut(), size, prot, flags, -1, 0);
+            if mem == libc::MAP_FAILED {
+                return Err(Error::MmapFailed(errno()));
+            }
+            Ok(Self { rx: mem as *const u8, rw: mem as *mut u8, size, free: vec![0..size] })
+        }
+    }
+
+    /// Copy code to `start` and return its executable address.
+    unsafe fn write(&mut self, start: usize, code: &[u8]) -> *const u8 {
+        #[cfg(target_os="macos")]
+        libc::pthread_jit_write_protect_np(0);
+
+        std::slice::from_raw_parts_mut(self.rw.add(start), code.len()).copy_from_slice(code);
+
+        #[cfg(target_os="macos")]
+        libc::pthread_jit_write_protect_np(1);
+
+        let bytes = self.rx.add(start);
+        clear_cache::clear_cache(bytes, bytes.add(code.len()));
+        bytes
+    }
+
+    /// Return a block to the free list, joining it to its neighbours.
+    fn release(&mut self, range: Range<usize>) {
+        let k = self.free.partition_point(|f| f.start < range.start);
+        self.free.insert(k, range);
+        if k + 1 < self.free.len() && self.free[k].end == self.free[k + 1].start {
+            self.free[k].end = self.free.remove(k + 1).end;
+        }
+        if k > 0 && self.free[k - 1].end == self.free[k].start {
+            self.free[k - 1].end = self.free.remove(k).end;
+        }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.arenas.borrow_mut().arenas[self.arena].release(self.range.clone());
+    }
+}
+
+impl Drop for Arenas {
+    fn drop(&mut self) {
+        for arena in &self.arenas {
+            unsafe {
+                libc::munmap(arena.rx as *mut libc::c_void, arena.size);
+                if arena.rw as *const u8 != arena.rx {
+                    libc::munmap(arena.rw as *mut libc::c_void, arena.size);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn heap_many() {
+        use Ins::*;
+        use regs::*;
+        let heap = CodeHeap::new();
+        let progs = (0..10000).map(|n| heap.compile(&[Movi(RES[0], n), Ret]).unwrap()).collect::<Vec<_>>();
+        for (n, prog) in progs.iter().enumerate() {
+            let (res, _) = unsafe { prog.call(0, &[]).unwrap() };
+            assert_eq!(res, n as u64);
+        }
+        assert_eq!(heap.arenas.borrow().arenas.len(), 1);
+    }
+
+    #[test]
+    fn heap_reuse() {
+        use Ins::*;
+        use regs::*;
+        let heap = CodeHeap::with_arena_size(1);
+        let size = heap.arenas.borrow().size;
+        let mut progs = (0..size / 64).map(|n| Some(heap.compile(&[Movi(RES[0], n as u64), Ret]).unwrap())).collect::<Vec<_>>();
+        assert_eq!(heap.arenas.borrow().arenas.len(), 1);
+        assert!(heap.arenas.borrow().arenas[0].free.is_empty());
+
+        // Free separate blocks then one between two free blocks, which must be joined.
+        for k in [1, 3, 5] {
+            progs[k] = None;
+        }
+        assert_eq!(heap.arenas.borrow().arenas[0].free, [64..128, 192..256, 320..384]);
+        progs[4] = None;
+        assert_eq!(heap.arenas.borrow().arenas[0].free, [64..128, 192..384]);
+        progs.clear();
+        assert_eq!(heap.arenas.borrow().arenas[0].free, [0..size]);
+
+        let prog = heap.compile(&[Movi(RES[0], 7), Ret]).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[]).unwrap() };
+        assert_eq!(res, 7);
+        assert_eq!(heap.arenas.borrow().arenas.len(), 1);
+    }
+
+    #[test]
+    fn heap_large() {
+        use Ins::*;
+        use regs::*;
+        let heap = CodeHeap::with_arena_size(1);
+        let size = heap.arenas.borrow().size;
+        let mut ins = vec![Movi(RES[0], 0)];
+        ins.extend((0..size).map(|_| Add(RES[0], RES[0], RES[0])));
+        ins.push(Ret);
+        let prog = heap.compile(&ins).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[]).unwrap() };
+        assert_eq!(res, 0);
+        assert_eq!(heap.compile(&[]).unwrap_err(), Error::EmptyProgram);
+    }
+}
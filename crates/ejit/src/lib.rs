@@ -1,13 +1,14 @@
 #![allow(warnings)]
 #![doc = include_str!("../../../README.md")]
 
+use std::collections::HashMap;
 use std::path::Display;
 
 use clear_cache::clear_cache;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 /// Virtual 64 bit integer register
-pub struct R(pub u8);
+pub struct R(pub u16);
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 /// Virtual vector register
@@ -30,6 +31,22 @@ pub enum Cond {
     Uge,
     Ult,
     Ule,
+    // The carry flag. After Adds and Adc this is the unsigned carry out and after Subs, Sbc
+    // and Cmp it is set when there is no borrow, as on aarch64. x86_64 inverts the condition
+    // after a subtraction, so test it in straight line code after the instruction setting the flags.
+    // On x86_64 testing it at the start of the program or after a Label is an UnsupportedOperation.
+    Cs,
+    Cc,
+    // Signed overflow.
+    Vs,
+    Vc,
+    // Negative result.
+    Mi,
+    Pl,
+    // After Fcmp, Eq, Ult, Ule, Sgt and Sge are false if either operand is a NaN,
+    // Ne, Ugt, Uge, Slt and Sle are true. Vs tests for a NaN and Vc for neither being one.
+    // Cc and Mi are the same as Ult, Cs and Pl as Uge. On x86_64 test the flags of Fcmp in
+    // straight line code after it, after a Label the conditions are those of an integer compare.
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -75,6 +92,132 @@ enum Fixup {
     Adr(R, u32),
     B(Cond, u32),
     J(u32),
+    /// The 19 bit offset of an aarch64 cbz or cbnz already in the code.
+    Imm19(u32),
+    /// The 14 bit offset of an aarch64 tbz or tbnz already in the code.
+    Imm14(u32),
+    /// A 64 bit offset from 16 bytes before the fixup, used by aarch64 veneers.
+    Rel64(u32),
+}
+
+impl Fixup {
+    fn label(&self) -> u32 {
+        match self {
+            Fixup::Adr(_, label)
+            | Fixup::B(_, label)
+            | Fixup::J(label)
+            | Fixup::Imm19(label)
+            | Fixup::Imm14(label)
+            | Fixup::Rel64(label) => *label,
+        }
+    }
+}
+
+/// Label ids must be less than this.
+pub const MAX_LABEL: u32 = 1 << 24;
+
+/// Patch the fixup at a location in the code to refer to an offset.
+trait Patch: FnMut(&mut [u8], usize, Fixup, usize) -> Result<(), Error> {}
+
+impl<P: FnMut(&mut [u8], usize, Fixup, usize) -> Result<(), Error>> Patch for P {}
+
+/// Label offsets indexed by id, with the fixups waiting for labels not yet defined.
+#[derive(Default)]
+struct Labels {
+    slots: Vec<Slot>,
+
+    /// Ids from `dense` up are hashed so that a few high ids do not need a large table.
+    sparse: HashMap<u32, Slot>,
+    dense: usize,
+
+    /// Location, fixup and the index of the next fixup waiting for the same label.
+    pending: Vec<(usize, Fixup, usize)>,
+
+    defined: Vec<(u32, usize)>,
+}
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Unused,
+    /// The index of the last fixup waiting for this label.
+    Pending(usize),
+    Defined(usize),
+}
+
+/// End of a chain of pending fixups.
+const END: usize = usize::MAX;
+
+impl Labels {
+    /// Labels for a program of `len` instructions, which has no more labels than that.
+    fn new(len: usize) -> Self {
+        Labels { dense: len, ..Default::default() }
+    }
+
+    fn slot(&mut self, label: u32) -> Result<&mut Slot, Error> {
+        if label >= MAX_LABEL {
+            return Err(Error::LabelOutOfRange(label));
+        }
+        let index = label as usize;
+        if index >= self.dense {
+            return Ok(self.sparse.entry(label).or_insert(Slot::Unused));
+        }
+        if index >= self.slots.len() {
+            self.slots.resize(index + 1, Slot::Unused);
+        }
+        Ok(&mut self.slots[index])
+    }
+
+    /// The offset of a label defined earlier.
+    fn offset(&self, label: u32) -> Option<usize> {
+        let slot = if (label as usize) < self.dense { self.slots.get(label as usize) } else { self.sparse.get(&label) };
+        match slot {
+            Some(Slot::Defined(offset)) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// Define a label at the end of the code and patch the fixups waiting for it.
+    fn define(&mut self, label: u32, code: &mut [u8], patch: &mut impl Patch) -> Result<(), Error> {
+        let offset = code.len();
+        let mut next = match std::mem::replace(self.slot(label)?, Slot::Defined(offset)) {
+            Slot::Unused => END,
+            Slot::Pending(last) => last,
+            Slot::Defined(_) => return Err(Error::DuplicateLabel(label)),
+        };
+        self.defined.push((label, offset));
+        while next != END {
+            let (loc, f, prev) = self.pending[next];
+            patch(code, loc, f, offset)?;
+            next = prev;
+        }
+        Ok(())
+    }
+
+    /// Patch a fixup now if its label is defined, otherwise when it is.
+    fn fixup(&mut self, loc: usize, f: Fixup, code: &mut [u8], patch: &mut impl Patch) -> Result<(), Error> {
+        let index = self.pending.len();
+        let slot = self.slot(f.label())?;
+        let prev = match *slot {
+            Slot::Defined(offset) => return patch(code, loc, f, offset),
+            Slot::Pending(last) => last,
+            Slot::Unused => END,
+        };
+        *slot = Slot::Pending(index);
+        self.pending.push((loc, f, prev));
+        Ok(())
+    }
+
+    /// Check that every label referred to is defined, returning the labels sorted by id.
+    fn finish(mut self) -> Result<Vec<(u32, usize)>, Error> {
+        if let Some(label) = self.slots.iter().position(|s| matches!(s, Slot::Pending(_))) {
+            return Err(Error::MissingLabel(label as u32));
+        }
+        if let Some(label) = self.sparse.iter().filter(|(_, s)| matches!(s, Slot::Pending(_))).map(|(l, _)| *l).min() {
+            return Err(Error::MissingLabel(label));
+        }
+        self.defined.sort_unstable();
+        Ok(self.defined)
+    }
 }
 
 
@@ -84,7 +227,9 @@ pub enum Ins {
     Label(u32),
 
     // Function entry & exit: Adjust sp.
-    // Must be modulo 16 bytes
+    // Must be modulo 16 bytes on aarch64 and modulo 8 bytes on x86_64.
+    // On x86_64 `Call` has pushed the return address, so the stack is 8 bytes below
+    // a multiple of 16 at entry and a frame of 16n + 8 bytes aligns it for a `Call`.
     Enter(u32),
     Leave(u32),
 
@@ -94,6 +239,9 @@ pub enum Ins {
     // Mem
     Ld(Type, R, R, i32),
     St(Type, R, R, i32),
+    // Mem at `base + (index << scale)`, the index may not be SP.
+    Ldx(Type, R, R, R, u8),
+    Stx(Type, R, R, R, u8),
     Vld(Type, Vsize, V, R, i32),
     Vst(Type, Vsize, V, R, i32),
 
@@ -107,6 +255,7 @@ pub enum Ins {
     Shr(R, R, R),
     Sar(R, R, R),
     Mul(R, R, R),
+    // Division by zero gives zero and SDiv of i64::MIN by -1 gives i64::MIN, neither traps.
     UDiv(R, R, R),
     SDiv(R, R, R),
     Mov(R, R),
@@ -116,12 +265,64 @@ pub enum Ins {
     Not(R, R),
     Neg(R, R),
 
+    // Integer arithmetic with an immediate. Shifts use the low six bits of the immediate.
+    Addi(R, R, u64),
+    Subi(R, R, u64),
+    Andi(R, R, u64),
+    Ori(R, R, u64),
+    Xori(R, R, u64),
+    Shli(R, R, u64),
+    Shri(R, R, u64),
+    Sari(R, R, u64),
+
+    // Arithmetic that sets the flags for Cs, Vs, Mi and the rest, Adc and Sbc also consume the carry.
+    // Chain Adds with Adc or Subs with Sbc for multi-word arithmetic. Other instructions may change the flags.
+    Adds(R, R, R),
+    Adc(R, R, R),
+    Subs(R, R, R),
+    Sbc(R, R, R),
+
+    // The high half of the 128 bit product.
+    UMulH(R, R, R),
+    SMulH(R, R, R),
+    // Both halves of the 128 bit product, low then high, which must be different registers.
+    UMulL(R, R, R, R),
+    SMulL(R, R, R, R),
+    // The remainder has the sign of the dividend. The remainder of division by zero is the dividend
+    // and of i64::MIN by -1 is zero, so `a == b * (a / b) + a % b` always holds.
+    URem(R, R, R),
+    SRem(R, R, R),
+
+    // Extend the low 8, 16 or 32 bits of the source, set by the size of the type, to 64 bits.
+    // Zext(U32, ..) truncates 64 bit arithmetic to 32 bits.
+    Sext(Type, R, R),
+    Zext(Type, R, R),
+
+    // Leading and trailing zero counts, 64 for zero, and the number of set bits.
+    Clz(R, R),
+    Ctz(R, R),
+    Popcnt(R, R),
+    // Reverse the bytes of a U16, U32 or U64 and zero extend the result, S16 and S32 sign extend it.
+    Bswap(Type, R, R),
+    // Rotates, using the low six bits of the count.
+    Rol(R, R, R),
+    Ror(R, R, R),
+    // Bitfields of `width` bits at bit `lsb`. Ubfx and Sbfx extract a field and extend it to 64 bits,
+    // Bfi replaces a field of the destination with the low bits of the source.
+    Ubfx(R, R, u8, u8),
+    Sbfx(R, R, u8, u8),
+    Bfi(R, R, u8, u8),
+
     /// Vector arithmetic
     Vadd(Type, Vsize, V, V, V),
     Vsub(Type, Vsize, V, V, V),
     Vand(Type, Vsize, V, V, V),
     Vor(Type, Vsize, V, V, V),
     Vxor(Type, Vsize, V, V, V),
+    /// Vshl(type, vsize, dest, src1, src2) shifts each lane of src1 left by the same lane of src2.
+    /// Counts from the lane width to 127 give zero. Other counts differ: aarch64 uses the signed
+    /// low byte and shifts right if it is negative, x86_64 gives zero. On x86_64 Vshl needs AVX2
+    /// and has only 32 and 64 bit lanes.
     Vshl(Type, Vsize, V, V, V),
     Vshr(Type, Vsize, V, V, V),
     Vmul(Type, Vsize, V, V, V),
@@ -133,6 +334,82 @@ pub enum Ins {
     Vrecpe(Type, Vsize, V, V),
     Vrsqrte(Type, Vsize, V, V),
 
+    // Moves between integer and vector registers, lanes are in the low 128 bits.
+    // VmovFromR leaves the other lanes unchanged, VmovToR sign extends signed types.
+    VmovFromR(Type, V, R, u8),
+    VmovToR(Type, R, V, u8),
+    Vdup(Type, Vsize, V, R),
+
+    // Lane masks. Vcmp sets each lane to all ones if the condition holds, otherwise to zero.
+    // Integer compares take their signedness from the condition, float compares treat NaNs as Fcmp does.
+    /// Vcmp(cond, type, vsize, dest, src1, src2)
+    Vcmp(Cond, Type, Vsize, V, V, V),
+    /// Vbsl(type, vsize, dest, mask, src1, src2) takes the bits of src1 where the mask is set and of src2 elsewhere.
+    Vbsl(Type, Vsize, V, V, V, V),
+    /// As Vbsl, but takes whole lanes selected by the top bit of each lane of the mask.
+    Vblend(Type, Vsize, V, V, V, V),
+    // Integer Vmin and Vmax take the signedness from the type and have no 64 bit lanes,
+    // float ones treat NaNs as Fmin and Fmax do. Vabs treats integer lanes as signed.
+    Vmin(Type, Vsize, V, V, V),
+    Vmax(Type, Vsize, V, V, V),
+    Vabs(Type, Vsize, V, V),
+
+    // Lane permutes, which need at least two lanes.
+    /// Vtbl(vsize, dest, table, index) looks up each byte of the index in a 16 byte table, giving zero if out of range.
+    /// V256 looks up each 128 bit half of the index in the same half of the table.
+    Vtbl(Vsize, V, V, V),
+    // With a last operand of 0 Vzip interleaves the low halves of the sources, Vuzp takes the even lanes
+    // of src1 followed by those of src2 and Vtrn takes the even lanes of src1 and src2 into alternate lanes.
+    // With 1 they take the high halves and the odd lanes.
+    Vzip(Type, Vsize, V, V, V, u8),
+    Vuzp(Type, Vsize, V, V, V, u8),
+    Vtrn(Type, Vsize, V, V, V, u8),
+    /// Vext(type, vsize, dest, src1, src2, n) takes the lanes of src1 from lane n followed by those of src2.
+    Vext(Type, Vsize, V, V, V, u8),
+    /// Reverse the order of the lanes.
+    Vrev(Type, Vsize, V, V),
+
+    // Saturating arithmetic on 8 and 16 bit lanes, signed or unsigned as the type.
+    Vqadd(Type, Vsize, V, V, V),
+    Vqsub(Type, Vsize, V, V, V),
+    // Widening and narrowing, the type and size are those of the wide vector, which is V128 or V256.
+    // Vaddl and Vmull extend the lanes of the low halves of the sources as the type, then add or multiply them.
+    // Vxtn truncates and Vqmovn saturates each lane into the low half of the destination, the high half is undefined.
+    // Vqmovn has no 64 bit lanes.
+    Vaddl(Type, Vsize, V, V, V),
+    Vmull(Type, Vsize, V, V, V),
+    Vxtn(Type, Vsize, V, V),
+    Vqmovn(Type, Vsize, V, V),
+    /// Vaddv adds all the integer lanes into the first lane of the destination, the other lanes are undefined.
+    Vaddv(Type, Vsize, V, V),
+    /// Vaddp adds adjacent pairs of lanes of src1 followed by those of src2.
+    Vaddp(Type, Vsize, V, V, V),
+    /// Vdot(type, vsize, dest, src1, src2) adds the dot products of each group of four S8 or U8 lanes
+    /// to the 32 bit lanes of dest. On aarch64 this needs the dotprod feature.
+    Vdot(Type, Vsize, V, V, V),
+
+    // Scalar floating point on the low lane of vector registers, the type is F32 or F64.
+    // Fmin and Fmax give a NaN if either source is a NaN, the sign of a zero result is not defined.
+    Fadd(Type, V, V, V),
+    Fsub(Type, V, V, V),
+    Fmul(Type, V, V, V),
+    Fdiv(Type, V, V, V),
+    Fmin(Type, V, V, V),
+    Fmax(Type, V, V, V),
+    Fsqrt(Type, V, V),
+    Fabs(Type, V, V),
+    /// dest = src1 * src2 + src3 with a single rounding.
+    Fma(Type, V, V, V, V),
+    /// Compare two scalars setting the flags, see `Cond` for unordered results.
+    Fcmp(Type, V, V),
+    /// Convert between F16, F32 and F64, the types are those of the destination and the source.
+    Fcvt(Type, Type, V, V),
+    /// Convert a signed 64 bit integer to floating point.
+    Scvtf(Type, V, R),
+    /// Convert to a signed 64 bit integer rounding toward zero.
+    /// Out of range values saturate and a NaN gives zero. The flags are not defined afterwards.
+    Fcvtzs(Type, R, V),
+
     // Control flow
     /// Call indirect using stack or R(30)
     Call(R),
@@ -145,8 +422,28 @@ pub enum Ins {
     B(Cond, u32),
     J(u32),
 
+    /// Branch if a register is zero or not zero. The flags are not defined afterwards.
+    Cbz(R, u32),
+    Cbnz(R, u32),
+
+    /// Branch if a bit of a register is zero or not zero. The flags are not defined afterwards.
+    Tbz(R, u8, u32),
+    Tbnz(R, u8, u32),
+
+    /// Compare two registers as Cmp does and branch if the condition holds.
+    Bcmp(Cond, R, R, u32),
+
+    /// Conditional select, dest = cond ? t : f
     Sel(Cond, R, R, R),
 
+    /// dest = cond ? 1 : 0
+    Cset(Cond, R),
+
+    /// dest = cond ? t : f + 1, dest = cond ? t : -f and dest = cond ? t : !f
+    Csinc(Cond, R, R, R),
+    Csneg(Cond, R, R, R),
+    Csinv(Cond, R, R, R),
+
     /// Return using stack or R(30)
     Ret,
 
@@ -166,6 +463,7 @@ pub enum Error {
     BranchNotMod4(u32),
     InvalidType(Ins),
     StackFrameMustBeModulo16(Ins),
+    StackFrameMustBeModulo8(Ins),
     InvalidVectorSize(Ins),
     VectorOperationNotSupported(Ins),
     VectorSizeNotSupported(Ins),
@@ -174,18 +472,38 @@ pub enum Error {
     UnsupportedBaseOperation(Ins),
     UnsupportedOperation(Ins),
     InvalidDataType(Ins),
+    TooManyRegisters(Ins),
+    MmapFailed(i32),
+    MprotectFailed(i32),
+    EmptyProgram,
+    DuplicateLabel(u32),
+    LabelOutOfRange(u32),
 }
 
 pub struct Executable {
     bytes: *const u8,
     len: usize,
+    memory: Memory,
     labels: Vec<(u32, usize)>,
 }
 
+/// Where the code of an `Executable` lives.
+enum Memory {
+    /// Pages mapped for this `Executable` alone, the length is a whole number of pages.
+    Mapped(usize),
+
+    /// Part of a `CodeHeap` arena, returned when dropped.
+    Heap(heap::Block),
+}
+
 impl Executable {
-    fn new(code: &[u8], labels: Vec<(u32, usize)>) -> Self {
+    fn new(code: &[u8], labels: Vec<(u32, usize)>) -> Result<Self, Error> {
+        if code.is_empty() {
+            return Err(Error::EmptyProgram);
+        }
         let addr = std::ptr::null_mut();
         let len = code.len();
+        let mapped = len.next_multiple_of(page_size());
         let fd = -1;
         let offset = 0;
         #[cfg(target_os="macos")]
@@ -194,7 +512,10 @@ impl Executable {
             // Ian Hobson's Mac Jit runes.
             let prot = libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE;
             let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_JIT;
-            let mem = libc::mmap(addr, len, prot, flags, fd, offset);
+            let mem = libc::mmap(addr, mapped, prot, flags, fd, offset);
+            if mem == libc::MAP_FAILED {
+                return Err(Error::MmapFailed(errno()));
+            }
 
             libc::pthread_jit_write_protect_np(0);
 
@@ -205,41 +526,101 @@ impl Executable {
 
             let bytes = mem as *const u8;
             clear_cache::clear_cache(bytes, bytes.offset(code.len() as isize));
-            Self { bytes, len, labels }
+            Ok(Self { bytes, len, memory: Memory::Mapped(mapped), labels })
         }
         #[cfg(target_os="linux")]
         unsafe {
-            let prot = libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE;
+            // Write the code then make it executable, the pages are never both.
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
             let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
-            let mem = libc::mmap(addr, len, prot, flags, fd, offset);
+            let mem = libc::mmap(addr, mapped, prot, flags, fd, offset);
+            if mem == libc::MAP_FAILED {
+                return Err(Error::MmapFailed(errno()));
+            }
             let slice = std::slice::from_raw_parts_mut(mem as *mut u8, len);
             slice.copy_from_slice(&code);
+            if libc::mprotect(mem, mapped, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                let err = errno();
+                libc::munmap(mem, mapped);
+                return Err(Error::MprotectFailed(err));
+            }
             let bytes = mem as *const u8;
             clear_cache::clear_cache(bytes, bytes.offset(code.len() as isize));
-            Self { bytes, len, labels }
+            Ok(Self { bytes, len, memory: Memory::Mapped(mapped), labels })
         }
     }
 
-    pub unsafe fn call(&self, offset: usize, iargs: &[u64]) -> Result<(u64, u64), Error> {
+    /// Address of the code at `offset`.
+    fn entry(&self, offset: usize) -> Result<*const u8, Error> {
         if offset >= self.len {
             return Err(Error::InvalidOffset);
         }
-        let addr = self.bytes.offset(offset as isize);
-        match iargs {
-            &[] => {
-                let code: extern "C" fn() -> (u64, u64) = std::mem::transmute(addr);
-                Ok(code())
-            }
-            &[a] => {
-                let code: extern "C" fn(u64) -> (u64, u64) = std::mem::transmute(addr);
-                Ok(code(a))
-            }
-            &[a, b] => {
-                let code: extern "C" fn(u64,u64) -> (u64, u64) = std::mem::transmute(addr);
-                Ok(code(a, b))
-            }
-            _ => Err(Error::InvalidArgs),
-        }
+        Ok(unsafe { self.bytes.add(offset) })
+    }
+
+    /// Call the code at `offset` with up to eight integer arguments.
+    /// On x86_64 arguments after the sixth are passed on the stack.
+    pub unsafe fn call(&self, offset: usize, iargs: &[u64]) -> Result<(u64, u64), Error> {
+        let addr = self.entry(offset)?;
+        let [a, b, c, d, e, f, g, h] = pad(iargs)?;
+        let code: extern "C" fn(u64, u64, u64, u64, u64, u64, u64, u64) -> (u64, u64) = std::mem::transmute(addr);
+        Ok(code(a, b, c, d, e, f, g, h))
+    }
+
+    /// Call the code at `label` with up to eight integer arguments.
+    pub unsafe fn call_label(&self, label: u32, iargs: &[u64]) -> Result<(u64, u64), Error> {
+        let offset = self.label_offset(label).ok_or(Error::MissingLabel(label))?;
+        self.call(offset, iargs)
+    }
+
+    /// The byte offset of `label` in the code.
+    pub fn label_offset(&self, label: u32) -> Option<usize> {
+        let index = self.labels.binary_search_by_key(&label, |&(n, _)| n).ok()?;
+        Some(self.labels[index].1)
+    }
+
+    /// Every label and its byte offset, in order of label id.
+    pub fn labels(&self) -> impl Iterator<Item = (u32, usize)> + '_ {
+        self.labels.iter().copied()
+    }
+
+    /// Call the code at `offset` with up to eight integer and eight vector arguments,
+    /// returning the vector result.
+    pub unsafe fn call_vector(&self, offset: usize, iargs: &[u64], vargs: &[[u8; 16]]) -> Result<[u8; 16], Error> {
+        let addr = self.entry(offset)?;
+        let [a, b, c, d, e, f, g, h] = pad(iargs)?;
+        let [va, vb, vc, vd, ve, vf, vg, vh] = pad(vargs)?.map(|v| std::mem::transmute::<[u8; 16], Vector>(v));
+        let code: extern "C" fn(u64, u64, u64, u64, u64, u64, u64, u64, Vector, Vector, Vector, Vector, Vector, Vector, Vector, Vector) -> Vector = std::mem::transmute(addr);
+        Ok(std::mem::transmute(code(a, b, c, d, e, f, g, h, va, vb, vc, vd, ve, vf, vg, vh)))
+    }
+
+    /// Call the code at `offset` with up to eight integer and eight `f64` arguments,
+    /// returning the `f64` result.
+    pub unsafe fn call_float(&self, offset: usize, iargs: &[u64], fargs: &[f64]) -> Result<f64, Error> {
+        let addr = self.entry(offset)?;
+        let [a, b, c, d, e, f, g, h] = pad(iargs)?;
+        let [fa, fb, fc, fd, fe, ff, fg, fh] = pad(fargs)?;
+        let code: extern "C" fn(u64, u64, u64, u64, u64, u64, u64, u64, f64, f64, f64, f64, f64, f64, f64, f64) -> f64 = std::mem::transmute(addr);
+        Ok(code(a, b, c, d, e, f, g, h, fa, fb, fc, fd, fe, ff, fg, fh))
+    }
+
+    /// A typed function pointer to the code at `label`.
+    ///
+    /// The pointer must not be called after the `Executable` is dropped
+    /// and the code must follow the C calling convention for `F`.
+    ///
+    /// ```
+    /// # use ejit::*;
+    /// use Ins::*;
+    /// use regs::*;
+    /// let prog = Executable::from_ir(&[Label(7), Sub(RES[0], ARG[0], ARG[1]), Ret]).unwrap();
+    /// let sub = unsafe { prog.get_fn::<extern "C" fn(u64, u64) -> u64>(7).unwrap() };
+    /// assert_eq!(sub(10, 3), 7);
+    /// ```
+    pub unsafe fn get_fn<F: FnPtr>(&self, label: u32) -> Result<F, Error> {
+        let offset = self.label_offset(label).ok_or(Error::MissingLabel(label))?;
+        let addr = self.entry(offset)?;
+        Ok(std::mem::transmute_copy(&addr))
     }
 
     fn to_bytes(&self) -> Vec<u8> {
@@ -253,10 +634,22 @@ impl Executable {
         self.to_bytes().chunks_exact(4).map(|c| format!("{:08x}", u32::from_be_bytes(c.try_into().unwrap()))).collect::<Vec<String>>().join(" ")
     }
 
+    /// Bytes separated by spaces, for variable length x86 code.
+    pub fn fmt_8(&self) -> String {
+        self.to_bytes().iter().map(|b| format!("{b:02x}")).collect::<Vec<String>>().join(" ")
+    }
+
+    #[cfg(target_arch = "aarch64")]
     pub fn fmt_url(&self) -> String {
         let opcodes = self.to_bytes().chunks_exact(4).map(|c| format!("{:08x}", u32::from_be_bytes(c.try_into().unwrap()))).collect::<Vec<String>>().join("+");
         format!("https://shell-storm.org/online/Online-Assembler-and-Disassembler/?opcodes={opcodes}&arch=arm64&endianness=little&baddr=0x00000000&dis_with_addr=True&dis_with_raw=True&dis_with_ins=True#disassembly")
     }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn fmt_url(&self) -> String {
+        let opcodes = self.to_bytes().iter().map(|b| format!("{b:02x}")).collect::<Vec<String>>().join("+");
+        format!("https://shell-storm.org/online/Online-Assembler-and-Disassembler/?opcodes={opcodes}&arch=x86-64&endianness=little&baddr=0x00000000&dis_with_addr=True&dis_with_raw=True&dis_with_ins=True#disassembly")
+    }
 }
 
 impl std::fmt::Debug for Executable {
@@ -265,10 +658,65 @@ impl std::fmt::Debug for Executable {
     }
 }
 
+/// Pad arguments with zeros, the callee ignores those it does not use.
+fn pad<T: Copy + Default, const N: usize>(args: &[T]) -> Result<[T; N], Error> {
+    if args.len() > N {
+        return Err(Error::InvalidArgs);
+    }
+    let mut res = [T::default(); N];
+    res[..args.len()].copy_from_slice(args);
+    Ok(res)
+}
+
+/// A 128 bit vector passed in a vector register.
+#[cfg(target_arch = "x86_64")]
+type Vector = std::arch::x86_64::__m128i;
+
+#[cfg(target_arch = "aarch64")]
+type Vector = std::arch::aarch64::uint8x16_t;
+
+/// Function pointer types which `Executable::get_fn` may return.
+pub unsafe trait FnPtr: Copy {}
+
+macro_rules! fn_ptr {
+    ($($arg:ident),*) => {
+        unsafe impl<Ret, $($arg),*> FnPtr for extern "C" fn($($arg),*) -> Ret {}
+        unsafe impl<Ret, $($arg),*> FnPtr for unsafe extern "C" fn($($arg),*) -> Ret {}
+    };
+}
+
+fn_ptr!();
+fn_ptr!(A);
+fn_ptr!(A, B);
+fn_ptr!(A, B, C);
+fn_ptr!(A, B, C, D);
+fn_ptr!(A, B, C, D, E);
+fn_ptr!(A, B, C, D, E, F);
+fn_ptr!(A, B, C, D, E, F, G);
+fn_ptr!(A, B, C, D, E, F, G, H);
+fn_ptr!(A, B, C, D, E, F, G, H, I);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J, K);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J, K, L);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J, K, L, M);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J, K, L, M, N);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O);
+fn_ptr!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
 impl Drop for Executable {
     fn drop(&mut self) {
-        unsafe {
-            libc::munmap(self.bytes as *mut libc::c_void, self.len as libc::size_t);
+        if let Memory::Mapped(mapped) = self.memory {
+            unsafe {
+                libc::munmap(self.bytes as *mut libc::c_void, mapped as libc::size_t);
+            }
         }
     }
 }
@@ -285,6 +733,14 @@ mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::regs;
 
+pub mod portable;
+
+pub mod regalloc;
+
+mod heap;
+
+pub use heap::CodeHeap;
+
 #[cfg(test)]
 mod generic_tests {
     //! Machine independent tests
@@ -357,6 +813,993 @@ mod generic_tests {
         test_one_branch(Ule, [true, true, false, true, false]);
     }
 
+    #[test]
+    fn generic_immediates() {
+        use Ins::*;
+        use regs::*;
+        let values = [0, 1, 0xfff, 0x1000, 0x1001, 0x123000, 0x1234_5678, 0x5555_5555_5555_5555, 0x1234_5678_9abc_def0, !0x1234, !0x1000, !0];
+        for imm in values {
+            let prog = Executable::from_ir(&[
+                Movi(RES[0], imm),
+                Ret,
+            ])
+            .unwrap();
+            let (res, _) = unsafe { prog.call(0, &[]).unwrap() };
+            assert_eq!(res, imm, "Movi({imm:#x})");
+
+            const EQ : u32 = 0;
+            let prog = Executable::from_ir(&[
+                Movi(RES[0], 1),
+                Cmpi(ARG[0], imm),
+                B(Cond::Eq, EQ),
+                Movi(RES[0], 0),
+                Label(EQ),
+                Ret,
+            ])
+            .unwrap();
+            for arg in values {
+                let (res, _) = unsafe { prog.call(0, &[arg]).unwrap() };
+                assert_eq!(res, (arg == imm) as u64, "Cmpi({arg:#x}, {imm:#x})");
+            }
+        }
+    }
+
+    #[test]
+    fn generic_carry() {
+        use Ins::*;
+        use regs::*;
+        // 128 bit add and subtract of (a1:a0) and (b1:b0).
+        let add = Executable::from_ir(&[Adds(RES[0], ARG[0], ARG[2]), Adc(RES[1], ARG[1], ARG[3]), Ret]).unwrap();
+        let sub = Executable::from_ir(&[Subs(RES[0], ARG[0], ARG[2]), Sbc(RES[1], ARG[1], ARG[3]), Ret]).unwrap();
+        let values = [0, 1, 0x8000_0000_0000_0000, !0, 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210, u128::MAX];
+        for a in values {
+            for b in values {
+                let args = [a as u64, (a >> 64) as u64, b as u64, (b >> 64) as u64];
+                let (lo, hi) = unsafe { add.call(0, &args).unwrap() };
+                assert_eq!((hi as u128) << 64 | lo as u128, a.wrapping_add(b), "{a:#x} + {b:#x}");
+                let (lo, hi) = unsafe { sub.call(0, &args).unwrap() };
+                assert_eq!((hi as u128) << 64 | lo as u128, a.wrapping_sub(b), "{a:#x} - {b:#x}");
+            }
+        }
+
+        // The carry out of a subtraction chain is set when there is no borrow.
+        const TAKEN: u32 = 0;
+        for (c, borrow) in [(Cond::Cs, false), (Cond::Cc, true)] {
+            let prog = Executable::from_ir(&[
+                Subs(ARG[0], ARG[0], ARG[2]),
+                Sbc(ARG[1], ARG[1], ARG[3]),
+                Movi(RES[0], 1),
+                B(c, TAKEN),
+                Movi(RES[0], 0),
+                Label(TAKEN),
+                Ret,
+            ])
+            .unwrap();
+            let cset = Executable::from_ir(&[Subs(ARG[0], ARG[0], ARG[2]), Sbc(ARG[1], ARG[1], ARG[3]), Cset(c, RES[0]), Ret]).unwrap();
+            for a in values {
+                for b in values {
+                    let args = [a as u64, (a >> 64) as u64, b as u64, (b >> 64) as u64];
+                    let (taken, _) = unsafe { prog.call(0, &args).unwrap() };
+                    assert_eq!(taken != 0, (a < b) == borrow, "{c:?} {a:#x} - {b:#x}");
+                    let (set, _) = unsafe { cset.call(0, &args).unwrap() };
+                    assert_eq!(set != 0, (a < b) == borrow, "Cset {c:?} {a:#x} - {b:#x}");
+                }
+            }
+        }
+
+        fn test_flags(c: Cond, op: fn(R, R, R) -> Ins, expected: [bool; 5]) {
+            use Ins::*;
+            use regs::*;
+            const IS_TRUE : u32 = 0;
+            let prog = Executable::from_ir(&[
+                op(ARG[0], ARG[0], ARG[1]),
+                Movi(RES[0], 1),
+                B(c, IS_TRUE),
+                Movi(RES[0], 0),
+                Label(IS_TRUE),
+                Ret,
+            ])
+            .unwrap();
+            let tv = [[1, 1], [!0, 1], [i64::MAX as u64, 1], [i64::MIN as u64, 1], [0, 0]];
+            let res = tv.map(|args| unsafe { prog.call(0, &args).unwrap().0 != 0 });
+            assert_eq!(expected, res, "{:?} {:?}", c, op(ARG[0], ARG[0], ARG[1]));
+        }
+
+        use Cond::*;
+        test_flags(Cs, Adds, [false, true, false, false, false]);
+        test_flags(Cc, Adds, [true, false, true, true, true]);
+        test_flags(Vs, Adds, [false, false, true, false, false]);
+        test_flags(Vc, Adds, [true, true, false, true, true]);
+        test_flags(Mi, Adds, [false, false, true, true, false]);
+        test_flags(Pl, Adds, [true, true, false, false, true]);
+        test_flags(Vs, Subs, [false, false, false, true, false]);
+        test_flags(Mi, Subs, [false, true, false, false, false]);
+        test_flags(Ult, Subs, [false, false, false, false, false]);
+        test_flags(Cs, Subs, [true, true, true, true, true]);
+        test_flags(Eq, Subs, [true, false, false, false, true]);
+    }
+
+    #[test]
+    fn generic_cond_select() {
+        use Cond::*;
+        use Ins::*;
+        use regs::*;
+        let conds = [Eq, Ne, Sgt, Sge, Slt, Sle, Ugt, Uge, Ult, Ule];
+        let expected = |c: &Cond, a: u64, b: u64| match c {
+            Eq => a == b,
+            Ne => a != b,
+            Sgt => (a as i64) > (b as i64),
+            Sge => (a as i64) >= (b as i64),
+            Slt => (a as i64) < (b as i64),
+            Sle => (a as i64) <= (b as i64),
+            Ugt => a > b,
+            Uge => a >= b,
+            Ult => a < b,
+            Ule => a <= b,
+            _ => unreachable!(),
+        };
+        let values = [0, 1, 2, i64::MAX as u64, i64::MIN as u64, !0];
+        let (t, f) = (ARG[2], ARG[3]);
+        for c in conds {
+            let ops: [(Ins, fn(bool, u64, u64) -> u64); 5] = [
+                (Cset(c, RES[0]), |c, _, _| c as u64),
+                (Sel(c, RES[0], t, f), |c, t, f| if c { t } else { f }),
+                (Csinc(c, RES[0], t, f), |c, t, f| if c { t } else { f.wrapping_add(1) }),
+                (Csneg(c, RES[0], t, f), |c, t, f| if c { t } else { f.wrapping_neg() }),
+                (Csinv(c, RES[0], t, f), |c, t, f| if c { t } else { !f }),
+            ];
+            for (ins, op) in ops {
+                let prog = Executable::from_ir(&[Cmp(ARG[0], ARG[1]), ins.clone(), Ret]).unwrap();
+                for a in values {
+                    for b in values {
+                        let (res, _) = unsafe { prog.call(0, &[a, b, 7, !0]).unwrap() };
+                        assert_eq!(res, op(expected(&c, a, b), 7, !0), "{ins:?} {a:#x} {b:#x}");
+                    }
+                }
+            }
+        }
+
+        // The destination may also be one of the sources.
+        let prog = Executable::from_ir(&[
+            Cmp(ARG[0], ARG[1]),
+            Csinc(Eq, ARG[2], ARG[3], ARG[2]),
+            Csneg(Eq, ARG[3], ARG[3], ARG[2]),
+            Sub(RES[0], ARG[2], ARG[3]),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(unsafe { prog.call(0, &[1, 1, 10, 3]).unwrap().0 }, 0);
+        assert_eq!(unsafe { prog.call(0, &[1, 2, 10, 3]).unwrap().0 }, 22);
+    }
+
+    /// Run scalar floating point instructions on `args` in V(0), V(1) and V(2) returning V(3).
+    fn run_float(ty: Type, ins: &[Ins], args: [f64; 3]) -> f64 {
+        use Ins::*;
+        use regs::*;
+        let (size, vsize) = if ty == Type::F32 { (4, Vsize::V32) } else { (8, Vsize::V64) };
+        let mut prog = (0..4).map(|n| Vld(ty, vsize, V(n as u8), ARG[0], n * size)).collect::<Vec<_>>();
+        prog.extend_from_slice(ins);
+        prog.extend([Vst(ty, vsize, V(3), ARG[1], 0), Ret]);
+        let prog = Executable::from_ir(&prog).unwrap();
+        let mut mem = [0_u64; 4];
+        let mut res = 0_u64;
+        for (m, a) in mem.iter_mut().zip(args) {
+            *m = if ty == Type::F32 { (a as f32).to_bits() as u64 } else { a.to_bits() };
+        }
+        if ty == Type::F32 {
+            let mem = mem.map(|m| m as u32);
+            unsafe { prog.call(0, &[mem.as_ptr() as u64, &mut res as *mut u64 as u64]).unwrap() };
+            f32::from_bits(res as u32) as f64
+        } else {
+            unsafe { prog.call(0, &[mem.as_ptr() as u64, &mut res as *mut u64 as u64]).unwrap() };
+            f64::from_bits(res)
+        }
+    }
+
+    #[test]
+    fn generic_float() {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        let same = |a: f64, b: f64| a == b || a.is_nan() && b.is_nan();
+        let nan_or = |a: f64, b: f64, f: fn(f64, f64) -> f64| if a.is_nan() || b.is_nan() { f64::NAN } else { f(a, b) };
+        let values = [0.0, -0.0, 1.5, -2.25, 0.1, 3.0, 1e10, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+        let (d, a, b, c) = (V(3), V(0), V(1), V(2));
+        for ty in [F32, F64] {
+            let round = |x: f64| if ty == F32 { x as f32 as f64 } else { x };
+            for x in values.map(round) {
+                for y in values.map(round) {
+                    let ops: [(Ins, f64); 6] = [
+                        (Fadd(ty, d, a, b), x + y),
+                        (Fsub(ty, d, a, b), x - y),
+                        (Fmul(ty, d, a, b), x * y),
+                        (Fdiv(ty, d, a, b), x / y),
+                        (Fmin(ty, d, a, b), nan_or(x, y, f64::min)),
+                        (Fmax(ty, d, a, b), nan_or(x, y, f64::max)),
+                    ];
+                    for (ins, expected) in ops {
+                        let res = run_float(ty, &[ins.clone()], [x, y, 0.0]);
+                        assert!(same(res, round(expected)), "{ins:?} {x} {y} = {res}");
+                    }
+                    // The destination may be a source.
+                    let res = run_float(ty, &[Fsub(ty, b, a, b), Fcvt(ty, ty, d, b)], [x, y, 0.0]);
+                    assert!(same(res, round(x - y)), "{x} - {y} = {res}");
+                }
+                let res = run_float(ty, &[Fsqrt(ty, d, a)], [x, 0.0, 0.0]);
+                assert!(same(res, round(x.sqrt())), "sqrt {x} = {res}");
+                let res = run_float(ty, &[Fabs(ty, d, a)], [x, 0.0, 0.0]);
+                assert!(same(res, x.abs()) && res.is_sign_positive(), "abs {x} = {res}");
+            }
+
+            for ins in [Fma(ty, d, a, b, c), Fma(ty, a, a, b, c), Fma(ty, b, a, b, c), Fma(ty, c, a, b, c)] {
+                let dest = match ins {
+                    Fma(_, dest, ..) => dest,
+                    _ => unreachable!(),
+                };
+                let ins = [ins, Fcvt(ty, ty, d, dest)];
+                match Executable::from_ir(&ins) {
+                    Err(Error::UnsupportedOperation(_)) => continue,
+                    res => res.unwrap(),
+                };
+                // 0.1 * 10 - 1 is not zero if rounded once.
+                let expected = round(0.1).mul_add(10.0, -1.0);
+                assert_eq!(run_float(ty, &ins, [0.1, 10.0, -1.0]), round(expected), "{ins:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn generic_float_compare() {
+        use Cond::*;
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        let values = [0.0, -0.0, 1.0, -1.0, f64::INFINITY, f64::NAN];
+        for ty in [F32, F64] {
+            let (size, vsize) = if ty == F32 { (4, Vsize::V32) } else { (8, Vsize::V64) };
+            for (c, f) in [
+                (Eq, (|a, b| a == b) as fn(f64, f64) -> bool),
+                (Ne, |a, b| a != b),
+                (Ult, |a, b| a < b),
+                (Ule, |a, b| a <= b),
+                (Sgt, |a, b| a > b),
+                (Sge, |a, b| a >= b),
+                (Ugt, |a, b| !(a <= b)),
+                (Uge, |a, b| !(a < b)),
+                (Slt, |a, b| !(a >= b)),
+                (Sle, |a, b| !(a > b)),
+                (Vs, |a, b| a.is_nan() || b.is_nan()),
+                (Vc, |a, b| !a.is_nan() && !b.is_nan()),
+                (Cs, |a, b| !(a < b)),
+                (Cc, |a, b| a < b),
+                (Mi, |a, b| a < b),
+                (Pl, |a, b| !(a < b)),
+            ] {
+                // Cset gives 1 if the condition holds, the others 2. Otherwise they give 0, 3 or Csinc 4.
+                for (n, test) in [
+                    vec![Cset(c, RES[0])],
+                    vec![Sel(c, RES[0], ARG[1], ARG[2])],
+                    vec![Mov(RES[0], ARG[1]), Sel(c, RES[0], RES[0], ARG[2])],
+                    vec![Mov(RES[0], ARG[2]), Sel(c, RES[0], ARG[1], RES[0])],
+                    vec![Movi(RES[0], 2), B(c, 0), Movi(RES[0], 3), Label(0)],
+                    vec![Csinc(c, RES[0], ARG[1], ARG[2])],
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    let mut ins = vec![Vld(ty, vsize, V(0), ARG[0], 0), Vld(ty, vsize, V(1), ARG[0], size), Fcmp(ty, V(0), V(1))];
+                    ins.extend(test);
+                    ins.push(Ret);
+                    let prog = Executable::from_ir(&ins).unwrap();
+                    for a in values {
+                        for b in values {
+                            let mem = if ty == F32 {
+                                [(a as f32).to_bits() as u64 | ((b as f32).to_bits() as u64) << 32, 0]
+                            } else {
+                                [a.to_bits(), b.to_bits()]
+                            };
+                            let (res, _) = unsafe { prog.call(0, &[mem.as_ptr() as u64, 2, 3]).unwrap() };
+                            let expected = match (n, f(a, b)) {
+                                (0, t) => t as u64,
+                                (_, true) => 2,
+                                (5, false) => 4,
+                                (_, false) => 3,
+                            };
+                            assert_eq!(res, expected, "{ty:?} {c:?} {n} {a} {b}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generic_float_convert() {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        for (ty, vsize) in [(F32, Vsize::V32), (F64, Vsize::V64)] {
+            let scvtf = Executable::from_ir(&[Scvtf(ty, V(0), ARG[0]), Vst(ty, vsize, V(0), ARG[1], 0), Ret]).unwrap();
+            let fcvtzs = Executable::from_ir(&[Vld(ty, vsize, V(0), ARG[0], 0), Fcvtzs(ty, RES[0], V(0)), Ret]).unwrap();
+            for i in [0, 1, -1, 3, 1 << 40, i64::MAX, i64::MIN, 0x0123_4567_89ab_cdef] {
+                let mut res = 0_u64;
+                unsafe { scvtf.call(0, &[i as u64, &mut res as *mut u64 as u64]).unwrap() };
+                if ty == F32 {
+                    assert_eq!(f32::from_bits(res as u32), i as f32);
+                } else {
+                    assert_eq!(f64::from_bits(res), i as f64);
+                }
+            }
+            for x in [0.0, -0.0, 0.5, -0.5, 1.9, -1.9, 1e10, -1e10, 1e30, -1e30, f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+                let bits = if ty == F32 { (x as f32).to_bits() as u64 } else { x.to_bits() };
+                let expected = if ty == F32 { x as f32 as i64 } else { x as i64 };
+                let (res, _) = unsafe { fcvtzs.call(0, &[&bits as *const u64 as u64]).unwrap() };
+                assert_eq!(res as i64, expected, "{ty:?} {x}");
+            }
+        }
+
+        // F16 bits, F32 value.
+        let halves = [(0x3c00, 1.0), (0xc000, -2.0), (0x7bff, 65504.0), (0x3800, 0.5), (0x3555, 0.333251953125), (0x7c00, f32::INFINITY)];
+        let convert = |dty: Type, sty: Type, x: u64| {
+            let vsize = |ty| if ty == F64 { Vsize::V64 } else { Vsize::V32 };
+            let ins = [Vld(sty, vsize(sty), V(0), ARG[0], 0), Fcvt(dty, sty, V(1), V(0)), Vst(dty, vsize(dty), V(1), ARG[1], 0), Ret];
+            let ins = ins.map(|i| match i {
+                // There are no F16 loads and stores, use the low bits of an F32.
+                Vld(F16, ..) => Vld(F32, Vsize::V32, V(0), ARG[0], 0),
+                Vst(F16, ..) => Vst(F32, Vsize::V32, V(1), ARG[1], 0),
+                i => i,
+            });
+            let prog = match Executable::from_ir(&ins) {
+                Err(Error::UnsupportedOperation(_)) => return None,
+                res => res.unwrap(),
+            };
+            let mut res = 0_u64;
+            unsafe { prog.call(0, &[&x as *const u64 as u64, &mut res as *mut u64 as u64]).unwrap() };
+            Some(res)
+        };
+        for (h, f) in halves {
+            let d = f as f64;
+            assert_eq!(convert(F64, F32, f.to_bits() as u64), Some(d.to_bits()));
+            assert_eq!(convert(F32, F64, d.to_bits()), Some(f.to_bits() as u64));
+            if let Some(res) = convert(F32, F16, h) {
+                assert_eq!(res as u32, f.to_bits(), "{h:#x}");
+                assert_eq!(convert(F16, F32, f.to_bits() as u64).map(|r| r & 0xffff), Some(h), "{f}");
+                assert_eq!(convert(F64, F16, h), Some(d.to_bits()), "{h:#x}");
+            }
+        }
+        // Rounded to nearest.
+        if let Some(res) = convert(F16, F32, (1.0_f32 / 3.0).to_bits() as u64) {
+            assert_eq!(res & 0xffff, 0x3555);
+        }
+    }
+
+    #[test]
+    fn generic_lanes() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        use regs::*;
+        let input: [u8; 16] = std::array::from_fn(|i| (i * 37 + 11) as u8);
+        let value = 0xfedc_ba98_7654_3210_u64;
+        for (ty, size) in [(U8, 1), (S8, 1), (U16, 2), (S16, 2), (U32, 4), (S32, 4), (F32, 4), (U64, 8), (F64, 8)] {
+            for lane in 0..16 / size {
+                let prog = Executable::from_ir(&[
+                    Vld(U8, V128, V(0), ARG[0], 0),
+                    VmovFromR(ty, V(0), ARG[2], lane as u8),
+                    Vst(U8, V128, V(0), ARG[1], 0),
+                    VmovToR(ty, RES[0], V(0), lane as u8),
+                    Ret,
+                ])
+                .unwrap();
+                let mut output = [0_u8; 16];
+                let args = [input.as_ptr() as u64, output.as_mut_ptr() as u64, value];
+                let (res, _) = unsafe { prog.call(0, &args).unwrap() };
+                let mut expected = input;
+                expected[lane * size..lane * size + size].copy_from_slice(&value.to_le_bytes()[0..size]);
+                assert_eq!(output, expected, "{ty:?} {lane}");
+                let bits = size as u32 * 8;
+                let expected = match ty {
+                    S8 | S16 | S32 => ((value << 64 - bits) as i64 >> 64 - bits) as u64,
+                    _ => value & (!0 >> 64 - bits),
+                };
+                assert_eq!(res, expected, "{ty:?} {lane}");
+            }
+            for vsize in [V64, V128] {
+                let prog = Executable::from_ir(&[Vdup(ty, vsize, V(0), ARG[0]), Vst(U8, vsize, V(0), ARG[1], 0), Ret]).unwrap();
+                let mut output = [0_u8; 16];
+                unsafe { prog.call(0, &[value, output.as_mut_ptr() as u64]).unwrap() };
+                let len = if vsize == V64 { 8 } else { 16 };
+                for lane in output[0..len].chunks(size) {
+                    assert_eq!(lane, &value.to_le_bytes()[0..size], "{ty:?} {vsize:?}");
+                }
+            }
+        }
+        let ins = VmovToR(U32, RES[0], V(0), 4);
+        assert_eq!(Executable::from_ir(&[ins.clone(), Ret]).unwrap_err(), Error::InvalidImmediate(ins));
+    }
+
+    /// Run `ins` with V(0), V(1) and V(2) loaded from `a`, `b` and `c`, returning V(3).
+    fn run_vector(vsize: Vsize, ins: &[Ins], a: [u8; 32], b: [u8; 32], c: [u8; 32]) -> [u8; 32] {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        let mut prog = vec![Vld(U8, vsize, V(0), ARG[0], 0), Vld(U8, vsize, V(1), ARG[1], 0), Vld(U8, vsize, V(2), ARG[2], 0)];
+        prog.extend_from_slice(ins);
+        prog.extend([Vst(U8, vsize, V(3), ARG[3], 0), Ret]);
+        let prog = Executable::from_ir(&prog).unwrap();
+        let mut output = [0_u8; 32];
+        let args = [a.as_ptr() as u64, b.as_ptr() as u64, c.as_ptr() as u64, output.as_mut_ptr() as u64];
+        unsafe { prog.call(0, &args).unwrap() };
+        output
+    }
+
+    /// Whether the host has AVX2, which x86_64 needs for `V256` and `Vshl`.
+    fn avx2() -> bool {
+        #[cfg(target_arch = "x86_64")]
+        return std::is_x86_feature_detected!("avx2");
+        #[cfg(target_arch = "aarch64")]
+        return false;
+    }
+
+    /// Lane `n` of `size` bytes.
+    fn lane(v: &[u8; 32], size: usize, n: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes[0..size].copy_from_slice(&v[n * size..n * size + size]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn generic_vector_shift() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let a: [u8; 32] = std::array::from_fn(|i| (i * 37 + 11) as u8);
+        let (d, x, y) = (V(3), V(0), V(1));
+        if cfg!(target_arch = "x86_64") && !avx2() {
+            return;
+        }
+        for (ty, size) in [(U8, 1), (S8, 1), (U16, 2), (S16, 2), (U32, 4), (S32, 4), (U64, 8), (S64, 8)] {
+            let bits = size * 8;
+            for vsize in [V64, V128] {
+                if cfg!(target_arch = "x86_64") && size < 4 {
+                    let ins = Vshl(ty, vsize, d, x, y);
+                    assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorTypeNotSupported(ins));
+                    continue;
+                }
+                let len = if vsize == V64 { 8 } else { 16 };
+                for count in [0, 1, 5, bits - 1, bits, 127] {
+                    let mut b = [0; 32];
+                    for n in 0..32 / size {
+                        b[n * size] = count as u8;
+                    }
+                    for dest in [d, x, y] {
+                        let ins = [Vshl(ty, vsize, dest, x, y), Vmov(U8, vsize, d, dest)];
+                        let output = run_vector(vsize, &ins, a, b, a);
+                        for n in 0..len / size {
+                            let expected = if count < bits { lane(&a, size, n) << count & !0 >> 64 - bits } else { 0 };
+                            assert_eq!(lane(&output, size, n), expected, "{ins:?} {count} lane {n}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generic_vector_select() {
+        use Cond::*;
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        // The high halves of a and b are equal.
+        let a: [u8; 32] = std::array::from_fn(|i| (i * 37 + 11) as u8);
+        let b: [u8; 32] = std::array::from_fn(|i| if i < 8 { (i * 91 + 200) as u8 } else { a[i] });
+        let c: [u8; 32] = std::array::from_fn(|i| (i * 59 + 130) as u8);
+        let (d, x, y, m) = (V(3), V(0), V(1), V(2));
+        let int_conds: [(Cond, fn(i64, i64, u64, u64) -> bool); 10] = [
+            (Eq, |_, _, a, b| a == b),
+            (Ne, |_, _, a, b| a != b),
+            (Sgt, |a, b, _, _| a > b),
+            (Sge, |a, b, _, _| a >= b),
+            (Slt, |a, b, _, _| a < b),
+            (Sle, |a, b, _, _| a <= b),
+            (Ugt, |_, _, a, b| a > b),
+            (Uge, |_, _, a, b| a >= b),
+            (Ult, |_, _, a, b| a < b),
+            (Ule, |_, _, a, b| a <= b),
+        ];
+        for (ty, size) in [(U8, 1), (S8, 1), (U16, 2), (S16, 2), (U32, 4), (S32, 4), (U64, 8), (S64, 8)] {
+            let bits = size * 8;
+            let ones = !0_u64 >> 64 - bits;
+            let ext = |x: u64| (x << 64 - bits) as i64 >> 64 - bits;
+            for vsize in [V64, V128] {
+                let len = if vsize == V64 { 8 } else { 16 };
+                let check = |ins: &[Ins], f: &dyn Fn(u64, u64, u64) -> u64| {
+                    let output = run_vector(vsize, ins, a, b, c);
+                    for n in 0..len / size {
+                        let expected = f(lane(&a, size, n), lane(&b, size, n), lane(&c, size, n));
+                        assert_eq!(lane(&output, size, n), expected, "{ins:?} lane {n}");
+                    }
+                };
+                for (cond, f) in int_conds {
+                    for dest in [d, x, y] {
+                        let ins = [Vcmp(cond, ty, vsize, dest, x, y), Vmov(U8, vsize, d, dest)];
+                        check(&ins, &|a, b, _| if f(ext(a), ext(b), a, b) { ones } else { 0 });
+                    }
+                }
+                for dest in [d, x, y, m] {
+                    check(&[Vbsl(ty, vsize, dest, m, x, y), Vmov(U8, vsize, d, dest)], &|a, b, c| c & a | !c & b);
+                    check(&[Vblend(ty, vsize, dest, m, x, y), Vmov(U8, vsize, d, dest)], &|a, b, c| if ext(c) < 0 { a } else { b });
+                }
+                if size == 8 {
+                    let ins = Vmin(ty, vsize, d, x, y);
+                    assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorTypeNotSupported(ins));
+                } else if matches!(ty, S8 | S16 | S32) {
+                    check(&[Vmin(ty, vsize, d, x, y)], &|a, b, _| ext(a).min(ext(b)) as u64 & ones);
+                    check(&[Vmax(ty, vsize, d, x, y)], &|a, b, _| ext(a).max(ext(b)) as u64 & ones);
+                } else {
+                    check(&[Vmin(ty, vsize, d, x, y)], &|a, b, _| a.min(b));
+                    check(&[Vmax(ty, vsize, d, x, y)], &|a, b, _| a.max(b));
+                }
+                check(&[Vabs(ty, vsize, d, x)], &|a, _, _| ext(a).unsigned_abs() & ones);
+            }
+        }
+
+        let float_conds: [(Cond, fn(f64, f64) -> bool); 10] = [
+            (Eq, |a, b| a == b),
+            (Ne, |a, b| a != b),
+            (Ult, |a, b| a < b),
+            (Ule, |a, b| a <= b),
+            (Sgt, |a, b| a > b),
+            (Sge, |a, b| a >= b),
+            (Ugt, |a, b| !(a <= b)),
+            (Uge, |a, b| !(a < b)),
+            (Slt, |a, b| !(a >= b)),
+            (Sle, |a, b| !(a > b)),
+        ];
+        let (inf, nan) = (f64::INFINITY, f64::NAN);
+        let pairs = [(1.0, 1.0), (nan, 1.0), (1.0, nan), (-0.0, 0.0), (2.5, -3.0), (inf, inf), (3.0, 4.0), (-1.0, -inf)];
+        for (ty, size) in [(F32, 4), (F64, 8)] {
+            let to_bits = |x: f64| if ty == F32 { (x as f32).to_bits() as u64 } else { x.to_bits() };
+            let from_bits = |x: u64| if ty == F32 { f32::from_bits(x as u32) as f64 } else { f64::from_bits(x) };
+            let ones = !0_u64 >> 64 - size * 8;
+            for vsize in [V64, V128] {
+                let len = if vsize == V64 { 8 } else { 16 };
+                for chunk in pairs.chunks(len / size) {
+                    let (mut a, mut b) = ([0; 32], [0; 32]);
+                    for (n, (p, q)) in chunk.iter().enumerate() {
+                        a[n * size..n * size + size].copy_from_slice(&to_bits(*p).to_le_bytes()[0..size]);
+                        b[n * size..n * size + size].copy_from_slice(&to_bits(*q).to_le_bytes()[0..size]);
+                    }
+                    let check = |ins: &[Ins], f: &dyn Fn(f64, f64, u64) -> bool| {
+                        let output = run_vector(vsize, ins, a, b, c);
+                        for (n, (p, q)) in chunk.iter().enumerate() {
+                            let res = lane(&output, size, n);
+                            assert!(f(*p, *q, res), "{ins:?} {p} {q} = {res:x}");
+                        }
+                    };
+                    for (cond, f) in float_conds {
+                        for dest in [d, x, y] {
+                            let ins = [Vcmp(cond, ty, vsize, dest, x, y), Vmov(U8, vsize, d, dest)];
+                            check(&ins, &|p, q, res| res == if f(p, q) { ones } else { 0 });
+                        }
+                    }
+                    let min_max = |p: f64, q: f64, res: u64, f: fn(f64, f64) -> f64| {
+                        let res = from_bits(res);
+                        if p.is_nan() || q.is_nan() {
+                            res.is_nan()
+                        } else {
+                            res == f(p, q)
+                        }
+                    };
+                    check(&[Vmin(ty, vsize, d, x, y)], &|p, q, res| min_max(p, q, res, f64::min));
+                    check(&[Vmax(ty, vsize, d, x, y)], &|p, q, res| min_max(p, q, res, f64::max));
+                    check(&[Vabs(ty, vsize, d, x)], &|p, _, res| res == to_bits(p.abs()));
+                }
+            }
+        }
+        let ins = Vcmp(Cs, U32, V128, d, x, y);
+        assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::UnsupportedVectorOperation(ins));
+    }
+
+    #[test]
+    fn generic_permute() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        use regs::*;
+        let a: [u8; 32] = std::array::from_fn(|i| i as u8 + 1);
+        let b: [u8; 32] = std::array::from_fn(|i| i as u8 + 0x81);
+        // Table indices, some out of range.
+        let mut c: [u8; 32] = std::array::from_fn(|i| (i * 11 % 23) as u8);
+        (c[5], c[9], c[20]) = (0xff, 0x80, 0x7f);
+        let (d, x, y, m) = (V(3), V(0), V(1), V(2));
+        let mut vsizes = vec![V64, V128];
+        if avx2() {
+            vsizes.push(V256);
+        }
+        for vsize in vsizes {
+            let len = match vsize {
+                V64 => 8,
+                V128 => 16,
+                _ => 32,
+            };
+            for dest in [d, x, m] {
+                // The table is always 16 bytes or more.
+                let ins = [Vld(U8, if vsize == V64 { V128 } else { vsize }, x, ARG[0], 0), Vtbl(vsize, dest, x, m), Vmov(U8, vsize, d, dest)];
+                let output = run_vector(vsize, &ins, a, b, c);
+                let expected: Vec<u8> = (0..len).map(|k| if c[k] < 16 { a[k & 16 | c[k] as usize] } else { 0 }).collect();
+                assert_eq!(&output[0..len], &expected, "Vtbl {vsize:?} {dest:?}");
+            }
+            for (ty, size) in [(U8, 1), (S16, 2), (U32, 4), (F32, 4), (U64, 8)] {
+                let n = len / size;
+                if n < 2 {
+                    let ins = Vzip(ty, vsize, d, x, y, 0);
+                    assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorSizeNotSupported(ins));
+                    continue;
+                }
+                let check = |ins: &[Ins], f: &dyn Fn(usize) -> u64| {
+                    let output = run_vector(vsize, ins, a, b, c);
+                    for k in 0..n {
+                        assert_eq!(lane(&output, size, k), f(k), "{ins:?} lane {k}");
+                    }
+                };
+                let (a, b) = (|k| lane(&a, size, k), |k| lane(&b, size, k));
+                let concat = |k| if k < n { a(k) } else { b(k - n) };
+                for dest in [d, x, y] {
+                    for part in [0, 1] {
+                        let half = n / 2;
+                        let zip = |k: usize| if k % 2 == 0 { a(part * half + k / 2) } else { b(part * half + k / 2) };
+                        let uzp = |k: usize| if k < half { a(2 * k + part) } else { b(2 * (k - half) + part) };
+                        let trn = |k: usize| if k % 2 == 0 { a(k + part) } else { b(k - 1 + part) };
+                        let p = part as u8;
+                        check(&[Vzip(ty, vsize, dest, x, y, p), Vmov(U8, vsize, d, dest)], &zip);
+                        check(&[Vuzp(ty, vsize, dest, x, y, p), Vmov(U8, vsize, d, dest)], &uzp);
+                        check(&[Vtrn(ty, vsize, dest, x, y, p), Vmov(U8, vsize, d, dest)], &trn);
+                    }
+                    for shift in [0, 1, n / 2, n - 1] {
+                        check(&[Vext(ty, vsize, dest, x, y, shift as u8), Vmov(U8, vsize, d, dest)], &|k| concat(k + shift));
+                    }
+                    check(&[Vrev(ty, vsize, dest, x), Vmov(U8, vsize, d, dest)], &|k| a(n - 1 - k));
+                }
+                let ins = Vext(ty, vsize, d, x, y, n as u8);
+                assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::InvalidImmediate(ins));
+                let ins = Vtrn(ty, vsize, d, x, y, 2);
+                assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::InvalidImmediate(ins));
+            }
+        }
+    }
+
+    #[test]
+    fn generic_widen_narrow() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let a: [u8; 32] = std::array::from_fn(|i| [0x7f, 0x80, 0xff, 0, 1, 0xfe, 0x81, 0x40][i % 8] ^ (i as u8 & 0x30));
+        let b: [u8; 32] = std::array::from_fn(|i| (i * 37 + 11) as u8);
+        let c: [u8; 32] = std::array::from_fn(|i| (i * 59 + 130) as u8);
+        let (d, x, y, m) = (V(3), V(0), V(1), V(2));
+        let mut vsizes = vec![V64, V128];
+        if avx2() {
+            vsizes.push(V256);
+        }
+        let signed = |ty| matches!(ty, S8 | S16 | S32 | S64);
+        // Lane `n` of `size` bytes, sign extended for signed types.
+        let get = |v: &[u8; 32], ty, size: usize, k| {
+            let bits = 64 - 8 * size as u32;
+            let x = lane(v, size, k) << bits;
+            if signed(ty) { ((x as i64) >> bits) as i128 } else { (x >> bits) as i128 }
+        };
+        let mask = |x: i128, size: usize| (x as u64) & (!0 >> (64 - 8 * size as u32));
+        for &vsize in &vsizes {
+            let len = match vsize {
+                V64 => 8,
+                V128 => 16,
+                _ => 32,
+            };
+            for (ty, size) in [(S8, 1), (U8, 1), (S16, 2), (U16, 2)] {
+                let (min, max) = if signed(ty) { (-1 << (8 * size - 1), (1 << (8 * size - 1)) - 1) } else { (0, (1 << (8 * size)) - 1) };
+                for dest in [d, x, y] {
+                    for (ins, f) in [
+                        (Vqadd(ty, vsize, dest, x, y), (|a, b| a + b) as fn(i128, i128) -> i128),
+                        (Vqsub(ty, vsize, dest, x, y), |a, b| a - b),
+                    ] {
+                        let output = run_vector(vsize, &[ins.clone(), Vmov(U8, vsize, d, dest)], a, b, c);
+                        for k in 0..len / size {
+                            let expected = f(get(&a, ty, size, k), get(&b, ty, size, k)).clamp(min, max);
+                            assert_eq!(lane(&output, size, k), mask(expected, size), "{ins:?} lane {k}");
+                        }
+                    }
+                }
+            }
+            let ins = Vqadd(U32, vsize, d, x, y);
+            assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorTypeNotSupported(ins));
+
+            if vsize == V64 {
+                let ins = Vaddl(U16, vsize, d, x, y);
+                assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorSizeNotSupported(ins));
+            } else {
+                for (ty, size) in [(S16, 2), (U16, 2), (S32, 4), (U32, 4), (S64, 8), (U64, 8)] {
+                    let half = size / 2;
+                    let (min, max) = if signed(ty) { (-1 << (8 * half - 1), (1 << (8 * half - 1)) - 1) } else { (0, (1 << (8 * half)) - 1) };
+                    let narrow = |ty| match ty {
+                        S16 => S8,
+                        U16 => U8,
+                        S32 => S16,
+                        U32 => U16,
+                        S64 => S32,
+                        _ => U32,
+                    };
+                    for dest in [d, x, y] {
+                        for (ins, f) in [
+                            (Vaddl(ty, vsize, dest, x, y), (|a, b| a + b) as fn(i128, i128) -> i128),
+                            (Vmull(ty, vsize, dest, x, y), |a, b| a * b),
+                        ] {
+                            let output = run_vector(vsize, &[ins.clone(), Vmov(U8, vsize, d, dest)], a, b, c);
+                            for k in 0..len / size {
+                                let expected = f(get(&a, narrow(ty), half, k), get(&b, narrow(ty), half, k));
+                                assert_eq!(lane(&output, size, k), mask(expected, size), "{ins:?} lane {k}");
+                            }
+                        }
+                        let output = run_vector(vsize, &[Vxtn(ty, vsize, dest, x), Vmov(U8, vsize, d, dest)], a, b, c);
+                        for k in 0..len / size {
+                            assert_eq!(lane(&output, half, k), mask(get(&a, ty, size, k), half), "Vxtn {ty:?} {vsize:?} lane {k}");
+                        }
+                        let ins = Vqmovn(ty, vsize, dest, x);
+                        if size == 8 {
+                            assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorTypeNotSupported(ins));
+                            continue;
+                        }
+                        let output = run_vector(vsize, &[ins.clone(), Vmov(U8, vsize, d, dest)], a, b, c);
+                        for k in 0..len / size {
+                            let expected = get(&a, ty, size, k).clamp(min, max);
+                            assert_eq!(lane(&output, half, k), mask(expected, half), "{ins:?} lane {k}");
+                        }
+                    }
+                }
+            }
+
+            for (ty, size) in [(U8, 1), (S16, 2), (U32, 4), (S64, 8)] {
+                let n = len / size;
+                if n < 2 {
+                    let ins = Vaddv(ty, vsize, d, x);
+                    assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::VectorSizeNotSupported(ins));
+                    continue;
+                }
+                let pair = |k| {
+                    let v = if k < n / 2 { &a } else { &b };
+                    let k = k % (n / 2) * 2;
+                    get(v, ty, size, k) + get(v, ty, size, k + 1)
+                };
+                for dest in [d, x, y] {
+                    let output = run_vector(vsize, &[Vaddv(ty, vsize, dest, x), Vmov(U8, vsize, d, dest)], a, b, c);
+                    let expected = (0..n).map(|k| get(&a, ty, size, k)).sum();
+                    assert_eq!(lane(&output, size, 0), mask(expected, size), "Vaddv {ty:?} {vsize:?} {dest:?}");
+                    let output = run_vector(vsize, &[Vaddp(ty, vsize, dest, x, y), Vmov(U8, vsize, d, dest)], a, b, c);
+                    for k in 0..n {
+                        assert_eq!(lane(&output, size, k), mask(pair(k), size), "Vaddp {ty:?} {vsize:?} {dest:?} lane {k}");
+                    }
+                }
+                // The same source twice.
+                let output = run_vector(vsize, &[Vaddp(ty, vsize, d, x, x)], a, b, c);
+                for k in 0..n {
+                    let k2 = k % (n / 2) * 2;
+                    let expected = get(&a, ty, size, k2) + get(&a, ty, size, k2 + 1);
+                    assert_eq!(lane(&output, size, k), mask(expected, size), "Vaddp {ty:?} {vsize:?} x, x lane {k}");
+                }
+            }
+            let fa: [u8; 32] = std::array::from_fn(|i| [0, 0, 0xc0, 0x3f, 0, 0, 0x20, 0x41][i % 8]);
+            let fb: [u8; 32] = std::array::from_fn(|i| [0, 0, 0x80, 0xbf, 0, 0, 0, 0x40][i % 8]);
+            let output = run_vector(vsize, &[Vaddp(F32, vsize, d, x, y)], fa, fb, c);
+            for k in 0..len / 4 {
+                let expected = if k < len / 8 { 1.5 + 10.0 } else { -1.0 + 2.0 };
+                assert_eq!(f32::from_bits(lane(&output, 4, k) as u32), expected, "Vaddp F32 {vsize:?} lane {k}");
+            }
+            if vsize != V64 {
+                let output = run_vector(vsize, &[Vaddp(F64, vsize, d, x, y)], fa, fb, c);
+                for k in 0..len / 8 {
+                    let v = if k < len / 16 { &fa } else { &fb };
+                    let expected = f64::from_bits(lane(v, 8, 0)) + f64::from_bits(lane(v, 8, 1));
+                    assert_eq!(f64::from_bits(lane(&output, 8, k)), expected, "Vaddp F64 {vsize:?} lane {k}");
+                }
+            }
+
+            for ty in [S8, U8] {
+                for dest in [d, x, y] {
+                    let ins = [Vmov(U8, vsize, d, m), Vdot(ty, vsize, dest, x, y), Vmov(U8, vsize, d, dest)];
+                    match Executable::from_ir(&ins) {
+                        Err(Error::UnsupportedOperation(_)) => continue,
+                        Err(Error::VectorSizeNotSupported(_)) if vsize == V256 => continue,
+                        res => res.unwrap(),
+                    };
+                    let output = run_vector(vsize, &ins, a, b, c);
+                    let acc = if dest == x { a } else if dest == y { b } else { c };
+                    for k in 0..len / 4 {
+                        let dot: i128 = (4 * k..4 * k + 4).map(|j| get(&a, ty, 1, j) * get(&b, ty, 1, j)).sum();
+                        let expected = get(&acc, U32, 4, k) + dot;
+                        assert_eq!(lane(&output, 4, k), mask(expected, 4), "Vdot {ty:?} {vsize:?} {dest:?} lane {k}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generic_fused_branches() {
+        use Cond::*;
+        use Ins::*;
+        use regs::*;
+        const TAKEN: u32 = 0;
+        let taken = |b: Ins, args: &[u64]| {
+            let prog = Executable::from_ir(&[b, Movi(RES[0], 0), Ret, Label(TAKEN), Movi(RES[0], 1), Ret]).unwrap();
+            unsafe { prog.call(0, args).unwrap().0 != 0 }
+        };
+        let values = [0, 1, 2, 0x8000_0000, i64::MAX as u64, i64::MIN as u64, !0];
+        for a in values {
+            assert_eq!(taken(Cbz(ARG[0], TAKEN), &[a]), a == 0);
+            assert_eq!(taken(Cbnz(ARG[0], TAKEN), &[a]), a != 0);
+            for bit in [0, 1, 31, 32, 63] {
+                assert_eq!(taken(Tbz(ARG[0], bit, TAKEN), &[a]), a >> bit & 1 == 0, "{a:#x} {bit}");
+                assert_eq!(taken(Tbnz(ARG[0], bit, TAKEN), &[a]), a >> bit & 1 != 0, "{a:#x} {bit}");
+            }
+            for b in values {
+                let (sa, sb) = (a as i64, b as i64);
+                let conds = [
+                    (Eq, a == b),
+                    (Ne, a != b),
+                    (Sgt, sa > sb),
+                    (Sge, sa >= sb),
+                    (Slt, sa < sb),
+                    (Sle, sa <= sb),
+                    (Ugt, a > b),
+                    (Uge, a >= b),
+                    (Ult, a < b),
+                    (Ule, a <= b),
+                ];
+                for (c, expected) in conds {
+                    assert_eq!(taken(Bcmp(c, ARG[0], ARG[1], TAKEN), &[a, b]), expected, "{c:?} {a:#x} {b:#x}");
+                }
+            }
+        }
+        let ins = Tbz(ARG[0], 64, TAKEN);
+        assert_eq!(Executable::from_ir(&[ins.clone(), Label(TAKEN), Ret]).unwrap_err(), Error::InvalidImmediate(ins));
+
+        // Sum of 1..=n counting down.
+        const LOOP: u32 = 1;
+        let prog = Executable::from_ir(&[
+            Movi(ARG[1], 0),
+            Cbz(ARG[0], TAKEN),
+            Label(LOOP),
+            Add(ARG[1], ARG[1], ARG[0]),
+            Subi(ARG[0], ARG[0], 1),
+            Cbnz(ARG[0], LOOP),
+            Label(TAKEN),
+            Mov(RES[0], ARG[1]),
+            Ret,
+        ])
+        .unwrap();
+        for n in [0, 1, 10, 1000] {
+            assert_eq!(unsafe { prog.call(0, &[n]).unwrap().0 }, n * (n + 1) / 2);
+        }
+    }
+
+    #[test]
+    fn generic_mul_div() {
+        use Ins::*;
+        use regs::*;
+        let ops: [(fn(R, R, R) -> Ins, fn(u64, u64) -> u64); 6] = [
+            (UMulH, |a, b| ((a as u128 * b as u128) >> 64) as u64),
+            (SMulH, |a, b| ((a as i64 as i128 * b as i64 as i128) >> 64) as u64),
+            (UDiv, |a, b| a.checked_div(b).unwrap_or(0)),
+            (SDiv, |a, b| if b == 0 { 0 } else { (a as i64).wrapping_div(b as i64) as u64 }),
+            (URem, |a, b| a.checked_rem(b).unwrap_or(a)),
+            (SRem, |a, b| if b == 0 { a } else { (a as i64).wrapping_rem(b as i64) as u64 }),
+        ];
+        let values = [0, 1, 2, 7, !0, !6, 0x0123_4567_89ab_cdef, i64::MIN as u64, i64::MAX as u64];
+        // Results in each of the argument registers as well as elsewhere.
+        for (op, f) in ops {
+            for ins in [op(RES[0], ARG[0], ARG[1]), op(ARG[0], ARG[0], ARG[1]), op(ARG[1], ARG[0], ARG[1])] {
+                let prog = Executable::from_ir(&[ins.clone(), Mov(RES[0], ins.def_use().0[0].unwrap()), Ret]).unwrap();
+                for a in values {
+                    for b in values {
+                        let (res, _) = unsafe { prog.call(0, &[a, b]).unwrap() };
+                        assert_eq!(res, f(a, b), "{ins:?} {a:#x} {b:#x}");
+                    }
+                }
+            }
+        }
+
+        // The full product, low then high.
+        let ops: [(fn(R, R, R, R) -> Ins, fn(u64, u64) -> u128); 2] = [
+            (UMulL, |a, b| a as u128 * b as u128),
+            (SMulL, |a, b| (a as i64 as i128 * b as i64 as i128) as u128),
+        ];
+        for (op, f) in ops {
+            for ins in [op(RES[0], RES[1], ARG[0], ARG[1]), op(RES[1], RES[0], ARG[0], ARG[1]), op(ARG[1], ARG[0], ARG[0], ARG[1])] {
+                let [lo, hi] = ins.def_use().0.map(Option::unwrap);
+                let prog = Executable::from_ir(&[ins.clone(), Mov(ARG[3], hi), Mov(RES[0], lo), Mov(RES[1], ARG[3]), Ret]).unwrap();
+                for a in values {
+                    for b in values {
+                        let (lo, hi) = unsafe { prog.call(0, &[a, b]).unwrap() };
+                        assert_eq!((hi as u128) << 64 | lo as u128, f(a, b), "{ins:?} {a:#x} {b:#x}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generic_bits() {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        let ops: [(Ins, fn(u64, u64) -> u64); 16] = [
+            (Clz(RES[0], ARG[0]), |a, _| a.leading_zeros() as u64),
+            (Ctz(RES[0], ARG[0]), |a, _| a.trailing_zeros() as u64),
+            (Popcnt(RES[0], ARG[0]), |a, _| a.count_ones() as u64),
+            (Bswap(U64, RES[0], ARG[0]), |a, _| a.swap_bytes()),
+            (Bswap(U32, RES[0], ARG[0]), |a, _| (a as u32).swap_bytes() as u64),
+            (Bswap(U16, RES[0], ARG[0]), |a, _| (a as u16).swap_bytes() as u64),
+            (Bswap(S32, RES[0], ARG[0]), |a, _| (a as i32).swap_bytes() as u64),
+            (Bswap(S16, RES[0], ARG[0]), |a, _| (a as i16).swap_bytes() as u64),
+            (Rol(RES[0], ARG[0], ARG[1]), |a, b| a.rotate_left(b as u32 & 63)),
+            (Ror(RES[0], ARG[0], ARG[1]), |a, b| a.rotate_right(b as u32 & 63)),
+            (Ubfx(RES[0], ARG[0], 4, 12), |a, _| a >> 4 & 0xfff),
+            (Ubfx(RES[0], ARG[0], 0, 64), |a, _| a),
+            (Sbfx(RES[0], ARG[0], 8, 8), |a, _| (a >> 8) as i8 as u64),
+            (Sbfx(RES[0], ARG[0], 32, 32), |a, _| (a >> 32) as i32 as u64),
+            (Bfi(ARG[1], ARG[0], 8, 12), |a, b| b & !0xfff00 | (a & 0xfff) << 8),
+            (Bfi(ARG[1], ARG[0], 60, 4), |a, b| b & !(0xf << 60) | a << 60),
+        ];
+        let values = [0, 1, 0x80, 0x8000_0000_0000_0000, 0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210, !0, 63, 65];
+        for (ins, f) in ops {
+            // The result is in RES[0] or ARG[1].
+            let res = ins.def_use().0[0].unwrap();
+            let prog = Executable::from_ir(&[ins.clone(), Mov(RES[0], res), Ret]).unwrap();
+            for a in values {
+                for b in values {
+                    let (res, _) = unsafe { prog.call(0, &[a, b]).unwrap() };
+                    assert_eq!(res, f(a, b), "{ins:?} {a:#x} {b:#x}");
+                }
+            }
+        }
+        for ins in [Ubfx(RES[0], ARG[0], 60, 8), Sbfx(RES[0], ARG[0], 0, 0), Bfi(RES[0], ARG[0], 64, 1)] {
+            assert_eq!(Executable::from_ir(&[ins.clone(), Ret]).unwrap_err(), Error::InvalidImmediate(ins));
+        }
+    }
+
+    #[test]
+    fn generic_extend() {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        let ops: [(Ins, fn(u64) -> u64); 8] = [
+            (Sext(S8, RES[0], ARG[0]), |a| a as i8 as u64),
+            (Sext(S16, RES[0], ARG[0]), |a| a as i16 as u64),
+            (Sext(S32, RES[0], ARG[0]), |a| a as i32 as u64),
+            (Zext(U8, RES[0], ARG[0]), |a| a as u8 as u64),
+            (Zext(U16, RES[0], ARG[0]), |a| a as u16 as u64),
+            (Zext(U32, RES[0], ARG[0]), |a| a as u32 as u64),
+            (Sext(U8, ARG[0], ARG[0]), |a| a as i8 as u64),
+            (Zext(S32, ARG[0], ARG[0]), |a| a as u32 as u64),
+        ];
+        for (ins, f) in ops {
+            let res = ins.def_use().0[0].unwrap();
+            let prog = Executable::from_ir(&[ins.clone(), Mov(RES[0], res), Ret]).unwrap();
+            for a in [0, 0x7f, 0x80, 0x7fff, 0x8000, 0x7fff_ffff, 0x8000_0000, 0x0123_4567_89ab_cdef, !0] {
+                let (res, _) = unsafe { prog.call(0, &[a]).unwrap() };
+                assert_eq!(res, f(a), "{ins:?} {a:#x}");
+            }
+        }
+
+        // 32 bit wraparound.
+        let prog = Executable::from_ir(&[Add(RES[0], ARG[0], ARG[1]), Zext(U32, RES[0], RES[0]), Ret]).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[0xffff_fffe, 3]).unwrap() };
+        assert_eq!(res, 1);
+        let ins = Sext(U64, RES[0], ARG[0]);
+        assert_eq!(Executable::from_ir(&[ins.clone(), Ret]).unwrap_err(), Error::InvalidType(ins));
+    }
+
     #[test]
     fn generic_loop() {
         for _ in 0..3 {
@@ -408,4 +1851,186 @@ mod generic_tests {
         #[cfg(target_endian="big")]
         assert_eq!(res, 0x3412);
     }
+
+    #[test]
+    fn generic_arith_imm() {
+        use Ins::*;
+        use regs::*;
+        let ops: [(fn(R, R, u64) -> Ins, fn(u64, u64) -> u64); 8] = [
+            (Addi, |a, b| a.wrapping_add(b)),
+            (Subi, |a, b| a.wrapping_sub(b)),
+            (Andi, |a, b| a & b),
+            (Ori, |a, b| a | b),
+            (Xori, |a, b| a ^ b),
+            (Shli, |a, b| a << (b & 63)),
+            (Shri, |a, b| a >> (b & 63)),
+            (Sari, |a, b| ((a as i64) >> (b & 63)) as u64),
+        ];
+        let values = [0, 1, 4, 63, 0x7f, 0x80, 0xfff, 0x1000, 0x123000, 0x1234_5678, 0xffff_ffff, 0x5555_5555_5555_5555, !0xff, !0];
+        for (op, f) in ops {
+            for imm in values {
+                let prog = Executable::from_ir(&[op(RES[0], ARG[0], imm), Ret]).unwrap();
+                let same = Executable::from_ir(&[op(ARG[0], ARG[0], imm), Mov(RES[0], ARG[0]), Ret]).unwrap();
+                for arg in [0x0123_4567_89ab_cdef, !0x0123_4567_89ab_cdef] {
+                    let (res, _) = unsafe { prog.call(0, &[arg]).unwrap() };
+                    assert_eq!(res, f(arg, imm), "{:?}", op(RES[0], ARG[0], imm));
+                    let (res, _) = unsafe { same.call(0, &[arg]).unwrap() };
+                    assert_eq!(res, f(arg, imm), "{:?}", op(ARG[0], ARG[0], imm));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generic_offsets() {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        let mut buf = (0..0x10000).map(|k| (k * 7 + k / 256) as u8).collect::<Vec<_>>();
+        let mid = buf.as_mut_ptr() as u64 + 0x8000;
+        let read = |buf: &[u8], addr: u64| u64::from_le_bytes(buf[addr.wrapping_sub(mid).wrapping_add(0x8000) as usize..][..8].try_into().unwrap());
+        for imm in [0, 8, 1, -1, -32, -256, 255, 256, 0x7ff8, -0x8000, 0x1235, -0x1235] {
+            let prog = Executable::from_ir(&[Ld(U64, RES[0], ARG[0], imm), Ret]).unwrap();
+            let (res, _) = unsafe { prog.call(0, &[mid]).unwrap() };
+            assert_eq!(res, read(&buf, mid.wrapping_add(imm as i64 as u64)), "Ld({imm})");
+
+            let prog = Executable::from_ir(&[St(U16, ARG[1], ARG[0], imm), Ret]).unwrap();
+            unsafe { prog.call(0, &[mid, 0xabcd]).unwrap() };
+            assert_eq!(read(&buf, mid.wrapping_add(imm as i64 as u64)) as u16, 0xabcd, "St({imm})");
+        }
+        for scale in [0, 1, 3, 4, 8] {
+            for index in [0_u64, 3, !0] {
+                let addr = mid.wrapping_add(index << scale);
+                let prog = Executable::from_ir(&[Ldx(S32, RES[0], ARG[0], ARG[1], scale), Ret]).unwrap();
+                let (res, _) = unsafe { prog.call(0, &[mid, index]).unwrap() };
+                assert_eq!(res, read(&buf, addr) as i32 as u64, "Ldx({index}, {scale})");
+
+                let prog = Executable::from_ir(&[Stx(U64, ARG[2], ARG[0], ARG[1], scale), Ret]).unwrap();
+                unsafe { prog.call(0, &[mid, index, 0x0123_4567_89ab_cdef]).unwrap() };
+                assert_eq!(read(&buf, addr), 0x0123_4567_89ab_cdef, "Stx({index}, {scale})");
+            }
+        }
+    }
+
+    #[test]
+    fn generic_call() {
+        use Ins::*;
+        use Type::*;
+        use regs::*;
+        // Sum every argument register.
+        let ins = ARG[1..].iter().map(|&a| Add(ARG[0], ARG[0], a))
+            .chain([Mov(RES[0], ARG[0]), Ret])
+            .collect::<Vec<_>>();
+        let prog = Executable::from_ir(&ins).unwrap();
+        let args = [1, 2, 4, 8, 16, 32, 64, 128];
+        let (res, _) = unsafe { prog.call(0, &args[..ARG.len()]).unwrap() };
+        assert_eq!(res, (1 << ARG.len()) - 1);
+        assert_eq!(unsafe { prog.call(0, &[0; 9]).unwrap_err() }, Error::InvalidArgs);
+        assert_eq!(unsafe { prog.call(1000, &[]).unwrap_err() }, Error::InvalidOffset);
+
+        let prog = Executable::from_ir(&[Vadd(U32, Vsize::V128, V(0), V(0), V(1)), Ret]).unwrap();
+        let a = std::array::from_fn(|i| i as u8);
+        let b = std::array::from_fn(|i| if i % 4 == 0 { 100 } else { 0 });
+        let res = unsafe { prog.call_vector(0, &[], &[a, b]).unwrap() };
+        assert_eq!(res, std::array::from_fn(|i| a[i] + b[i]));
+
+        let prog = Executable::from_ir(&[Vadd(F64, Vsize::V64, V(0), V(0), V(1)), Ret]).unwrap();
+        let res = unsafe { prog.call_float(0, &[], &[1.5, 2.25]).unwrap() };
+        assert_eq!(res, 3.75);
+    }
+
+    #[test]
+    fn generic_get_fn() {
+        use Ins::*;
+        use regs::*;
+        let prog = Executable::from_ir(&[Ret, Label(1), Add(RES[0], ARG[0], ARG[1]), Ret]).unwrap();
+        let add = unsafe { prog.get_fn::<extern "C" fn(*const u8, u64) -> u64>(1).unwrap() };
+        assert_eq!(add(std::ptr::null::<u8>().wrapping_add(3), 4), 7);
+        assert_eq!(unsafe { prog.get_fn::<extern "C" fn()>(2).unwrap_err() }, Error::MissingLabel(2));
+    }
+
+    #[test]
+    fn generic_labels() {
+        use Ins::*;
+        use regs::*;
+        let prog = Executable::from_ir(&[
+            Label(10),
+            Movi(RES[0], 1),
+            Ret,
+            Label(20),
+            Movi(RES[0], 2),
+            Ret,
+        ])
+        .unwrap();
+        let labels = prog.labels().collect::<Vec<_>>();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0], (10, 0));
+        assert_eq!(prog.label_offset(20), Some(labels[1].1));
+        assert_eq!(prog.label_offset(30), None);
+        assert_eq!(unsafe { prog.call_label(10, &[]).unwrap().0 }, 1);
+        assert_eq!(unsafe { prog.call_label(20, &[]).unwrap().0 }, 2);
+        assert_eq!(unsafe { prog.call_label(30, &[]).unwrap_err() }, Error::MissingLabel(30));
+    }
+
+    #[test]
+    fn generic_label_errors() {
+        use Ins::*;
+        assert_eq!(Executable::from_ir(&[Label(1), Label(1), Ret]).unwrap_err(), Error::DuplicateLabel(1));
+        assert_eq!(Executable::from_ir(&[J(MAX_LABEL), Ret]).unwrap_err(), Error::LabelOutOfRange(MAX_LABEL));
+        assert_eq!(Executable::from_ir(&[J(3), J(2), Label(3), Ret]).unwrap_err(), Error::MissingLabel(2));
+    }
+
+    #[test]
+    fn generic_high_label() {
+        use Ins::*;
+        use regs::*;
+        // A high id does not need a table of every lower one.
+        const L: u32 = 16_000_000;
+        let prog = Executable::from_ir(&[Movi(RES[0], 1), J(L), Movi(RES[0], 2), Label(L), B(Cond::Eq, 7), Label(7), Ret]).unwrap();
+        assert_eq!(prog.labels().map(|(l, _)| l).collect::<Vec<_>>(), [7, L]);
+        assert_eq!(unsafe { prog.call(0, &[]).unwrap().0 }, 1);
+        assert_eq!(Executable::from_ir(&[J(L + 1), J(L), Label(L + 1), Ret]).unwrap_err(), Error::MissingLabel(L));
+    }
+
+    #[test]
+    fn generic_many_labels() {
+        use Ins::*;
+        use regs::*;
+        // A chain of forward jumps, each label also jumped to from the start.
+        const N: u32 = 1000;
+        let mut ins = vec![Movi(RES[0], 0)];
+        for l in 0..N {
+            ins.extend([Cmpi(ARG[0], l as u64), B(Cond::Eq, 10000 + l)]);
+        }
+        for l in 0..N {
+            ins.extend([Label(10000 + l), Add(RES[0], RES[0], ARG[1])]);
+        }
+        ins.push(Ret);
+        let prog = Executable::from_ir(&ins).unwrap();
+        for l in [0, 1, 500, N - 1] {
+            let (res, _) = unsafe { prog.call(0, &[l as u64, 1]).unwrap() };
+            assert_eq!(res, (N - l) as u64);
+        }
+    }
+
+    #[test]
+    fn generic_empty() {
+        assert_eq!(Executable::from_ir(&[]).unwrap_err(), Error::EmptyProgram);
+        assert_eq!(Executable::from_ir(&[Ins::Label(0)]).unwrap_err(), Error::EmptyProgram);
+    }
+
+    #[test]
+    #[cfg(target_os="linux")]
+    fn generic_write_xor_execute() {
+        let prog = Executable::from_ir(&[Ins::Ret]).unwrap();
+        let addr = prog.bytes as usize;
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let perms = maps.lines().find_map(|line| {
+            let (range, rest) = line.split_once(' ')?;
+            let (lo, hi) = range.split_once('-')?;
+            let range = usize::from_str_radix(lo, 16).ok()?..usize::from_str_radix(hi, 16).ok()?;
+            range.contains(&addr).then(|| rest[..4].to_string())
+        });
+        assert_eq!(perms.as_deref(), Some("r-xp"));
+    }
 }
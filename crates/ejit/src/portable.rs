@@ -0,0 +1,253 @@
+//! Portable register numbering.
+//!
+//! [`Executable::from_portable_ir`] accepts the same IR as [`Executable::from_ir`]
+//! but treats `R(n)` and `V(n)` as abstract registers which are mapped
+//! to machine registers for the current target.
+//!
+//! On every target `R(0)..R(6)` are caller-saved and `R(7)..R(12)` are callee-saved
+//! and must be preserved by the program. Targets with more registers
+//! provide further ones from `R(13)`, up to [`regs::R_COUNT`].
+//! [`regs::SP`] is the stack pointer and the scratch registers used by the
+//! backend are not available.
+//!
+//! Use [`regs::ARG`] and [`regs::RES`] for arguments and results, but do not assume
+//! that they overlap as they do on aarch64.
+//!
+//! ```
+//! # use ejit::*;
+//! use Ins::*;
+//! use portable::regs::*;
+//! let prog = Executable::from_portable_ir(&[
+//!     Add(R(6), ARG[0], ARG[1]),
+//!     Mov(RES[0], R(6)),
+//!     Ret,
+//! ]).unwrap();
+//! let (res, _) = unsafe { prog.call(0, &[1, 2]).unwrap() };
+//! assert_eq!(res, 3);
+//! ```
+use crate::{Error, Executable, Ins, R, V};
+
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::portable as target;
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::portable as target;
+
+pub mod regs {
+    pub use super::target::regs::*;
+
+    /// Number of portable integer registers on this target, excluding `SP`.
+    pub const R_COUNT: usize = super::target::R_MAP.len();
+
+    /// Number of portable vector registers on this target.
+    pub const V_COUNT: usize = super::target::V_MAP.len();
+}
+
+impl Executable {
+    /// Compile IR which uses portable register numbers.
+    pub fn from_portable_ir(ins: &[Ins]) -> Result<Executable, Error> {
+        let ins = map(ins)?;
+        Executable::from_ir(&ins)
+    }
+}
+
+/// Replace portable register numbers with machine registers.
+pub fn map(ins: &[Ins]) -> Result<Vec<Ins>, Error> {
+    ins.iter()
+        .map(|i| {
+            i.map_regs(
+                |r| match r {
+                    r if r == regs::SP => Some(R(target::SP as u16)),
+                    R(n) => target::R_MAP.get(n as usize).map(|&n| R(n as u16)),
+                },
+                |v| target::V_MAP.get(v.0 as usize).map(|&n| V(n)),
+            )
+            .ok_or_else(|| Error::TooManyRegisters(i.clone()))
+        })
+        .collect()
+}
+
+impl Ins {
+    /// Rewrite every register operand, `None` if any register is rejected.
+    pub(crate) fn map_regs(&self, mut r: impl FnMut(R) -> Option<R>, mut v: impl FnMut(V) -> Option<V>) -> Option<Ins> {
+        use Ins::*;
+        Some(match self {
+            Label(_) | Enter(_) | Leave(_) | B(..) | J(_) | Ret | D(..) => self.clone(),
+            Addr(dest, label) => Addr(r(*dest)?, *label),
+            Ld(ty, dest, base, imm) => Ld(*ty, r(*dest)?, r(*base)?, *imm),
+            St(ty, src, base, imm) => St(*ty, r(*src)?, r(*base)?, *imm),
+            Ldx(ty, dest, base, index, scale) => Ldx(*ty, r(*dest)?, r(*base)?, r(*index)?, *scale),
+            Stx(ty, src, base, index, scale) => Stx(*ty, r(*src)?, r(*base)?, r(*index)?, *scale),
+            Vld(ty, vsize, dest, base, imm) => Vld(*ty, *vsize, v(*dest)?, r(*base)?, *imm),
+            Vst(ty, vsize, src, base, imm) => Vst(*ty, *vsize, v(*src)?, r(*base)?, *imm),
+            Add(d, a, b) => Add(r(*d)?, r(*a)?, r(*b)?),
+            Sub(d, a, b) => Sub(r(*d)?, r(*a)?, r(*b)?),
+            And(d, a, b) => And(r(*d)?, r(*a)?, r(*b)?),
+            Or(d, a, b) => Or(r(*d)?, r(*a)?, r(*b)?),
+            Xor(d, a, b) => Xor(r(*d)?, r(*a)?, r(*b)?),
+            Shl(d, a, b) => Shl(r(*d)?, r(*a)?, r(*b)?),
+            Shr(d, a, b) => Shr(r(*d)?, r(*a)?, r(*b)?),
+            Sar(d, a, b) => Sar(r(*d)?, r(*a)?, r(*b)?),
+            Mul(d, a, b) => Mul(r(*d)?, r(*a)?, r(*b)?),
+            UDiv(d, a, b) => UDiv(r(*d)?, r(*a)?, r(*b)?),
+            SDiv(d, a, b) => SDiv(r(*d)?, r(*a)?, r(*b)?),
+            Mov(d, a) => Mov(r(*d)?, r(*a)?),
+            Movi(d, imm) => Movi(r(*d)?, *imm),
+            Cmp(a, b) => Cmp(r(*a)?, r(*b)?),
+            Cmpi(a, imm) => Cmpi(r(*a)?, *imm),
+            Not(d, a) => Not(r(*d)?, r(*a)?),
+            Neg(d, a) => Neg(r(*d)?, r(*a)?),
+            Addi(d, a, imm) => Addi(r(*d)?, r(*a)?, *imm),
+            Subi(d, a, imm) => Subi(r(*d)?, r(*a)?, *imm),
+            Andi(d, a, imm) => Andi(r(*d)?, r(*a)?, *imm),
+            Ori(d, a, imm) => Ori(r(*d)?, r(*a)?, *imm),
+            Xori(d, a, imm) => Xori(r(*d)?, r(*a)?, *imm),
+            Shli(d, a, imm) => Shli(r(*d)?, r(*a)?, *imm),
+            Shri(d, a, imm) => Shri(r(*d)?, r(*a)?, *imm),
+            Sari(d, a, imm) => Sari(r(*d)?, r(*a)?, *imm),
+            Adds(d, a, b) => Adds(r(*d)?, r(*a)?, r(*b)?),
+            Adc(d, a, b) => Adc(r(*d)?, r(*a)?, r(*b)?),
+            Subs(d, a, b) => Subs(r(*d)?, r(*a)?, r(*b)?),
+            Sbc(d, a, b) => Sbc(r(*d)?, r(*a)?, r(*b)?),
+            UMulH(d, a, b) => UMulH(r(*d)?, r(*a)?, r(*b)?),
+            SMulH(d, a, b) => SMulH(r(*d)?, r(*a)?, r(*b)?),
+            UMulL(lo, hi, a, b) => UMulL(r(*lo)?, r(*hi)?, r(*a)?, r(*b)?),
+            SMulL(lo, hi, a, b) => SMulL(r(*lo)?, r(*hi)?, r(*a)?, r(*b)?),
+            URem(d, a, b) => URem(r(*d)?, r(*a)?, r(*b)?),
+            SRem(d, a, b) => SRem(r(*d)?, r(*a)?, r(*b)?),
+            Sext(ty, d, a) => Sext(*ty, r(*d)?, r(*a)?),
+            Zext(ty, d, a) => Zext(*ty, r(*d)?, r(*a)?),
+            Clz(d, a) => Clz(r(*d)?, r(*a)?),
+            Ctz(d, a) => Ctz(r(*d)?, r(*a)?),
+            Popcnt(d, a) => Popcnt(r(*d)?, r(*a)?),
+            Bswap(ty, d, a) => Bswap(*ty, r(*d)?, r(*a)?),
+            Rol(d, a, b) => Rol(r(*d)?, r(*a)?, r(*b)?),
+            Ror(d, a, b) => Ror(r(*d)?, r(*a)?, r(*b)?),
+            Ubfx(d, a, lsb, width) => Ubfx(r(*d)?, r(*a)?, *lsb, *width),
+            Sbfx(d, a, lsb, width) => Sbfx(r(*d)?, r(*a)?, *lsb, *width),
+            Bfi(d, a, lsb, width) => Bfi(r(*d)?, r(*a)?, *lsb, *width),
+            Vadd(ty, vs, d, a, b) => Vadd(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vsub(ty, vs, d, a, b) => Vsub(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vand(ty, vs, d, a, b) => Vand(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vor(ty, vs, d, a, b) => Vor(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vxor(ty, vs, d, a, b) => Vxor(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vshl(ty, vs, d, a, b) => Vshl(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vshr(ty, vs, d, a, b) => Vshr(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vmul(ty, vs, d, a, b) => Vmul(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vdiv(ty, vs, d, a, b) => Vdiv(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vmov(ty, vs, d, a) => Vmov(*ty, *vs, v(*d)?, v(*a)?),
+            Vmovi(ty, vs, d, imm) => Vmovi(*ty, *vs, v(*d)?, *imm),
+            Vnot(ty, vs, d, a) => Vnot(*ty, *vs, v(*d)?, v(*a)?),
+            Vneg(ty, vs, d, a) => Vneg(*ty, *vs, v(*d)?, v(*a)?),
+            Vrecpe(ty, vs, d, a) => Vrecpe(*ty, *vs, v(*d)?, v(*a)?),
+            Vrsqrte(ty, vs, d, a) => Vrsqrte(*ty, *vs, v(*d)?, v(*a)?),
+            VmovFromR(ty, d, a, lane) => VmovFromR(*ty, v(*d)?, r(*a)?, *lane),
+            VmovToR(ty, d, a, lane) => VmovToR(*ty, r(*d)?, v(*a)?, *lane),
+            Vdup(ty, vs, d, a) => Vdup(*ty, *vs, v(*d)?, r(*a)?),
+            Vcmp(cond, ty, vs, d, a, b) => Vcmp(*cond, *ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vbsl(ty, vs, d, m, a, b) => Vbsl(*ty, *vs, v(*d)?, v(*m)?, v(*a)?, v(*b)?),
+            Vblend(ty, vs, d, m, a, b) => Vblend(*ty, *vs, v(*d)?, v(*m)?, v(*a)?, v(*b)?),
+            Vmin(ty, vs, d, a, b) => Vmin(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vmax(ty, vs, d, a, b) => Vmax(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vabs(ty, vs, d, a) => Vabs(*ty, *vs, v(*d)?, v(*a)?),
+            Vtbl(vs, d, a, b) => Vtbl(*vs, v(*d)?, v(*a)?, v(*b)?),
+            Vzip(ty, vs, d, a, b, part) => Vzip(*ty, *vs, v(*d)?, v(*a)?, v(*b)?, *part),
+            Vuzp(ty, vs, d, a, b, part) => Vuzp(*ty, *vs, v(*d)?, v(*a)?, v(*b)?, *part),
+            Vtrn(ty, vs, d, a, b, part) => Vtrn(*ty, *vs, v(*d)?, v(*a)?, v(*b)?, *part),
+            Vext(ty, vs, d, a, b, n) => Vext(*ty, *vs, v(*d)?, v(*a)?, v(*b)?, *n),
+            Vrev(ty, vs, d, a) => Vrev(*ty, *vs, v(*d)?, v(*a)?),
+            Vqadd(ty, vs, d, a, b) => Vqadd(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vqsub(ty, vs, d, a, b) => Vqsub(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vaddl(ty, vs, d, a, b) => Vaddl(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vmull(ty, vs, d, a, b) => Vmull(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vxtn(ty, vs, d, a) => Vxtn(*ty, *vs, v(*d)?, v(*a)?),
+            Vqmovn(ty, vs, d, a) => Vqmovn(*ty, *vs, v(*d)?, v(*a)?),
+            Vaddv(ty, vs, d, a) => Vaddv(*ty, *vs, v(*d)?, v(*a)?),
+            Vaddp(ty, vs, d, a, b) => Vaddp(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Vdot(ty, vs, d, a, b) => Vdot(*ty, *vs, v(*d)?, v(*a)?, v(*b)?),
+            Fadd(ty, d, a, b) => Fadd(*ty, v(*d)?, v(*a)?, v(*b)?),
+            Fsub(ty, d, a, b) => Fsub(*ty, v(*d)?, v(*a)?, v(*b)?),
+            Fmul(ty, d, a, b) => Fmul(*ty, v(*d)?, v(*a)?, v(*b)?),
+            Fdiv(ty, d, a, b) => Fdiv(*ty, v(*d)?, v(*a)?, v(*b)?),
+            Fmin(ty, d, a, b) => Fmin(*ty, v(*d)?, v(*a)?, v(*b)?),
+            Fmax(ty, d, a, b) => Fmax(*ty, v(*d)?, v(*a)?, v(*b)?),
+            Fsqrt(ty, d, a) => Fsqrt(*ty, v(*d)?, v(*a)?),
+            Fabs(ty, d, a) => Fabs(*ty, v(*d)?, v(*a)?),
+            Fma(ty, d, a, b, c) => Fma(*ty, v(*d)?, v(*a)?, v(*b)?, v(*c)?),
+            Fcmp(ty, a, b) => Fcmp(*ty, v(*a)?, v(*b)?),
+            Fcvt(dty, sty, d, a) => Fcvt(*dty, *sty, v(*d)?, v(*a)?),
+            Scvtf(ty, d, a) => Scvtf(*ty, v(*d)?, r(*a)?),
+            Fcvtzs(ty, d, a) => Fcvtzs(*ty, r(*d)?, v(*a)?),
+            Call(target) => Call(r(*target)?),
+            Branch(target) => Branch(r(*target)?),
+            Cbz(a, label) => Cbz(r(*a)?, *label),
+            Cbnz(a, label) => Cbnz(r(*a)?, *label),
+            Tbz(a, bit, label) => Tbz(r(*a)?, *bit, *label),
+            Tbnz(a, bit, label) => Tbnz(r(*a)?, *bit, *label),
+            Bcmp(cond, a, b, label) => Bcmp(*cond, r(*a)?, r(*b)?, *label),
+            Sel(cond, d, t, f) => Sel(*cond, r(*d)?, r(*t)?, r(*f)?),
+            Cset(cond, d) => Cset(*cond, r(*d)?),
+            Csinc(cond, d, t, f) => Csinc(*cond, r(*d)?, r(*t)?, r(*f)?),
+            Csneg(cond, d, t, f) => Csneg(*cond, r(*d)?, r(*t)?, r(*f)?),
+            Csinv(cond, d, t, f) => Csinv(*cond, r(*d)?, r(*t)?, r(*f)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn portable_loop() {
+        use Ins::*;
+        use portable::regs::*;
+        const LOOP: u32 = 0;
+        let mut ins = vec![Movi(R(0), 0)];
+        // Touch every caller-saved register.
+        for r in 1..7 {
+            ins.push(Movi(R(r), r as u64));
+            ins.push(Add(R(0), R(0), R(r)));
+        }
+        ins.extend([
+            Label(LOOP),
+            Add(R(0), R(0), R(6)),
+            Sub(R(6), R(6), R(1)),
+            Cmpi(R(6), 0),
+            B(Cond::Ne, LOOP),
+            Mov(RES[0], R(0)),
+            Ret,
+        ]);
+        let prog = Executable::from_portable_ir(&ins).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[]).unwrap() };
+        assert_eq!(res, 21 + 21);
+    }
+
+    #[test]
+    fn portable_stack() {
+        use Ins::*;
+        use Type::*;
+        use portable::regs::*;
+        let prog = Executable::from_portable_ir(&[
+            Enter(16),
+            St(U64, ARG[1], SP, 8),
+            Ld(U64, RES[0], SP, 8),
+            Leave(16),
+            Ret,
+        ])
+        .unwrap();
+        let (res, _) = unsafe { prog.call(0, &[1, 1234]).unwrap() };
+        assert_eq!(res, 1234);
+    }
+
+    #[test]
+    fn too_many_registers() {
+        use Ins::*;
+        use portable::regs::*;
+        let r = R(R_COUNT as u16);
+        assert_eq!(Executable::from_portable_ir(&[Movi(r, 1), Ret]).unwrap_err(), Error::TooManyRegisters(Movi(r, 1)));
+        let v = V(V_COUNT as u8);
+        let i = Vmov(Type::U8, Vsize::V128, v, V(0));
+        assert_eq!(portable::map(&[i.clone()]).unwrap_err(), Error::TooManyRegisters(i));
+    }
+}
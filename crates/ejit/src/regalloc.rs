@@ -0,0 +1,574 @@
+//! Linear scan register allocation.
+//!
+//! [`Executable::from_virtual_ir`] accepts the same IR as [`Executable::from_ir`]
+//! but treats `R(n)` as a virtual register. Virtual registers are numbered
+//! from 0 to 65279, below [`regs::PHYS`], and each is given a machine register
+//! for the whole of its live range or a stack slot if there are too few.
+//!
+//! [`regs::ARG`], [`regs::RES`] and [`regs::SP`] name the machine registers
+//! used to pass arguments and results and the stack pointer.
+//! Copy arguments into virtual registers before the first `Call`
+//! and results into `RES` just before `Ret`. `Call` clobbers every caller-saved
+//! register and reads every `ARG` register.
+//!
+//! The program is treated as a single function entered at its first instruction.
+//! A frame for spill slots and callee-saved registers is made with `Enter` at the start
+//! and released with `Leave` before every `Ret`, sized to leave the stack aligned for `Call`.
+//! The program may use `Enter` and `Leave` itself as long as the stack depth is the same
+//! on every path.
+//! `Branch` may go to any label. Vector registers are not allocated.
+//!
+//! ```
+//! # use ejit::*;
+//! use Ins::*;
+//! use regalloc::regs::*;
+//! let prog = Executable::from_virtual_ir(&[
+//!     Add(R(1000), ARG[0], ARG[1]),
+//!     Mov(RES[0], R(1000)),
+//!     Ret,
+//! ]).unwrap();
+//! let (res, _) = unsafe { prog.call(0, &[1, 2]).unwrap() };
+//! assert_eq!(res, 3);
+//! ```
+use crate::{Error, Executable, Ins, Type, R};
+
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::portable as target;
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::portable as target;
+
+pub mod regs {
+    use crate::R;
+
+    /// Registers from `R(PHYS)` up are machine registers, which caps virtual registers at 65280.
+    pub const PHYS: u16 = 0xff00;
+
+    const fn phys<const N: usize>(mut r: [R; N]) -> [R; N] {
+        let mut i = 0;
+        while i < N {
+            r[i] = R(PHYS + r[i].0);
+            i += 1;
+        }
+        r
+    }
+
+    pub const ARG: [R; crate::regs::ARG.len()] = phys(crate::regs::ARG);
+    pub const RES: [R; crate::regs::RES.len()] = phys(crate::regs::RES);
+    pub const SP: R = R(PHYS + crate::regs::SP.0);
+}
+
+impl Executable {
+    /// Compile IR which uses virtual registers.
+    pub fn from_virtual_ir(ins: &[Ins]) -> Result<Executable, Error> {
+        let ins = allocate(ins)?;
+        Executable::from_ir(&ins)
+    }
+}
+
+/// Replace virtual registers with machine registers, adding spill code and a frame.
+pub fn allocate(ins: &[Ins]) -> Result<Vec<Ins>, Error> {
+    if ins.is_empty() {
+        return Ok(Vec::new());
+    }
+    let live = Liveness::new(ins)?;
+    let pool = target::R_MAP.len();
+    let alloc = live.scan(pool);
+    if alloc.slots == 0 {
+        return Ok(live.rewrite(ins, &alloc));
+    }
+    // Keep registers back to reload spilled operands.
+    let alloc = live.scan(pool - RELOAD);
+    Ok(live.rewrite(ins, &alloc))
+}
+
+/// Registers kept back for spilled operands, one for each register an instruction reads.
+const RELOAD: usize = 3;
+
+/// Registers read and written by each instruction and the live range of each register.
+///
+/// Instruction `x` reads its operands at point `2x` and writes its result at point `2x + 1`
+/// so a register may be reused by the result of the instruction that last reads it.
+struct Liveness {
+    /// Virtual and machine register numbers, sorted. Ids index this.
+    ids: Vec<u16>,
+
+    /// Index into `R_MAP` of machine register ids.
+    row: Vec<Option<usize>>,
+
+    /// First and last point at which each virtual register is live.
+    range: Vec<(usize, usize)>,
+
+    /// Per `R_MAP` entry, the number of points before `p` at which the machine register is live.
+    busy: Vec<Vec<u32>>,
+}
+
+struct Assignment {
+    /// Index into `R_MAP` for each id in a register.
+    reg: Vec<Option<usize>>,
+
+    /// Spill slot for each id on the stack.
+    slot: Vec<Option<usize>>,
+
+    slots: usize,
+
+    /// `R_MAP` entries used to reload spilled operands.
+    reload: std::ops::Range<usize>,
+}
+
+impl Liveness {
+    fn new(ins: &[Ins]) -> Result<Self, Error> {
+        use Ins::*;
+        let n = ins.len();
+        let precoloured = |r: &R| *r == regs::SP || regs::ARG.contains(r) || regs::RES.contains(r);
+        let mut ids: Vec<u16> = target::R_MAP.iter().map(|&p| regs::PHYS + p as u16).collect();
+        for i in ins {
+            let (d, u) = i.def_use();
+            for r in d.iter().flatten().chain(u.iter().flatten()) {
+                if r.0 >= regs::PHYS && !precoloured(r) {
+                    return Err(Error::InvalidRegisterNumber(i.clone()));
+                }
+                if *r != regs::SP {
+                    ids.push(r.0);
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        let id = |r: &R| ids.binary_search(&r.0).unwrap();
+        let phys = |p: &u8| ids.binary_search(&(regs::PHYS + *p as u16)).unwrap();
+        let row: Vec<Option<usize>> = ids
+            .iter()
+            .map(|&r| r.checked_sub(regs::PHYS).and_then(|p| target::R_MAP.iter().position(|&m| m as u16 == p)))
+            .collect();
+
+        // Registers written and read by each instruction, including those implied by `Call` and `Ret`.
+        let caller_saved: Vec<usize> = target::R_MAP.iter().filter(|p| !target::CALLEE_SAVED.contains(p)).map(phys).collect();
+        let (mut defs, mut def_end) = (Vec::new(), Vec::with_capacity(n));
+        let (mut uses, mut use_end) = (Vec::new(), Vec::with_capacity(n));
+        for i in ins {
+            let (d, u) = i.def_use();
+            defs.extend(d.iter().flatten().filter(|r| **r != regs::SP).map(id));
+            uses.extend(u.iter().flatten().filter(|r| **r != regs::SP).map(id));
+            match i {
+                Call(_) => {
+                    defs.extend(&caller_saved);
+                    uses.extend(regs::ARG.iter().map(id));
+                }
+                Ret => uses.extend(regs::RES.iter().map(id)),
+                _ => (),
+            }
+            def_end.push(defs.len());
+            use_end.push(uses.len());
+        }
+        let defs_of = |x: usize| &defs[if x == 0 { 0 } else { def_end[x - 1] }..def_end[x]];
+        let uses_of = |x: usize| &uses[if x == 0 { 0 } else { use_end[x - 1] }..use_end[x]];
+
+        // Basic blocks start at labels and after branches.
+        let mut starts = vec![0];
+        for (x, i) in ins.iter().enumerate() {
+            match i {
+                Label(_) if x != *starts.last().unwrap() => starts.push(x),
+                B(..) | Cbz(..) | Cbnz(..) | Tbz(..) | Tbnz(..) | Bcmp(..) | J(_) | Branch(_) | Ret if x + 1 < n => {
+                    starts.push(x + 1)
+                }
+                _ => (),
+            }
+        }
+        let nb = starts.len();
+        let end = |b: usize| starts.get(b + 1).copied().unwrap_or(n);
+        let mut labels: Vec<(u32, usize)> = (0..nb)
+            .filter_map(|b| match ins[starts[b]] {
+                Label(label) => Some((label, b)),
+                _ => None,
+            })
+            .collect();
+        labels.sort_unstable();
+        let block = |label: u32| {
+            labels
+                .binary_search_by_key(&label, |&(l, _)| l)
+                .map(|k| labels[k].1)
+                .map_err(|_| Error::MissingLabel(label))
+        };
+        let mut succ = Vec::with_capacity(nb);
+        for b in 0..nb {
+            let next = (b + 1 < nb).then_some(b + 1);
+            succ.push(match &ins[end(b) - 1] {
+                J(label) => vec![block(*label)?],
+                B(_, label) | Cbz(_, label) | Cbnz(_, label) | Tbz(_, _, label) | Tbnz(_, _, label) | Bcmp(_, _, _, label) => {
+                    [block(*label)?].into_iter().chain(next).collect()
+                }
+                Branch(_) => labels.iter().map(|&(_, b)| b).collect(),
+                Ret => vec![],
+                _ => next.into_iter().collect(),
+            });
+        }
+
+        // Solve live-in = use | (live-out & !def) backwards over the blocks.
+        let w = ids.len().div_ceil(64);
+        let (mut used, mut defined) = (vec![0_u64; nb * w], vec![0_u64; nb * w]);
+        for b in 0..nb {
+            for x in starts[b]..end(b) {
+                for &u in uses_of(x) {
+                    if defined[b * w + u / 64] & 1 << u % 64 == 0 {
+                        used[b * w + u / 64] |= 1 << u % 64;
+                    }
+                }
+                for &d in defs_of(x) {
+                    defined[b * w + d / 64] |= 1 << d % 64;
+                }
+            }
+        }
+        let (mut live_in, mut live_out) = (vec![0_u64; nb * w], vec![0_u64; nb * w]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..nb).rev() {
+                for k in 0..w {
+                    let out = succ[b].iter().fold(0, |out, s| out | live_in[s * w + k]);
+                    let new_in = used[b * w + k] | out & !defined[b * w + k];
+                    live_out[b * w + k] = out;
+                    changed |= new_in != live_in[b * w + k];
+                    live_in[b * w + k] = new_in;
+                }
+            }
+        }
+
+        // Walk each block backwards recording the points at which registers are live.
+        let mut range = vec![(usize::MAX, 0); ids.len()];
+        let mut busy = vec![vec![0_u32; 2 * n + 1]; target::R_MAP.len()];
+        let mut mark = |id: usize, p: usize| match row[id] {
+            Some(r) => busy[r][p + 1] = 1,
+            None => range[id] = (range[id].0.min(p), range[id].1.max(p)),
+        };
+        for b in 0..nb {
+            let mut live = live_out[b * w..(b + 1) * w].to_vec();
+            for x in (starts[b]..end(b)).rev() {
+                for id in bits(&live).chain(defs_of(x).iter().copied()) {
+                    mark(id, 2 * x + 1);
+                }
+                for &d in defs_of(x) {
+                    live[d / 64] &= !(1 << d % 64);
+                }
+                for &u in uses_of(x) {
+                    live[u / 64] |= 1 << u % 64;
+                }
+                for id in bits(&live) {
+                    mark(id, 2 * x);
+                }
+            }
+        }
+        for b in &mut busy {
+            for p in 1..b.len() {
+                b[p] += b[p - 1];
+            }
+        }
+        Ok(Self { ids, row, range, busy })
+    }
+
+    /// True if machine register `r` is live anywhere from point `s` to point `e`.
+    fn busy(&self, r: usize, s: usize, e: usize) -> bool {
+        self.busy[r][e + 1] != self.busy[r][s]
+    }
+
+    /// Assign the first `pool` entries of `R_MAP` to virtual registers in order of their first use,
+    /// spilling the register that is live the longest when there are none left.
+    fn scan(&self, pool: usize) -> Assignment {
+        let mut order: Vec<usize> = (0..self.ids.len())
+            .filter(|&v| self.row[v].is_none() && self.range[v].0 != usize::MAX)
+            .collect();
+        order.sort_by_key(|&v| self.range[v].0);
+        let mut reg = vec![None; self.ids.len()];
+        let mut slot = vec![None; self.ids.len()];
+        let mut slots = 0;
+        let mut active: Vec<usize> = Vec::new();
+        for v in order {
+            let (s, e) = self.range[v];
+            active.retain(|&a| self.range[a].1 >= s);
+            if let Some(r) = (0..pool).find(|&r| !active.iter().any(|&a| reg[a] == Some(r)) && !self.busy(r, s, e)) {
+                reg[v] = Some(r);
+                active.push(v);
+                continue;
+            }
+            let victim = active
+                .iter()
+                .copied()
+                .filter(|&a| !self.busy(reg[a].unwrap(), s, e))
+                .max_by_key(|&a| self.range[a].1);
+            match victim {
+                Some(a) if self.range[a].1 > e => {
+                    reg[v] = reg[a].take();
+                    slot[a] = Some(slots);
+                    active.retain(|&x| x != a);
+                    active.push(v);
+                }
+                _ => slot[v] = Some(slots),
+            }
+            slots += 1;
+        }
+        Assignment { reg, slot, slots, reload: pool..target::R_MAP.len().min(pool + RELOAD) }
+    }
+
+    fn rewrite(&self, ins: &[Ins], alloc: &Assignment) -> Vec<Ins> {
+        use Ins::*;
+        use Type::*;
+        let sp = crate::regs::SP;
+        let mut saved: Vec<u8> = target::R_MAP
+            .iter()
+            .enumerate()
+            .filter(|(r, p)| {
+                target::CALLEE_SAVED.contains(p)
+                    && (alloc.reg.contains(&Some(*r)) || alloc.slots != 0 && alloc.reload.contains(r))
+            })
+            .map(|(_, &p)| p)
+            .collect();
+        let calls = ins.iter().any(|i| matches!(i, Call(_)));
+        if calls {
+            saved.extend(target::LINK);
+        }
+        let bytes = (saved.len() + alloc.slots) * 8;
+        // Without a link register the return address is on the stack and takes the other 8 bytes.
+        let frame = match target::LINK {
+            None if calls => (bytes + 8).next_multiple_of(16) - 8,
+            _ => bytes.next_multiple_of(16),
+        } as u32;
+        let offset = |depth: u32, k: usize| (depth as usize + k * 8) as i32;
+
+        let mut res = Vec::with_capacity(ins.len() + saved.len() * 2 + 2);
+        if frame != 0 {
+            res.push(Enter(frame));
+            res.extend(saved.iter().enumerate().map(|(k, &p)| St(U64, R(p as u16), sp, offset(0, k))));
+        }
+        let mut depth = 0;
+        for i in ins {
+            match i {
+                Enter(size) => depth += size,
+                Leave(size) => depth -= size,
+                Ret if frame != 0 => {
+                    res.extend(saved.iter().enumerate().map(|(k, &p)| Ld(U64, R(p as u16), sp, offset(depth, k))));
+                    res.push(Leave(frame));
+                }
+                _ => (),
+            }
+
+            // Spilled operands are loaded into reload registers and results stored from them.
+            let (d, u) = i.def_use();
+            let slot = |r: &R| match r {
+                r if r.0 >= regs::PHYS => None,
+                r => self.ids.binary_search(&r.0).ok().and_then(|v| alloc.slot[v]),
+            };
+            let mut local: Vec<(R, usize)> = Vec::new();
+            for r in u.iter().flatten() {
+                if let Some(s) = slot(r) {
+                    if !local.iter().any(|(l, _)| l == r) {
+                        let reload = alloc.reload.start + local.len();
+                        local.push((*r, reload));
+                        res.push(Ld(U64, R(target::R_MAP[reload] as u16), sp, offset(depth, saved.len() + s)));
+                    }
+                }
+            }
+            // Results that are also operands keep their reload register, others take one no other result uses.
+            let mut taken: Vec<usize> =
+                d.iter().flatten().filter_map(|r| local.iter().find(|(l, _)| l == r).map(|&(_, reload)| reload)).collect();
+            let mut stores = Vec::new();
+            for r in d.iter().flatten() {
+                let Some(s) = slot(r) else { continue };
+                let reload = match local.iter().find(|(l, _)| l == r) {
+                    Some(&(_, reload)) => reload,
+                    None => {
+                        let reload = alloc.reload.clone().find(|k| !taken.contains(k)).expect("a free reload register");
+                        taken.push(reload);
+                        local.push((*r, reload));
+                        reload
+                    }
+                };
+                stores.push(St(U64, R(target::R_MAP[reload] as u16), sp, offset(depth, saved.len() + s)));
+            }
+            let mapped = i.map_regs(
+                |r| match r {
+                    r if r == regs::SP => Some(sp),
+                    r if r.0 >= regs::PHYS => Some(R(r.0 - regs::PHYS)),
+                    r => {
+                        let row = match local.iter().find(|(l, _)| *l == r) {
+                            Some(&(_, reload)) => reload,
+                            None => alloc.reg[self.ids.binary_search(&r.0).ok()?]?,
+                        };
+                        Some(R(target::R_MAP[row] as u16))
+                    }
+                },
+                Some,
+            );
+            res.push(mapped.expect("every virtual register has a register or a slot"));
+            res.extend(stores);
+        }
+        res
+    }
+}
+
+/// Ids in a bit set.
+fn bits(set: &[u64]) -> impl Iterator<Item = usize> + '_ {
+    set.iter().enumerate().flat_map(|(k, &w)| (0..64).filter(move |b| w & 1 << b != 0).map(move |b| k * 64 + b))
+}
+
+impl Ins {
+    /// The registers written and the registers read by this instruction.
+    pub(crate) fn def_use(&self) -> ([Option<R>; 2], [Option<R>; 3]) {
+        use Ins::*;
+        match self {
+            Label(_) | Enter(_) | Leave(_) | B(..) | J(_) | Ret | D(..) => ([None, None], [None, None, None]),
+            Addr(d, _) | Movi(d, _) => ([Some(*d), None], [None, None, None]),
+            Ld(_, d, base, _) => ([Some(*d), None], [Some(*base), None, None]),
+            St(_, src, base, _) => ([None, None], [Some(*src), Some(*base), None]),
+            Ldx(_, d, base, index, _) => ([Some(*d), None], [Some(*base), Some(*index), None]),
+            Stx(_, src, base, index, _) => ([None, None], [Some(*src), Some(*base), Some(*index)]),
+            Vld(_, _, _, base, _) | Vst(_, _, _, base, _) => ([None, None], [Some(*base), None, None]),
+            Add(d, a, b) | Sub(d, a, b) | And(d, a, b) | Or(d, a, b) | Xor(d, a, b) | Shl(d, a, b) | Shr(d, a, b)
+            | Sar(d, a, b) | Mul(d, a, b) | UDiv(d, a, b) | SDiv(d, a, b) | Adds(d, a, b) | Adc(d, a, b) | Subs(d, a, b)
+            | Sbc(d, a, b) | UMulH(d, a, b) | SMulH(d, a, b) | URem(d, a, b) | SRem(d, a, b) | Rol(d, a, b)
+            | Ror(d, a, b) => {
+                ([Some(*d), None], [Some(*a), Some(*b), None])
+            }
+            UMulL(lo, hi, a, b) | SMulL(lo, hi, a, b) => ([Some(*lo), Some(*hi)], [Some(*a), Some(*b), None]),
+            Mov(d, a) | Not(d, a) | Neg(d, a) | Clz(d, a) | Ctz(d, a) | Popcnt(d, a) | Bswap(_, d, a) | Ubfx(d, a, ..)
+            | Sbfx(d, a, ..) | Sext(_, d, a) | Zext(_, d, a) => ([Some(*d), None], [Some(*a), None, None]),
+            Bfi(d, a, ..) => ([Some(*d), None], [Some(*a), Some(*d), None]),
+            Addi(d, a, _) | Subi(d, a, _) | Andi(d, a, _) | Ori(d, a, _) | Xori(d, a, _) | Shli(d, a, _) | Shri(d, a, _)
+            | Sari(d, a, _) => ([Some(*d), None], [Some(*a), None, None]),
+            Cmp(a, b) => ([None, None], [Some(*a), Some(*b), None]),
+            Cmpi(a, _) => ([None, None], [Some(*a), None, None]),
+            Vadd(..) | Vsub(..) | Vand(..) | Vor(..) | Vxor(..) | Vshl(..) | Vshr(..) | Vmul(..) | Vdiv(..) | Vmov(..)
+            | Vmovi(..) | Vnot(..) | Vneg(..) | Vrecpe(..) | Vrsqrte(..) | Vcmp(..) | Vbsl(..) | Vblend(..) | Vmin(..)
+            | Vmax(..) | Vabs(..) => ([None, None], [None, None, None]),
+            Vtbl(..) | Vzip(..) | Vuzp(..) | Vtrn(..) | Vext(..) | Vrev(..) => ([None, None], [None, None, None]),
+            Vqadd(..) | Vqsub(..) | Vaddl(..) | Vmull(..) | Vxtn(..) | Vqmovn(..) | Vaddv(..) | Vaddp(..)
+            | Vdot(..) => ([None, None], [None, None, None]),
+            Fadd(..) | Fsub(..) | Fmul(..) | Fdiv(..) | Fmin(..) | Fmax(..) | Fsqrt(..) | Fabs(..) | Fma(..) | Fcmp(..)
+            | Fcvt(..) => ([None, None], [None, None, None]),
+            Scvtf(_, _, a) | VmovFromR(_, _, a, _) | Vdup(_, _, _, a) => ([None, None], [Some(*a), None, None]),
+            Fcvtzs(_, d, _) | VmovToR(_, d, _, _) => ([Some(*d), None], [None, None, None]),
+            Call(target) | Branch(target) => ([None, None], [Some(*target), None, None]),
+            Cbz(a, _) | Cbnz(a, _) | Tbz(a, ..) | Tbnz(a, ..) => ([None, None], [Some(*a), None, None]),
+            Bcmp(_, a, b, _) => ([None, None], [Some(*a), Some(*b), None]),
+            Sel(_, d, t, f) | Csinc(_, d, t, f) | Csneg(_, d, t, f) | Csinv(_, d, t, f) => {
+                ([Some(*d), None], [Some(*t), Some(*f), None])
+            }
+            Cset(_, d) => ([Some(*d), None], [None, None, None]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn regalloc_loop() {
+        use Ins::*;
+        use regalloc::regs::*;
+        const COUNT: R = R(1000);
+        const TOT: R = R(2000);
+        const INC: R = R(3000);
+        const LOOP: u32 = 0;
+        let prog = Executable::from_virtual_ir(&[
+            Mov(COUNT, ARG[0]),
+            Movi(TOT, 0),
+            Movi(INC, 1),
+            Label(LOOP),
+            Add(TOT, TOT, COUNT),
+            Sub(COUNT, COUNT, INC),
+            Cmpi(COUNT, 0),
+            B(Cond::Ne, LOOP),
+            Mov(RES[0], TOT),
+            Ret,
+        ])
+        .unwrap();
+        let (res, _) = unsafe { prog.call(0, &[100]).unwrap() };
+        assert_eq!(res, 5050);
+    }
+
+    #[test]
+    fn regalloc_spill() {
+        use Ins::*;
+        use regalloc::regs::*;
+        // Forty registers live at once.
+        let mut ins: Vec<Ins> = (0..40).map(|r| Add(R(r), ARG[0], ARG[0])).collect();
+        ins.extend((1..40).map(|r| Add(R(r), R(r), R(r - 1))));
+        ins.extend([Mov(RES[0], R(39)), Ret]);
+        let alloc = regalloc::allocate(&ins).unwrap();
+        assert!(matches!(alloc[0], Enter(_)));
+        let prog = Executable::from_ir(&alloc).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[1]).unwrap() };
+        assert_eq!(res, 80);
+    }
+
+    #[test]
+    fn regalloc_spill_pair() {
+        use Ins::*;
+        use regalloc::regs::*;
+        // Both results of UMulL spilled while forty registers are live.
+        let mut ins: Vec<Ins> = (0..40).map(|r| Add(R(r), ARG[0], ARG[0])).collect();
+        ins.push(UMulL(R(40), R(41), R(0), R(1)));
+        ins.extend((1..40).map(|r| Add(R(r), R(r), R(r - 1))));
+        ins.extend([Add(R(42), R(40), R(41)), Add(RES[0], R(42), R(39)), Ret]);
+        let prog = Executable::from_virtual_ir(&ins).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[1 << 40]).unwrap() };
+        assert_eq!(res, (1 << 18) + 40 * (1 << 41));
+    }
+
+    #[test]
+    fn regalloc_call() {
+        use Ins::*;
+        use regalloc::regs::*;
+        extern "C" fn double(x: u64) -> u64 {
+            x * 2
+        }
+        // R(1) is live across the call so must be in a callee-saved register.
+        let prog = Executable::from_virtual_ir(&[
+            Mov(R(1), ARG[1]),
+            Movi(R(0), double as usize as u64),
+            Call(R(0)),
+            Add(RES[0], RES[0], R(1)),
+            Ret,
+        ])
+        .unwrap();
+        let (res, _) = unsafe { prog.call(0, &[10, 3]).unwrap() };
+        assert_eq!(res, 23);
+    }
+
+    /// The stack pointer at the call site modulo 16, which the ABI requires to be 0.
+    #[cfg(target_arch = "x86_64")]
+    #[unsafe(naked)]
+    extern "C" fn call_site_misalignment() -> u64 {
+        core::arch::naked_asm!("lea rax, [rsp + 8]", "and eax, 15", "ret")
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[unsafe(naked)]
+    extern "C" fn call_site_misalignment() -> u64 {
+        core::arch::naked_asm!("mov x0, sp", "and x0, x0, #15", "ret")
+    }
+
+    #[test]
+    fn regalloc_call_alignment() {
+        use Ins::*;
+        use regalloc::regs::*;
+        // No, one and two values live across the call need 0, 8 and 16 bytes of saved registers.
+        for live in 0..3 {
+            let mut ins: Vec<Ins> = (0..live).map(|k| Mov(R(1 + k), ARG[1])).collect();
+            ins.extend([Movi(R(0), call_site_misalignment as usize as u64), Call(R(0))]);
+            ins.extend((0..live).map(|k| Add(RES[0], RES[0], R(1 + k))));
+            ins.push(Ret);
+            let prog = Executable::from_virtual_ir(&ins).unwrap();
+            let (res, _) = unsafe { prog.call(0, &[0, 0]).unwrap() };
+            assert_eq!(res, 0, "{live} live");
+        }
+    }
+
+    #[test]
+    fn regalloc_errors() {
+        use Ins::*;
+        let i = Movi(R(regalloc::regs::PHYS + 100), 1);
+        assert_eq!(regalloc::allocate(&[i.clone(), Ret]).unwrap_err(), Error::InvalidRegisterNumber(i));
+        assert_eq!(regalloc::allocate(&[J(7)]).unwrap_err(), Error::MissingLabel(7));
+    }
+}
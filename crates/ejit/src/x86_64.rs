@@ -1,4 +1,5 @@
-use crate::{Cond, Error, Executable, Fixup, Ins, Type, Vsize, R, V};
+use crate::{Cond, Error, Executable, Fixup, Ins, Labels, Type, Vsize, R, V};
+use vector::Op;
 
 mod base;
 mod vector;
@@ -6,238 +7,2387 @@ mod vector;
 pub mod regs {
     use crate::R;
 
-    // See https://github.com/ARM-software/abi-aa/blob/main/aapcs64/aapcs64.rst
-    pub const ARG: [R; 8] = [R(0), R(1), R(2), R(3), R(4), R(5), R(6), R(7)];
-    pub const RES: [R; 2] = [R(0), R(1)];
-    pub const SP: R = R(31);
+    // See https://gitlab.com/x86-psABIs/x86-64-ABI
+    // Registers are numbered rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi, r8..r15.
+    // R(10) and R(11) are used as scratch registers by some instructions,
+    // V(14) and V(15) by some vector instructions.
+    pub const ARG: [R; 6] = [R(7), R(6), R(2), R(1), R(8), R(9)];
+    pub const RES: [R; 2] = [R(0), R(2)];
+    pub const SP: R = R(4);
 }
 
+/// Portable register assignment, see [`crate::portable`].
+pub(crate) mod portable {
+    pub mod regs {
+        use crate::R;
+
+        pub const ARG: [R; 6] = [R(0), R(1), R(2), R(3), R(4), R(5)];
+        pub const RES: [R; 2] = [R(6), R(2)];
+        pub const SP: R = R(31);
+    }
+
+    /// rdi, rsi, rdx, rcx, r8, r9, rax caller-saved, rbx, rbp, r12-r15 callee-saved.
+    /// r10 and r11 are kept for the backend.
+    pub const R_MAP: &[u8] = &[7, 6, 2, 1, 8, 9, 0, 3, 5, 12, 13, 14, 15];
+
+    /// xmm14 and xmm15 are kept for the backend.
+    pub const V_MAP: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13];
+
+    pub const SP: u8 = 4;
+
+    /// rbx, rbp and r12-r15.
+    pub const CALLEE_SAVED: &[u8] = &[3, 5, 12, 13, 14, 15];
+
+    /// The return address is on the stack.
+    pub const LINK: Option<u8> = None;
+}
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const R10: u8 = 10;
+const R11: u8 = 11;
+const XMM14: u8 = 14;
+const XMM15: u8 = 15;
+
+/// Optional instructions used when the host has them.
+#[derive(Clone, Copy, Debug)]
+struct Features {
+    lzcnt: bool,
+    bmi1: bool,
+    popcnt: bool,
+    fma: bool,
+    f16c: bool,
+    /// Also covers SSE3 and SSSE3, which every SSE4.1 processor has.
+    sse4_1: bool,
+    sse4_2: bool,
+    avx2: bool,
+}
+
+impl Features {
+    fn host() -> Self {
+        Self {
+            lzcnt: is_x86_feature_detected!("lzcnt"),
+            bmi1: is_x86_feature_detected!("bmi1"),
+            popcnt: is_x86_feature_detected!("popcnt"),
+            fma: is_x86_feature_detected!("fma"),
+            f16c: is_x86_feature_detected!("f16c"),
+            sse4_1: is_x86_feature_detected!("sse4.1"),
+            sse4_2: is_x86_feature_detected!("sse4.2"),
+            avx2: is_x86_feature_detected!("avx2"),
+        }
+    }
+}
+
+/// REX prefix with the W bit set for 64 bit operands.
+const REX_W: u8 = 0x48;
+
+/// Empty REX prefix, needed to address spl, bpl, sil and dil.
+const REX: u8 = 0x40;
 
 impl Executable {
     pub fn from_ir(ins: &[Ins]) -> Result<Executable, Error> {
+        let (code, labels) = Self::compile(ins)?;
+        Executable::new(&code, labels)
+    }
+
+    /// Generate the machine code and label offsets.
+    pub(crate) fn compile(ins: &[Ins]) -> Result<(Vec<u8>, Vec<(u32, usize)>), Error> {
+        Self::compile_with(ins, Features::host())
+    }
+
+    /// Generate code using only the optional instructions in `features`.
+    fn compile_with(ins: &[Ins], features: Features) -> Result<(Vec<u8>, Vec<(u32, usize)>), Error> {
         let mut code = Vec::new();
-        let mut labels: Vec<(u32, usize)> = Vec::new();
-        let mut fixups: Vec<(usize, Fixup)> = Vec::new();
+        let mut labels = Labels::new(ins.len());
+        let mut flags = Flags::Unknown;
+        // Clear the upper ymm halves before leaving code that uses V256 to avoid SSE transition penalties.
+        let ymm = ins.iter().any(|i| vector::vsize(i) == Some(Vsize::V256));
         for i in ins {
             use Ins::*;
-            // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions
+            // https://www.felixcloutier.com/x86/
             match i {
                 Add(..) | Sub(..) | And(..) | Or(..) | Xor(..) | Shl(..) | Shr(..) | Sar(..) | Mul(..) | UDiv(..) | SDiv(..) | Not(..) | Neg(..) | Movi(..) | Mov(..)  | Cmpi(..) | Cmp(..) => {
-                    // base::gen_base_aarch64(&mut code, &i)?;
-                }
-                
-                Label(label) => labels.push((*label, code.len())),
-
-                // Addr(dest, label) => {
-                //     fixups.push((code.len(), Fixup::Adr(*dest, *label)));
-                //     code.extend(0x10000000_u32.to_le_bytes());
-                // }
-                // Call(target) => {
-                //     let opcode = 0xd63f0000_u32 | target.to_aarch64() << 5;
-                //     code.extend(opcode.to_le_bytes());
-                // }
-                // Branch(target) => {
-                //     let opcode = 0xd61f0000_u32 | target.to_aarch64() << 5;
-                //     code.extend(opcode.to_le_bytes());
-                // }
-                // B(cond, label) => {
-                //     fixups.push((code.len(), Fixup::B(*cond, *label)));
-                //     code.extend(0x10000000_u32.to_le_bytes());
-                // }
-                // J(label) => {
-                //     fixups.push((code.len(), Fixup::J(*label)));
-                //     code.extend(0x14000000_u32.to_le_bytes());
-                // }
-                // Ret => {
-                //     code.extend(0xd65f03c0_u32.to_le_bytes());
-                // }
-                // Sel(cond, d, t, f) => {
-                //     let opcode: u32 = match cond {
-                //         Cond::Eq => 0x9a800000,
-                //         Cond::Ne => 0x9a801000,
-                //         Cond::Sgt => 0x9a80C000,
-                //         Cond::Sge => 0x9a80A000,
-                //         Cond::Slt => 0x9a80B000,
-                //         Cond::Sle => 0x9a80D000,
-                //         Cond::Ugt => 0x9a808000,
-                //         Cond::Uge => 0x9a802000,
-                //         Cond::Ult => 0x9a803000,
-                //         Cond::Ule => 0x9a809000,
-                //     };
-                //     let opcode =
-                //         opcode | f.to_aarch64() << 16 | t.to_aarch64() << 5 | d.to_aarch64();
-                //     code.extend(opcode.to_le_bytes());
-                // }
-                // Enter(imm) => {
-                //     // FF0300D1 	    sub sp, sp, #0
-                //     if *imm >= 0x1000 {
-                //         return Err(Error::InvalidImmediate(i.clone()));
-                //     }
-                //     if *imm & 0x0f != 0 {
-                //         return Err(Error::StackFrameMustBeModulo16(i.clone()));
-                //     }
-                //     let opcode = 0xd10003ff_u32 | (*imm as u32) << 10;
-                //     code.extend(opcode.to_le_bytes());
-                // }
-                // Leave(imm) => {
-                //     // FF030091 	    add sp, sp, #0
-                //     if *imm >= 0x1000 {
-                //         return Err(Error::InvalidImmediate(i.clone()));
-                //     }
-                //     if *imm & 0x0f != 0 {
-                //         return Err(Error::StackFrameMustBeModulo16(i.clone()));
-                //     }
-                //     let opcode = 0x910003ff_u32 | (*imm as u32) << 10;
-                //     code.extend(opcode.to_le_bytes());
-                // }
-                // Ld(ty, r, ra, imm) => {
-                //     // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/LDR--register---Load-register--register--?lang=en
-                //     use Type::*;
-                //     let (shift, opcode) = match ty {
-                //         U8 => (0, 0x39400000),  // 00004039 	    ldrb w0, [x0, #0]
-                //         U16 => (1, 0x79400000), // 00004079 	    ldrh w0, [x0, #0]
-                //         U32 => (2, 0xb9400000), // 000040B9 	    ldr w0, [x0, #0]
-                //         U64 => (3, 0xf9400000), // 000040F9 	    ldr x0, [x0, #0]
-                //         S8 => (0, 0x39c00000),  // 0000C039 	    ldrsb w0, [x0, #0]
-                //         S16 => (1, 0x79c00000), // 0000C079 	    ldrsh w0, [x0, #0]
-                //         S32 => (2, 0xb9800000), // 000080B9 	    ldrsw x0, [x0, #0]
-                //         S64 => (3, 0xf9400000), // 000040F9 	    ldr x0, [x0, #0]
-                //         _ => return Err(Error::InvalidType(i.clone())),
-                //     };
-                //     if *imm >> shift << shift != *imm {
-                //         return Err(Error::InvalidImmediate(i.clone()));
-                //     }
-                //     if *imm >> shift >= 0x1000 {
-                //         return Err(Error::InvalidImmediate(i.clone()));
-                //     }
-
-                //     let opcode = opcode
-                //         | ((*imm >> shift) as u32) << 10
-                //         | ra.to_aarch64() << 5
-                //         | r.to_aarch64();
-                //     code.extend(opcode.to_le_bytes());
-                // }
-                // St(ty, r, ra, imm) => {
-                //     use Type::*;
-                //     let (shift, opcode) = match ty {
-                //         U8 => (0, 0x39000000),  // 00000039 	    strb w0, [x0, #0]
-                //         U16 => (1, 0x79000000), // 00000079 	    strh w0, [x0, #0]
-                //         U32 => (2, 0xB9000000), // 000000B9 	    str w0, [x0, #0]
-                //         U64 => (3, 0xF9000000), // 000000F9 	    str x0, [x0, #0]
-                //         S8 => (0, 0x39000000),  // 00000039 	    strb w0, [x0, #0]
-                //         S16 => (1, 0x79000000), // 00000079 	    strh w0, [x0, #0]
-                //         S32 => (2, 0xB9000000), // 000000B9 	    str w0, [x0, #0]
-                //         S64 => (3, 0xF9000000), // 000000F9 	    str x0, [x0, #0]
-                //         _ => return Err(Error::InvalidType(i.clone())),
-                //     };
-                //     if *imm >> shift << shift != *imm {
-                //         return Err(Error::InvalidImmediate(i.clone()));
-                //     }
-                //     if *imm >> shift >= 0x1000 {
-                //         return Err(Error::InvalidImmediate(i.clone()));
-                //     }
-                //     let opcode = opcode
-                //         | ((*imm >> shift) as u32) << 10
-                //         | ra.to_aarch64() << 5
-                //         | r.to_aarch64();
-                //     code.extend(opcode.to_le_bytes());
-                // }
-
-                Vmov(..) | Vnot(..) | Vneg(..) | Vadd(..) | Vsub(..) | Vdiv(..)
-                | Vand(..) | Vor(..) | Vxor(..) | Vld(..) | Vst(..) | Vshl(..)
-                | Vshr(..) | Vmovi(..) | Vrecpe(..) | Vrsqrte(..) => {
-                    // vector::gen_vector_aarch64(&mut code, &i)?,
+                    base::gen_base_x86_64(&mut code, i)?;
                 }
 
-                D(ty, value) => {
+                Addi(dest, src, imm) => gen_arithi(&mut code, 0, 0x01, dest, src, *imm, i)?,
+                Subi(dest, src, imm) => gen_arithi(&mut code, 5, 0x29, dest, src, *imm, i)?,
+                Andi(dest, src, imm) => gen_arithi(&mut code, 4, 0x21, dest, src, *imm, i)?,
+                Ori(dest, src, imm) => gen_arithi(&mut code, 1, 0x09, dest, src, *imm, i)?,
+                Xori(dest, src, imm) => gen_arithi(&mut code, 6, 0x31, dest, src, *imm, i)?,
+                Shli(dest, src, imm) => gen_shifti(&mut code, 4, dest, src, *imm, i)?,
+                Shri(dest, src, imm) => gen_shifti(&mut code, 5, dest, src, *imm, i)?,
+                Sari(dest, src, imm) => gen_shifti(&mut code, 7, dest, src, *imm, i)?,
+
+                // 4801C8            add rax, rcx
+                // 4811C8            adc rax, rcx
+                // 4829C8            sub rax, rcx
+                // 4819C8            sbb rax, rcx
+                Adds(dest, src1, src2) => gen_arith(&mut code, 0x01, dest, src1, src2, true, i)?,
+                Adc(dest, src1, src2) => gen_arith(&mut code, 0x11, dest, src1, src2, true, i)?,
+                Subs(dest, src1, src2) => gen_arith(&mut code, 0x29, dest, src1, src2, false, i)?,
+                Sbc(dest, src1, src2) => gen_arith(&mut code, 0x19, dest, src1, src2, false, i)?,
+
+                UMulH(dest, src1, src2) => gen_rdx_rax(&mut code, RdxRax::UMul, None, Some(dest), src1, src2, i)?,
+                SMulH(dest, src1, src2) => gen_rdx_rax(&mut code, RdxRax::SMul, None, Some(dest), src1, src2, i)?,
+                UMulL(lo, hi, src1, src2) => gen_rdx_rax(&mut code, RdxRax::UMul, Some(lo), Some(hi), src1, src2, i)?,
+                SMulL(lo, hi, src1, src2) => gen_rdx_rax(&mut code, RdxRax::SMul, Some(lo), Some(hi), src1, src2, i)?,
+                URem(dest, src1, src2) => gen_rdx_rax(&mut code, RdxRax::UDiv, None, Some(dest), src1, src2, i)?,
+                SRem(dest, src1, src2) => gen_rdx_rax(&mut code, RdxRax::SDiv, None, Some(dest), src1, src2, i)?,
+
+                Sext(ty, dest, src) => {
+                    // 480FBEC1          movsx rax, cl
+                    // 480FBFC1          movsx rax, cx
+                    // 4863C1            movsxd rax, ecx
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
                     match ty {
-                        Type::U8 => code.extend([*value as u8]),
-                        Type::U16 => code.extend((*value as u16).to_le_bytes()),
-                        Type::U32 => code.extend((*value as u32).to_le_bytes()),
-                        Type::U64 => code.extend((*value as u64).to_le_bytes()),
-                        _ => return Err(Error::InvalidDataType(i.clone())),
+                        Type::U8 | Type::S8 => gen_rr(&mut code, REX_W, &[0x0f, 0xbe], dest, src),
+                        Type::U16 | Type::S16 => gen_rr(&mut code, REX_W, &[0x0f, 0xbf], dest, src),
+                        Type::U32 | Type::S32 => gen_rr(&mut code, REX_W, &[0x63], dest, src),
+                        _ => return Err(Error::InvalidType(i.clone())),
                     }
                 }
+                Zext(ty, dest, src) => {
+                    // 0FB6C1            movzx eax, cl
+                    // 400FB6C6          movzx eax, sil
+                    // 0FB7C1            movzx eax, cx
+                    // 89C8              mov eax, ecx
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+                    match ty {
+                        Type::U8 | Type::S8 => gen_rr(&mut code, if src >= 4 { REX } else { 0 }, &[0x0f, 0xb6], dest, src),
+                        Type::U16 | Type::S16 => gen_rr(&mut code, 0, &[0x0f, 0xb7], dest, src),
+                        Type::U32 | Type::S32 => gen_rr(&mut code, 0, &[0x89], src, dest),
+                        _ => return Err(Error::InvalidType(i.clone())),
+                    }
+                }
+                Clz(dest, src) => {
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+                    if features.lzcnt {
+                        // F3480FBDC1        lzcnt rax, rcx
+                        code.push(0xf3);
+                        gen_rr(&mut code, REX_W, &[0x0f, 0xbd], dest, src);
+                    } else {
+                        // 41BA7F000000      mov r10d, 127
+                        // 4C0FBDD9          bsr r11, rcx
+                        // 4D0F44DA          cmove r11, r10
+                        // 4983F33F          xor r11, 63
+                        // 4C89D8            mov rax, r11
+                        gen_movi(&mut code, &R(R10 as u16), &127, i)?;
+                        gen_rr(&mut code, REX_W, &[0x0f, 0xbd], R11, src);
+                        gen_rr(&mut code, REX_W, &[0x0f, 0x44], R11, R10);
+                        gen_rr(&mut code, REX_W, &[0x83], 6, R11);
+                        code.push(63);
+                        gen_mov(&mut code, dest, R11);
+                    }
+                }
+                Ctz(dest, src) => {
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+                    if features.bmi1 {
+                        // F3480FBCC1        tzcnt rax, rcx
+                        code.push(0xf3);
+                        gen_rr(&mut code, REX_W, &[0x0f, 0xbc], dest, src);
+                    } else {
+                        // 41BA40000000      mov r10d, 64
+                        // 4C0FBCD9          bsf r11, rcx
+                        // 4D0F44DA          cmove r11, r10
+                        // 4C89D8            mov rax, r11
+                        gen_movi(&mut code, &R(R10 as u16), &64, i)?;
+                        gen_rr(&mut code, REX_W, &[0x0f, 0xbc], R11, src);
+                        gen_rr(&mut code, REX_W, &[0x0f, 0x44], R11, R10);
+                        gen_mov(&mut code, dest, R11);
+                    }
+                }
+                Popcnt(dest, src) => {
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+                    if features.popcnt {
+                        // F3480FB8C1        popcnt rax, rcx
+                        code.push(0xf3);
+                        gen_rr(&mut code, REX_W, &[0x0f, 0xb8], dest, src);
+                    } else {
+                        gen_popcnt(&mut code, dest, src, i)?;
+                    }
+                }
+                Bswap(ty, dest, src) => {
+                    // 480FC8            bswap rax
+                    // 0FC8              bswap eax
+                    // 48C1E830          shr rax, 48
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+                    let (w, ext, shift) = match ty {
+                        Type::U64 | Type::S64 => (REX_W, 0, 0),
+                        Type::U32 => (0, 0, 0),
+                        Type::U16 => (REX_W, 5, 48),
+                        Type::S32 => (REX_W, 7, 32),
+                        Type::S16 => (REX_W, 7, 48),
+                        _ => return Err(Error::InvalidType(i.clone())),
+                    };
+                    gen_mov(&mut code, dest, src);
+                    emit_rex(&mut code, w, 0, dest);
+                    code.extend([0x0f, 0xc8 | dest & 7]);
+                    if shift != 0 {
+                        gen_rr(&mut code, REX_W, &[0xc1], ext, dest);
+                        code.push(shift);
+                    }
+                }
+                // 48D3C0            rol rax, cl
+                // 48D3C8            ror rax, cl
+                Rol(dest, src1, src2) => gen_shift(&mut code, 0, dest, src1, src2, i)?,
+                Ror(dest, src1, src2) => gen_shift(&mut code, 1, dest, src1, src2, i)?,
+                Ubfx(dest, src, lsb, width) => gen_bfx(&mut code, 5, dest, src, *lsb, *width, i)?,
+                Sbfx(dest, src, lsb, width) => gen_bfx(&mut code, 7, dest, src, *lsb, *width, i)?,
+                Bfi(dest, src, lsb, width) => {
+                    // 4989CB            mov r11, rcx
+                    // 49C1E338          shl r11, 56
+                    // 49C1EB30          shr r11, 48
+                    // 49BAFFFFFFFFFFFF00FF movabs r10, 0xff00ffffffffffff
+                    // 4C21D0            and rax, r10
+                    // 4C09D8            or rax, r11
+                    check_bitfield(*lsb, *width, i)?;
+                    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+                    let mask = (!0_u64 >> (64 - width)) << lsb;
+                    gen_mov(&mut code, R11, src);
+                    gen_rr(&mut code, REX_W, &[0xc1], 4, R11);
+                    code.push(64 - width);
+                    if 64 - width - lsb != 0 {
+                        gen_rr(&mut code, REX_W, &[0xc1], 5, R11);
+                        code.push(64 - width - lsb);
+                    }
+                    gen_movi(&mut code, &R(R10 as u16), &!mask, i)?;
+                    gen_rr(&mut code, REX_W, &[0x21], R10, dest);
+                    gen_rr(&mut code, REX_W, &[0x09], R11, dest);
+                }
 
-                _ => todo!("{i:?}"),
+                Label(label) => labels.define(*label, &mut code, &mut patch)?,
 
-            }
-        }
-        for (loc, f) in fixups {
-            match f {
-                Fixup::Adr(dest, label) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/ADR--Form-PC-relative-address-?lang=en
-                    if let Some((_, offset)) = labels.iter().find(|(n, _)| *n == label) {
-                        let delta = *offset as isize - loc as isize;
-                        let opcode = 0x10000000_u32
-                            | ((delta & 3) as u32) << 29
-                            | ((delta >> 2 & 0x1fffff) as u32) * 32
-                            | dest.to_aarch64();
-                        code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
-                    } else {
-                        return Err(Error::MissingLabel(label));
+                Addr(dest, label) => {
+                    // 488D0500000000    lea rax, [rip + 0]
+                    let dest = dest.to_x86(i)?;
+                    emit_rex(&mut code, REX_W, dest, 0);
+                    code.extend([0x8d, 0x05 | (dest & 7) << 3]);
+                    code.extend(0_u32.to_le_bytes());
+                    labels.fixup(code.len() - 4, Fixup::Adr(R(dest as u16), *label), &mut code, &mut patch)?;
+                }
+                Call(target) => {
+                    // C5F877            vzeroupper
+                    // FFD0              call rax
+                    if ymm {
+                        code.extend([0xc5, 0xf8, 0x77]);
                     }
+                    gen_rr(&mut code, 0, &[0xff], 2, target.to_x86(i)?);
                 }
-                Fixup::B(cond, label) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/B-cond--Branch-conditionally-?lang=en
-                    if let Some((_, offset)) = labels.iter().find(|(n, _)| *n == label) {
-                        let delta = *offset as isize - loc as isize;
-                        if (delta & 3) != 0 {
-                            return Err(Error::BranchNotMod4(label));
-                        }
-                        if delta < -(1 << 19 + 2 - 1) || delta >= (1 << 19 + 2 - 1) {
-                            return Err(Error::BranchOutOfRange(label));
-                        }
-                        let opcode = match cond {
-                            // Cond::Always => 0x5400000e,
-                            Cond::Eq => 0x54000000,
-                            Cond::Ne => 0x54000001,
-                            Cond::Sgt => 0x5400000c,
-                            Cond::Sge => 0x5400000a,
-                            Cond::Slt => 0x5400000b,
-                            Cond::Sle => 0x5400000d,
-                            Cond::Ugt => 0x54000008,
-                            Cond::Uge => 0x54000002,
-                            Cond::Ult => 0x54000003,
-                            Cond::Ule => 0x54000009,
-                        } | ((delta >> 1) & 0xfffff) as u32 * 16;
-                        code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
+                Branch(target) => {
+                    // FFE0              jmp rax
+                    if ymm {
+                        code.extend([0xc5, 0xf8, 0x77]);
+                    }
+                    gen_rr(&mut code, 0, &[0xff], 4, target.to_x86(i)?);
+                }
+                B(cond, label) => gen_jtest(&mut code, &mut labels, cond.test(flags, i)?, *label)?,
+                Cbz(src, label) | Cbnz(src, label) => {
+                    // 4885C0            test rax, rax
+                    let src = src.to_x86(i)?;
+                    gen_rr(&mut code, REX_W, &[0x85], src, src);
+                    let cond = if matches!(i, Cbz(..)) { Cond::Eq } else { Cond::Ne };
+                    gen_jcc(&mut code, &mut labels, cond.to_x86(), *label)?;
+                }
+                Tbz(src, bit, label) | Tbnz(src, bit, label) => {
+                    // 480FBAE03F        bt rax, 63
+                    if *bit >= 64 {
+                        return Err(Error::InvalidImmediate(i.clone()));
+                    }
+                    gen_rr(&mut code, REX_W, &[0x0f, 0xba], 4, src.to_x86(i)?);
+                    code.push(*bit);
+                    let cond = if matches!(i, Tbz(..)) { Cond::Cc } else { Cond::Cs };
+                    gen_jcc(&mut code, &mut labels, cond.to_x86(), *label)?;
+                }
+                Bcmp(cond, src1, src2, label) => {
+                    gen_cmp(&mut code, src1, src2, i)?;
+                    gen_jtest(&mut code, &mut labels, cond.test(Flags::Sub, i)?, *label)?;
+                }
+                J(label) => {
+                    // EB00              jmp l1
+                    // E900000000        jmp l1
+                    if let Some(delta) = short_delta(&labels, *label, code.len() + 2) {
+                        code.extend([0xeb, delta as u8]);
                     } else {
-                        return Err(Error::MissingLabel(label));
+                        code.push(0xe9);
+                        code.extend(0_u32.to_le_bytes());
+                        labels.fixup(code.len() - 4, Fixup::J(*label), &mut code, &mut patch)?;
                     }
                 }
-                Fixup::J(label) => {
-                    // https://developer.arm.com/documentation/ddi0602/2024-12/Base-Instructions/B-cond--Branch-conditionally-?lang=en
-                    if let Some((_, offset)) = labels.iter().find(|(n, _)| *n == label) {
-                        let delta = *offset as isize - loc as isize;
-                        if (delta & 3) != 0 {
-                            return Err(Error::BranchNotMod4(label));
-                        }
-                        if delta < -(1 << 26 + 2 - 1) || delta >= (1 << 26 + 2 - 1) {
-                            return Err(Error::BranchOutOfRange(label));
+                Ret => {
+                    if ymm {
+                        code.extend([0xc5, 0xf8, 0x77]);
+                    }
+                    code.push(0xc3);
+                }
+                Sel(cond, d, t, f) => {
+                    // 480F44C1          cmove rax, rcx
+                    let (d, t, f) = (d.to_x86(i)?, t.to_x86(i)?, f.to_x86(i)?);
+                    gen_csel(&mut code, cond.test(flags, i)?, d, t, f);
+                }
+                Cset(cond, d) => {
+                    // 0F94C0            sete al
+                    // 0FB6C0            movzx eax, al
+                    // xor would clobber the flags before the setcc.
+                    // 7B05              jnp done
+                    // B800000000        mov eax, 0
+                    let d = d.to_x86(i)?;
+                    let rex = if d >= 4 { REX } else { 0 };
+                    let (cc, nan) = match cond.test(flags, i)? {
+                        Test::Tttn(cc) => (cc, None),
+                        Test::AndNp(cc) => (cc, Some(0)),
+                        Test::OrP(cc) => (cc, Some(1)),
+                    };
+                    gen_rr(&mut code, rex, &[0x0f, 0x90 | cc], 0, d);
+                    gen_rr(&mut code, rex, &[0x0f, 0xb6], d, d);
+                    if let Some(nan) = nan {
+                        code.extend([0x7b, 0]);
+                        let start = code.len();
+                        gen_movi(&mut code, &R(d as u16), &nan, i)?;
+                        code[start - 1] = (code.len() - start) as u8;
+                    }
+                }
+                Csinc(cond, d, t, f) | Csneg(cond, d, t, f) | Csinv(cond, d, t, f) => {
+                    // 4C8D5901          lea r11, [rcx + 1]
+                    // 49F7D3            not r11
+                    // 4D8D5B01          lea r11, [r11 + 1]
+                    // 490F45C3          cmovne rax, r11
+                    // Compute the false value in r11 without changing the flags.
+                    let (d, t, f) = (d.to_x86(i)?, t.to_x86(i)?, f.to_x86(i)?);
+                    match i {
+                        Csinc(..) => gen_rm(&mut code, REX_W, &[0x8d], 11, f, 1),
+                        _ => {
+                            gen_mov(&mut code, 11, f);
+                            gen_rr(&mut code, REX_W, &[0xf7], 2, 11);
+                            if matches!(i, Csneg(..)) {
+                                gen_rm(&mut code, REX_W, &[0x8d], 11, 11, 1);
+                            }
                         }
-                        let opcode = 0x14000000_u32 | ((delta >> 1) & 0x3ffffff) as u32;
-                        code[loc..loc + 4].copy_from_slice(&opcode.to_le_bytes());
-                    } else {
-                        return Err(Error::MissingLabel(label));
+                    }
+                    gen_csel(&mut code, cond.test(flags, i)?, d, t, 11);
+                }
+                Enter(imm) => {
+                    // 4883EC00          sub rsp, 0
+                    if *imm & 0x07 != 0 {
+                        return Err(Error::StackFrameMustBeModulo8(i.clone()));
+                    }
+                    gen_sp_adjust(&mut code, 5, *imm, i)?;
+                }
+                Leave(imm) => {
+                    // 4883C400          add rsp, 0
+                    if *imm & 0x07 != 0 {
+                        return Err(Error::StackFrameMustBeModulo8(i.clone()));
+                    }
+                    gen_sp_adjust(&mut code, 0, *imm, i)?;
+                }
+                Ld(ty, r, ra, imm) => gen_ld(&mut code, *ty, r.to_x86(i)?, Mem::Disp(ra.to_x86(i)?, *imm), i)?,
+                St(ty, r, ra, imm) => gen_st(&mut code, *ty, r.to_x86(i)?, Mem::Disp(ra.to_x86(i)?, *imm), i)?,
+                Ldx(ty, r, ra, rb, scale) => {
+                    let mem = gen_index(&mut code, ra, rb, *scale, i)?;
+                    gen_ld(&mut code, *ty, r.to_x86(i)?, mem, i)?;
+                }
+                Stx(ty, r, ra, rb, scale) => {
+                    let mem = gen_index(&mut code, ra, rb, *scale, i)?;
+                    gen_st(&mut code, *ty, r.to_x86(i)?, mem, i)?;
+                }
+
+                Vmov(..) | Vnot(..) | Vneg(..) | Vadd(..) | Vsub(..) | Vmul(..) | Vdiv(..)
+                | Vand(..) | Vor(..) | Vxor(..) | Vld(..) | Vst(..) | Vshl(..)
+                | Vshr(..) | Vmovi(..) | Vrecpe(..) | Vrsqrte(..) | VmovFromR(..) | VmovToR(..) | Vdup(..)
+                | Vcmp(..) | Vbsl(..) | Vblend(..) | Vmin(..) | Vmax(..) | Vabs(..) | Vtbl(..) | Vzip(..) | Vuzp(..)
+                | Vtrn(..) | Vext(..) | Vrev(..) | Vqadd(..) | Vqsub(..) | Vaddl(..) | Vmull(..) | Vxtn(..) | Vqmovn(..)
+                | Vaddv(..) | Vaddp(..) | Vdot(..) => {
+                    vector::gen_vector_x86_64(&mut code, i, features)?
+                }
+                Fadd(..) | Fsub(..) | Fmul(..) | Fdiv(..) | Fmin(..) | Fmax(..) | Fsqrt(..) | Fabs(..) | Fma(..) | Fcmp(..)
+                | Fcvt(..) | Scvtf(..) | Fcvtzs(..) => vector::gen_float_x86_64(&mut code, i, features)?,
+
+                D(ty, value) => {
+                    match ty {
+                        Type::U8 => code.extend([*value as u8]),
+                        Type::U16 => code.extend((*value as u16).to_le_bytes()),
+                        Type::U32 => code.extend((*value as u32).to_le_bytes()),
+                        Type::U64 => code.extend((*value as u64).to_le_bytes()),
+                        _ => return Err(Error::InvalidDataType(i.clone())),
                     }
                 }
             }
+            flags = match i {
+                Subs(..) | Sbc(..) | Cmp(..) | Cmpi(..) | Bcmp(..) => Flags::Sub,
+                Adds(..) | Adc(..) => Flags::Add,
+                Fcmp(..) => Flags::Fcmp,
+                Label(_) => Flags::Unknown,
+                _ => flags,
+            };
         }
-        Ok(Executable::new(&code, labels))
+        Ok((code, labels.finish()?))
     }
 }
 
+/// Return the rel8 displacement to an already defined label if it fits.
+fn short_delta(labels: &Labels, label: u32, end: usize) -> Option<i8> {
+    let offset = labels.offset(label)?;
+    i8::try_from(offset as isize - end as isize).ok()
+}
+
+/// Jump to a label if the condition holds.
+fn gen_jcc(code: &mut Vec<u8>, labels: &mut Labels, cc: u8, label: u32) -> Result<(), Error> {
+    // 7400              je l1
+    // 0F8400000000      je l1
+    if let Some(delta) = short_delta(labels, label, code.len() + 2) {
+        code.extend([0x70 | cc, delta as u8]);
+    } else {
+        code.extend([0x0f, 0x80 | cc]);
+        code.extend(0_u32.to_le_bytes());
+        labels.fixup(code.len() - 4, Fixup::J(label), code, &mut patch)?;
+    }
+    Ok(())
+}
+
+fn gen_jtest(code: &mut Vec<u8>, labels: &mut Labels, test: Test, label: u32) -> Result<(), Error> {
+    match test {
+        Test::Tttn(cc) => gen_jcc(code, labels, cc, label),
+        Test::OrP(cc) => {
+            // 7A00              jp l1
+            // 7400              je l1
+            gen_jcc(code, labels, 0xa, label)?;
+            gen_jcc(code, labels, cc, label)
+        }
+        Test::AndNp(cc) => {
+            // 7A02              jp done
+            // 7400              je l1
+            code.extend([0x7a, 0]);
+            let start = code.len();
+            gen_jcc(code, labels, cc, label)?;
+            code[start - 1] = (code.len() - start) as u8;
+            Ok(())
+        }
+    }
+}
+
+/// Set d to t if the test holds, otherwise to f, without changing the flags.
+fn gen_csel(code: &mut Vec<u8>, test: Test, d: u8, t: u8, f: u8) {
+    // 480F44C1          cmove rax, rcx
+    let (cc, t, f) = match test {
+        Test::Tttn(cc) => {
+            if d == t {
+                gen_rr(code, REX_W, &[0x0f, 0x40 | cc ^ 1], d, f);
+            } else {
+                gen_mov(code, d, f);
+                gen_rr(code, REX_W, &[0x0f, 0x40 | cc], d, t);
+            }
+            return;
+        }
+        Test::AndNp(cc) => (cc, t, f),
+        Test::OrP(cc) => (cc ^ 1, f, t),
+    };
+    // Take t if cc holds and there is no NaN.
+    if d == t {
+        // 480F45C1          cmovne rax, rcx
+        // 480F4AC1          cmovp rax, rcx
+        gen_rr(code, REX_W, &[0x0f, 0x40 | cc ^ 1], d, f);
+        gen_rr(code, REX_W, &[0x0f, 0x4a], d, f);
+    } else if d == f {
+        // 7A04              jp done
+        // 480F44C1          cmove rax, rcx
+        code.extend([0x7a, 0]);
+        let start = code.len();
+        gen_rr(code, REX_W, &[0x0f, 0x40 | cc], d, t);
+        code[start - 1] = (code.len() - start) as u8;
+    } else {
+        gen_mov(code, d, f);
+        gen_rr(code, REX_W, &[0x0f, 0x40 | cc], d, t);
+        gen_rr(code, REX_W, &[0x0f, 0x4a], d, f);
+    }
+}
+
+/// All x86 fixups are rel32 fields relative to the end of the field.
+fn patch(code: &mut [u8], loc: usize, f: Fixup, offset: usize) -> Result<(), Error> {
+    let delta = offset as isize - (loc + 4) as isize;
+    let Ok(delta) = i32::try_from(delta) else {
+        return Err(Error::BranchOutOfRange(f.label()));
+    };
+    code[loc..loc + 4].copy_from_slice(&delta.to_le_bytes());
+    Ok(())
+}
+
 impl R {
-    // Return the REX bit and the MODRM bits.
-    pub fn to_x86(&self) -> u32 {
-        self.0 as u32
+    // Return the register number, REX bit included.
+    pub fn to_x86(&self, i: &Ins) -> Result<u8, Error> {
+        if self.0 >= 16 {
+            return Err(Error::InvalidRegisterNumber(i.clone()));
+        }
+        Ok(self.0 as u8)
     }
 }
 
 impl V {
-    // Return the REX bit and the MODRM bits.
-    pub fn to_aarch64(&self) -> u32 {
-        self.0 as u32
+    // Return the register number, REX bit included.
+    pub fn to_x86(&self, i: &Ins) -> Result<u8, Error> {
+        if self.0 >= 16 {
+            return Err(Error::InvalidRegisterNumber(i.clone()));
+        }
+        Ok(self.0)
+    }
+}
+
+/// What last set the flags, which decides how a condition is tested.
+#[derive(Clone, Copy, PartialEq)]
+enum Flags {
+    /// At the start and after a Label, where the flags may come from any instruction.
+    Unknown,
+    Add,
+    /// x86 sets the carry on a borrow, the inverse of aarch64.
+    Sub,
+    /// ucomis sets the flags of an unsigned compare, and ZF, PF and CF if unordered.
+    Fcmp,
+}
+
+/// A condition as a tttn field, after Fcmp some are combined with the parity flag.
+#[derive(Clone, Copy)]
+enum Test {
+    Tttn(u8),
+    /// The condition holds and there is no NaN.
+    AndNp(u8),
+    /// The condition holds or there is a NaN.
+    OrP(u8),
+}
+
+impl Cond {
+    /// Cs and Cc are rejected where it is not known which instruction set the flags.
+    fn test(self, flags: Flags, i: &Ins) -> Result<Test, Error> {
+        use Cond::*;
+        Ok(match (flags, self) {
+            (Flags::Fcmp, Eq) => Test::AndNp(0x4),
+            (Flags::Fcmp, Ne) => Test::OrP(0x5),
+            (Flags::Fcmp, Ult | Cc | Mi) => Test::AndNp(0x2),
+            (Flags::Fcmp, Ule) => Test::AndNp(0x6),
+            (Flags::Fcmp, Ugt) => Test::OrP(0x7),
+            (Flags::Fcmp, Uge | Cs | Pl) => Test::OrP(0x3),
+            (Flags::Fcmp, Sgt) => Test::Tttn(0x7),
+            (Flags::Fcmp, Sge) => Test::Tttn(0x3),
+            (Flags::Fcmp, Slt) => Test::Tttn(0x2),
+            (Flags::Fcmp, Sle) => Test::Tttn(0x6),
+            (Flags::Fcmp, Vs) => Test::Tttn(0xa),
+            (Flags::Fcmp, Vc) => Test::Tttn(0xb),
+            (Flags::Unknown, Cs | Cc) => return Err(Error::UnsupportedOperation(i.clone())),
+            (Flags::Sub, Cs) => Test::Tttn(Cc.to_x86()),
+            (Flags::Sub, Cc) => Test::Tttn(Cs.to_x86()),
+            (_, cond) => Test::Tttn(cond.to_x86()),
+        })
+    }
+
+    /// The tttn condition field of jcc, setcc and cmovcc.
+    fn to_x86(&self) -> u8 {
+        match self {
+            Cond::Eq => 0x4,
+            Cond::Ne => 0x5,
+            Cond::Sgt => 0xf,
+            Cond::Sge => 0xd,
+            Cond::Slt => 0xc,
+            Cond::Sle => 0xe,
+            Cond::Ugt => 0x7,
+            Cond::Uge => 0x3,
+            Cond::Ult => 0x2,
+            Cond::Ule => 0x6,
+            Cond::Cs => 0x2,
+            Cond::Cc => 0x3,
+            Cond::Vs => 0x0,
+            Cond::Vc => 0x1,
+            Cond::Mi => 0x8,
+            Cond::Pl => 0x9,
+        }
+    }
+}
+
+/// Emit a REX prefix if the operands need one.
+/// `w` is `REX_W` for 64 bit operands, `REX` to force a prefix or zero.
+fn emit_rex(code: &mut Vec<u8>, w: u8, reg: u8, base: u8) {
+    let rex = w | (reg >> 3 & 1) << 2 | (base >> 3 & 1);
+    if rex != 0 {
+        code.push(rex | 0x40);
+    }
+}
+
+/// Register to register form, `reg` is either a register or an opcode extension.
+fn gen_rr(code: &mut Vec<u8>, w: u8, opcode: &[u8], reg: u8, rm: u8) {
+    emit_rex(code, w, reg, rm);
+    code.extend(opcode);
+    code.push(0xc0 | (reg & 7) << 3 | rm & 7);
+}
+
+/// Register to memory form, `[base + disp]`.
+fn gen_rm(code: &mut Vec<u8>, w: u8, opcode: &[u8], reg: u8, base: u8, disp: i32) {
+    emit_rex(code, w, reg, base);
+    code.extend(opcode);
+    emit_modrm_mem(code, reg, base, disp);
+}
+
+/// MODRM, SIB and displacement bytes for `[base + disp]`.
+fn emit_modrm_mem(code: &mut Vec<u8>, reg: u8, base: u8, disp: i32) {
+    // rbp and r13 have no zero displacement form.
+    let mode = if disp == 0 && base & 7 != 5 {
+        0x00
+    } else if i8::try_from(disp).is_ok() {
+        0x40
+    } else {
+        0x80
+    };
+    code.push(mode | (reg & 7) << 3 | base & 7);
+    // rsp and r12 need a SIB byte.
+    if base & 7 == 4 {
+        code.push(0x24);
+    }
+    match mode {
+        0x40 => code.push(disp as u8),
+        0x80 => code.extend(disp.to_le_bytes()),
+        _ => (),
+    }
+}
+
+/// A memory operand.
+#[derive(Clone, Copy)]
+enum Mem {
+    /// `[base + disp]`
+    Disp(u8, i32),
+    /// `[base + index * (1 << scale)]`
+    Index(u8, u8, u8),
+}
+
+/// Register to memory form, `[base + index * (1 << scale)]`.
+fn gen_rmx(code: &mut Vec<u8>, w: u8, opcode: &[u8], reg: u8, base: u8, index: u8, scale: u8) {
+    let rex = w | (reg >> 3 & 1) << 2 | (index >> 3 & 1) << 1 | (base >> 3 & 1);
+    if rex != 0 {
+        code.push(rex | 0x40);
+    }
+    code.extend(opcode);
+    // rbp and r13 have no zero displacement form.
+    let mode = if base & 7 == 5 { 0x40 } else { 0x00 };
+    code.push(mode | (reg & 7) << 3 | 4);
+    code.push(scale << 6 | (index & 7) << 3 | base & 7);
+    if mode == 0x40 {
+        code.push(0);
+    }
+}
+
+fn gen_mem(code: &mut Vec<u8>, w: u8, opcode: &[u8], reg: u8, mem: Mem) {
+    match mem {
+        Mem::Disp(base, disp) => gen_rm(code, w, opcode, reg, base, disp),
+        Mem::Index(base, index, scale) => gen_rmx(code, w, opcode, reg, base, index, scale),
+    }
+}
+
+/// The operand `[ra + (rb << scale)]`, shifting the index into r11 if the scale is more than 3.
+fn gen_index(code: &mut Vec<u8>, ra: &R, rb: &R, scale: u8, i: &Ins) -> Result<Mem, Error> {
+    let (base, index) = (ra.to_x86(i)?, rb.to_x86(i)?);
+    // rsp can not be an index.
+    if index == 4 {
+        return Err(Error::InvalidRegisterNumber(i.clone()));
+    }
+    match scale {
+        0..=3 => Ok(Mem::Index(base, index, scale)),
+        4..=63 => {
+            // 4C89D3            mov r11, rdx
+            // 49C1E304          shl r11, 4
+            gen_mov(code, R11, index);
+            gen_rr(code, REX_W, &[0xc1], 4, R11);
+            code.push(scale);
+            Ok(Mem::Index(base, R11, 0))
+        }
+        _ => Err(Error::InvalidImmediate(i.clone())),
+    }
+}
+
+fn gen_ld(code: &mut Vec<u8>, ty: Type, r: u8, mem: Mem, i: &Ins) -> Result<(), Error> {
+    use Type::*;
+    let (w, opcode): (u8, &[u8]) = match ty {
+        U8 => (0, &[0x0f, 0xb6]),      // 0FB600       movzx eax, byte ptr [rax]
+        U16 => (0, &[0x0f, 0xb7]),     // 0FB700       movzx eax, word ptr [rax]
+        U32 => (0, &[0x8b]),           // 8B00         mov eax, dword ptr [rax]
+        U64 => (REX_W, &[0x8b]),       // 488B00       mov rax, qword ptr [rax]
+        S8 => (REX_W, &[0x0f, 0xbe]),  // 480FBE00     movsx rax, byte ptr [rax]
+        S16 => (REX_W, &[0x0f, 0xbf]), // 480FBF00     movsx rax, word ptr [rax]
+        S32 => (REX_W, &[0x63]),       // 486300       movsxd rax, dword ptr [rax]
+        S64 => (REX_W, &[0x8b]),       // 488B00       mov rax, qword ptr [rax]
+        _ => return Err(Error::InvalidType(i.clone())),
+    };
+    gen_mem(code, w, opcode, r, mem);
+    Ok(())
+}
+
+fn gen_st(code: &mut Vec<u8>, ty: Type, r: u8, mem: Mem, i: &Ins) -> Result<(), Error> {
+    use Type::*;
+    match ty {
+        // 8800         mov byte ptr [rax], al
+        U8 | S8 => gen_mem(code, if r >= 4 { REX } else { 0 }, &[0x88], r, mem),
+        // 668900       mov word ptr [rax], ax
+        U16 | S16 => {
+            code.push(0x66);
+            gen_mem(code, 0, &[0x89], r, mem);
+        }
+        // 8900         mov dword ptr [rax], eax
+        U32 | S32 => gen_mem(code, 0, &[0x89], r, mem),
+        // 488900       mov qword ptr [rax], rax
+        U64 | S64 => gen_mem(code, REX_W, &[0x89], r, mem),
+        _ => return Err(Error::InvalidType(i.clone())),
+    }
+    Ok(())
+}
+
+/// 4889C8            mov rax, rcx
+fn gen_mov(code: &mut Vec<u8>, dest: u8, src: u8) {
+    if dest != src {
+        gen_rr(code, REX_W, &[0x89], src, dest);
+    }
+}
+
+/// Add to or subtract from rsp, `ext` is the opcode extension of add or sub.
+fn gen_sp_adjust(code: &mut Vec<u8>, ext: u8, imm: u32, i: &Ins) -> Result<(), Error> {
+    let Ok(imm) = i32::try_from(imm) else {
+        return Err(Error::InvalidImmediate(i.clone()));
+    };
+    if let Ok(imm) = i8::try_from(imm) {
+        gen_rr(code, REX_W, &[0x83], ext, 4);
+        code.push(imm as u8);
+    } else {
+        gen_rr(code, REX_W, &[0x81], ext, 4);
+        code.extend(imm.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Two operand arithmetic `op dest, src` on three operand IR.
+fn gen_arith(code: &mut Vec<u8>, opcode: u8, dest: &R, src1: &R, src2: &R, commutative: bool, i: &Ins) -> Result<(), Error> {
+    let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+    if dest == src1 {
+        gen_rr(code, REX_W, &[opcode], src2, dest);
+    } else if dest == src2 && commutative {
+        gen_rr(code, REX_W, &[opcode], src1, dest);
+    } else if dest == src2 {
+        gen_mov(code, R11, src2);
+        gen_mov(code, dest, src1);
+        gen_rr(code, REX_W, &[opcode], R11, dest);
+    } else {
+        gen_mov(code, dest, src1);
+        gen_rr(code, REX_W, &[opcode], src2, dest);
+    }
+    Ok(())
+}
+
+/// Arithmetic with an immediate, `ext` is the opcode extension of the immediate form
+/// and `opcode` the register form used when the immediate is not a sign extended imm32.
+fn gen_arithi(code: &mut Vec<u8>, ext: u8, opcode: u8, dest: &R, src: &R, imm: u64, i: &Ins) -> Result<(), Error> {
+    if let Ok(imm) = i8::try_from(imm as i64) {
+        // 4883C012          add rax, 0x12
+        let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+        gen_mov(code, dest, src);
+        gen_rr(code, REX_W, &[0x83], ext, dest);
+        code.push(imm as u8);
+    } else if let Ok(imm) = i32::try_from(imm as i64) {
+        // 4881C078563412    add rax, 0x12345678
+        let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+        gen_mov(code, dest, src);
+        gen_rr(code, REX_W, &[0x81], ext, dest);
+        code.extend(imm.to_le_bytes());
+    } else {
+        // 49BB...           movabs r11, imm64
+        // 4C01D8            add rax, r11
+        let tmp = R(R11 as u16);
+        gen_movi(code, &tmp, &imm, i)?;
+        gen_arith(code, opcode, dest, src, &tmp, false, i)?;
+    }
+    Ok(())
+}
+
+/// Shift by an immediate, `ext` is the opcode extension.
+/// 48C1E004          shl rax, 4
+fn gen_shifti(code: &mut Vec<u8>, ext: u8, dest: &R, src: &R, imm: u64, i: &Ins) -> Result<(), Error> {
+    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+    gen_mov(code, dest, src);
+    gen_rr(code, REX_W, &[0xc1], ext, dest);
+    code.push(imm as u8 & 63);
+    Ok(())
+}
+
+/// Extract a bitfield with a left shift then a right shift, `ext` is shr or sar.
+/// 48C1E038          shl rax, 56
+/// 48C1E830          shr rax, 48
+fn gen_bfx(code: &mut Vec<u8>, ext: u8, dest: &R, src: &R, lsb: u8, width: u8, i: &Ins) -> Result<(), Error> {
+    check_bitfield(lsb, width, i)?;
+    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+    gen_mov(code, dest, src);
+    if 64 - width - lsb != 0 {
+        gen_rr(code, REX_W, &[0xc1], 4, dest);
+        code.push(64 - width - lsb);
+    }
+    if width != 64 {
+        gen_rr(code, REX_W, &[0xc1], ext, dest);
+        code.push(64 - width);
+    }
+    Ok(())
+}
+
+/// A bitfield must have at least one bit and fit in 64 bits.
+fn check_bitfield(lsb: u8, width: u8, i: &Ins) -> Result<(), Error> {
+    if width == 0 || lsb as u32 + width as u32 > 64 {
+        return Err(Error::InvalidImmediate(i.clone()));
+    }
+    Ok(())
+}
+
+/// Population count without the popcnt instruction, adding bits in pairs, nibbles then bytes.
+fn gen_popcnt(code: &mut Vec<u8>, dest: u8, src: u8, i: &Ins) -> Result<(), Error> {
+    let shr = |code: &mut Vec<u8>, r: u8, n: u8| {
+        gen_rr(code, REX_W, &[0xc1], 5, r);
+        code.push(n);
+    };
+    let dest_r = R(dest as u16);
+    // x - ((x >> 1) & 0x55..)
+    gen_mov(code, R11, src);
+    shr(code, R11, 1);
+    gen_movi(code, &R(R10 as u16), &0x5555_5555_5555_5555, i)?;
+    gen_rr(code, REX_W, &[0x21], R10, R11);
+    gen_mov(code, R10, src);
+    gen_rr(code, REX_W, &[0x29], R11, R10);
+    // (x & 0x33..) + ((x >> 2) & 0x33..), src is no longer needed so dest is free.
+    gen_mov(code, R11, R10);
+    shr(code, R11, 2);
+    gen_movi(code, &dest_r, &0x3333_3333_3333_3333, i)?;
+    gen_rr(code, REX_W, &[0x21], dest, R10);
+    gen_rr(code, REX_W, &[0x21], dest, R11);
+    gen_rr(code, REX_W, &[0x01], R11, R10);
+    // (x + (x >> 4)) & 0x0f..
+    gen_mov(code, R11, R10);
+    shr(code, R11, 4);
+    gen_rr(code, REX_W, &[0x01], R11, R10);
+    gen_movi(code, &dest_r, &0x0f0f_0f0f_0f0f_0f0f, i)?;
+    gen_rr(code, REX_W, &[0x21], dest, R10);
+    // Add the bytes into the top byte.
+    gen_movi(code, &dest_r, &0x0101_0101_0101_0101, i)?;
+    gen_rr(code, REX_W, &[0x0f, 0xaf], R10, dest);
+    shr(code, R10, 56);
+    gen_mov(code, dest, R10);
+    Ok(())
+}
+
+/// 480FAFC1          imul rax, rcx
+fn gen_mul(code: &mut Vec<u8>, dest: &R, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+    if dest == src2 {
+        gen_rr(code, REX_W, &[0x0f, 0xaf], dest, src1);
+    } else {
+        gen_mov(code, dest, src1);
+        gen_rr(code, REX_W, &[0x0f, 0xaf], dest, src2);
+    }
+    Ok(())
+}
+
+/// Shifts take their count in cl, `ext` is the opcode extension.
+/// 48D3E0            shl rax, cl
+fn gen_shift(code: &mut Vec<u8>, ext: u8, dest: &R, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+    if src2 == RCX && dest == src1 {
+        gen_rr(code, REX_W, &[0xd3], ext, dest);
+    } else if src2 == RCX {
+        gen_mov(code, R11, src1);
+        gen_rr(code, REX_W, &[0xd3], ext, R11);
+        gen_mov(code, dest, R11);
+    } else {
+        gen_mov(code, R11, src1);
+        gen_mov(code, R10, RCX);
+        gen_mov(code, RCX, src2);
+        gen_rr(code, REX_W, &[0xd3], ext, R11);
+        gen_mov(code, RCX, R10);
+        gen_mov(code, dest, R11);
+    }
+    Ok(())
+}
+
+/// Multiplications and divisions that use rdx:rax.
+#[derive(Clone, Copy, PartialEq)]
+enum RdxRax {
+    UMul,
+    SMul,
+    UDiv,
+    SDiv,
+}
+
+/// Division, see `gen_rdx_rax`.
+fn gen_div(code: &mut Vec<u8>, signed: bool, dest: &R, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    let op = if signed { RdxRax::SDiv } else { RdxRax::UDiv };
+    gen_rdx_rax(code, op, Some(dest), None, src1, src2, i)
+}
+
+/// Multiply or divide, with the low half or quotient going to `lo` and the
+/// high half or remainder to `hi`. rdx and rax are preserved unless they are results.
+/// Division by zero and i64::MIN / -1 are handled without trapping.
+fn gen_rdx_rax(code: &mut Vec<u8>, op: RdxRax, lo: Option<&R>, hi: Option<&R>, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    let (src1, src2) = (src1.to_x86(i)?, src2.to_x86(i)?);
+    let lo = lo.map(|r| r.to_x86(i)).transpose()?;
+    let hi = hi.map(|r| r.to_x86(i)).transpose()?;
+    if lo.is_some() && lo == hi {
+        return Err(Error::InvalidRegisterNumber(i.clone()));
+    }
+    let saved = |r| match r {
+        RAX => R10,
+        RDX => R11,
+        r => r,
+    };
+    gen_mov(code, R10, RAX);
+    gen_mov(code, R11, RDX);
+    gen_mov(code, RAX, saved(src1));
+    let src2 = saved(src2);
+    match op {
+        // 48F7E1            mul rcx
+        RdxRax::UMul => gen_rr(code, REX_W, &[0xf7], 4, src2),
+        // 48F7E9            imul rcx
+        RdxRax::SMul => gen_rr(code, REX_W, &[0xf7], 5, src2),
+        RdxRax::UDiv => {
+            // 31D2              xor edx, edx
+            // 4885C9            test rcx, rcx
+            // 7504              jne div
+            // 4892              xchg rax, rdx
+            // EB03              jmp done
+            // 48F7F1      div:  div rcx
+            code.extend([0x31, 0xd2]);
+            gen_rr(code, REX_W, &[0x85], src2, src2);
+            let div = jump8(code, 0x75);
+            code.extend([0x48, 0x92]);
+            let done = jump8(code, 0xeb);
+            land8(code, div);
+            gen_rr(code, REX_W, &[0xf7], 6, src2);
+            land8(code, done);
+        }
+        RdxRax::SDiv => {
+            // 4899              cqo
+            // 4885C9            test rcx, rcx
+            // 7412              je zero
+            // 4883F9FF          cmp rcx, -1
+            // 7405              je neg
+            // 48F7F9            idiv rcx
+            // EB0C              jmp done
+            // 48F7D8      neg:  neg rax
+            // 31D2              xor edx, edx
+            // EB05              jmp done
+            // 4889C2      zero: mov rdx, rax
+            // 31C0              xor eax, eax
+            code.extend([0x48, 0x99]);
+            gen_rr(code, REX_W, &[0x85], src2, src2);
+            let zero = jump8(code, 0x74);
+            gen_rr(code, REX_W, &[0x83], 7, src2);
+            code.push(0xff);
+            let neg = jump8(code, 0x74);
+            gen_rr(code, REX_W, &[0xf7], 7, src2);
+            let done1 = jump8(code, 0xeb);
+            land8(code, neg);
+            code.extend([0x48, 0xf7, 0xd8, 0x31, 0xd2]);
+            let done2 = jump8(code, 0xeb);
+            land8(code, zero);
+            gen_mov(code, RDX, RAX);
+            code.extend([0x31, 0xc0]);
+            land8(code, done1);
+            land8(code, done2);
+        }
+    }
+
+    // Move the results out of rax and rdx, then restore whichever of those is not a result.
+    if lo == Some(RDX) && hi == Some(RAX) {
+        code.extend([0x48, 0x92]);
+    } else if hi == Some(RAX) {
+        if let Some(lo) = lo {
+            gen_mov(code, lo, RAX);
+        }
+        gen_mov(code, RAX, RDX);
+        gen_mov(code, RDX, R11);
+    } else if lo == Some(RDX) {
+        if let Some(hi) = hi {
+            gen_mov(code, hi, RDX);
+        }
+        gen_mov(code, RDX, RAX);
+        gen_mov(code, RAX, R10);
+    } else {
+        if let Some(lo) = lo {
+            gen_mov(code, lo, RAX);
+        }
+        if let Some(hi) = hi {
+            gen_mov(code, hi, RDX);
+        }
+        if lo != Some(RAX) {
+            gen_mov(code, RAX, R10);
+        }
+        if hi != Some(RDX) {
+            gen_mov(code, RDX, R11);
+        }
+    }
+    Ok(())
+}
+
+/// A short jump to be landed later, returns its location.
+fn jump8(code: &mut Vec<u8>, opcode: u8) -> usize {
+    code.extend([opcode, 0]);
+    code.len()
+}
+
+/// Land a short jump here.
+fn land8(code: &mut Vec<u8>, loc: usize) {
+    code[loc - 1] = (code.len() - loc) as u8;
+}
+
+/// `ext` is the opcode extension of not or neg.
+/// 48F7D0            not rax
+fn gen_unary(code: &mut Vec<u8>, ext: u8, dest: &R, src: &R, i: &Ins) -> Result<(), Error> {
+    let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+    gen_mov(code, dest, src);
+    gen_rr(code, REX_W, &[0xf7], ext, dest);
+    Ok(())
+}
+
+/// Pick the shortest encoding, mov r32 zero extends.
+fn gen_movi(code: &mut Vec<u8>, dest: &R, imm: &u64, i: &Ins) -> Result<(), Error> {
+    let dest = dest.to_x86(i)?;
+    if let Ok(imm) = u32::try_from(*imm) {
+        // B801000000        mov eax, 1
+        emit_rex(code, 0, 0, dest);
+        code.push(0xb8 | dest & 7);
+        code.extend(imm.to_le_bytes());
+    } else if let Ok(imm) = i32::try_from(*imm as i64) {
+        // 48C7C0FFFFFFFF    mov rax, -1
+        gen_rr(code, REX_W, &[0xc7], 0, dest);
+        code.extend(imm.to_le_bytes());
+    } else {
+        // 48B8...           movabs rax, imm64
+        emit_rex(code, REX_W, 0, dest);
+        code.push(0xb8 | dest & 7);
+        code.extend(imm.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// 4839C8            cmp rax, rcx
+fn gen_cmp(code: &mut Vec<u8>, src1: &R, src2: &R, i: &Ins) -> Result<(), Error> {
+    gen_rr(code, REX_W, &[0x39], src2.to_x86(i)?, src1.to_x86(i)?);
+    Ok(())
+}
+
+/// The immediate is sign extended to 64 bits.
+fn gen_cmpi(code: &mut Vec<u8>, src: &R, imm: &u64, i: &Ins) -> Result<(), Error> {
+    let src = src.to_x86(i)?;
+    if let Ok(imm) = i8::try_from(*imm as i64) {
+        // 4883F812          cmp rax, 0x12
+        gen_rr(code, REX_W, &[0x83], 7, src);
+        code.push(imm as u8);
+    } else if let Ok(imm) = i32::try_from(*imm as i64) {
+        // 4881F834120000    cmp rax, 0x1234
+        gen_rr(code, REX_W, &[0x81], 7, src);
+        code.extend(imm.to_le_bytes());
+    } else {
+        // 49BB...           movabs r11, imm64
+        // 4C39D8            cmp rax, r11
+        gen_movi(code, &R(R11 as u16), imm, i)?;
+        gen_rr(code, REX_W, &[0x39], R11, src);
+    }
+    Ok(())
+}
+
+/// Emit a VEX prefix, `vvvv` is the extra source register.
+fn emit_vex(code: &mut Vec<u8>, op: Op, l: bool, reg: u8, vvvv: u8, base: u8) {
+    let pp = match op.prefix {
+        0x66 => 1,
+        0xf3 => 2,
+        0xf2 => 3,
+        _ => 0,
+    };
+    let r = (!reg >> 3 & 1) << 7;
+    let b = (!base >> 3 & 1) << 5;
+    let wvlpp = (op.w as u8) << 7 | (!vvvv & 15) << 3 | (l as u8) << 2 | pp;
+    if op.map == 1 && !op.w && base < 8 {
+        // C5 RvvvvLpp
+        code.extend([0xc5, r | wvlpp]);
+    } else {
+        // C4 RXBmmmmm WvvvvLpp
+        code.extend([0xc4, r | 0x40 | b | op.map, wvlpp]);
+    }
+}
+
+/// Emit a legacy SSE prefix, REX and opcode.
+fn emit_sse(code: &mut Vec<u8>, op: Op, reg: u8, base: u8) {
+    if op.prefix != 0 {
+        code.push(op.prefix);
+    }
+    emit_rex(code, 0, reg, base);
+    match op.map {
+        2 => code.extend([0x0f, 0x38]),
+        3 => code.extend([0x0f, 0x3a]),
+        _ => code.push(0x0f),
+    }
+    code.push(op.opcode);
+}
+
+/// `op reg, vvvv, rm` on registers, SSE when possible, otherwise VEX.
+/// SSE has no `vvvv` operand so `reg` must equal `vvvv` if `l` is false.
+fn emit_vop(code: &mut Vec<u8>, op: Op, l: bool, reg: u8, vvvv: u8, rm: u8) {
+    if l || op.vex {
+        emit_vex(code, op, l, reg, vvvv, rm);
+        code.push(op.opcode);
+    } else {
+        emit_sse(code, op, reg, rm);
+    }
+    code.push(0xc0 | (reg & 7) << 3 | rm & 7);
+}
+
+/// 0F28C1            movaps xmm0, xmm1
+/// C5FC28C1          vmovaps ymm0, ymm1
+fn vmov(code: &mut Vec<u8>, l: bool, dest: u8, src: u8) {
+    if dest != src {
+        emit_vop(code, vector::MOVAPS, l, dest, 0, src);
+    }
+}
+
+/// Two operand vector instruction, `op dest, src`.
+fn vgen2(code: &mut Vec<u8>, l: bool, op: Op, dest: &V, src: &V, i: &Ins) -> Result<(), Error> {
+    emit_vop(code, op, l, dest.to_x86(i)?, 0, src.to_x86(i)?);
+    Ok(())
+}
+
+/// Three operand vector instruction, SSE needs a copy or the scratch register
+/// unless `dest` is the first source.
+fn vgen3(code: &mut Vec<u8>, l: bool, op: Op, dest: &V, src1: &V, src2: &V, commutative: bool, i: &Ins) -> Result<(), Error> {
+    let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+    vop3(code, l, op, dest, src1, src2, commutative);
+    Ok(())
+}
+
+fn vop3(code: &mut Vec<u8>, l: bool, op: Op, dest: u8, src1: u8, src2: u8, commutative: bool) {
+    if l || op.vex || dest == src1 {
+        emit_vop(code, op, l, dest, src1, src2);
+    } else if dest == src2 && commutative {
+        emit_vop(code, op, l, dest, dest, src1);
+    } else if dest == src2 {
+        vmov(code, l, XMM15, src2);
+        vmov(code, l, dest, src1);
+        emit_vop(code, op, l, dest, dest, XMM15);
+    } else {
+        vmov(code, l, dest, src1);
+        emit_vop(code, op, l, dest, dest, src2);
+    }
+}
+
+/// Shift by immediate, `ext` is the opcode extension of the 71, 72 or 73 group.
+/// 660F73F03F        psllq xmm0, 63
+fn vshifti(code: &mut Vec<u8>, l: bool, op: Op, ext: u8, dest: u8, src: u8, imm: u8) {
+    if l {
+        emit_vex(code, op, l, ext, dest, src);
+        code.push(op.opcode);
+        code.push(0xc0 | ext << 3 | src & 7);
+    } else {
+        vmov(code, l, dest, src);
+        emit_sse(code, op, ext, dest);
+        code.push(0xc0 | ext << 3 | dest & 7);
+    }
+    code.push(imm);
+}
+
+/// Load or store `[base + disp]`.
+fn vgenmem(code: &mut Vec<u8>, l: bool, op: Op, v: &V, r: &R, imm: &i32, i: &Ins) -> Result<(), Error> {
+    let (v, r) = (v.to_x86(i)?, r.to_x86(i)?);
+    if l {
+        emit_vex(code, op, l, v, 0, r);
+        code.push(op.opcode);
+    } else {
+        emit_sse(code, op, v, r);
+    }
+    emit_modrm_mem(code, v, r, *imm);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Features;
+    use crate::*;
+
+    /// Every optional instruction set, so that encodings do not depend on the host.
+    const ALL: Features = Features { lzcnt: true, bmi1: true, popcnt: true, fma: true, f16c: true, sse4_1: true, sse4_2: true, avx2: true };
+
+    /// Only SSE2, which every x86_64 processor has.
+    const SSE2: Features = Features { lzcnt: false, bmi1: false, popcnt: false, fma: false, f16c: false, sse4_1: false, sse4_2: false, avx2: false };
+
+    fn compile_for(ins: &[Ins], features: Features) -> Result<Executable, Error> {
+        let (code, labels) = Executable::compile_with(ins, features)?;
+        Executable::new(&code, labels)
+    }
+
+    #[test]
+    fn basic() {
+        use Ins::*;
+        {
+            // 488D05F9FFFFFF    lea rax, [rip - 7]
+            // 488D05F2FFFFFF    lea rax, [rip - 14]
+            // 488D0D00000000    lea rcx, [rip]
+            // 4C8D05F9FFFFFF    lea r8, [rip - 7]
+            let prog = Executable::from_ir(&[
+                Label(0),
+                Addr(R(0), 0),
+                Addr(R(0), 0),
+                Addr(R(1), 1),
+                Label(1),
+                Addr(R(8), 1),
+                Ret,
+            ])
+            .unwrap();
+            assert_eq!(prog.fmt_8(), "48 8d 05 f9 ff ff ff 48 8d 05 f2 ff ff ff 48 8d 0d 00 00 00 00 4c 8d 05 f9 ff ff ff c3");
+        }
+        {
+            // 0F8406000000      je l4
+            // 0F8500000000      jne l4
+            // 7FFE              jg l4
+            // 7DFC              jge l4
+            // EBFA              jmp l4
+            use Cond::*;
+            let prog = Executable::from_ir(&[
+                B(Eq, 4),
+                B(Ne, 4),
+                Label(4),
+                B(Sgt, 4),
+                B(Sge, 4),
+                J(4),
+                Ret,
+            ])
+            .unwrap();
+            assert_eq!(prog.fmt_8(), "0f 84 06 00 00 00 0f 85 00 00 00 00 7f fe 7d fc eb fa c3");
+        }
+        {
+            // 4885C0            test rax, rax
+            // 74FB              je l0
+            // 490FBAE13F        bt r9, 63
+            // 0F8209000000      jb l1
+            // 4839D1            cmp rcx, rdx
+            // 0F8C00000000      jl l1
+            use Cond::*;
+            let prog = Executable::from_ir(&[
+                Label(0),
+                Cbz(R(0), 0),
+                Tbnz(R(9), 63, 1),
+                Bcmp(Slt, R(1), R(2), 1),
+                Label(1),
+                Ret,
+            ])
+            .unwrap();
+            assert_eq!(prog.fmt_8(), "48 85 c0 74 fb 49 0f ba e1 3f 0f 82 09 00 00 00 48 39 d1 0f 8c 00 00 00 00 c3");
+        }
+    }
+
+    #[test]
+    fn arith() {
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Add(R(0), R(0), R(1)),
+            Add(R(0), R(1), R(0)),
+            Add(R(0), R(1), R(2)),
+            Sub(R(8), R(9), R(8)),
+            Mul(R(0), R(1), R(0)),
+            Shl(R(0), R(0), R(1)),
+            Shr(R(2), R(3), R(1)),
+            Sar(R(0), R(1), R(2)),
+            Not(R(15), R(15)),
+            Neg(R(0), R(7)),
+            Movi(R(0), 1),
+            Movi(R(9), !0),
+            Movi(R(0), 0x123456789),
+            Cmp(R(0), R(1)),
+            Cmpi(R(12), 0x12),
+            Cmpi(R(0), 0x1234),
+            Mov(R(0), R(13)),
+            Ret,
+        ])
+        .unwrap();
+        // https://shell-storm.org/online/Online-Assembler-and-Disassembler/?arch=x86-64
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "48 01 c8",                               // add rax, rcx
+                "48 01 c8",                               // add rax, rcx
+                "48 89 c8 48 01 d0",                      // mov rax, rcx; add rax, rdx
+                "4d 89 c3 4d 89 c8 4d 29 d8",             // mov r11, r8; mov r8, r9; sub r8, r11
+                "48 0f af c1",                            // imul rax, rcx
+                "48 d3 e0",                               // shl rax, cl
+                "49 89 db 49 d3 eb 4c 89 da",             // mov r11, rbx; shr r11, cl; mov rdx, r11
+                "49 89 cb 49 89 ca 48 89 d1 49 d3 fb 4c 89 d1 4c 89 d8", // sar via rcx
+                "49 f7 d7",                               // not r15
+                "48 89 f8 48 f7 d8",                      // mov rax, rdi; neg rax
+                "b8 01 00 00 00",                         // mov eax, 1
+                "49 c7 c1 ff ff ff ff",                   // mov r9, -1
+                "48 b8 89 67 45 23 01 00 00 00",          // movabs rax, 0x123456789
+                "48 39 c8",                               // cmp rax, rcx
+                "49 83 fc 12",                            // cmp r12, 0x12
+                "48 81 f8 34 12 00 00",                   // cmp rax, 0x1234
+                "4c 89 e8",                               // mov rax, r13
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn load_store() {
+        use Ins::*;
+        use Type::*;
+        let prog = Executable::from_ir(&[
+            Ld(U8, R(0), R(1), 0),
+            Ld(U16, R(0), R(4), 8),
+            Ld(U32, R(0), R(5), 0),
+            Ld(U64, R(8), R(12), 0x1000),
+            Ld(S8, R(0), R(13), -8),
+            Ld(S16, R(0), R(1), 0),
+            Ld(S32, R(0), R(1), 0),
+            St(U8, R(7), R(4), 6),
+            St(U16, R(0), R(1), 0),
+            St(U32, R(9), R(1), 0),
+            St(U64, R(0), R(1), -0x1000),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "0f b6 01",                // movzx eax, byte ptr [rcx]
+                "0f b7 44 24 08",          // movzx eax, word ptr [rsp + 8]
+                "8b 45 00",                // mov eax, dword ptr [rbp]
+                "4d 8b 84 24 00 10 00 00", // mov r8, qword ptr [r12 + 0x1000]
+                "49 0f be 45 f8",          // movsx rax, byte ptr [r13 - 8]
+                "48 0f bf 01",             // movsx rax, word ptr [rcx]
+                "48 63 01",                // movsxd rax, dword ptr [rcx]
+                "40 88 7c 24 06",          // mov byte ptr [rsp + 6], dil
+                "66 89 01",                // mov word ptr [rcx], ax
+                "44 89 09",                // mov dword ptr [rcx], r9d
+                "48 89 81 00 f0 ff ff",    // mov qword ptr [rcx - 0x1000], rax
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn arith_imm() {
+        use Ins::*;
+        let prog = Executable::from_ir(&[
+            Addi(R(0), R(0), 0x12),
+            Subi(R(0), R(1), 0x1234),
+            Andi(R(8), R(8), 0xffff_ffff),
+            Xori(R(1), R(1), !0),
+            Shli(R(0), R(0), 4),
+            Sari(R(9), R(2), 65),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "48 83 c0 12",                // add rax, 0x12
+                "48 89 c8 48 81 e8 34 12 00 00", // mov rax, rcx; sub rax, 0x1234
+                "41 bb ff ff ff ff 4d 21 d8", // mov r11d, 0xffffffff; and r8, r11
+                "48 83 f1 ff",                // xor rcx, -1
+                "48 c1 e0 04",                // shl rax, 4
+                "49 89 d1 49 c1 f9 01",       // mov r9, rdx; sar r9, 1
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn float() {
+        use Ins::*;
+        use Type::*;
+        let (code, _) = Executable::compile_with(
+            &[
+                Fadd(F32, V(0), V(1), V(2)),
+                Fsub(F64, V(9), V(9), V(2)),
+                Fmin(F64, V(0), V(1), V(2)),
+                Fabs(F32, V(0), V(1)),
+                Fma(F64, V(0), V(1), V(2), V(3)),
+                Fma(F32, V(1), V(1), V(2), V(3)),
+                Fcmp(F64, V(1), V(2)),
+                Fcvt(F32, F16, V(0), V(1)),
+                Fcvt(F16, F32, V(0), V(1)),
+                Scvtf(F64, V(0), R(9)),
+                Fcvtzs(F32, R(0), V(1)),
+                Ret,
+            ],
+            ALL,
+        )
+        .unwrap();
+        let prog = Executable::new(&code, Vec::new()).unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "0f 28 c1 f3 0f 58 c2",                   // movaps xmm0, xmm1; addss xmm0, xmm2
+                "f2 44 0f 5c ca",                         // subsd xmm9, xmm2
+                "44 0f 28 f1 f2 44 0f 5d f2",             // movaps xmm14, xmm1; minsd xmm14, xmm2
+                "44 0f 28 f9 f2 44 0f c2 f9 03",          // movaps xmm15, xmm1; cmpunordsd xmm15, xmm1
+                "44 0f 54 f9 45 0f 56 f7 41 0f 28 c6",    // andps xmm15, xmm1; orps xmm14, xmm15; movaps xmm0, xmm14
+                "66 45 0f 76 ff 66 41 0f 72 d7 01",       // pcmpeqd xmm15, xmm15; psrld xmm15, 1
+                "0f 28 c1 41 0f 54 c7",                   // movaps xmm0, xmm1; andps xmm0, xmm15
+                "0f 28 c3 c4 e2 f1 b9 c2",                // movaps xmm0, xmm3; vfmadd231sd xmm0, xmm1, xmm2
+                "c4 e2 69 a9 cb",                         // vfmadd213ss xmm1, xmm2, xmm3
+                "66 0f 2e ca",                            // ucomisd xmm1, xmm2
+                "c4 e2 79 13 c1",                         // vcvtph2ps xmm0, xmm1
+                "c4 e3 79 1d c8 04",                      // vcvtps2ph xmm0, xmm1, 4
+                "0f 57 c0 f2 49 0f 2a c1",                // xorps xmm0, xmm0; cvtsi2sd xmm0, r9
+                "f3 48 0f 2c c1 48 83 f8 01 71 13",       // cvttss2si rax, xmm1; cmp rax, 1; jno done
+                "45 0f 57 ff 41 0f 2e cf 7a 07 76 07",    // xorps xmm15, xmm15; ucomiss xmm1, xmm15; jp nan; jbe done
+                "48 f7 d0 eb 02 31 c0",                   // not rax; jmp done; nan: xor eax, eax
+                "c3",
+            ]
+            .join(" ")
+        );
+
+        for ins in [Fma(F64, V(0), V(1), V(2), V(3)), Fcvt(F32, F16, V(0), V(1)), Fcvt(F16, F64, V(0), V(1))] {
+            let res = Executable::compile_with(&[ins.clone(), Ret], SSE2);
+            assert_eq!(res.unwrap_err(), Error::UnsupportedOperation(ins));
+        }
+    }
+
+    #[test]
+    fn lane_moves() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = compile_for(
+            &[
+                VmovFromR(U8, V(0), R(1), 14),
+                VmovFromR(U16, V(9), R(10), 3),
+                VmovFromR(F64, V(0), R(1), 1),
+                VmovToR(S8, R(0), V(1), 2),
+                VmovToR(U64, R(9), V(1), 1),
+                Vdup(U16, V256, V(0), R(1)),
+                Vdup(U64, V64, V(0), R(1)),
+                Vdup(U8, V128, V(0), R(1)),
+                Ret,
+            ],
+            ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "66 0f 3a 20 c1 0e",             // pinsrb xmm0, ecx, 14
+                "66 45 0f c4 ca 03",             // pinsrw xmm9, r10d, 3
+                "66 48 0f 3a 22 c1 01",          // pinsrq xmm0, rcx, 1
+                "66 0f 3a 14 c8 02 48 0f be c0", // pextrb eax, xmm1, 2; movsx rax, al
+                "66 49 0f 3a 16 c9 01",          // pextrq r9, xmm1, 1
+                "66 0f 6e c1 c4 e2 7d 79 c0",    // movd xmm0, ecx; vpbroadcastw ymm0, xmm0
+                "66 48 0f 6e c1",                // movq xmm0, rcx
+                "66 0f 6e c1 66 0f 60 c0",       // movd xmm0, ecx; punpcklbw xmm0, xmm0
+                "f2 0f 70 c0 00 66 0f 70 c0 00", // pshuflw xmm0, xmm0, 0; pshufd xmm0, xmm0, 0
+                "c5 f8 77",                      // vzeroupper
+                "c3",
+            ]
+            .join(" ")
+        );
+
+        // Without SSE4.1 only pinsrw remains and Vdup shuffles instead of broadcasting.
+        let prog = compile_for(
+            &[
+                VmovFromR(U16, V(9), R(10), 3),
+                Vdup(U8, V32, V(0), R(1)),
+                Vdup(U16, V64, V(0), R(1)),
+                Vdup(U32, V128, V(0), R(1)),
+                Vdup(U64, V128, V(9), R(1)),
+                Ret,
+            ],
+            SSE2,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "66 45 0f c4 ca 03", // pinsrw xmm9, r10d, 3
+                "66 0f 6e c1",       // movd xmm0, ecx
+                "66 0f 60 c0",       // punpcklbw xmm0, xmm0
+                "f2 0f 70 c0 00",    // pshuflw xmm0, xmm0, 0
+                "66 0f 6e c1",       // movd xmm0, ecx
+                "f2 0f 70 c0 00",    // pshuflw xmm0, xmm0, 0
+                "66 0f 6e c1",       // movd xmm0, ecx
+                "66 0f 70 c0 00",    // pshufd xmm0, xmm0, 0
+                "66 4c 0f 6e c9",    // movq xmm9, rcx
+                "66 45 0f 70 c9 44", // pshufd xmm9, xmm9, 68
+                "c3",
+            ]
+            .join(" ")
+        );
+        for ins in [VmovFromR(U8, V(0), R(1), 14), VmovToR(S8, R(0), V(1), 2), Vdup(U16, V256, V(0), R(1))] {
+            assert_eq!(compile_for(&[ins.clone(), Ret], SSE2).unwrap_err(), Error::UnsupportedOperation(ins));
+        }
+    }
+
+    #[test]
+    fn vector_select() {
+        use Cond::*;
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = compile_for(
+            &[
+                Vcmp(Eq, U8, V128, V(0), V(1), V(2)),
+                Vcmp(Sge, S32, V128, V(0), V(1), V(2)),
+                Vcmp(Ult, U16, V256, V(0), V(1), V(2)),
+                Vcmp(Ugt, U64, V128, V(0), V(1), V(2)),
+                Vcmp(Slt, F32, V128, V(0), V(1), V(2)),
+                Vcmp(Eq, F64, V256, V(0), V(1), V(2)),
+                Vbsl(U8, V128, V(0), V(1), V(2), V(3)),
+                Vblend(U8, V128, V(0), V(1), V(2), V(3)),
+                Vblend(S16, V256, V(0), V(1), V(2), V(3)),
+                Vblend(F64, V128, V(0), V(9), V(2), V(3)),
+                Vmin(S8, V128, V(0), V(1), V(2)),
+                Vmax(U32, V256, V(0), V(1), V(2)),
+                Vmin(F32, V128, V(0), V(1), V(2)),
+                Vabs(S16, V128, V(0), V(1)),
+                Vabs(S64, V128, V(0), V(1)),
+                Vabs(F64, V128, V(0), V(1)),
+                Ret,
+            ],
+            ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "0f 28 c1",           // movaps xmm0, xmm1
+                "66 0f 74 c2",        // pcmpeqb xmm0, xmm2
+                "0f 28 c2",           // movaps xmm0, xmm2
+                "66 0f 66 c1",        // pcmpgtd xmm0, xmm1
+                "66 45 0f 76 ff",     // pcmpeqd xmm15, xmm15
+                "66 41 0f ef c7",     // pxor xmm0, xmm15
+                "c4 62 75 3e fa",     // vpmaxuw ymm15, ymm1, ymm2
+                "c5 85 75 c1",        // vpcmpeqw ymm0, ymm15, ymm1
+                "c4 41 05 76 ff",     // vpcmpeqd ymm15, ymm15, ymm15
+                "c4 c1 7d ef c7",     // vpxor ymm0, ymm0, ymm15
+                "66 45 0f 76 ff",     // pcmpeqd xmm15, xmm15
+                "66 41 0f 73 f7 3f",  // psllq xmm15, 63
+                "44 0f 28 f2",        // movaps xmm14, xmm2
+                "66 45 0f ef f7",     // pxor xmm14, xmm15
+                "66 44 0f ef f9",     // pxor xmm15, xmm1
+                "41 0f 28 c7",        // movaps xmm0, xmm15
+                "66 41 0f 38 37 c6",  // pcmpgtq xmm0, xmm14
+                "0f 28 c2",           // movaps xmm0, xmm2
+                "0f c2 c1 06",        // cmpnleps xmm0, xmm1
+                "c5 f5 c2 c2 00",     // vcmpeqpd ymm0, ymm1, ymm2
+                "44 0f 28 f9",        // movaps xmm15, xmm1
+                "66 44 0f db fa",     // pand xmm15, xmm2
+                "44 0f 28 f1",        // movaps xmm14, xmm1
+                "66 44 0f df f3",     // pandn xmm14, xmm3
+                "41 0f 28 c6",        // movaps xmm0, xmm14
+                "66 41 0f eb c7",     // por xmm0, xmm15
+                "c4 e3 61 4c c2 10",  // vpblendvb xmm0, xmm3, xmm2, xmm1
+                "c5 85 71 e1 0f",     // vpsraw ymm15, ymm1, 15
+                "c4 e3 65 4c c2 f0",  // vpblendvb ymm0, ymm3, ymm2, ymm15
+                "c4 e3 61 4b c2 90",  // vblendvpd xmm0, xmm3, xmm2, xmm9
+                "0f 28 c1",           // movaps xmm0, xmm1
+                "66 0f 38 38 c2",     // pminsb xmm0, xmm2
+                "c4 e2 75 3f c2",     // vpmaxud ymm0, ymm1, ymm2
+                "44 0f 28 f1",        // movaps xmm14, xmm1
+                "44 0f 5d f2",        // minps xmm14, xmm2
+                "44 0f 28 f9",        // movaps xmm15, xmm1
+                "44 0f c2 f9 03",     // cmpunordps xmm15, xmm1
+                "44 0f 54 f9",        // andps xmm15, xmm1
+                "45 0f 56 f7",        // orps xmm14, xmm15
+                "41 0f 28 c6",        // movaps xmm0, xmm14
+                "66 0f 38 1d c1",     // pabsw xmm0, xmm1
+                "44 0f 28 f9",        // movaps xmm15, xmm1
+                "66 41 0f 72 e7 1f",  // psrad xmm15, 31
+                "66 45 0f 70 ff f5",  // pshufd xmm15, xmm15, 245
+                "0f 28 c1",           // movaps xmm0, xmm1
+                "66 41 0f ef c7",     // pxor xmm0, xmm15
+                "66 41 0f fb c7",     // psubq xmm0, xmm15
+                "66 45 0f 76 ff",     // pcmpeqd xmm15, xmm15
+                "66 41 0f 73 d7 01",  // psrlq xmm15, 1
+                "0f 28 c1",           // movaps xmm0, xmm1
+                "41 0f 54 c7",        // andps xmm0, xmm15
+                "c5 f8 77",           // vzeroupper
+                "c3",
+            ]
+            .join(" ")
+        );
+        assert!(compile_for(&[Vmin(U64, V128, V(0), V(1), V(2))], ALL).is_err());
+        assert!(compile_for(&[Vcmp(Vs, F32, V128, V(0), V(1), V(2))], ALL).is_err());
+
+        // Without AVX Vblend spreads the top bit of each mask lane and selects with pand, pandn and por.
+        let prog = compile_for(
+            &[
+                Vcmp(Ugt, U8, V128, V(0), V(1), V(2)),
+                Vblend(U8, V128, V(0), V(1), V(2), V(3)),
+                Vblend(S16, V128, V(0), V(1), V(2), V(3)),
+                Vblend(F32, V64, V(0), V(1), V(2), V(3)),
+                Vblend(F64, V128, V(0), V(9), V(2), V(3)),
+                Vmin(S16, V128, V(0), V(1), V(2)),
+                Vmax(U8, V128, V(0), V(1), V(2)),
+                Ret,
+            ],
+            SSE2,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 44 0f da fa",    // pminub xmm15, xmm2
+                "41 0f 28 c7",       // movaps xmm0, xmm15
+                "66 0f 74 c1",       // pcmpeqb xmm0, xmm1
+                "66 45 0f 76 ff",    // pcmpeqd xmm15, xmm15
+                "66 41 0f ef c7",    // pxor xmm0, xmm15
+                "66 45 0f ef ff",    // pxor xmm15, xmm15
+                "66 44 0f 64 f9",    // pcmpgtb xmm15, xmm1
+                "45 0f 28 f7",       // movaps xmm14, xmm15
+                "66 44 0f df f3",    // pandn xmm14, xmm3
+                "66 44 0f db fa",    // pand xmm15, xmm2
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f eb c7",    // por xmm0, xmm15
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 41 0f 71 e7 0f", // psraw xmm15, 15
+                "45 0f 28 f7",       // movaps xmm14, xmm15
+                "66 44 0f df f3",    // pandn xmm14, xmm3
+                "66 44 0f db fa",    // pand xmm15, xmm2
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f eb c7",    // por xmm0, xmm15
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 41 0f 72 e7 1f", // psrad xmm15, 31
+                "45 0f 28 f7",       // movaps xmm14, xmm15
+                "66 44 0f df f3",    // pandn xmm14, xmm3
+                "66 44 0f db fa",    // pand xmm15, xmm2
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f eb c7",    // por xmm0, xmm15
+                "45 0f 28 f9",       // movaps xmm15, xmm9
+                "66 41 0f 72 e7 1f", // psrad xmm15, 31
+                "66 45 0f 70 ff f5", // pshufd xmm15, xmm15, 245
+                "45 0f 28 f7",       // movaps xmm14, xmm15
+                "66 44 0f df f3",    // pandn xmm14, xmm3
+                "66 44 0f db fa",    // pand xmm15, xmm2
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f eb c7",    // por xmm0, xmm15
+                "0f 28 c1",          // movaps xmm0, xmm1
+                "66 0f ea c2",       // pminsw xmm0, xmm2
+                "0f 28 c1",          // movaps xmm0, xmm1
+                "66 0f de c2",       // pmaxub xmm0, xmm2
+                "c3",
+            ]
+            .join(" ")
+        );
+        for ins in [
+            Vcmp(Eq, U64, V128, V(0), V(1), V(2)),
+            Vcmp(Sgt, S64, V128, V(0), V(1), V(2)),
+            Vcmp(Ult, U32, V128, V(0), V(1), V(2)),
+            Vmin(S32, V128, V(0), V(1), V(2)),
+            Vabs(S8, V128, V(0), V(1)),
+            Vblend(S16, V256, V(0), V(1), V(2), V(3)),
+        ] {
+            assert_eq!(compile_for(&[ins.clone(), Ret], SSE2).unwrap_err(), Error::UnsupportedOperation(ins));
+        }
+    }
+
+    #[test]
+    fn permute() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = compile_for(
+            &[
+                Vtbl(V128, V(0), V(1), V(2)),
+                Vzip(U32, V256, V(0), V(1), V(2), 0),
+                Vuzp(U8, V128, V(0), V(1), V(2), 0),
+                Vtrn(U16, V128, V(0), V(1), V(2), 1),
+                Vext(U32, V128, V(0), V(1), V(2), 1),
+                Vext(U64, V256, V(0), V(1), V(2), 3),
+                Vrev(U8, V128, V(0), V(1)),
+                Vrev(U64, V256, V(0), V(1)),
+                Ret,
+            ],
+            ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "49 bb 70 70 70 70 70 70 70 70",  // movabs r11, 0x7070707070707070
+                "66 4d 0f 6e fb",                 // movq xmm15, r11
+                "66 45 0f 70 ff 44",              // pshufd xmm15, xmm15, 68
+                "66 44 0f dc fa",                 // paddusb xmm15, xmm2
+                "0f 28 c1",                       // movaps xmm0, xmm1
+                "66 41 0f 38 00 c7",              // pshufb xmm0, xmm15
+                "c4 63 fd 00 f1 d8",              // vpermq ymm14, ymm1, 216
+                "c4 63 fd 00 fa d8",              // vpermq ymm15, ymm2, 216
+                "c4 c1 0d 62 c7",                 // vpunpckldq ymm0, ymm14, ymm15
+                "66 45 0f 76 ff",                 // pcmpeqd xmm15, xmm15
+                "66 41 0f 71 d7 08",              // psrlw xmm15, 8
+                "44 0f 28 f1",                    // movaps xmm14, xmm1
+                "66 45 0f db f7",                 // pand xmm14, xmm15
+                "66 44 0f db fa",                 // pand xmm15, xmm2
+                "41 0f 28 c6",                    // movaps xmm0, xmm14
+                "66 41 0f 67 c7",                 // packuswb xmm0, xmm15
+                "44 0f 28 fa",                    // movaps xmm15, xmm2
+                "66 41 0f 72 d7 10",              // psrld xmm15, 16
+                "66 41 0f 72 f7 10",              // pslld xmm15, 16
+                "44 0f 28 f1",                    // movaps xmm14, xmm1
+                "66 41 0f 72 d6 10",              // psrld xmm14, 16
+                "41 0f 28 c6",                    // movaps xmm0, xmm14
+                "66 41 0f eb c7",                 // por xmm0, xmm15
+                "0f 28 c2",                       // movaps xmm0, xmm2
+                "66 0f 3a 0f c1 04",              // palignr xmm0, xmm1, 4
+                "c4 63 75 46 fa 21",              // vperm2i128 ymm15, ymm1, ymm2, 33
+                "c4 c3 6d 0f c7 08",              // vpalignr ymm0, ymm2, ymm15, 8
+                "49 bb 0f 0e 0d 0c 0b 0a 09 08",  // movabs r11, 0x08090a0b0c0d0e0f
+                "66 4d 0f 6e fb",                 // movq xmm15, r11
+                "49 bb 07 06 05 04 03 02 01 00",  // movabs r11, 0x0001020304050607
+                "66 4d 0f 3a 22 fb 01",           // pinsrq xmm15, r11, 1
+                "0f 28 c1",                       // movaps xmm0, xmm1
+                "66 41 0f 38 00 c7",              // pshufb xmm0, xmm15
+                "c4 e3 fd 00 c1 1b",              // vpermq ymm0, ymm1, 27
+                "c5 f8 77",                       // vzeroupper
+                "c3",
+            ]
+            .join(" ")
+        );
+        assert!(compile_for(&[Vuzp(U64, V64, V(0), V(1), V(2), 0)], ALL).is_err());
+        assert!(compile_for(&[Vext(U8, V256, V(0), V(1), V(2), 32)], ALL).is_err());
+
+        // The unpacks, shifts and pshufd are SSE2, pshufb and palignr need SSSE3.
+        let prog = compile_for(
+            &[
+                Vzip(U32, V128, V(0), V(1), V(2), 0),
+                Vuzp(U8, V128, V(0), V(1), V(2), 0),
+                Vtrn(U16, V128, V(0), V(1), V(2), 1),
+                Vext(U8, V64, V(0), V(1), V(2), 3),
+                Vrev(U32, V128, V(0), V(1)),
+                Vrev(U64, V128, V(0), V(1)),
+                Ret,
+            ],
+            SSE2,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "0f 28 c1",          // movaps xmm0, xmm1
+                "66 0f 62 c2",       // punpckldq xmm0, xmm2
+                "66 45 0f 76 ff",    // pcmpeqd xmm15, xmm15
+                "66 41 0f 71 d7 08", // psrlw xmm15, 8
+                "44 0f 28 f1",       // movaps xmm14, xmm1
+                "66 45 0f db f7",    // pand xmm14, xmm15
+                "66 44 0f db fa",    // pand xmm15, xmm2
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f 67 c7",    // packuswb xmm0, xmm15
+                "44 0f 28 fa",       // movaps xmm15, xmm2
+                "66 41 0f 72 d7 10", // psrld xmm15, 16
+                "66 41 0f 72 f7 10", // pslld xmm15, 16
+                "44 0f 28 f1",       // movaps xmm14, xmm1
+                "66 41 0f 72 d6 10", // psrld xmm14, 16
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f eb c7",    // por xmm0, xmm15
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 44 0f 6c fa",    // punpcklqdq xmm15, xmm2
+                "41 0f 28 c7",       // movaps xmm0, xmm15
+                "66 0f 73 d8 03",    // psrldq xmm0, 3
+                "66 0f 70 c1 1b",    // pshufd xmm0, xmm1, 27
+                "66 0f 70 c1 4e",    // pshufd xmm0, xmm1, 78
+                "c3",
+            ]
+            .join(" ")
+        );
+        for ins in [
+            Vtbl(V128, V(0), V(1), V(2)),
+            Vext(U32, V128, V(0), V(1), V(2), 1),
+            Vrev(U8, V128, V(0), V(1)),
+            Vrev(U16, V64, V(0), V(1)),
+            Vzip(U32, V256, V(0), V(1), V(2), 0),
+        ] {
+            assert_eq!(compile_for(&[ins.clone(), Ret], SSE2).unwrap_err(), Error::UnsupportedOperation(ins));
+        }
+    }
+
+    #[test]
+    fn widen_narrow() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = compile_for(
+            &[
+                Vqadd(S8, V128, V(0), V(1), V(2)),
+                Vqsub(U16, V256, V(0), V(1), V(2)),
+                Vaddl(S16, V128, V(0), V(1), V(2)),
+                Vmull(U64, V128, V(0), V(1), V(2)),
+                Vxtn(U16, V128, V(0), V(1)),
+                Vqmovn(S32, V256, V(0), V(1)),
+                Vaddv(U32, V128, V(0), V(1)),
+                Vaddp(U16, V64, V(0), V(1), V(2)),
+                Vaddp(F32, V128, V(0), V(1), V(2)),
+                Vdot(U8, V128, V(0), V(1), V(2)),
+                Ret,
+            ],
+            ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "0f 28 c1",                       // movaps xmm0, xmm1
+                "66 0f ec c2",                    // paddsb xmm0, xmm2
+                "c5 f5 d9 c2",                    // vpsubusw ymm0, ymm1, ymm2
+                "66 44 0f 38 20 f1",              // pmovsxbw xmm14, xmm1
+                "66 44 0f 38 20 fa",              // pmovsxbw xmm15, xmm2
+                "41 0f 28 c6",                    // movaps xmm0, xmm14
+                "66 41 0f fd c7",                 // paddw xmm0, xmm15
+                "66 44 0f 38 35 f1",              // pmovzxdq xmm14, xmm1
+                "66 44 0f 38 35 fa",              // pmovzxdq xmm15, xmm2
+                "41 0f 28 c6",                    // movaps xmm0, xmm14
+                "66 41 0f f4 c7",                 // pmuludq xmm0, xmm15
+                "66 45 0f 76 ff",                 // pcmpeqd xmm15, xmm15
+                "66 41 0f 71 d7 08",              // psrlw xmm15, 8
+                "66 44 0f db f9",                 // pand xmm15, xmm1
+                "41 0f 28 c7",                    // movaps xmm0, xmm15
+                "66 41 0f 67 c7",                 // packuswb xmm0, xmm15
+                "c5 f5 6b c1",                    // vpackssdw ymm0, ymm1, ymm1
+                "c4 e3 fd 00 c0 08",              // vpermq ymm0, ymm0, 8
+                "44 0f 28 f9",                    // movaps xmm15, xmm1
+                "66 41 0f 73 df 08",              // psrldq xmm15, 8
+                "44 0f 28 f1",                    // movaps xmm14, xmm1
+                "66 45 0f fe f7",                 // paddd xmm14, xmm15
+                "45 0f 28 fe",                    // movaps xmm15, xmm14
+                "66 41 0f 73 df 04",              // psrldq xmm15, 4
+                "66 45 0f fe f7",                 // paddd xmm14, xmm15
+                "41 0f 28 c6",                    // movaps xmm0, xmm14
+                "44 0f 28 f1",                    // movaps xmm14, xmm1
+                "66 44 0f 6c f2",                 // punpcklqdq xmm14, xmm2
+                "41 0f 28 c6",                    // movaps xmm0, xmm14
+                "66 41 0f 38 01 c6",              // phaddw xmm0, xmm14
+                "0f 28 c1",                       // movaps xmm0, xmm1
+                "f2 0f 7c c2",                    // haddps xmm0, xmm2
+                "44 0f 28 f1",                    // movaps xmm14, xmm1
+                "66 41 0f 71 f6 08",              // psllw xmm14, 8
+                "66 41 0f 71 d6 08",              // psrlw xmm14, 8
+                "44 0f 28 fa",                    // movaps xmm15, xmm2
+                "66 41 0f 71 f7 08",              // psllw xmm15, 8
+                "66 41 0f 71 d7 08",              // psrlw xmm15, 8
+                "66 45 0f f5 f7",                 // pmaddwd xmm14, xmm15
+                "66 41 0f fe c6",                 // paddd xmm0, xmm14
+                "44 0f 28 f1",                    // movaps xmm14, xmm1
+                "66 41 0f 71 d6 08",              // psrlw xmm14, 8
+                "44 0f 28 fa",                    // movaps xmm15, xmm2
+                "66 41 0f 71 d7 08",              // psrlw xmm15, 8
+                "66 45 0f f5 f7",                 // pmaddwd xmm14, xmm15
+                "66 41 0f fe c6",                 // paddd xmm0, xmm14
+                "c5 f8 77",                       // vzeroupper
+                "c3",
+            ]
+            .join(" ")
+        );
+        assert!(compile_for(&[Vqmovn(U64, V128, V(0), V(1))], ALL).is_err());
+        assert!(compile_for(&[Vdot(S8, V256, V(0), V(1), V(2))], ALL).is_err());
+
+        // Without SSE4.1 there is no pmovsx, pminud, packusdw or phadd. A V64 Vdot into
+        // one of its sources keeps the even sums in r10.
+        let prog = compile_for(
+            &[
+                Vqadd(S8, V128, V(0), V(1), V(2)),
+                Vxtn(U16, V128, V(0), V(1)),
+                Vqmovn(S32, V128, V(0), V(1)),
+                Vaddp(U64, V128, V(0), V(1), V(2)),
+                Vdot(S8, V64, V(0), V(0), V(1)),
+                Ret,
+            ],
+            SSE2,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "0f 28 c1",          // movaps xmm0, xmm1
+                "66 0f ec c2",       // paddsb xmm0, xmm2
+                "66 45 0f 76 ff",    // pcmpeqd xmm15, xmm15
+                "66 41 0f 71 d7 08", // psrlw xmm15, 8
+                "66 44 0f db f9",    // pand xmm15, xmm1
+                "41 0f 28 c7",       // movaps xmm0, xmm15
+                "66 41 0f 67 c7",    // packuswb xmm0, xmm15
+                "0f 28 c1",          // movaps xmm0, xmm1
+                "66 0f 6b c1",       // packssdw xmm0, xmm1
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 44 0f 6d fa",    // punpckhqdq xmm15, xmm2
+                "44 0f 28 f1",       // movaps xmm14, xmm1
+                "66 44 0f 6c f2",    // punpcklqdq xmm14, xmm2
+                "41 0f 28 c6",       // movaps xmm0, xmm14
+                "66 41 0f d4 c7",    // paddq xmm0, xmm15
+                "44 0f 28 f0",       // movaps xmm14, xmm0
+                "66 41 0f 71 f6 08", // psllw xmm14, 8
+                "66 41 0f 71 e6 08", // psraw xmm14, 8
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 41 0f 71 f7 08", // psllw xmm15, 8
+                "66 41 0f 71 e7 08", // psraw xmm15, 8
+                "66 45 0f f5 f7",    // pmaddwd xmm14, xmm15
+                "66 4d 0f 7e f2",    // movq r10, xmm14
+                "44 0f 28 f0",       // movaps xmm14, xmm0
+                "66 41 0f 71 e6 08", // psraw xmm14, 8
+                "44 0f 28 f9",       // movaps xmm15, xmm1
+                "66 41 0f 71 e7 08", // psraw xmm15, 8
+                "66 45 0f f5 f7",    // pmaddwd xmm14, xmm15
+                "66 4d 0f 6e fa",    // movq xmm15, r10
+                "66 45 0f fe f7",    // paddd xmm14, xmm15
+                "66 41 0f fe c6",    // paddd xmm0, xmm14
+                "c3",
+            ]
+            .join(" ")
+        );
+        for ins in [
+            Vaddl(S16, V128, V(0), V(1), V(2)),
+            Vmull(U64, V128, V(0), V(1), V(2)),
+            Vqmovn(U16, V128, V(0), V(1)),
+            Vqmovn(U32, V128, V(0), V(1)),
+            Vaddp(U16, V64, V(0), V(1), V(2)),
+            Vaddp(F32, V128, V(0), V(1), V(2)),
+            Vdot(U8, V128, V(0), V(0), V(1)),
+            Vqsub(U16, V256, V(0), V(1), V(2)),
+        ] {
+            assert_eq!(compile_for(&[ins.clone(), Ret], SSE2).unwrap_err(), Error::UnsupportedOperation(ins));
+        }
+    }
+
+    #[test]
+    fn bits_without_features() {
+        use Ins::*;
+        let features = SSE2;
+        let (code, _) = Executable::compile_with(&[Clz(R(0), R(1)), Ctz(R(2), R(1)), Ret], features).unwrap();
+        let prog = Executable::new(&code, Vec::new()).unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "41 ba 7f 00 00 00 4c 0f bd d9 4d 0f 44 da 49 83 f3 3f 4c 89 d8", // mov r10d, 127; bsr r11, rcx; cmove r11, r10; xor r11, 63; mov rax, r11
+                "41 ba 40 00 00 00 4c 0f bc d9 4d 0f 44 da 4c 89 da",             // mov r10d, 64; bsf r11, rcx; cmove r11, r10; mov rdx, r11
+                "c3",
+            ]
+            .join(" ")
+        );
+
+        // Compare the fallbacks with the CPU, with the result in a new register and in the source.
+        for op in [Clz, Ctz, Popcnt] {
+            for ins in [[op(R(0), R(7)), Ret], [op(R(7), R(7)), Mov(R(0), R(7))]] {
+                let (code, _) = Executable::compile_with(&[ins[0].clone(), ins[1].clone(), Ret], features).unwrap();
+                let prog = Executable::new(&code, Vec::new()).unwrap();
+                for a in [0_u64, 1, 0x8000_0000_0000_0000, 0x0123_4567_89ab_cdef, !0] {
+                    let expected = match op(R(0), R(0)) {
+                        Clz(..) => a.leading_zeros(),
+                        Ctz(..) => a.trailing_zeros(),
+                        _ => a.count_ones(),
+                    };
+                    let (res, _) = unsafe { prog.call(0, &[a]).unwrap() };
+                    assert_eq!(res, expected as u64, "{:?} {a:#x}", ins[0]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vector_without_features() {
+        use Cond::*;
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        use regs::*;
+        let features = Features { lzcnt: true, bmi1: true, popcnt: true, fma: true, f16c: true, sse4_1: false, sse4_2: false, avx2: false };
+        for ins in [
+            Vadd(U8, V256, V(0), V(1), V(2)),
+            Vadd(F32, V256, V(0), V(1), V(2)),
+            Vdup(U16, V256, V(0), R(1)),
+            Vshl(U32, V128, V(0), V(1), V(2)),
+            Vshl(S64, V64, V(0), V(1), V(2)),
+            Vmul(U32, V128, V(0), V(1), V(2)),
+            VmovFromR(U32, V(0), R(1), 1),
+            VmovToR(U16, R(0), V(1), 1),
+            Vcmp(Eq, U64, V128, V(0), V(1), V(2)),
+            Vcmp(Sgt, S64, V128, V(0), V(1), V(2)),
+            Vcmp(Ult, U32, V128, V(0), V(1), V(2)),
+            Vmin(S32, V128, V(0), V(1), V(2)),
+            Vabs(S8, V128, V(0), V(1)),
+            Vtbl(V128, V(0), V(1), V(2)),
+            Vext(U8, V128, V(0), V(1), V(2), 3),
+            Vrev(U8, V128, V(0), V(1)),
+            Vaddl(S16, V128, V(0), V(1), V(2)),
+            Vqmovn(U32, V128, V(0), V(1)),
+            Vqmovn(U16, V128, V(0), V(1)),
+            Vaddp(S16, V128, V(0), V(1), V(2)),
+            Vdot(S8, V128, V(0), V(0), V(1)),
+        ] {
+            let res = Executable::compile_with(&[ins.clone(), Ret], features);
+            assert_eq!(res.unwrap_err(), Error::UnsupportedOperation(ins));
+        }
+
+        // The SSE2 fallbacks still compile.
+        for ins in [
+            Vadd(U8, V128, V(0), V(1), V(2)),
+            Vmul(U16, V128, V(0), V(1), V(2)),
+            VmovFromR(U16, V(0), R(1), 1),
+            Vdup(U8, V128, V(0), R(1)),
+            Vdup(U64, V128, V(0), R(1)),
+            Vcmp(Ugt, U8, V128, V(0), V(1), V(2)),
+            Vcmp(Sgt, S32, V128, V(0), V(1), V(2)),
+            Vmin(U8, V128, V(0), V(1), V(2)),
+            Vrev(U32, V128, V(0), V(1)),
+            Vaddp(U64, V128, V(0), V(1), V(2)),
+            Vdot(S8, V128, V(0), V(1), V(2)),
+        ] {
+            assert!(Executable::compile_with(&[ins.clone(), Ret], features).is_ok(), "{ins:?}");
+        }
+
+        let (code, _) = Executable::compile_with(
+            &[Vld(S64, V128, V(0), ARG[0], 0), Vabs(S64, V128, V(1), V(0)), Vst(S64, V128, V(1), ARG[1], 0), Ret],
+            features,
+        )
+        .unwrap();
+        let prog = Executable::new(&code, Vec::new()).unwrap();
+        let (a, mut res) = ([-5_i64, i64::MAX], [0_i64; 2]);
+        unsafe { prog.call(0, &[a.as_ptr() as u64, res.as_mut_ptr() as u64]).unwrap() };
+        assert_eq!(res, [5, i64::MAX]);
+
+        // Vblend selects with and, andn and or.
+        let a: [u8; 48] = std::array::from_fn(|i| (i * 37 + 11) as u8);
+        for (ty, size) in [(U8, 1), (S16, 2), (F32, 4), (U64, 8)] {
+            let ins = [
+                Vld(U8, V128, V(0), ARG[0], 0),
+                Vld(U8, V128, V(1), ARG[0], 16),
+                Vld(U8, V128, V(2), ARG[0], 32),
+                Vblend(ty, V128, V(3), V(0), V(1), V(2)),
+                Vst(U8, V128, V(3), ARG[1], 0),
+                Ret,
+            ];
+            let (code, _) = Executable::compile_with(&ins, features).unwrap();
+            let prog = Executable::new(&code, Vec::new()).unwrap();
+            let mut res = [0_u8; 16];
+            unsafe { prog.call(0, &[a.as_ptr() as u64, res.as_mut_ptr() as u64]).unwrap() };
+            for (k, lane) in res.chunks(size).enumerate() {
+                let src = if a[k * size + size - 1] & 0x80 != 0 { 16 } else { 32 };
+                assert_eq!(lane, &a[src + k * size..src + k * size + size], "{ty:?} lane {k}");
+            }
+        }
+    }
+
+    #[test]
+    fn indexed() {
+        use Ins::*;
+        use Type::*;
+        let prog = Executable::from_ir(&[
+            Ldx(U64, R(0), R(1), R(2), 3),
+            Ldx(U8, R(8), R(5), R(9), 0),
+            Ldx(S32, R(0), R(4), R(13), 2),
+            Ldx(U16, R(0), R(1), R(2), 4),
+            Stx(U8, R(6), R(12), R(2), 0),
+            Stx(U64, R(0), R(13), R(1), 1),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "48 8b 04 d1",          // mov rax, qword ptr [rcx + 8*rdx]
+                "46 0f b6 44 0d 00",    // movzx r8d, byte ptr [rbp + r9]
+                "4a 63 04 ac",          // movsxd rax, dword ptr [rsp + 4*r13]
+                "49 89 d3 49 c1 e3 04", // mov r11, rdx; shl r11, 4
+                "42 0f b7 04 19",       // movzx eax, word ptr [rcx + r11]
+                "41 88 34 14",          // mov byte ptr [r12 + rdx], sil
+                "49 89 44 4d 00",       // mov qword ptr [r13 + 2*rcx], rax
+                "c3",
+            ]
+            .join(" ")
+        );
+        assert!(Executable::from_ir(&[Ldx(U64, R(0), R(1), R(4), 0)]).is_err());
+    }
+
+    #[test]
+    fn enter_leave() {
+        use Ins::*;
+        let prog = Executable::from_ir(&[Enter(128), Leave(128), Enter(16), Leave(16), Enter(8), Leave(8), Ret]).unwrap();
+        assert_eq!(prog.fmt_8(), "48 81 ec 80 00 00 00 48 81 c4 80 00 00 00 48 83 ec 10 48 83 c4 10 48 83 ec 08 48 83 c4 08 c3");
+        let ins = Enter(12);
+        assert_eq!(Executable::from_ir(&[ins.clone()]).unwrap_err(), Error::StackFrameMustBeModulo8(ins));
+    }
+
+    #[test]
+    fn sel() {
+        use Ins::*;
+        use Cond::*;
+        let prog = Executable::from_ir(&[
+            Sel(Eq, R(0), R(0), R(1)),
+            Sel(Ult, R(0), R(1), R(0)),
+            Sel(Sgt, R(0), R(1), R(2)),
+            Cset(Ugt, R(6)),
+            Csinc(Ult, R(8), R(1), R(2)),
+            Call(R(0)),
+            Branch(R(11)),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "48 0f 45 c1",                      // cmovne rax, rcx
+                "48 0f 42 c1",                      // cmovb rax, rcx
+                "48 89 d0 48 0f 4f c1",             // mov rax, rdx; cmovg rax, rcx
+                "40 0f 97 c6 40 0f b6 f6",          // seta sil; movzx esi, sil
+                "4c 8d 5a 01 4d 89 d8 4c 0f 42 c1", // lea r11, [rdx + 1]; mov r8, r11; cmovb r8, rcx
+                "ff d0",                            // call rax
+                "41 ff e3",                         // jmp r11
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn carry_state() {
+        use Ins::*;
+        use Cond::*;
+        let prog = Executable::from_ir(&[Adds(R(1), R(1), R(2)), Cset(Cs, R(0)), Cmp(R(1), R(2)), Cset(Cs, R(0)), Ret]).unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "48 01 d1",          // add rcx, rdx
+                "0f 92 c0 0f b6 c0", // setb al; movzx eax, al
+                "48 39 d1",          // cmp rcx, rdx
+                "0f 93 c0 0f b6 c0", // setae al; movzx eax, al
+                "c3",
+            ]
+            .join(" ")
+        );
+
+        // The flags at a Label may come from an addition or a subtraction.
+        for ins in [B(Cs, 1), Sel(Cc, R(0), R(1), R(2)), Cset(Cs, R(0)), Csinc(Cc, R(0), R(1), R(2))] {
+            let res = Executable::from_ir(&[ins.clone(), Ret]);
+            assert_eq!(res.unwrap_err(), Error::UnsupportedOperation(ins.clone()));
+            let res = Executable::from_ir(&[Adds(R(1), R(1), R(2)), Label(0), ins.clone(), Label(1), Ret]);
+            assert_eq!(res.unwrap_err(), Error::UnsupportedOperation(ins.clone()));
+            let res = Executable::from_ir(&[Label(0), Subs(R(1), R(1), R(2)), ins.clone(), Label(1), Ret]);
+            assert!(res.is_ok());
+        }
+    }
+
+    #[test]
+    fn fcmp_flags() {
+        use Ins::*;
+        use Cond::*;
+        let prog = Executable::from_ir(&[
+            Fcmp(Type::F64, V(0), V(1)),
+            B(Eq, 0),
+            B(Ne, 0),
+            B(Sgt, 0),
+            Sel(Ult, R(0), R(1), R(2)),
+            Sel(Ult, R(0), R(0), R(2)),
+            Sel(Ugt, R(0), R(1), R(0)),
+            Sel(Ult, R(0), R(1), R(0)),
+            Cset(Eq, R(0)),
+            Cset(Uge, R(6)),
+            Csinc(Ne, R(0), R(1), R(2)),
+            Label(0),
+            Ret,
+        ])
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "66 0f 2e c1",                         // ucomisd xmm0, xmm1
+                "7a 06 0f 84 5e 00 00 00",             // jp done; je l0; done:
+                "0f 8a 58 00 00 00 0f 85 52 00 00 00", // jp l0; jne l0
+                "0f 87 4c 00 00 00",                   // ja l0
+                "48 89 d0 48 0f 42 c1 48 0f 4a c2",    // mov rax, rdx; cmovb rax, rcx; cmovp rax, rdx
+                "48 0f 43 c2 48 0f 4a c2",             // cmovae rax, rdx; cmovp rax, rdx
+                "48 0f 47 c1 48 0f 4a c1",             // cmova rax, rcx; cmovp rax, rcx
+                "7a 04 48 0f 42 c1",                   // jp done; cmovb rax, rcx; done:
+                "0f 94 c0 0f b6 c0",                   // sete al; movzx eax, al
+                "7b 05 b8 00 00 00 00",                // jnp done; mov eax, 0; done:
+                "40 0f 93 c6 40 0f b6 f6",             // setae sil; movzx esi, sil
+                "7b 05 be 01 00 00 00",                // jnp done; mov esi, 1; done:
+                "4c 8d 5a 01 48 89 c8",                // lea r11, [rdx + 1]; mov rax, rcx
+                "49 0f 44 c3 48 0f 4a c1",             // cmove rax, r11; cmovp rax, rcx
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn div() {
+        use Ins::*;
+        use regs::*;
+        let prog = Executable::from_ir(&[UDiv(RES[0], ARG[0], ARG[1]), Ret]).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[100, 7]).unwrap() };
+        assert_eq!(res, 14);
+        let prog = Executable::from_ir(&[Movi(R(0), 7), SDiv(R(8), ARG[0], R(0)), Mov(RES[0], R(8)), Ret]).unwrap();
+        let (res, _) = unsafe { prog.call(0, &[-100_i64 as u64]).unwrap() };
+        assert_eq!(res as i64, -14);
+    }
+
+    /// Load V(0) and V(1) from ARG[0], run `ops` and store V(2) to ARG[1].
+    fn run_vector(features: Features, ty: Type, vsize: Vsize, ops: &[Ins], a: &[u8; 64]) -> [u8; 32] {
+        use Ins::*;
+        use regs::*;
+        let mut ins = vec![Vld(ty, vsize, V(0), ARG[0], 0), Vld(ty, vsize, V(1), ARG[0], 32)];
+        ins.extend_from_slice(ops);
+        ins.extend([Vst(ty, vsize, V(2), ARG[1], 0), Ret]);
+        let prog = compile_for(&ins, features).unwrap();
+        let mut res = [0; 32];
+        unsafe { prog.call(0, &[a.as_ptr() as u64, res.as_mut_ptr() as u64]).unwrap() };
+        res
+    }
+
+    fn lanes<const N: usize, T: Copy>(bytes: &[u8], f: impl Fn([u8; N]) -> T) -> Vec<T> {
+        bytes.chunks_exact(N).map(|c| f(c.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn vector_int() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let a: [u8; 64] = std::array::from_fn(|i| (i * 37 + 11) as u8);
+        let (x, y) = (&a[0..32], &a[32..64]);
+
+        let res = run_vector(SSE2, U8, V128, &[Vadd(U8, V128, V(2), V(0), V(1))], &a);
+        assert_eq!(res[0..16], *lanes(&x[0..16], |[b]: [u8; 1]| b).iter().zip(&y[0..16]).map(|(a, b)| a.wrapping_add(*b)).collect::<Vec<_>>());
+
+        let res = run_vector(SSE2, U16, V128, &[Vmov(U16, V128, V(2), V(1)), Vsub(U16, V128, V(2), V(0), V(2))], &a);
+        let expected = lanes(&x[0..16], u16::from_le_bytes).iter().zip(lanes(&y[0..16], u16::from_le_bytes)).map(|(a, b)| a.wrapping_sub(b)).collect::<Vec<_>>();
+        assert_eq!(lanes(&res[0..16], u16::from_le_bytes), expected);
+
+        // V256 and Vshl need AVX2 on the host.
+        let host = Features::host();
+        for vsize in [V64, V128, V256] {
+            let features = match vsize {
+                V256 if !host.avx2 => continue,
+                V256 => host,
+                _ => SSE2,
+            };
+            let n = match vsize { V64 => 8, V128 => 16, _ => 32 };
+            let res = run_vector(features, U8, vsize, &[Vmul(U8, vsize, V(2), V(0), V(1))], &a);
+            let expected = x.iter().zip(y).map(|(a, b)| a.wrapping_mul(*b)).collect::<Vec<_>>();
+            assert_eq!(res[0..n], expected[0..n], "{vsize:?}");
+
+            let res = run_vector(features, U8, vsize, &[Vmov(U8, vsize, V(2), V(1)), Vmul(U8, vsize, V(2), V(0), V(2))], &a);
+            assert_eq!(res[0..n], expected[0..n], "{vsize:?}");
+        }
+
+        let res = run_vector(SSE2, S32, V128, &[Vmov(S32, V128, V(2), V(0)), Vneg(S32, V128, V(2), V(2))], &a);
+        let expected = lanes(&x[0..16], i32::from_le_bytes).iter().map(|a| a.wrapping_neg()).collect::<Vec<_>>();
+        assert_eq!(lanes(&res[0..16], i32::from_le_bytes), expected);
+
+        if !host.avx2 {
+            return;
+        }
+        let res = run_vector(host, U32, V256, &[Vmul(U32, V256, V(2), V(0), V(1))], &a);
+        let expected = lanes(x, u32::from_le_bytes).iter().zip(lanes(y, u32::from_le_bytes)).map(|(a, b)| a.wrapping_mul(b)).collect::<Vec<_>>();
+        assert_eq!(lanes(&res, u32::from_le_bytes), expected);
+
+        let res = run_vector(host, U64, V256, &[Vnot(U64, V256, V(2), V(0))], &a);
+        assert_eq!(res.to_vec(), x.iter().map(|a| !a).collect::<Vec<_>>());
+
+        let shifts: [u8; 64] = std::array::from_fn(|i| if i < 32 { a[i] } else if i % 4 == 0 { (i / 4) as u8 } else { 0 });
+        let res = run_vector(host, U32, V128, &[Vshl(U32, V128, V(2), V(0), V(1))], &shifts);
+        let expected = lanes(&x[0..16], u32::from_le_bytes).iter().enumerate().map(|(i, a)| a << (i + 8)).collect::<Vec<_>>();
+        assert_eq!(lanes(&res[0..16], u32::from_le_bytes), expected);
+    }
+
+    #[test]
+    fn vector_float() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let mut a = [0; 64];
+        for (i, c) in a.chunks_exact_mut(8).enumerate() {
+            c.copy_from_slice(&(i as f64 * 1.5 + 0.25).to_le_bytes());
+        }
+        let (x, y) = (lanes(&a[0..32], f64::from_le_bytes), lanes(&a[32..64], f64::from_le_bytes));
+
+        let res = run_vector(SSE2, F64, V64, &[Vadd(F64, V64, V(2), V(0), V(1))], &a);
+        assert_eq!(lanes(&res[0..8], f64::from_le_bytes), [x[0] + y[0]]);
+
+        let res = run_vector(SSE2, F64, V128, &[Vneg(F64, V128, V(2), V(0))], &a);
+        assert_eq!(lanes(&res[0..16], f64::from_le_bytes), [-x[0], -x[1]]);
+
+        let res = run_vector(SSE2, F64, V128, &[Vrecpe(F64, V128, V(2), V(0))], &a);
+        assert_eq!(lanes(&res[0..16], f64::from_le_bytes), [1.0 / x[0], 1.0 / x[1]]);
+
+        let res = run_vector(SSE2, F32, V32, &[Vmul(F32, V32, V(2), V(0), V(1)), Vneg(F32, V32, V(2), V(2))], &a);
+        let (x, y) = (f32::from_le_bytes(a[0..4].try_into().unwrap()), f32::from_le_bytes(a[32..36].try_into().unwrap()));
+        assert_eq!(f32::from_le_bytes(res[0..4].try_into().unwrap()), -(x * y));
+
+        let host = Features::host();
+        if !host.avx2 {
+            return;
+        }
+        let (x, y) = (lanes(&a[0..32], f64::from_le_bytes), lanes(&a[32..64], f64::from_le_bytes));
+        let res = run_vector(host, F64, V256, &[Vmov(F64, V256, V(2), V(1)), Vdiv(F64, V256, V(2), V(0), V(2))], &a);
+        assert_eq!(lanes(&res, f64::from_le_bytes), x.iter().zip(&y).map(|(a, b)| a / b).collect::<Vec<_>>());
+
+        let res = run_vector(host, F64, V256, &[Vrsqrte(F64, V256, V(2), V(1))], &a);
+        assert_eq!(lanes(&res, f64::from_le_bytes), y.iter().map(|a| 1.0 / a.sqrt()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vector_encoding() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        let prog = compile_for(
+            &[
+                Vadd(U8, V128, V(0), V(0), V(1)),
+                Vadd(F32, V32, V(8), V(8), V(1)),
+                Vadd(F64, V256, V(0), V(1), V(9)),
+                Vshl(U64, V128, V(0), V(1), V(2)),
+                Vld(U32, V128, V(9), R(12), 16),
+                Vst(F32, V256, V(0), R(0), 0),
+                Vst(U8, V64, V(1), R(1), 0),
+                Ret,
+            ],
+            ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "66 0f fc c1",             // paddb xmm0, xmm1
+                "f3 44 0f 58 c1",          // addss xmm8, xmm1
+                "c4 c1 75 58 c1",          // vaddpd ymm0, ymm1, ymm9
+                "c4 e2 f1 47 c2",          // vpsllvq xmm0, xmm1, xmm2
+                "f3 45 0f 6f 4c 24 10",    // movdqu xmm9, xmmword ptr [r12 + 16]
+                "c5 fe 7f 00",             // vmovdqu ymmword ptr [rax], ymm0
+                "66 0f d6 09",             // movq qword ptr [rcx], xmm1
+                "c5 f8 77",                // vzeroupper
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn vzeroupper() {
+        use Ins::*;
+        use Type::*;
+        use Vsize::*;
+        // Only programs using V256 clear the upper ymm halves before they call out or return.
+        let prog = compile_for(&[Vadd(U32, V128, V(0), V(0), V(1)), Call(R(0)), Ret], ALL).unwrap();
+        assert_eq!(prog.fmt_8(), "66 0f fe c1 ff d0 c3");
+        let prog = compile_for(&[Vadd(U32, V256, V(0), V(0), V(1)), Call(R(0)), Branch(R(1)), Ret], ALL).unwrap();
+        assert_eq!(
+            prog.fmt_8(),
+            [
+                "c5 fd fe c1", // vpaddd ymm0, ymm0, ymm1
+                "c5 f8 77",    // vzeroupper
+                "ff d0",       // call rax
+                "c5 f8 77",    // vzeroupper
+                "ff e1",       // jmp rcx
+                "c5 f8 77",    // vzeroupper
+                "c3",
+            ]
+            .join(" ")
+        );
+    }
+
+    #[test]
+    fn ins_size() {
+        assert_eq!(std::mem::size_of::<Ins>(), 16);
     }
 }
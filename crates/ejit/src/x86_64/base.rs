@@ -0,0 +1,29 @@
+use crate::{Error, Ins};
+use super::{gen_arith, gen_cmp, gen_cmpi, gen_div, gen_mov, gen_movi, gen_mul, gen_shift, gen_unary};
+
+pub fn gen_base_x86_64(code: &mut Vec<u8>, i: &Ins) -> Result<(), Error> {
+    use Ins::*;
+    match i {
+        Add(dest, src1, src2) => gen_arith(code, 0x01, dest, src1, src2, true, &i), // 4801C8 	add rax, rcx
+        Sub(dest, src1, src2) => gen_arith(code, 0x29, dest, src1, src2, false, &i), // 4829C8 	sub rax, rcx
+        And(dest, src1, src2) => gen_arith(code, 0x21, dest, src1, src2, true, &i), // 4821C8 	and rax, rcx
+        Or(dest, src1, src2) => gen_arith(code, 0x09, dest, src1, src2, true, &i), // 4809C8 	or rax, rcx
+        Xor(dest, src1, src2) => gen_arith(code, 0x31, dest, src1, src2, true, &i), // 4831C8 	xor rax, rcx
+        Shl(dest, src1, src2) => gen_shift(code, 4, dest, src1, src2, &i), // 48D3E0 	shl rax, cl
+        Shr(dest, src1, src2) => gen_shift(code, 5, dest, src1, src2, &i), // 48D3E8 	shr rax, cl
+        Sar(dest, src1, src2) => gen_shift(code, 7, dest, src1, src2, &i), // 48D3F8 	sar rax, cl
+        Mul(dest, src1, src2) => gen_mul(code, dest, src1, src2, &i), // 480FAFC1 	imul rax, rcx
+        UDiv(dest, src1, src2) => gen_div(code, false, dest, src1, src2, &i), // 48F7F1 	div rcx
+        SDiv(dest, src1, src2) => gen_div(code, true, dest, src1, src2, &i), // 48F7F9 	idiv rcx
+        Not(dest, src) => gen_unary(code, 2, dest, src, &i), // 48F7D0 	not rax
+        Neg(dest, src) => gen_unary(code, 3, dest, src, &i), // 48F7D8 	neg rax
+        Movi(dest, imm) => gen_movi(code, dest, imm, &i), // B834120000 	mov eax, 0x1234
+        Mov(dest, src) => {
+            gen_mov(code, dest.to_x86(i)?, src.to_x86(i)?); // 4889C8 	mov rax, rcx
+            Ok(())
+        }
+        Cmpi(dest, imm) => gen_cmpi(code, dest, imm, &i), // 4883F812 	cmp rax, 0x12
+        Cmp(dest, src) => gen_cmp(code, dest, src, &i), // 4839C8 	cmp rax, rcx
+        _ => Err(Error::UnsupportedOperation(i.clone()))
+    }
+}
@@ -0,0 +1,1099 @@
+//! SSE2 encodings for `V32`, `V64` and `V128`, AVX2 for `V256`.
+//! Operations which need SSSE3, SSE4.1, SSE4.2 or AVX2 give `Error::UnsupportedOperation`
+//! if the host does not have them.
+//!
+//! `V32` and `V64` operations use the low lanes of an xmm register,
+//! the remaining lanes of the result are undefined.
+use crate::{Cond, Error, Ins, Type, Vsize, R, V};
+use super::{emit_rex, emit_vop, gen_movi, gen_rr, jump8, land8, vgen2, vgen3, vgenmem, vmov, vop3, vshifti, Features, R10, R11, REX_W, XMM14, XMM15};
+
+/// A vector opcode with its mandatory prefix (0x66, 0xf3, 0xf2 or 0)
+/// and opcode map (1 = 0F, 2 = 0F38, 3 = 0F3A).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Op {
+    pub prefix: u8,
+    pub map: u8,
+    pub opcode: u8,
+    /// VEX.W
+    pub w: bool,
+    /// Only available with a VEX prefix.
+    pub vex: bool,
+}
+
+const fn op(prefix: u8, map: u8, opcode: u8) -> Op {
+    Op { prefix, map, opcode, w: false, vex: false }
+}
+
+const fn vex(prefix: u8, map: u8, opcode: u8, w: bool) -> Op {
+    Op { prefix, map, opcode, w, vex: true }
+}
+
+pub const MOVAPS: Op = op(0x00, 1, 0x28); // 0F28C1 	movaps xmm0, xmm1
+const MOVSS_LD: Op = op(0xf3, 1, 0x10); // F30F1000 	movss xmm0, dword ptr [rax]
+const MOVSS_ST: Op = op(0xf3, 1, 0x11); // F30F1100 	movss dword ptr [rax], xmm0
+const MOVQ_LD: Op = op(0xf3, 1, 0x7e); // F30F7E00 	movq xmm0, qword ptr [rax]
+const MOVQ_ST: Op = op(0x66, 1, 0xd6); // 660FD600 	movq qword ptr [rax], xmm0
+const MOVDQU_LD: Op = op(0xf3, 1, 0x6f); // F30F6F00 	movdqu xmm0, xmmword ptr [rax]
+const MOVDQU_ST: Op = op(0xf3, 1, 0x7f); // F30F7F00 	movdqu xmmword ptr [rax], xmm0
+
+const PADD: [Op; 4] = [op(0x66, 1, 0xfc), op(0x66, 1, 0xfd), op(0x66, 1, 0xfe), op(0x66, 1, 0xd4)]; // 660FFCC1 	paddb xmm0, xmm1
+const PSUB: [Op; 4] = [op(0x66, 1, 0xf8), op(0x66, 1, 0xf9), op(0x66, 1, 0xfa), op(0x66, 1, 0xfb)]; // 660FF8C1 	psubb xmm0, xmm1
+const PMULLW: Op = op(0x66, 1, 0xd5); // 660FD5C1 	pmullw xmm0, xmm1
+const PMULLD: Op = op(0x66, 2, 0x40); // 660F3840C1 	pmulld xmm0, xmm1
+const PAND: Op = op(0x66, 1, 0xdb); // 660FDBC1 	pand xmm0, xmm1
+const POR: Op = op(0x66, 1, 0xeb); // 660FEBC1 	por xmm0, xmm1
+const PXOR: Op = op(0x66, 1, 0xef); // 660FEFC1 	pxor xmm0, xmm1
+const PCMPEQD: Op = op(0x66, 1, 0x76); // 660F76C0 	pcmpeqd xmm0, xmm0
+const PANDN: Op = op(0x66, 1, 0xdf); // 660FDFC1 	pandn xmm0, xmm1
+const PCMPEQ: [Op; 4] = [op(0x66, 1, 0x74), op(0x66, 1, 0x75), PCMPEQD, op(0x66, 2, 0x29)]; // 660F3829C1 	pcmpeqq xmm0, xmm1
+const PCMPGT: [Op; 4] = [op(0x66, 1, 0x64), op(0x66, 1, 0x65), op(0x66, 1, 0x66), op(0x66, 2, 0x37)]; // 660F3837C1 	pcmpgtq xmm0, xmm1
+const PMINS: [Op; 3] = [op(0x66, 2, 0x38), op(0x66, 1, 0xea), op(0x66, 2, 0x39)]; // 660F3838C1 	pminsb xmm0, xmm1
+const PMAXS: [Op; 3] = [op(0x66, 2, 0x3c), op(0x66, 1, 0xee), op(0x66, 2, 0x3d)]; // 660FEEC1 	pmaxsw xmm0, xmm1
+const PMINU: [Op; 3] = [op(0x66, 1, 0xda), op(0x66, 2, 0x3a), op(0x66, 2, 0x3b)]; // 660F383BC1 	pminud xmm0, xmm1
+const PMAXU: [Op; 3] = [op(0x66, 1, 0xde), op(0x66, 2, 0x3e), op(0x66, 2, 0x3f)]; // 660FDEC1 	pmaxub xmm0, xmm1
+const PABS: [Op; 3] = [op(0x66, 2, 0x1c), op(0x66, 2, 0x1d), op(0x66, 2, 0x1e)]; // 660F381DC1 	pabsw xmm0, xmm1
+// The mask register is in the top four bits of an immediate.
+const VPBLENDVB: Op = vex(0x66, 3, 0x4c, false); // C4E3714CC230 	vpblendvb xmm0, xmm1, xmm2, xmm3
+const VBLENDVPS: Op = vex(0x66, 3, 0x4a, false); // C4E3714AC230 	vblendvps xmm0, xmm1, xmm2, xmm3
+const VBLENDVPD: Op = vex(0x66, 3, 0x4b, false); // C4E3714BC230 	vblendvpd xmm0, xmm1, xmm2, xmm3
+const PSHIFTW: Op = op(0x66, 1, 0x71); // 660F71F008 	psllw xmm0, 8
+const PSHIFTD: Op = op(0x66, 1, 0x72); // 660F72F01F 	pslld xmm0, 31
+const PSHIFTQ: Op = op(0x66, 1, 0x73); // 660F73F03F 	psllq xmm0, 63
+const VPSLLVD: Op = vex(0x66, 2, 0x47, false); // C4E27147C2 	vpsllvd xmm0, xmm1, xmm2
+const VPSLLVQ: Op = vex(0x66, 2, 0x47, true); // C4E2F147C2 	vpsllvq xmm0, xmm1, xmm2
+const XORPS: Op = op(0x00, 1, 0x57); // 0F57C1 	xorps xmm0, xmm1
+const ANDPS: Op = op(0x00, 1, 0x54); // 0F54C1 	andps xmm0, xmm1
+const ORPS: Op = op(0x00, 1, 0x56); // 0F56C1 	orps xmm0, xmm1
+const CVTSS2SD: Op = op(0xf3, 1, 0x5a); // F30F5AC1 	cvtss2sd xmm0, xmm1
+const CVTSD2SS: Op = op(0xf2, 1, 0x5a); // F20F5AC1 	cvtsd2ss xmm0, xmm1
+const VCVTPH2PS: Op = vex(0x66, 2, 0x13, false); // C4E27913C1 	vcvtph2ps xmm0, xmm1
+const VCVTPS2PH: Op = vex(0x66, 3, 0x1d, false); // C4E3791DC804 	vcvtps2ph xmm0, xmm1, 4
+const VFMADD213SS: Op = vex(0x66, 2, 0xa9, false); // C4E271A9C2 	vfmadd213ss xmm0, xmm1, xmm2
+const VFMADD231SS: Op = vex(0x66, 2, 0xb9, false); // C4E271B9C2 	vfmadd231ss xmm0, xmm1, xmm2
+
+const PUNPCKL: [Op; 4] = [op(0x66, 1, 0x60), op(0x66, 1, 0x61), op(0x66, 1, 0x62), op(0x66, 1, 0x6c)]; // 660F6CC1 	punpcklqdq xmm0, xmm1
+const PUNPCKH: [Op; 4] = [op(0x66, 1, 0x68), op(0x66, 1, 0x69), op(0x66, 1, 0x6a), op(0x66, 1, 0x6d)]; // 660F68C1 	punpckhbw xmm0, xmm1
+const PACKUSWB: Op = op(0x66, 1, 0x67); // 660F67C1 	packuswb xmm0, xmm1
+const PACKSSDW: Op = op(0x66, 1, 0x6b); // 660F6BC1 	packssdw xmm0, xmm1
+const PADDUSB: Op = op(0x66, 1, 0xdc); // 660FDCC1 	paddusb xmm0, xmm1
+const PSHUFB: Op = op(0x66, 2, 0x00); // 660F3800C1 	pshufb xmm0, xmm1
+// With an immediate.
+const PSHUFD: Op = op(0x66, 1, 0x70); // 660F70C11B 	pshufd xmm0, xmm1, 27
+const PSHUFLW: Op = op(0xf2, 1, 0x70); // F20F70C100 	pshuflw xmm0, xmm1, 0
+const SHUFPS: Op = op(0x00, 1, 0xc6); // 0FC6C188 	shufps xmm0, xmm1, 136
+const PALIGNR: Op = op(0x66, 3, 0x0f); // 660F3A0FC104 	palignr xmm0, xmm1, 4
+const VPERMQ: Op = vex(0x66, 3, 0x00, true); // C4E3FD00C1D8 	vpermq ymm0, ymm1, 216
+const VPERM2I128: Op = vex(0x66, 3, 0x46, false); // C4E37546C221 	vperm2i128 ymm0, ymm1, ymm2, 33
+
+// Saturating, widening and narrowing.
+const PADDS: [Op; 2] = [op(0x66, 1, 0xec), op(0x66, 1, 0xed)]; // 660FECC1 	paddsb xmm0, xmm1
+const PADDUS: [Op; 2] = [PADDUSB, op(0x66, 1, 0xdd)]; // 660FDDC1 	paddusw xmm0, xmm1
+const PSUBS: [Op; 2] = [op(0x66, 1, 0xe8), op(0x66, 1, 0xe9)]; // 660FE8C1 	psubsb xmm0, xmm1
+const PSUBUS: [Op; 2] = [op(0x66, 1, 0xd8), op(0x66, 1, 0xd9)]; // 660FD9C1 	psubusw xmm0, xmm1
+const PMOVSX: [Op; 3] = [op(0x66, 2, 0x20), op(0x66, 2, 0x23), op(0x66, 2, 0x25)]; // 660F3823C1 	pmovsxwd xmm0, xmm1
+const PMOVZX: [Op; 3] = [op(0x66, 2, 0x30), op(0x66, 2, 0x33), op(0x66, 2, 0x35)]; // 660F3830C1 	pmovzxbw xmm0, xmm1
+const PMULDQ: Op = op(0x66, 2, 0x28); // 660F3828C1 	pmuldq xmm0, xmm1
+const PMULUDQ: Op = op(0x66, 1, 0xf4); // 660FF4C1 	pmuludq xmm0, xmm1
+const PACKSSWB: Op = op(0x66, 1, 0x63); // 660F63C1 	packsswb xmm0, xmm1
+const PACKUSDW: Op = op(0x66, 2, 0x2b); // 660F382BC1 	packusdw xmm0, xmm1
+const PMADDWD: Op = op(0x66, 1, 0xf5); // 660FF5C1 	pmaddwd xmm0, xmm1
+const PHADD: [Op; 2] = [op(0x66, 2, 0x01), op(0x66, 2, 0x02)]; // 660F3801C1 	phaddw xmm0, xmm1
+const HADDPS: Op = op(0xf2, 1, 0x7c); // F20F7CC1 	haddps xmm0, xmm1
+const HADDPD: Op = op(0x66, 1, 0x7c); // 660F7CC1 	haddpd xmm0, xmm1
+const VEXTRACTI128: Op = vex(0x66, 3, 0x39, false); // C4E37D39C801 	vextracti128 xmm0, ymm1, 1
+
+// Lane moves, with a 66 prefix and the xmm register in reg.
+const PINSR: [&[u8]; 4] = [&[0x0f, 0x3a, 0x20], &[0x0f, 0xc4], &[0x0f, 0x3a, 0x22], &[0x0f, 0x3a, 0x22]]; // 660F3A20C102 	pinsrb xmm0, ecx, 2
+const PEXTR: [&[u8]; 4] = [&[0x0f, 0x3a, 0x14], &[0x0f, 0x3a, 0x15], &[0x0f, 0x3a, 0x16], &[0x0f, 0x3a, 0x16]]; // 66480F3A16C801 	pextrq rax, xmm1, 1
+const MOVD: [u8; 2] = [0x0f, 0x6e]; // 66480F6EC1 	movq xmm0, rcx
+const VPBROADCAST: [Op; 4] = [vex(0x66, 2, 0x78, false), vex(0x66, 2, 0x79, false), vex(0x66, 2, 0x58, false), vex(0x66, 2, 0x59, false)]; // C4E27D78C0 	vpbroadcastb ymm0, xmm0
+
+// Shift group opcode extensions.
+const SRL: u8 = 2;
+const SRA: u8 = 4;
+/// Byte shift right of the 73 group.
+const SRLDQ: u8 = 3;
+const SLL: u8 = 6;
+
+// Floating point opcodes, the prefix depends on the type and size.
+const ADD: u8 = 0x58; // F30F58C1 	addss xmm0, xmm1
+const MUL: u8 = 0x59; // 0F59C1 	mulps xmm0, xmm1
+const SUB: u8 = 0x5c; // F20F5CC1 	subsd xmm0, xmm1
+const DIV: u8 = 0x5e; // 660F5EC1 	divpd xmm0, xmm1
+const SQRT: u8 = 0x51; // 660F51C1 	sqrtpd xmm0, xmm1
+const RSQRT: u8 = 0x52; // 0F52C1 	rsqrtps xmm0, xmm1
+const RCP: u8 = 0x53; // 0F53C1 	rcpps xmm0, xmm1
+const MIN: u8 = 0x5d; // F30F5DC1 	minss xmm0, xmm1
+const MAX: u8 = 0x5f; // F20F5FC1 	maxsd xmm0, xmm1
+const CMP: u8 = 0xc2; // F30FC2C103 	cmpunordss xmm0, xmm1
+const UCOMIS: u8 = 0x2e; // 660F2EC1 	ucomisd xmm0, xmm1
+const CVTSI2: u8 = 0x2a; // F2480F2AC1 	cvtsi2sd xmm0, rcx
+const CVTT2SI: u8 = 0x2c; // F2480F2CC1 	cvttsd2si rax, xmm1
+
+pub fn gen_vector_x86_64(code: &mut Vec<u8>, i: &Ins, features: Features) -> Result<(), Error> {
+    use Type::*;
+    use Vsize::*;
+    use Ins::*;
+    if vsize(i) == Some(V256) {
+        need(features.avx2, i)?;
+    }
+    match i {
+        Vadd(ty @ (F32 | F64), vsize, dest, src1, src2) => vgen3(code, l(*vsize), fop(*ty, *vsize, ADD, i)?, dest, src1, src2, true, i),
+        Vsub(ty @ (F32 | F64), vsize, dest, src1, src2) => vgen3(code, l(*vsize), fop(*ty, *vsize, SUB, i)?, dest, src1, src2, false, i),
+        Vmul(ty @ (F32 | F64), vsize, dest, src1, src2) => vgen3(code, l(*vsize), fop(*ty, *vsize, MUL, i)?, dest, src1, src2, true, i),
+        Vdiv(ty @ (F32 | F64), vsize, dest, src1, src2) => vgen3(code, l(*vsize), fop(*ty, *vsize, DIV, i)?, dest, src1, src2, false, i),
+        Vadd(ty, vsize, dest, src1, src2) => vgen3(code, l(*vsize), iop(*ty, *vsize, PADD, i)?, dest, src1, src2, true, i),
+        Vsub(ty, vsize, dest, src1, src2) => vgen3(code, l(*vsize), iop(*ty, *vsize, PSUB, i)?, dest, src1, src2, false, i),
+        Vand(ty, vsize, dest, src1, src2) => vgen3(code, l(*vsize), iop(*ty, *vsize, [PAND; 4], i)?, dest, src1, src2, true, i),
+        Vor(ty, vsize, dest, src1, src2) => vgen3(code, l(*vsize), iop(*ty, *vsize, [POR; 4], i)?, dest, src1, src2, true, i),
+        Vxor(ty, vsize, dest, src1, src2) => vgen3(code, l(*vsize), iop(*ty, *vsize, [PXOR; 4], i)?, dest, src1, src2, true, i),
+        Vmul(S16 | U16, vsize, dest, src1, src2) => vgen3(code, l(*vsize), iop(U16, *vsize, [PMULLW; 4], i)?, dest, src1, src2, true, i),
+        Vmul(S32 | U32, vsize, dest, src1, src2) => {
+            need(features.sse4_1, i)?;
+            vgen3(code, l(*vsize), iop(U32, *vsize, [PMULLD; 4], i)?, dest, src1, src2, true, i)
+        }
+        Vmul(S8 | U8, vsize, dest, src1, src2) => {
+            // There is no byte multiply, multiply the even and odd bytes as words.
+            let l = il(U8, *vsize, i)?;
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            vop3(code, l, PMULLW, XMM15, src1, src2, true);
+            vshifti(code, l, PSHIFTW, SRL, XMM14, src2, 8);
+            vshifti(code, l, PSHIFTW, SRL, dest, src1, 8);
+            vop3(code, l, PMULLW, dest, dest, XMM14, true);
+            vshifti(code, l, PSHIFTW, SLL, dest, dest, 8);
+            vshifti(code, l, PSHIFTW, SLL, XMM15, XMM15, 8);
+            vshifti(code, l, PSHIFTW, SRL, XMM15, XMM15, 8);
+            vop3(code, l, POR, dest, dest, XMM15, true);
+            Ok(())
+        }
+        Vshl(ty @ (S32 | U32 | S64 | U64), vsize, dest, src1, src2) => {
+            // There are no variable shifts before AVX2.
+            need(features.avx2, i)?;
+            let op = iop(*ty, *vsize, [VPSLLVD, VPSLLVD, VPSLLVD, VPSLLVQ], i)?;
+            vgen3(code, l(*vsize), op, dest, src1, src2, false, i)
+        }
+        Vshl(..) => Err(Error::VectorTypeNotSupported(i.clone())),
+        Vnot(ty, vsize, dest, src) => {
+            let l = il(*ty, *vsize, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+            vop3(code, l, PXOR, dest, src, XMM15, true);
+            Ok(())
+        }
+        Vneg(ty @ (F32 | F64), vsize, dest, src) => {
+            // Flip the sign bits.
+            let l = fl(*ty, *vsize, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+            if *ty == F32 {
+                vshifti(code, l, PSHIFTD, SLL, XMM15, XMM15, 31);
+            } else {
+                vshifti(code, l, PSHIFTQ, SLL, XMM15, XMM15, 63);
+            }
+            vop3(code, l, XORPS, dest, src, XMM15, true);
+            Ok(())
+        }
+        Vneg(ty, vsize, dest, src) => {
+            let psub = iop(*ty, *vsize, PSUB, i)?;
+            let l = l(*vsize);
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            if dest == src {
+                vop3(code, l, PXOR, XMM15, XMM15, XMM15, true);
+                vop3(code, l, psub, XMM15, XMM15, src, false);
+                vmov(code, l, dest, XMM15);
+            } else {
+                vop3(code, l, PXOR, dest, dest, dest, true);
+                vop3(code, l, psub, dest, dest, src, false);
+            }
+            Ok(())
+        }
+        Vmov(ty @ (F32 | F64), vsize, dest, src) => vgen2(code, fl(*ty, *vsize, i)?, MOVAPS, dest, src, i),
+        Vmov(ty, vsize, dest, src) => vgen2(code, il(*ty, *vsize, i)?, MOVAPS, dest, src, i),
+        Vrecpe(F32, vsize, dest, src) => vgen2(code, l(*vsize), fop(F32, *vsize, RCP, i)?, dest, src, i),
+        Vrsqrte(F32, vsize, dest, src) => vgen2(code, l(*vsize), fop(F32, *vsize, RSQRT, i)?, dest, src, i),
+        Vrecpe(F64, vsize, dest, src) | Vrsqrte(F64, vsize, dest, src) => {
+            // There are no double precision estimates, divide 1.0 by the (square root of the) source.
+            let div = fop(F64, *vsize, DIV, i)?;
+            let l = l(*vsize);
+            let (dest, mut src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            if let Vrsqrte(..) = i {
+                emit_vop(code, fop(F64, *vsize, SQRT, i)?, l, dest, 0, src);
+                src = dest;
+            }
+            // 0x3ff0000000000000 is 1.0
+            vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+            vshifti(code, l, PSHIFTQ, SLL, XMM15, XMM15, 54);
+            vshifti(code, l, PSHIFTQ, SRL, XMM15, XMM15, 2);
+            vop3(code, l, div, XMM15, XMM15, src, false);
+            vmov(code, l, dest, XMM15);
+            Ok(())
+        }
+        VmovFromR(ty, dest, src, lane) => {
+            // pinsrw is SSE2, the others SSE4.1.
+            let shift = lane_shift(*ty, *lane, i)?;
+            need(shift == 1 || features.sse4_1, i)?;
+            let w = if shift == 3 { REX_W } else { 0 };
+            gen_lane(code, w, PINSR[shift], dest.to_x86(i)?, src.to_x86(i)?, *lane);
+            Ok(())
+        }
+        VmovToR(ty, dest, src, lane) => {
+            // pextrb, pextrw and pextrd zero extend.
+            let shift = lane_shift(*ty, *lane, i)?;
+            need(features.sse4_1, i)?;
+            let w = if shift == 3 { REX_W } else { 0 };
+            let dest = dest.to_x86(i)?;
+            gen_lane(code, w, PEXTR[shift], src.to_x86(i)?, dest, *lane);
+            // 480FBEC0          movsx rax, al
+            // 4863C0            movsxd rax, eax
+            match ty {
+                S8 => gen_rr(code, REX_W, &[0x0f, 0xbe], dest, dest),
+                S16 => gen_rr(code, REX_W, &[0x0f, 0xbf], dest, dest),
+                S32 => gen_rr(code, REX_W, &[0x63], dest, dest),
+                _ => (),
+            }
+            Ok(())
+        }
+        Vdup(ty, vsize, dest, src) => {
+            let shift = lane_shift(*ty, 0, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            let bytes = match vsize {
+                V32 if shift < 3 => 4,
+                V64 => 8,
+                V128 => 16,
+                V256 => 32,
+                _ => return Err(Error::VectorSizeNotSupported(i.clone())),
+            };
+            code.push(0x66);
+            gen_rr(code, if shift == 3 { REX_W } else { 0 }, &MOVD, dest, src);
+            if *vsize == V256 {
+                emit_vop(code, VPBROADCAST[shift], true, dest, 0, dest);
+                return Ok(());
+            }
+            // SSE2 has no broadcast: pair the bytes, copy the low word across 64 bits, then the low dword or qword.
+            if shift == 0 {
+                vop3(code, false, PUNPCKL[0], dest, dest, dest, false);
+            }
+            if shift < 2 {
+                emit_vop(code, PSHUFLW, false, dest, 0, dest);
+                code.push(0x00);
+            }
+            let filled = if shift < 2 { 8 } else { 1 << shift };
+            if bytes > filled {
+                emit_vop(code, PSHUFD, false, dest, 0, dest);
+                code.push(if shift == 3 { 0x44 } else { 0x00 });
+            }
+            Ok(())
+        }
+        Vcmp(cond, ty @ (F32 | F64), vsize, dest, src1, src2) => {
+            // cmpps predicates: 1 lt, 2 le, 5 not lt and 6 not le, the negations are true if unordered.
+            let (src1, src2, pred) = match cond {
+                Cond::Eq => (src1, src2, 0),
+                Cond::Ne => (src1, src2, 4),
+                Cond::Ult => (src1, src2, 1),
+                Cond::Ule => (src1, src2, 2),
+                Cond::Sgt => (src2, src1, 1),
+                Cond::Sge => (src2, src1, 2),
+                Cond::Slt => (src2, src1, 6),
+                Cond::Sle => (src2, src1, 5),
+                Cond::Ugt => (src1, src2, 6),
+                Cond::Uge => (src1, src2, 5),
+                _ => return Err(Error::UnsupportedVectorOperation(i.clone())),
+            };
+            vgen3(code, l(*vsize), fop(*ty, *vsize, CMP, i)?, dest, src1, src2, false, i)?;
+            code.push(pred);
+            Ok(())
+        }
+        Vcmp(cond, ty, vsize, dest, src1, src2) => {
+            let (eq, gt) = (iop(*ty, *vsize, PCMPEQ, i)?, iop(*ty, *vsize, PCMPGT, i)?);
+            let shift = lane_shift(*ty, 0, i)?;
+            // pcmpeqq and the 16 and 32 bit unsigned min and max are SSE4.1, pcmpgtq SSE4.2.
+            match (cond, shift) {
+                (Cond::Eq | Cond::Ne, 3) | (Cond::Ugt | Cond::Uge | Cond::Ult | Cond::Ule, 1 | 2) => need(features.sse4_1, i)?,
+                (_, 3) => need(features.sse4_2, i)?,
+                _ => (),
+            }
+            let l = l(*vsize);
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            let negate = match cond {
+                Cond::Eq | Cond::Ne => {
+                    vop3(code, l, eq, dest, src1, src2, true);
+                    *cond == Cond::Ne
+                }
+                Cond::Sgt | Cond::Sle => {
+                    vop3(code, l, gt, dest, src1, src2, false);
+                    *cond == Cond::Sle
+                }
+                Cond::Slt | Cond::Sge => {
+                    vop3(code, l, gt, dest, src2, src1, false);
+                    *cond == Cond::Sge
+                }
+                Cond::Ugt | Cond::Uge | Cond::Ult | Cond::Ule if shift == 3 => {
+                    // There is no unsigned 64 bit min or max, flip the sign bits and compare as signed.
+                    vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+                    vshifti(code, l, PSHIFTQ, SLL, XMM15, XMM15, 63);
+                    vop3(code, l, PXOR, XMM14, src2, XMM15, true);
+                    vop3(code, l, PXOR, XMM15, src1, XMM15, true);
+                    if matches!(cond, Cond::Ugt | Cond::Ule) {
+                        vop3(code, l, gt, dest, XMM15, XMM14, false);
+                    } else {
+                        vop3(code, l, gt, dest, XMM14, XMM15, false);
+                    }
+                    matches!(cond, Cond::Ule | Cond::Uge)
+                }
+                Cond::Uge | Cond::Ult => {
+                    // src1 >= src2 if max(src1, src2) == src1
+                    vop3(code, l, PMAXU[shift], XMM15, src1, src2, true);
+                    vop3(code, l, eq, dest, XMM15, src1, true);
+                    *cond == Cond::Ult
+                }
+                Cond::Ule | Cond::Ugt => {
+                    vop3(code, l, PMINU[shift], XMM15, src1, src2, true);
+                    vop3(code, l, eq, dest, XMM15, src1, true);
+                    *cond == Cond::Ugt
+                }
+                _ => return Err(Error::UnsupportedVectorOperation(i.clone())),
+            };
+            if negate {
+                vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+                vop3(code, l, PXOR, dest, dest, XMM15, true);
+            }
+            Ok(())
+        }
+        Vbsl(ty, vsize, dest, mask, src1, src2) => {
+            let l = il(*ty, *vsize, i).or_else(|_| fl(*ty, *vsize, i))?;
+            let (dest, mask, src1, src2) = (dest.to_x86(i)?, mask.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            vop3(code, l, PAND, XMM15, mask, src1, true);
+            vop3(code, l, PANDN, XMM14, mask, src2, false);
+            vop3(code, l, POR, dest, XMM14, XMM15, true);
+            Ok(())
+        }
+        Vblend(ty, vsize, dest, mask, src1, src2) => {
+            let l = il(*ty, *vsize, i).or_else(|_| fl(*ty, *vsize, i))?;
+            let (dest, mut mask, src1, src2) = (dest.to_x86(i)?, mask.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            let shift = lane_shift(*ty, 0, i)?;
+            if !features.avx2 {
+                // The variable blends are VEX only, spread the top bit of each lane of the mask and select as Vbsl.
+                match shift {
+                    0 => {
+                        vop3(code, l, PXOR, XMM15, XMM15, XMM15, true);
+                        vop3(code, l, PCMPGT[0], XMM15, XMM15, mask, false);
+                    }
+                    1 => vshifti(code, l, PSHIFTW, SRA, XMM15, mask, 15),
+                    2 => vshifti(code, l, PSHIFTD, SRA, XMM15, mask, 31),
+                    _ => {
+                        vshifti(code, l, PSHIFTD, SRA, XMM15, mask, 31);
+                        emit_vop(code, PSHUFD, l, XMM15, 0, XMM15);
+                        code.push(0xf5);
+                    }
+                }
+                vop3(code, l, PANDN, XMM14, XMM15, src2, false);
+                vop3(code, l, PAND, XMM15, XMM15, src1, true);
+                vop3(code, l, POR, dest, XMM14, XMM15, true);
+                return Ok(());
+            }
+            let op = match shift {
+                0 => VPBLENDVB,
+                1 => {
+                    // Spread the top bit of each word to both of its bytes.
+                    vshifti(code, l, PSHIFTW, SRA, XMM15, mask, 15);
+                    mask = XMM15;
+                    VPBLENDVB
+                }
+                2 => VBLENDVPS,
+                _ => VBLENDVPD,
+            };
+            emit_vop(code, op, l, dest, src2, src1);
+            code.push(mask << 4);
+            Ok(())
+        }
+        Vmin(ty @ (F32 | F64), vsize, dest, src1, src2) => gen_minmax(code, l(*vsize), fop(*ty, *vsize, MIN, i)?, fop(*ty, *vsize, CMP, i)?, dest, src1, src2, i),
+        Vmax(ty @ (F32 | F64), vsize, dest, src1, src2) => gen_minmax(code, l(*vsize), fop(*ty, *vsize, MAX, i)?, fop(*ty, *vsize, CMP, i)?, dest, src1, src2, i),
+        Vmin(ty, vsize, dest, src1, src2) | Vmax(ty, vsize, dest, src1, src2) => {
+            // There is no 64 bit min or max before AVX-512.
+            let ops = match (i, ty) {
+                (Vmin(..), S8 | S16 | S32) => PMINS,
+                (Vmin(..), U8 | U16 | U32) => PMINU,
+                (Vmax(..), S8 | S16 | S32) => PMAXS,
+                (Vmax(..), U8 | U16 | U32) => PMAXU,
+                _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+            };
+            let op = ops[lane_shift(*ty, 0, i)?];
+            // Only pminsw, pmaxsw, pminub and pmaxub are SSE2.
+            need(op.map == 1 || features.sse4_1, i)?;
+            vgen3(code, il(*ty, *vsize, i)?, op, dest, src1, src2, true, i)
+        }
+        Vabs(ty @ (F32 | F64), vsize, dest, src) => {
+            // Clear the sign bits.
+            let l = fl(*ty, *vsize, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+            if *ty == F32 {
+                vshifti(code, l, PSHIFTD, SRL, XMM15, XMM15, 1);
+            } else {
+                vshifti(code, l, PSHIFTQ, SRL, XMM15, XMM15, 1);
+            }
+            vop3(code, l, ANDPS, dest, src, XMM15, true);
+            Ok(())
+        }
+        Vabs(ty @ (S64 | U64), vsize, dest, src) => {
+            // There is no pabsq before AVX-512, negate with (src ^ sign) - sign
+            // copying the sign of the high dword of each lane to the low one.
+            let l = il(*ty, *vsize, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            vshifti(code, l, PSHIFTD, SRA, XMM15, src, 31);
+            emit_vop(code, PSHUFD, l, XMM15, 0, XMM15);
+            code.push(0xf5);
+            vop3(code, l, PXOR, dest, src, XMM15, true);
+            vop3(code, l, PSUB[3], dest, dest, XMM15, false);
+            Ok(())
+        }
+        Vabs(ty, vsize, dest, src) => {
+            need(features.sse4_1, i)?;
+            vgen2(code, il(*ty, *vsize, i)?, PABS[lane_shift(*ty, 0, i)?], dest, src, i)
+        }
+        Vtbl(vsize, dest, table, index) => {
+            // pshufb gives zero if the top bit of the index is set, otherwise it uses the low four bits.
+            // Adding 0x70 with saturation sets the top bit of indices over 15.
+            let l = il(U8, *vsize, i)?;
+            need(features.sse4_1, i)?;
+            let (dest, table, index) = (dest.to_x86(i)?, table.to_x86(i)?, index.to_x86(i)?);
+            gen_const(code, l, [0x7070_7070_7070_7070; 2], i)?;
+            vop3(code, l, PADDUSB, XMM15, XMM15, index, true);
+            vop3(code, l, PSHUFB, dest, table, XMM15, false);
+            Ok(())
+        }
+        Vzip(ty, vsize, dest, src1, src2, part) => {
+            let (shift, l) = permute_shape(*ty, *vsize, *part, i)?;
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            let punpck = if *part == 0 { PUNPCKL[shift] } else { PUNPCKH[shift] };
+            match vsize {
+                V64 => {
+                    vop3(code, l, PUNPCKL[shift], dest, src1, src2, false);
+                    if *part == 1 {
+                        vshifti(code, l, PSHIFTQ, SRLDQ, dest, dest, 8);
+                    }
+                }
+                V128 => vop3(code, l, punpck, dest, src1, src2, false),
+                _ => {
+                    // Unpack works within 128 bit halves, put the quarters of each source in the order 0 2 1 3.
+                    emit_vop(code, VPERMQ, l, XMM14, 0, src1);
+                    code.push(0xd8);
+                    emit_vop(code, VPERMQ, l, XMM15, 0, src2);
+                    code.push(0xd8);
+                    vop3(code, l, punpck, dest, XMM14, XMM15, false);
+                }
+            }
+            Ok(())
+        }
+        Vuzp(ty, vsize, dest, src1, src2, part) => {
+            let (shift, l) = permute_shape(*ty, *vsize, *part, i)?;
+            let (dest, mut src1, mut src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            if *vsize == V64 {
+                // Take the lanes of both 64 bit sources from one register.
+                vop3(code, l, PUNPCKL[3], XMM14, src1, src2, false);
+                (src1, src2) = (XMM14, XMM14);
+            }
+            match (shift, part) {
+                (0, 0) => {
+                    // Clear the odd bytes and pack the words, which does not saturate.
+                    vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+                    vshifti(code, l, PSHIFTW, SRL, XMM15, XMM15, 8);
+                    vop3(code, l, PAND, XMM14, src1, XMM15, true);
+                    vop3(code, l, PAND, XMM15, src2, XMM15, true);
+                    vop3(code, l, PACKUSWB, dest, XMM14, XMM15, false);
+                }
+                (0, _) => {
+                    vshifti(code, l, PSHIFTW, SRL, XMM15, src2, 8);
+                    vshifti(code, l, PSHIFTW, SRL, XMM14, src1, 8);
+                    vop3(code, l, PACKUSWB, dest, XMM14, XMM15, false);
+                }
+                (1, _) => {
+                    // Sign extend the even or odd words and pack the dwords, which does not saturate.
+                    for (reg, src) in [(XMM15, src2), (XMM14, src1)] {
+                        if *part == 0 {
+                            vshifti(code, l, PSHIFTD, SLL, reg, src, 16);
+                            vshifti(code, l, PSHIFTD, SRA, reg, reg, 16);
+                        } else {
+                            vshifti(code, l, PSHIFTD, SRA, reg, src, 16);
+                        }
+                    }
+                    vop3(code, l, PACKSSDW, dest, XMM14, XMM15, false);
+                }
+                (2, _) => {
+                    vop3(code, l, SHUFPS, dest, src1, src2, false);
+                    code.push(if *part == 0 { 0x88 } else { 0xdd });
+                }
+                _ => vop3(code, l, if *part == 0 { PUNPCKL[3] } else { PUNPCKH[3] }, dest, src1, src2, false),
+            }
+            if l {
+                // Each 128 bit half holds a quarter from each source.
+                emit_vop(code, VPERMQ, l, dest, 0, dest);
+                code.push(0xd8);
+            }
+            Ok(())
+        }
+        Vtrn(ty, vsize, dest, src1, src2, part) => {
+            // Shift within pairs of lanes.
+            let (shift, l) = permute_shape(*ty, *vsize, *part, i)?;
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            if shift == 3 {
+                vop3(code, l, if *part == 0 { PUNPCKL[3] } else { PUNPCKH[3] }, dest, src1, src2, false);
+                return Ok(());
+            }
+            let (pshift, bits) = ([PSHIFTW, PSHIFTD, PSHIFTQ][shift], 8_u8 << shift);
+            if *part == 0 {
+                vshifti(code, l, pshift, SLL, XMM15, src2, bits);
+                vshifti(code, l, pshift, SLL, XMM14, src1, bits);
+                vshifti(code, l, pshift, SRL, XMM14, XMM14, bits);
+            } else {
+                vshifti(code, l, pshift, SRL, XMM15, src2, bits);
+                vshifti(code, l, pshift, SLL, XMM15, XMM15, bits);
+                vshifti(code, l, pshift, SRL, XMM14, src1, bits);
+            }
+            vop3(code, l, POR, dest, XMM14, XMM15, true);
+            Ok(())
+        }
+        Vext(ty, vsize, dest, src1, src2, n) => {
+            let (shift, l) = permute_shape(*ty, *vsize, 0, i)?;
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            let bytes = (*n as usize) << shift;
+            match vsize {
+                V64 if bytes < 8 => {
+                    vop3(code, l, PUNPCKL[3], XMM15, src1, src2, false);
+                    vshifti(code, l, PSHIFTQ, SRLDQ, dest, XMM15, bytes as u8);
+                }
+                V128 if bytes < 16 => {
+                    need(features.sse4_1, i)?;
+                    vop3(code, l, PALIGNR, dest, src2, src1, false);
+                    code.push(bytes as u8);
+                }
+                V256 if bytes < 32 => {
+                    // palignr works within 128 bit halves, make the middle half of the concatenation.
+                    emit_vop(code, VPERM2I128, l, XMM15, src1, src2);
+                    code.push(0x21);
+                    if bytes < 16 {
+                        emit_vop(code, PALIGNR, l, dest, XMM15, src1);
+                        code.push(bytes as u8);
+                    } else {
+                        emit_vop(code, PALIGNR, l, dest, src2, XMM15);
+                        code.push(bytes as u8 - 16);
+                    }
+                }
+                _ => return Err(Error::InvalidImmediate(i.clone())),
+            }
+            Ok(())
+        }
+        Vrev(ty, vsize, dest, src) => {
+            let (shift, l) = permute_shape(*ty, *vsize, 0, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            match (shift, vsize) {
+                (3, V128) => {
+                    emit_vop(code, PSHUFD, l, dest, 0, src);
+                    code.push(0x4e);
+                }
+                (3, _) => {
+                    emit_vop(code, VPERMQ, l, dest, 0, src);
+                    code.push(0x1b);
+                }
+                (2, _) => {
+                    emit_vop(code, PSHUFD, l, dest, 0, src);
+                    code.push(if *vsize == V64 { 0xe1 } else { 0x1b });
+                }
+                (_, V64) => {
+                    need(features.sse4_1, i)?;
+                    gen_const(code, l, [[0x0001_0203_0405_0607, 0x0100_0302_0504_0706][shift], 0], i)?;
+                    vop3(code, l, PSHUFB, dest, src, XMM15, false);
+                }
+                _ => {
+                    need(features.sse4_1, i)?;
+                    let constant = [[0x0809_0a0b_0c0d_0e0f, 0x0001_0203_0405_0607], [0x0908_0b0a_0d0c_0f0e, 0x0100_0302_0504_0706]];
+                    gen_const(code, l, constant[shift], i)?;
+                    vop3(code, l, PSHUFB, dest, src, XMM15, false);
+                }
+            }
+            if l && shift < 3 {
+                // Swap the 128 bit halves.
+                emit_vop(code, VPERMQ, l, dest, 0, dest);
+                code.push(0x4e);
+            }
+            Ok(())
+        }
+        Vqadd(ty, vsize, dest, src1, src2) | Vqsub(ty, vsize, dest, src1, src2) => {
+            let ops = match (i, ty) {
+                (Vqadd(..), S8 | S16) => PADDS,
+                (Vqadd(..), U8 | U16) => PADDUS,
+                (Vqsub(..), S8 | S16) => PSUBS,
+                (Vqsub(..), U8 | U16) => PSUBUS,
+                _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+            };
+            vgen3(code, il(*ty, *vsize, i)?, ops[lane_shift(*ty, 0, i)?], dest, src1, src2, matches!(i, Vqadd(..)), i)
+        }
+        Vaddl(ty, vsize, dest, src1, src2) | Vmull(ty, vsize, dest, src1, src2) => {
+            let (shift, signed, l) = wide_shape(*ty, *vsize, i)?;
+            need(features.sse4_1, i)?;
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            let pmov = if signed { PMOVSX[shift - 1] } else { PMOVZX[shift - 1] };
+            emit_vop(code, pmov, l, XMM14, 0, src1);
+            emit_vop(code, pmov, l, XMM15, 0, src2);
+            // The products fit in the wide lanes, pmuldq and pmuludq use the low half of each.
+            let op = match (i, shift) {
+                (Vaddl(..), _) => PADD[shift],
+                (_, 1) => PMULLW,
+                (_, 2) => PMULLD,
+                _ if signed => PMULDQ,
+                _ => PMULUDQ,
+            };
+            vop3(code, l, op, dest, XMM14, XMM15, true);
+            Ok(())
+        }
+        Vxtn(ty, vsize, dest, src) | Vqmovn(ty, vsize, dest, src) => {
+            // Pack each lane with a value that does not saturate, packssdw and packsswb saturate signed lanes.
+            let (shift, signed, l) = wide_shape(*ty, *vsize, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            match (i, shift, signed) {
+                (Vxtn(..), 1, _) | (Vqmovn(..), 1, false) => {
+                    vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+                    vshifti(code, l, PSHIFTW, SRL, XMM15, XMM15, 8);
+                    let op = if matches!(i, Vxtn(..)) { PAND } else { PMINU[1] };
+                    need(op == PAND || features.sse4_1, i)?;
+                    vop3(code, l, op, XMM15, XMM15, src, true);
+                    vop3(code, l, PACKUSWB, dest, XMM15, XMM15, false);
+                }
+                (Vxtn(..), 2, _) => {
+                    vshifti(code, l, PSHIFTD, SLL, XMM15, src, 16);
+                    vshifti(code, l, PSHIFTD, SRA, XMM15, XMM15, 16);
+                    vop3(code, l, PACKSSDW, dest, XMM15, XMM15, false);
+                }
+                (Vqmovn(..), 2, false) => {
+                    need(features.sse4_1, i)?;
+                    vop3(code, l, PCMPEQD, XMM15, XMM15, XMM15, true);
+                    vshifti(code, l, PSHIFTD, SRL, XMM15, XMM15, 16);
+                    vop3(code, l, PMINU[2], XMM15, XMM15, src, true);
+                    vop3(code, l, PACKUSDW, dest, XMM15, XMM15, false);
+                }
+                (Vqmovn(..), 1, true) => vop3(code, l, PACKSSWB, dest, src, src, false),
+                (Vqmovn(..), 2, true) => vop3(code, l, PACKSSDW, dest, src, src, false),
+                (Vxtn(..), _, _) => {
+                    emit_vop(code, PSHUFD, l, dest, 0, src);
+                    code.push(0x08);
+                }
+                // There is no 64 bit min or max before AVX-512.
+                _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+            }
+            if l {
+                // Gather the low quarter of each half.
+                emit_vop(code, VPERMQ, l, dest, 0, dest);
+                code.push(0x08);
+            }
+            Ok(())
+        }
+        Vaddv(ty, vsize, dest, src) => {
+            // Add the high half to the low half until one lane is left.
+            let (shift, l) = permute_shape(*ty, *vsize, 0, i)?;
+            let padd = iop(*ty, *vsize, PADD, i)?;
+            let (dest, mut src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            let mut bytes = if *vsize == V64 { 8 } else { 16 };
+            if l {
+                emit_vop(code, VEXTRACTI128, l, src, 0, XMM15);
+                code.push(1);
+                vop3(code, false, padd, XMM14, src, XMM15, true);
+                src = XMM14;
+            }
+            while bytes > 1 << shift {
+                bytes /= 2;
+                vshifti(code, false, PSHIFTQ, SRLDQ, XMM15, src, bytes);
+                vop3(code, false, padd, XMM14, src, XMM15, true);
+                src = XMM14;
+            }
+            vmov(code, false, dest, src);
+            Ok(())
+        }
+        Vaddp(ty, vsize, dest, src1, src2) => {
+            let (shift, l) = permute_shape(*ty, *vsize, 0, i)?;
+            let (dest, mut src1, mut src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            if *vsize == V64 {
+                // Take the lanes of both 64 bit sources from one register.
+                vop3(code, l, PUNPCKL[3], XMM14, src1, src2, false);
+                (src1, src2) = (XMM14, XMM14);
+            }
+            // haddps is SSE3 and phaddw SSSE3.
+            need(matches!(ty, S8 | U8 | S64 | U64 | F16) || features.sse4_1, i)?;
+            match (ty, shift) {
+                (F32, _) => vop3(code, l, HADDPS, dest, src1, src2, false),
+                (F64, _) => vop3(code, l, HADDPD, dest, src1, src2, false),
+                (F16, _) => return Err(Error::VectorTypeNotSupported(i.clone())),
+                (_, 0) => {
+                    // Add the odd bytes to the even ones as words and pack the low bytes.
+                    vshifti(code, l, PSHIFTW, SRL, XMM15, src2, 8);
+                    vop3(code, l, PADD[0], XMM15, XMM15, src2, true);
+                    vshifti(code, l, PSHIFTW, SLL, XMM15, XMM15, 8);
+                    vshifti(code, l, PSHIFTW, SRL, XMM15, XMM15, 8);
+                    if src1 == src2 {
+                        vop3(code, l, PACKUSWB, dest, XMM15, XMM15, false);
+                    } else {
+                        vshifti(code, l, PSHIFTW, SRL, XMM14, src1, 8);
+                        vop3(code, l, PADD[0], XMM14, XMM14, src1, true);
+                        vshifti(code, l, PSHIFTW, SLL, XMM14, XMM14, 8);
+                        vshifti(code, l, PSHIFTW, SRL, XMM14, XMM14, 8);
+                        vop3(code, l, PACKUSWB, dest, XMM14, XMM15, false);
+                    }
+                }
+                (_, 3) => {
+                    vop3(code, l, PUNPCKH[3], XMM15, src1, src2, false);
+                    vop3(code, l, PUNPCKL[3], XMM14, src1, src2, false);
+                    vop3(code, l, PADD[3], dest, XMM14, XMM15, true);
+                }
+                _ => vop3(code, l, PHADD[shift - 1], dest, src1, src2, false),
+            }
+            if l {
+                // Each 128 bit half holds a quarter from each source.
+                emit_vop(code, VPERMQ, l, dest, 0, dest);
+                code.push(0xd8);
+            }
+            Ok(())
+        }
+        Vdot(ty @ (S8 | U8), vsize @ (V64 | V128), dest, src1, src2) => {
+            // Multiply and add the even and then the odd bytes as words with pmaddwd.
+            let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+            let ext = if *ty == S8 { SRA } else { SRL };
+            let alias = dest == src1 || dest == src2;
+            need(!alias || *vsize == V64 || features.sse4_1, i)?;
+            for odd in [false, true] {
+                for (reg, src) in [(XMM14, src1), (XMM15, src2)] {
+                    if odd {
+                        vshifti(code, false, PSHIFTW, ext, reg, src, 8);
+                    } else {
+                        vshifti(code, false, PSHIFTW, SLL, reg, src, 8);
+                        vshifti(code, false, PSHIFTW, ext, reg, reg, 8);
+                    }
+                }
+                vop3(code, false, PMADDWD, XMM14, XMM14, XMM15, true);
+                if !alias {
+                    vop3(code, false, PADD[2], dest, dest, XMM14, true);
+                } else if !odd {
+                    // Keep the even sums in r10 and r11 while the sources are still needed.
+                    // 664D0F7EF2        movq r10, xmm14
+                    code.push(0x66);
+                    gen_rr(code, REX_W, &[0x0f, 0x7e], XMM14, R10);
+                    if *vsize == V128 {
+                        gen_lane(code, REX_W, PEXTR[3], XMM14, R11, 1);
+                    }
+                } else {
+                    code.push(0x66);
+                    gen_rr(code, REX_W, &MOVD, XMM15, R10);
+                    if *vsize == V128 {
+                        gen_lane(code, REX_W, PINSR[3], XMM15, R11, 1);
+                    }
+                    vop3(code, false, PADD[2], XMM14, XMM14, XMM15, true);
+                    vop3(code, false, PADD[2], dest, dest, XMM14, true);
+                }
+            }
+            Ok(())
+        }
+        Vdot(S8 | U8, ..) => Err(Error::VectorSizeNotSupported(i.clone())),
+        Vdot(..) => Err(Error::VectorTypeNotSupported(i.clone())),
+        Vld(ty, vsize, v, r, imm) => vgenmem(code, l(*vsize), mem_op(*ty, *vsize, [MOVSS_LD, MOVQ_LD, MOVDQU_LD], i)?, v, r, imm, i),
+        Vst(ty, vsize, v, r, imm) => vgenmem(code, l(*vsize), mem_op(*ty, *vsize, [MOVSS_ST, MOVQ_ST, MOVDQU_ST], i)?, v, r, imm, i),
+        _ => Err(Error::UnsupportedVectorOperation(i.clone()))
+    }
+}
+
+/// Scalar floating point, F16 conversions need F16C and Fma needs FMA.
+pub fn gen_float_x86_64(code: &mut Vec<u8>, i: &Ins, features: Features) -> Result<(), Error> {
+    use Ins::*;
+    use Type::*;
+    match i {
+        Fadd(ty, dest, src1, src2) => vgen3(code, false, sop(*ty, ADD, i)?, dest, src1, src2, true, i),
+        Fsub(ty, dest, src1, src2) => vgen3(code, false, sop(*ty, SUB, i)?, dest, src1, src2, false, i),
+        Fmul(ty, dest, src1, src2) => vgen3(code, false, sop(*ty, MUL, i)?, dest, src1, src2, true, i),
+        Fdiv(ty, dest, src1, src2) => vgen3(code, false, sop(*ty, DIV, i)?, dest, src1, src2, false, i),
+        Fmin(ty, dest, src1, src2) | Fmax(ty, dest, src1, src2) => {
+            let op = sop(*ty, if matches!(i, Fmin(..)) { MIN } else { MAX }, i)?;
+            gen_minmax(code, false, op, sop(*ty, CMP, i)?, dest, src1, src2, i)
+        }
+        Fsqrt(ty, dest, src) => vgen2(code, false, sop(*ty, SQRT, i)?, dest, src, i),
+        Fabs(ty, dest, src) => {
+            // Clear the sign bit.
+            sop(*ty, ADD, i)?;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            vop3(code, false, PCMPEQD, XMM15, XMM15, XMM15, true);
+            if *ty == F32 {
+                vshifti(code, false, PSHIFTD, SRL, XMM15, XMM15, 1);
+            } else {
+                vshifti(code, false, PSHIFTQ, SRL, XMM15, XMM15, 1);
+            }
+            vop3(code, false, ANDPS, dest, src, XMM15, true);
+            Ok(())
+        }
+        Fma(ty, dest, src1, src2, src3) => {
+            sop(*ty, ADD, i)?;
+            if !features.fma {
+                return Err(Error::UnsupportedOperation(i.clone()));
+            }
+            let w = *ty == F64;
+            let (vfmadd213, vfmadd231) = (Op { w, ..VFMADD213SS }, Op { w, ..VFMADD231SS });
+            let (dest, src1, src2, src3) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?, src3.to_x86(i)?);
+            if dest == src3 {
+                emit_vop(code, vfmadd231, false, dest, src1, src2);
+            } else if dest == src1 {
+                emit_vop(code, vfmadd213, false, dest, src2, src3);
+            } else if dest == src2 {
+                emit_vop(code, vfmadd213, false, dest, src1, src3);
+            } else {
+                vmov(code, false, dest, src3);
+                emit_vop(code, vfmadd231, false, dest, src1, src2);
+            }
+            Ok(())
+        }
+        Fcmp(ty, src1, src2) => {
+            // ucomiss sets the flags of an unsigned compare, and ZF, PF and CF if unordered.
+            // B, Sel, Cset and the Cs* instructions test these as the aarch64 fcmp flags.
+            let ucomis = match ty {
+                F32 => op(0x00, 1, UCOMIS),
+                F64 => op(0x66, 1, UCOMIS),
+                _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+            };
+            emit_vop(code, ucomis, false, src1.to_x86(i)?, 0, src2.to_x86(i)?);
+            Ok(())
+        }
+        Fcvt(dty, sty, dest, src) => {
+            let (d, s) = (dest.to_x86(i)?, src.to_x86(i)?);
+            match (dty, sty) {
+                (F32, F32) | (F64, F64) | (F16, F16) => vmov(code, false, d, s),
+                (F64, F32) => emit_vop(code, CVTSS2SD, false, d, d, s),
+                (F32, F64) => emit_vop(code, CVTSD2SS, false, d, d, s),
+                (F32 | F64, F16) if features.f16c => {
+                    emit_vop(code, VCVTPH2PS, false, d, 0, s);
+                    if *dty == F64 {
+                        emit_vop(code, CVTSS2SD, false, d, d, d);
+                    }
+                }
+                (F16, F32) if features.f16c => {
+                    // Round using MXCSR.
+                    emit_vop(code, VCVTPS2PH, false, s, 0, d);
+                    code.push(4);
+                }
+                // There is no direct conversion and rounding twice may differ.
+                (F16, F64) | (F32 | F64, F16) | (F16, F32) => return Err(Error::UnsupportedOperation(i.clone())),
+                _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+            }
+            Ok(())
+        }
+        Scvtf(ty, dest, src) => {
+            let prefix = sop(*ty, CVTSI2, i)?.prefix;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            // Break the dependency on the old value of dest.
+            vop3(code, false, XORPS, dest, dest, dest, true);
+            code.push(prefix);
+            gen_rr(code, REX_W, &[0x0f, CVTSI2], dest, src);
+            Ok(())
+        }
+        Fcvtzs(ty, dest, src) => {
+            // cvttsd2si gives i64::MIN for a NaN or out of range value,
+            // so check the sign of the source if it does.
+            let prefix = sop(*ty, CVTT2SI, i)?.prefix;
+            let (dest, src) = (dest.to_x86(i)?, src.to_x86(i)?);
+            code.push(prefix);
+            gen_rr(code, REX_W, &[0x0f, CVTT2SI], dest, src);
+            // F2480F2CC0        cvttsd2si rax, xmm0
+            // 4883F801          cmp rax, 1
+            // 7114              jno done
+            // 450F57FF          xorps xmm15, xmm15
+            // 66410F2EC7        ucomisd xmm0, xmm15
+            // 7A07              jp nan
+            // 7607              jbe done
+            // 48F7D0            not rax
+            // EB02              jmp done
+            // 31C0       nan:   xor eax, eax
+            gen_rr(code, REX_W, &[0x83], 7, dest);
+            code.push(1);
+            let jno = jump8(code, 0x71);
+            vop3(code, false, XORPS, XMM15, XMM15, XMM15, true);
+            let ucomis = if *ty == F32 { op(0x00, 1, UCOMIS) } else { op(0x66, 1, UCOMIS) };
+            emit_vop(code, ucomis, false, src, 0, XMM15);
+            let jp = jump8(code, 0x7a);
+            let jbe = jump8(code, 0x76);
+            gen_rr(code, REX_W, &[0xf7], 2, dest);
+            let jmp = jump8(code, 0xeb);
+            land8(code, jp);
+            gen_rr(code, 0, &[0x31], dest, dest);
+            land8(code, jno);
+            land8(code, jbe);
+            land8(code, jmp);
+            Ok(())
+        }
+        _ => Err(Error::UnsupportedOperation(i.clone())),
+    }
+}
+
+/// Check for an optional instruction set.
+fn need(feature: bool, i: &Ins) -> Result<(), Error> {
+    if feature { Ok(()) } else { Err(Error::UnsupportedOperation(i.clone())) }
+}
+
+/// The size of a vector operation.
+pub fn vsize(i: &Ins) -> Option<Vsize> {
+    use Ins::*;
+    match i {
+        Vld(_, vsize, ..) | Vst(_, vsize, ..) | Vadd(_, vsize, ..) | Vsub(_, vsize, ..) | Vand(_, vsize, ..)
+        | Vor(_, vsize, ..) | Vxor(_, vsize, ..) | Vshl(_, vsize, ..) | Vshr(_, vsize, ..) | Vmul(_, vsize, ..)
+        | Vdiv(_, vsize, ..) | Vmov(_, vsize, ..) | Vmovi(_, vsize, ..) | Vnot(_, vsize, ..) | Vneg(_, vsize, ..)
+        | Vrecpe(_, vsize, ..) | Vrsqrte(_, vsize, ..) | Vdup(_, vsize, ..) | Vcmp(_, _, vsize, ..) | Vbsl(_, vsize, ..)
+        | Vblend(_, vsize, ..) | Vmin(_, vsize, ..) | Vmax(_, vsize, ..) | Vabs(_, vsize, ..) | Vtbl(vsize, ..)
+        | Vzip(_, vsize, ..) | Vuzp(_, vsize, ..) | Vtrn(_, vsize, ..) | Vext(_, vsize, ..) | Vrev(_, vsize, ..)
+        | Vqadd(_, vsize, ..) | Vqsub(_, vsize, ..) | Vaddl(_, vsize, ..) | Vmull(_, vsize, ..) | Vxtn(_, vsize, ..)
+        | Vqmovn(_, vsize, ..) | Vaddv(_, vsize, ..) | Vaddp(_, vsize, ..) | Vdot(_, vsize, ..) => Some(*vsize),
+        _ => None,
+    }
+}
+
+/// minss and maxss give the second source if either is a NaN,
+/// or in the result with the first source if that is a NaN.
+fn gen_minmax(code: &mut Vec<u8>, l: bool, op: Op, cmp: Op, dest: &V, src1: &V, src2: &V, i: &Ins) -> Result<(), Error> {
+    let (dest, src1, src2) = (dest.to_x86(i)?, src1.to_x86(i)?, src2.to_x86(i)?);
+    vop3(code, l, op, XMM14, src1, src2, false);
+    vop3(code, l, cmp, XMM15, src1, src1, false);
+    code.push(3);
+    vop3(code, l, ANDPS, XMM15, XMM15, src1, true);
+    vop3(code, l, ORPS, XMM14, XMM14, XMM15, true);
+    vmov(code, l, dest, XMM14);
+    Ok(())
+}
+
+/// Load a 128 bit constant into xmm15, or both halves of ymm15, using r11.
+fn gen_const(code: &mut Vec<u8>, l: bool, value: [u64; 2], i: &Ins) -> Result<(), Error> {
+    // movq zeroes the high half.
+    gen_movi(code, &R(11), &value[0], i)?;
+    code.push(0x66);
+    gen_rr(code, REX_W, &MOVD, XMM15, 11);
+    if value[1] == value[0] {
+        emit_vop(code, PSHUFD, false, XMM15, 0, XMM15);
+        code.push(0x44);
+    } else if value[1] != 0 {
+        gen_movi(code, &R(11), &value[1], i)?;
+        gen_lane(code, REX_W, PINSR[3], XMM15, 11, 1);
+    }
+    if l {
+        emit_vop(code, VPERMQ, l, XMM15, 0, XMM15);
+        code.push(0x44);
+    }
+    Ok(())
+}
+
+/// Permutes need at least two lanes and a part of 0 or 1, returns the lane shift and VEX.L
+fn permute_shape(ty: Type, vsize: Vsize, part: u8, i: &Ins) -> Result<(usize, bool), Error> {
+    let shift = lane_shift(ty, 0, i)?;
+    if part > 1 {
+        return Err(Error::InvalidImmediate(i.clone()));
+    }
+    match vsize {
+        Vsize::V64 if shift < 3 => Ok((shift, false)),
+        Vsize::V128 | Vsize::V256 => Ok((shift, l(vsize))),
+        _ => Err(Error::VectorSizeNotSupported(i.clone())),
+    }
+}
+
+/// Widening and narrowing use a V128 or V256 wide vector of 16, 32 or 64 bit integers,
+/// returns the lane shift of the wide type, whether it is signed and VEX.L
+fn wide_shape(ty: Type, vsize: Vsize, i: &Ins) -> Result<(usize, bool, bool), Error> {
+    use Type::*;
+    let signed = match ty {
+        S16 | S32 | S64 => true,
+        U16 | U32 | U64 => false,
+        _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+    };
+    match vsize {
+        Vsize::V128 | Vsize::V256 => Ok((lane_shift(ty, 0, i)?, signed, l(vsize))),
+        _ => Err(Error::VectorSizeNotSupported(i.clone())),
+    }
+}
+
+/// Select the ss or sd form of a scalar floating point opcode.
+fn sop(ty: Type, opcode: u8, i: &Ins) -> Result<Op, Error> {
+    match ty {
+        Type::F32 => Ok(op(0xf3, 1, opcode)),
+        Type::F64 => Ok(op(0xf2, 1, opcode)),
+        _ => Err(Error::VectorTypeNotSupported(i.clone())),
+    }
+}
+
+/// Log2 of the lane size in bytes, checking that the lane is in the low 128 bits.
+fn lane_shift(ty: Type, lane: u8, i: &Ins) -> Result<usize, Error> {
+    use Type::*;
+    let shift = match ty {
+        U8 | S8 => 0,
+        U16 | S16 | F16 => 1,
+        U32 | S32 | F32 => 2,
+        U64 | S64 | F64 => 3,
+        _ => return Err(Error::VectorTypeNotSupported(i.clone())),
+    };
+    if lane >= 16 >> shift {
+        return Err(Error::InvalidImmediate(i.clone()));
+    }
+    Ok(shift)
+}
+
+/// A 66 prefixed lane insert or extract with an immediate lane number.
+fn gen_lane(code: &mut Vec<u8>, w: u8, opcode: &[u8], reg: u8, rm: u8, lane: u8) {
+    code.push(0x66);
+    gen_rr(code, w, opcode, reg, rm);
+    code.push(lane);
+}
+
+/// 256 bit operations use VEX.L
+fn l(vsize: Vsize) -> bool {
+    vsize == Vsize::V256
+}
+
+/// Check an integer type and size, returning VEX.L
+fn il(ty: Type, vsize: Vsize, i: &Ins) -> Result<bool, Error> {
+    iop(ty, vsize, PADD, i).map(|_| l(vsize))
+}
+
+/// Check a floating point type and size, returning VEX.L
+fn fl(ty: Type, vsize: Vsize, i: &Ins) -> Result<bool, Error> {
+    fop(ty, vsize, ADD, i).map(|_| l(vsize))
+}
+
+/// Select the integer opcode by lane size.
+fn iop(ty: Type, vsize: Vsize, ops: [Op; 4], i: &Ins) -> Result<Op, Error> {
+    use Type::*;
+    use Vsize::*;
+    if !matches!(vsize, V64 | V128 | V256) {
+        return Err(Error::VectorSizeNotSupported(i.clone()));
+    }
+    match ty {
+        S8 | U8 => Ok(ops[0]),
+        S16 | U16 => Ok(ops[1]),
+        S32 | U32 => Ok(ops[2]),
+        S64 | U64 => Ok(ops[3]),
+        _ => Err(Error::VectorTypeNotSupported(i.clone())),
+    }
+}
+
+/// Select the scalar (ss, sd) or packed (ps, pd) form of a floating point opcode.
+fn fop(ty: Type, vsize: Vsize, opcode: u8, i: &Ins) -> Result<Op, Error> {
+    use Type::*;
+    use Vsize::*;
+    match (ty, vsize) {
+        (F32, V32) => Ok(op(0xf3, 1, opcode)),
+        (F32, V64 | V128 | V256) => Ok(op(0x00, 1, opcode)),
+        (F64, V64) => Ok(op(0xf2, 1, opcode)),
+        (F64, V128 | V256) => Ok(op(0x66, 1, opcode)),
+        (F32 | F64, _) => Err(Error::VectorSizeNotSupported(i.clone())),
+        _ => Err(Error::VectorTypeNotSupported(i.clone())),
+    }
+}
+
+/// Select a 32, 64, 128 or 256 bit load or store.
+fn mem_op(ty: Type, vsize: Vsize, ops: [Op; 3], i: &Ins) -> Result<Op, Error> {
+    use Type::*;
+    use Vsize::*;
+    match (ty, vsize) {
+        (F32, V32) => Ok(ops[0]),
+        (S8 | U8 | S16 | U16 | S32 | U32 | S64 | U64 | F32 | F64, V64) => Ok(ops[1]),
+        (S8 | U8 | S16 | U16 | S32 | U32 | S64 | U64 | F32 | F64, V128 | V256) => Ok(ops[2]),
+        (S8 | U8 | S16 | U16 | S32 | U32 | S64 | U64 | F32 | F64, _) => Err(Error::VectorSizeNotSupported(i.clone())),
+        _ => Err(Error::VectorTypeNotSupported(i.clone())),
+    }
+}